@@ -0,0 +1,389 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+// The reader as it was before `lexer` and `borrowed`, reading chars one
+// at a time through a `Peekable` and copying every atom, kept as the
+// baseline for the benchmark. Only the number parsing, which used the
+// `onigiri` crate, is done with `str::parse` instead.
+#![allow(clippy::all)]
+
+use std::iter::Peekable;
+use lprp::reader::{Token, LprpError};
+
+pub fn read_all(src: &str) -> Result<Vec<Token>, LprpError> {
+    match read_expr(&mut src.chars().peekable())? {
+        Token::List(l) => Ok(l),
+        _ => Err(LprpError::ReadError),
+    }
+}
+
+// ***** Int, Float *****
+fn is_lprp_num(ch: &char) -> bool {
+    (ch.is_ascii_digit())||(ch == &'-')||(ch == &'.')
+}
+
+fn read_num<I>(chars: &mut Peekable<I>) -> Result<Token, LprpError>
+    where I: Iterator<Item=char>
+{
+    let mut num: Vec<char> = vec![];
+
+    loop {
+        match chars.peek() {
+            Some(c) if is_lprp_num(&c) => {
+                num.push(*c);
+            }
+            _ => {
+                let s: String = num.iter().collect();
+                if let Ok(i) = s.parse::<i64>() {
+                    return Ok(Token::Int(i));
+                } else if let Ok(f) = s.parse::<f64>() {
+                    return Ok(Token::Float(f));
+                } else {
+                    return Err(LprpError::ReadNumError);
+                }
+            }
+        }
+        chars.next();
+    }
+}
+
+// ***** Symbol *****
+fn is_lprp_symbol(ch: &char) -> bool {
+    (ch.is_ascii_alphabetic())||(ch == &'-')
+}
+
+fn read_symbol<I>(chars: &mut Peekable<I>) -> Token
+    where I: Iterator<Item=char>
+{
+    let mut sym = String::new();
+
+    loop {
+        match chars.peek() {
+            Some(c) if is_lprp_symbol(&c) => {
+                sym.push(*c);
+            }
+            _ => {
+                if (&sym[..] == "nil")||(&sym[..] == "NIL") {
+                    return Token::Nil;
+                } else if &sym[..] == "t" {
+                    return Token::T;
+                } else {
+                    return Token::Symbol(sym.to_string());
+                }
+            }
+        }
+        chars.next();
+    }
+}
+
+// ***** Keyword Symbol *****
+fn is_lprp_keyword(ch: &char) -> bool {
+    (is_lprp_symbol(&ch))||(ch == &':')
+}
+
+fn read_keyword<I>(chars: &mut Peekable<I>) -> Result<Token, LprpError>
+    where I: Iterator<Item=char>
+{
+    let mut k: Vec<char> = vec![];
+
+    loop {
+        match chars.peek() {
+            Some(c) if is_lprp_keyword(&c) => {
+                k.push(*c);
+            }
+            _ => {
+                let cnt = &k.iter()
+                    .filter(|&c| c == &':')
+                    .count();
+                if cnt != &1_usize {
+                    return Err(LprpError::SyntaxError);
+                } else {
+                    let k2 = k.iter().collect::<String>();
+                    if !k2.starts_with(':') {
+                        return Err(LprpError::SyntaxError);
+                    } else {
+                        return Ok(Token::Symbol(k2.to_string()));
+                    }
+                }
+            }
+        }
+        chars.next();
+    }
+}
+
+// ***** Special Symbol *****
+fn is_lprp_special(ch: &char) -> bool {
+    (is_lprp_symbol(&ch))||(ch == &'*')
+}
+
+fn read_special<I>(chars: &mut Peekable<I>) -> Result<Token, LprpError>
+    where I: Iterator<Item=char>
+{
+    let mut sp: Vec<char> = vec![];
+    
+    loop {
+        match chars.peek() {
+            Some(c) if is_lprp_special(&c) => {
+                sp.push(*c);
+            }
+            _ => {
+                let cnt = &sp.iter()
+                    .filter(|&c| c == &'*')
+                    .count();
+                if cnt != &2_usize {
+                    return Err(LprpError::SyntaxError);
+                } else {
+                    let sp2 = sp.iter().collect::<String>();
+                    if (sp2.starts_with('*'))&&(sp2.ends_with('*')) {
+                        return Ok(Token::Symbol(sp2.to_string()));
+                    } else {
+                        return Err(LprpError::SyntaxError);
+                    }
+                }
+            }
+        }
+        chars.next();
+    }
+}
+
+// ***** Str *****
+fn read_string<I>(chars: &mut Peekable<I>) -> Token
+    where I: Iterator<Item=char>
+{
+    chars.next();
+    
+    let mut s = String::new();
+
+    loop {
+        match chars.peek() {
+            Some(c) if c != &'\"' => {
+                s.push(*c);
+            }
+            _ => {
+                chars.next();
+                return Token::Str(s.to_string());
+            }
+        }
+        chars.next();
+    }
+}
+
+// ***** List *****
+fn read_list<I>(mut chars: &mut Peekable<I>) -> Result<Token, LprpError>
+    where I: Iterator<Item=char>
+{
+    chars.next();
+
+    let mut v: Vec<Token> = vec![];
+
+    loop {
+        match chars.peek() {
+            Some(c) => {
+                match *c {
+                    '(' => {
+                        match read_list(&mut chars) {
+                            Ok(l) => {
+                                v.push(l);
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    ')' => {
+                        chars.next();
+                        return Ok(Token::List(v.to_vec()));
+                    },
+                    ' '|'\n'|'\t' => {
+                        chars.next();
+                    },
+                    '0' ..= '9'|'-' => {
+                        match read_num(&mut chars) {
+                            Ok(n) => {
+                                v.push(n);
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    'a' ..= 'z'|'A' ..= 'Z' => {
+                        v.push(read_symbol(&mut chars));
+                    },
+                    '*' => {
+                        match read_special(&mut chars) {
+                            Ok(sp) => {
+                                v.push(sp);
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    ':' => {
+                        match read_keyword(&mut chars) {
+                            Ok(k) => {
+                                v.push(k);
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    '\"' => {
+                        v.push(read_string(&mut chars));
+                    },
+                    '\'' => {
+                        match read_quote(&mut chars) {
+                            Ok(q) => {
+                                v.push(q);
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    _ => {
+                        return Err(LprpError::SyntaxError);
+                    }
+                }
+            },
+            _ => return Ok(Token::List(v.to_vec())),
+        }
+    }
+}
+
+// ***** Quote *****
+fn read_quote<I>(mut chars: &mut Peekable<I>) -> Result<Token, LprpError>
+    where I: Iterator<Item=char>
+{
+    chars.next();
+
+    match chars.peek() {
+        Some(c) => {
+            match *c {
+                '(' => {
+                    match read_list(&mut chars) {
+                        Ok(l) => Ok(Token::Quote(Box::new(l))),
+                        Err(e) => Err(e)
+                    }
+                },
+                '0' ..= '9'|'-' => {
+                    match read_num(&mut chars) {
+                        Ok(n) => Ok(Token::Quote(Box::new(n))),
+                        Err(e) => Err(e),
+                    }
+                },
+                'a' ..= 'z'|'A' ..= 'Z' => Ok(Token::Quote(Box::new(read_symbol(&mut chars)))),
+                ':' => {
+                    match read_keyword(&mut chars) {
+                        Ok(k) => Ok(Token::Quote(Box::new(k))),
+                        Err(e) => Err(e),
+                    }
+                },
+                '*' => {
+                    match read_special(&mut chars) {
+                        Ok(sp) => Ok(Token::Quote(Box::new(sp))),
+                        Err(e) => Err(e),
+                    }
+                },
+                '\"' => Ok(Token::Quote(Box::new(read_string(&mut chars)))),
+                '\'' => {
+                    match read_quote(&mut chars) {
+                        Ok(q) => Ok(Token::Quote(Box::new(q))),
+                        Err(e) => Err(e),
+                    }
+                },
+                _ => Err(LprpError::SyntaxError)
+            }
+        },
+        _ => Err(LprpError::SyntaxError)
+    }
+}
+
+fn read_expr<I>(mut chars: &mut Peekable<I>) -> Result<Token, LprpError>
+    where I: Iterator<Item=char>
+{
+    let mut v: Vec<Token> = vec![];
+
+    loop {
+        match chars.peek() {
+            Some(c) => {
+                match *c {
+                    '(' => {
+                        match read_list(&mut chars) {
+                            Ok(l) => {
+                                v.push(l);
+                                chars.next();
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    '0' ..= '9'|'-' => {
+                        match read_num(&mut chars) {
+                            Ok(num) => {
+                                v.push(num);
+                                chars.next();
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    'a' ..= 'z'|'A' ..= 'Z' => {
+                        v.push(read_symbol(&mut chars));
+                        chars.next();
+                    },
+                    '*' => {
+                        match read_special(&mut chars) {
+                            Ok(sp) => {
+                                v.push(sp);
+                                chars.next();
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                       }
+                    },
+                    ':' => {
+                        match read_keyword(&mut chars) {
+                            Ok(k) => {
+                                v.push(k);
+                                chars.next();
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    '\"' => {
+                        v.push(read_string(&mut chars));
+                        chars.next();
+                    },
+                    '\'' => {
+                        match read_quote(&mut chars) {
+                            Ok(q) => {
+                                v.push(q);
+                                chars.next();
+                            },
+                            Err(e) => {
+                                return Err(e);
+                            }
+                        }
+                    },
+                    ' '|'\n'|'\t' => {
+                        chars.next();
+                    }
+                    _ => return Err(LprpError::SyntaxError),
+                }
+            },
+            None => return Ok(Token::List(v.to_vec())),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+use lprp::{binary, borrowed, lexer, reader};
+
+mod baseline;
+
+// Compare the original char reader, kept in `baseline/mod.rs`, with
+// `reader`, which builds on `borrowed` and then copies the atoms, with
+// `borrowed` itself and with the binary format, on a few megabytes of
+// forms the original can read. Run with `cargo bench --bench reader`.
+fn corpus() -> String {
+    let form = "(defun handler (request :port 8080 :tags (\"web\" \"api\"))
+                  (let ((body (read-body request)) (size -1.5))
+                    (if (plusp size) 'ok (error \"bad request\" *context*))))\n";
+    form.repeat(20000)
+}
+
+// The best of a few runs, leaving out dropping the result.
+fn time<T, F: FnMut() -> T>(mut f: F) -> Duration {
+    (0..5).map(|_| {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        drop(result);
+        elapsed
+    }).min().unwrap()
+}
+
+fn main() {
+    let src = corpus();
+    let owned = reader::read_all(&src).unwrap();
+    let encoded = binary::encode_all(&owned).unwrap();
+    assert_eq!(baseline::read_all(&src).unwrap(), owned);
+    assert_eq!(borrowed::read_all(&src).unwrap().len(), owned.len());
+    assert_eq!(binary::decode_all(&encoded).unwrap(), owned);
+
+    let lex = time(|| lexer::lex(&src));
+    let base = time(|| baseline::read_all(&src).unwrap());
+    let read = time(|| reader::read_all(&src).unwrap());
+    let bytes = time(|| borrowed::read_all(&src).unwrap());
+    let decode = time(|| binary::decode_all(&encoded).unwrap());
+    let speedup = |d: Duration| base.as_secs_f64() / d.as_secs_f64();
+
+    println!("{} bytes, {} forms", src.len(), owned.len());
+    println!("lexer    {:>10.2?}", lex);
+    println!("baseline {:>10.2?}", base);
+    println!("reader   {:>10.2?}  x{:.2}", read, speedup(read));
+    println!("borrowed {:>10.2?}  x{:.2}", bytes, speedup(bytes));
+    println!("binary   {:>10.2?}  x{:.2}  ({} bytes)", decode, speedup(decode), encoded.len());
+}
@@ -0,0 +1,32 @@
+use std::time::Instant;
+use lprp::eval::Interp;
+
+// Compare the tree-walking evaluator with the bytecode VM.
+// Run with `cargo bench`.
+const PROGRAMS: &[(&str, &str)] = &[
+    ("fib", "(defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+             (fib 22)"),
+    ("loop", "(defun loop (n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1))))
+              (loop 300000 0)"),
+    ("closures", "(defun counter () (let ((n 0)) (lambda () (setq n (+ n 1)))))
+                  (defun run (c n) (if (= n 0) (c) (progn (c) (run c (- n 1)))))
+                  (run (counter) 100000)"),
+];
+
+fn main() {
+    for (name, src) in PROGRAMS {
+        let start = Instant::now();
+        let expected = Interp::new().eval_str(src).unwrap();
+        let eval = start.elapsed();
+
+        let start = Instant::now();
+        let got = Interp::new().exec_str(src).unwrap();
+        let vm = start.elapsed();
+
+        assert_eq!(got, expected);
+        println!(
+            "{:10} eval {:>10.2?}  vm {:>10.2?}  x{:.2}",
+            name, eval, vm, eval.as_secs_f64() / vm.as_secs_f64()
+            );
+    }
+}
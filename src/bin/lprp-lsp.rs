@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::iter;
+use serde_json::{json, Value as Json};
+use lprp::core;
+use lprp::eval::Interp;
+use lprp::lexer::{self, Atom, Kind, Span};
+use lprp::reader::{self, Token};
+use lprp::syntax::{self, Node, NodeKind};
+use lprp::value::Value;
+
+// A language server for lprp files, speaking LSP over stdio. Documents
+// are synced in full and read with `syntax::parse`, so diagnostics,
+// outlines and selections keep working while a file has errors.
+
+// Lists wider than this are broken over lines when formatting.
+const WIDTH: usize = 80;
+
+// ***** Messages *****
+// Each message is a `Content-Length` header, a blank line and JSON.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            length = n.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// ***** Positions *****
+// A document with the byte offset where each line starts, so offsets and
+// LSP positions convert both ways by binary search. LSP counts columns
+// in UTF-16 units.
+struct Text<'a> {
+    src: &'a str,
+    lines: Vec<usize>,
+}
+
+impl<'a> Text<'a> {
+    fn new(src: &'a str) -> Text<'a> {
+        let lines = iter::once(0).chain(src.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        Text { src, lines }
+    }
+
+    // The line holding byte `offset`, and where it starts.
+    fn line(&self, offset: usize) -> (usize, usize) {
+        let line = self.lines.partition_point(|&start| start <= offset) - 1;
+        (line, self.lines[line])
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let (line, start) = self.line(offset);
+        json!({ "line": line, "character": self.src[start..offset].encode_utf16().count() })
+    }
+
+    fn range(&self, span: Span) -> Json {
+        json!({ "start": self.position(span.start), "end": self.position(span.end) })
+    }
+
+    // The column of a byte offset in chars, as the printer counts them.
+    fn column(&self, offset: usize) -> usize {
+        let (_, start) = self.line(offset);
+        self.src[start..offset].chars().count()
+    }
+
+    // The byte offset of an LSP position, clamped to the text.
+    fn offset(&self, pos: &Json) -> usize {
+        let line = pos["line"].as_u64().unwrap_or(0) as usize;
+        let character = pos["character"].as_u64().unwrap_or(0) as usize;
+        let start = match self.lines.get(line) {
+            Some(&start) => start,
+            None => return self.src.len(),
+        };
+        let mut units = 0;
+        for (i, c) in self.src[start..].char_indices() {
+            if c == '\n' || units >= character {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        self.src.len()
+    }
+}
+
+// ***** Features *****
+fn diagnostics(uri: &str, text: &Text) -> Json {
+    let parse = syntax::parse(text.src);
+    let diagnostics: Vec<Json> = parse.diagnostics.iter().map(|d| {
+        let related: Vec<Json> = d.notes.iter().map(|n| json!({
+            "location": { "uri": uri, "range": text.range(n.span) },
+            "message": n.message,
+        })).collect();
+        json!({
+            "range": text.range(d.span),
+            "severity": 1,
+            "source": "lprp",
+            "message": d.to_string(),
+            "relatedInformation": related,
+        })
+    }).collect();
+    json!({ "uri": uri, "diagnostics": diagnostics })
+}
+
+fn symbol_name(node: &Node) -> Option<&str> {
+    match &node.kind {
+        NodeKind::Atom(Token::Symbol(s)) => Some(s),
+        _ => None,
+    }
+}
+
+// Top-level forms headed by a symbol. `(defun f ...)` is named `f`, with
+// `defun` as its detail; other forms are named by their head.
+fn document_symbols(text: &Text) -> Json {
+    let parse = syntax::parse(text.src);
+    let symbols: Vec<Json> = parse.forms.iter().filter_map(|node| {
+        let tk = node.partial_token()?;
+        let head = match core::get_sym(&tk)? {
+            Token::Symbol(s) => s.clone(),
+            _ => return None,
+        };
+        let (name, detail, span) = match node.children().get(1) {
+            Some(second) if head.starts_with("def") && symbol_name(second).is_some() => {
+                (symbol_name(second).unwrap().to_string(), head.clone(), second.span)
+            },
+            _ => (head.clone(), String::new(), node.children()[0].span),
+        };
+        let kind = match &head[..] {
+            "defun"|"defmacro" => 12,
+            "defvar"|"defparameter" => 13,
+            "defconstant" => 14,
+            _ => 19,
+        };
+        Some(json!({
+            "name": name,
+            "detail": detail,
+            "kind": kind,
+            "range": text.range(node.span),
+            "selectionRange": text.range(span),
+        }))
+    }).collect();
+    Json::Array(symbols)
+}
+
+// The parens of every list, paired up lexically.
+fn paren_pairs(src: &str) -> Vec<(Span, Span)> {
+    let mut open = vec![];
+    let mut pairs = vec![];
+    for lexeme in lexer::lex(src) {
+        match lexeme.kind {
+            Kind::LParen => open.push(lexeme.span),
+            Kind::RParen => pairs.extend(open.pop().map(|o| (o, lexeme.span))),
+            _ => (),
+        }
+    }
+    pairs
+}
+
+// The matching paren of the one just before the cursor, or else the one
+// after it.
+fn matching_parens(text: &Text, at: usize) -> Json {
+    let pairs = paren_pairs(text.src);
+    let pair = pairs.iter().find(|(o, c)| o.end == at || c.end == at)
+        .or_else(|| pairs.iter().find(|(o, c)| o.start == at || c.start == at));
+    match pair {
+        Some((o, c)) => json!([{ "range": text.range(*o) }, { "range": text.range(*c) }]),
+        None => json!([]),
+    }
+}
+
+// From the innermost node around the cursor out to its top-level form.
+fn selection_range(text: &Text, forms: &[Node], at: usize) -> Json {
+    let mut spans = vec![];
+    let mut nodes = forms;
+    while let Some(node) = nodes.iter().find(|n| n.span.start <= at && at <= n.span.end) {
+        spans.push(node.span);
+        nodes = node.children();
+    }
+    if spans.is_empty() {
+        spans.push(Span { start: at, end: at });
+    }
+    spans.into_iter().fold(Json::Null, |parent, span| {
+        let mut r = json!({ "range": text.range(span) });
+        if !parent.is_null() {
+            r["parent"] = parent;
+        }
+        r
+    })
+}
+
+fn arity(min: usize, max: Option<usize>) -> String {
+    let n = match max {
+        Some(m) if m == min => format!("{}", min),
+        Some(m) => format!("{} to {}", min, m),
+        None => format!("at least {}", min),
+    };
+    let plural = if max == Some(1) && min == 1 { "argument" } else { "arguments" };
+    format!("{} {}", n, plural)
+}
+
+fn hover(text: &Text, interp: &Interp, at: usize) -> Json {
+    let src = text.src;
+    let lexeme = lexer::lex(src).into_iter()
+        .find(|l| l.span.start <= at && at <= l.span.end && l.kind == Kind::Atom(Atom::Symbol));
+    let lexeme = match lexeme {
+        Some(l) => l,
+        None => return Json::Null,
+    };
+    match interp.global().lookup(lexeme.text(src)) {
+        Some(Value::Builtin(b)) => json!({
+            "contents": { "kind": "markdown", "value": format!("`{}` builtin, takes {}", b.name, arity(b.min, b.max)) },
+            "range": text.range(lexeme.span),
+        }),
+        _ => Json::Null,
+    }
+}
+
+// Lay a form out with `reader::write`, breaking lists that do not fit
+// after their head and first argument, with the rest indented under them.
+// None if the form has an atom that would not read back, such as a float
+// literal too large to be finite.
+fn layout(tk: &Token, column: usize, out: &mut String) -> Option<()> {
+    let flat = reader::write(tk).ok()?;
+    let wide = column + flat.chars().count() > WIDTH;
+    let items = match tk {
+        Token::List(items) if wide && items.len() > 2 => items,
+        Token::List(items) if wide && items.len() == 2 && items[0].as_symbol().is_none() => items,
+        Token::Quote(q) if wide => {
+            out.push('\'');
+            return layout(q, column + 1, out);
+        },
+        _ => {
+            out.push_str(&flat);
+            return Some(());
+        },
+    };
+    out.push('(');
+    let (first, indent) = match &items[0] {
+        Token::Symbol(s) => {
+            out.push_str(s);
+            out.push(' ');
+            layout(&items[1], column + s.chars().count() + 2, out)?;
+            (2, column + 2)
+        },
+        tk => {
+            layout(tk, column + 1, out)?;
+            (1, column + 1)
+        },
+    };
+    for tk in &items[first..] {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+        layout(tk, indent, out)?;
+    }
+    out.push(')');
+    Some(())
+}
+
+// Edits re-printing each top-level form. Files with errors are left
+// alone, as are forms holding comments, which the printer would drop,
+// and forms that would not print as they read.
+fn formatting(text: &Text) -> Json {
+    let src = text.src;
+    let parse = syntax::parse(src);
+    if !parse.is_ok() {
+        return json!([]);
+    }
+    let comments: Vec<usize> = lexer::lex(src).into_iter()
+        .filter(|l| l.kind == Kind::Comment)
+        .map(|l| l.span.start)
+        .collect();
+    let edits: Vec<Json> = parse.forms.iter().filter_map(|node| {
+        let next = comments.partition_point(|&c| c <= node.span.start);
+        if comments.get(next).is_some_and(|&c| c < node.span.end) {
+            return None;
+        }
+        let mut out = String::new();
+        layout(&node.to_token()?, text.column(node.span.start), &mut out)?;
+        if out == src[node.span.start..node.span.end] {
+            return None;
+        }
+        Some(json!({ "range": text.range(node.span), "newText": out }))
+    }).collect();
+    Json::Array(edits)
+}
+
+// ***** Server *****
+struct Server {
+    docs: HashMap<String, String>,
+    interp: Interp,
+    shutdown: bool,
+}
+
+impl Server {
+    fn doc(&self, params: &Json) -> Option<Text<'_>> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        self.docs.get(uri).map(|src| Text::new(src))
+    }
+
+    // The result of a request, or an error code and message.
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        let at = |text: &Text| text.offset(&params["position"]);
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "documentSymbolProvider": true,
+                    "documentHighlightProvider": true,
+                    "selectionRangeProvider": true,
+                    "documentFormattingProvider": true,
+                    "hoverProvider": true,
+                },
+                "serverInfo": { "name": "lprp-lsp", "version": env!("CARGO_PKG_VERSION") },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            },
+            "textDocument/documentSymbol" => self.doc(params).map_or(Json::Null, |t| document_symbols(&t)),
+            "textDocument/documentHighlight" => self.doc(params).map_or(Json::Null, |t| matching_parens(&t, at(&t))),
+            "textDocument/selectionRange" => {
+                let text = match self.doc(params) {
+                    Some(text) => text,
+                    None => return Ok(Json::Null),
+                };
+                let forms = syntax::parse(text.src).forms;
+                let positions = params["positions"].as_array().map_or(&[][..], |p| &p[..]);
+                positions.iter().map(|p| selection_range(&text, &forms, text.offset(p))).collect()
+            },
+            "textDocument/formatting" => self.doc(params).map_or(Json::Null, |t| formatting(&t)),
+            "textDocument/hover" => self.doc(params).map_or(Json::Null, |t| hover(&t, &self.interp, at(&t))),
+            _ => return Err((-32601, format!("unknown method {}", method))),
+        };
+        Ok(result)
+    }
+
+    // Diagnostics to publish after a notification, if any.
+    fn notification(&mut self, method: &str, params: &Json) -> Option<Json> {
+        let uri = params["textDocument"]["uri"].as_str()?.to_string();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str()?,
+            // Full sync: the last change holds the whole text.
+            "textDocument/didChange" => params["contentChanges"].as_array()?.last()?["text"].as_str()?,
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                return Some(json!({ "uri": uri, "diagnostics": [] }));
+            },
+            _ => return None,
+        };
+        let diagnostics = diagnostics(&uri, &Text::new(text));
+        self.docs.insert(uri, text.to_string());
+        Some(diagnostics)
+    }
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut server = Server { docs: HashMap::new(), interp: Interp::new(), shutdown: false };
+    let send = |output: &mut io::Stdout, message: Json| {
+        write_message(output, &message).unwrap_or_else(|e| {
+            eprintln!("lprp-lsp: {}", e);
+            std::process::exit(1);
+        })
+    };
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("lprp-lsp: {}", e);
+                std::process::exit(1);
+            },
+        };
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        if method == "exit" {
+            std::process::exit(if server.shutdown { 0 } else { 1 });
+        }
+        match message.get("id") {
+            Some(id) => {
+                let reply = match server.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, m)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": m } }),
+                };
+                send(&mut output, reply);
+            },
+            None => {
+                if let Some(params) = server.notification(method, params) {
+                    let n = json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": params });
+                    send(&mut output, n);
+                }
+            },
+        }
+    }
+}
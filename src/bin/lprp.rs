@@ -0,0 +1,262 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use lprp::eval::Interp;
+#[cfg(feature = "json")]
+use lprp::json::{Encoding, SymbolStyle, KeywordStyle, QuoteStyle, BooleanStyle};
+use lprp::reader::Token;
+#[cfg(feature = "json")]
+use lprp::reader::{read_all, write};
+use lprp::syntax;
+use lprp::value::Value;
+
+const USAGE: &str = "\
+usage: lprp [--read-only] [file|- [args...]]    run a script
+       lprp [--read-only] -e expr [args...]     evaluate expr and print its value
+       lprp json [options] [file]               S-expressions to JSON
+       lprp sexp [options] [file]               JSON to S-expressions
+
+Reads stdin when no file is given. The args are bound to *argv*, a list
+of strings. --read-only only checks that the input reads, and takes any
+number of files; use it in pre-commit hooks.
+
+exit status: 0 success, 1 an evaluation error, 2 bad usage,
+             3 a syntax error, 4 an unreadable file
+
+conversion options:
+  --symbols tagged|bare|prefixed[:C]
+  --keywords symbols|objects
+  --quotes tagged|list
+  --booleans json|symbols
+  --forms        convert every form of the file, as a JSON array
+  --compact      write JSON on one line";
+
+fn usage(message: &str) -> ! {
+    eprintln!("lprp: {}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("lprp: {}", message);
+    process::exit(1);
+}
+
+// Input that does not read, as S-expressions or as JSON.
+#[cfg(feature = "json")]
+fn syntax_error(message: String) -> ! {
+    eprintln!("lprp: {}", message);
+    process::exit(EXIT_SYNTAX);
+}
+
+fn input(file: Option<&String>) -> String {
+    let mut s = String::new();
+    let result = match file {
+        Some(path) => fs::read_to_string(path).map(|text| s = text),
+        None => io::stdin().read_to_string(&mut s).map(|_| ()),
+    };
+    if let Err(e) = result {
+        eprintln!("lprp: {}: {}", file.map_or("stdin", |f| &f[..]), e);
+        process::exit(EXIT_IO);
+    }
+    s
+}
+
+// ***** Running *****
+const EXIT_EVAL: i32 = 1;
+const EXIT_SYNTAX: i32 = 3;
+const EXIT_IO: i32 = 4;
+
+// A file, stdin (`-`) or an `-e` expression, with the name to report.
+struct Script {
+    name: String,
+    src: String,
+}
+
+impl Script {
+    fn load(file: Option<&String>) -> Script {
+        let file = file.filter(|f| f.as_str() != "-");
+        let mut src = input(file);
+        // Leave a `#!` line out, keeping the lines numbered.
+        if src.starts_with("#!") {
+            src.replace_range(..src.find('\n').unwrap_or(src.len()), "");
+        }
+        Script { name: file.map_or("stdin".to_string(), |f| f.clone()), src }
+    }
+
+    // The forms, or None after printing every syntax error.
+    fn read(&self) -> Option<Vec<Token>> {
+        let parse = syntax::parse(&self.src);
+        for d in &parse.diagnostics {
+            let (line, col) = syntax::position(&self.src, d.span.start);
+            eprintln!("{}:{}:{}: {}", self.name, line + 1, col + 1, d);
+        }
+        if parse.is_ok() { Some(parse.tokens()) } else { None }
+    }
+}
+
+fn run(args: &[String]) {
+    let mut read_only = false;
+    let mut expr = None;
+    let mut args = args.iter();
+    let mut rest = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--read-only" => read_only = true,
+            "-e" => expr = Some(args.next().unwrap_or_else(|| usage("-e needs an expression")).clone()),
+            "--" => {
+                rest.extend(args.cloned());
+                break;
+            },
+            a if a.starts_with('-') && a != "-" => usage(&format!("unknown option {}", a)),
+            _ => {
+                rest.push(arg.clone());
+                rest.extend(args.cloned());
+                break;
+            },
+        }
+    }
+
+    // An expression's value is printed; a script prints what it likes.
+    let print = expr.is_some();
+    let (script, argv) = match expr {
+        Some(src) => (Script { name: "-e".to_string(), src }, &rest[..]),
+        None if read_only && rest.len() > 1 => {
+            let failed = rest.iter().filter(|file| Script::load(Some(file)).read().is_none()).count();
+            process::exit(if failed == 0 { 0 } else { EXIT_SYNTAX });
+        },
+        None => (Script::load(rest.first()), rest.get(1..).unwrap_or(&[])),
+    };
+    let forms = script.read().unwrap_or_else(|| process::exit(EXIT_SYNTAX));
+    if read_only {
+        return;
+    }
+
+    let mut interp = Interp::new();
+    interp.define("*argv*", Value::list(argv.iter().map(|a| Value::Str(a.clone())).collect()));
+    let mut last = Value::Nil;
+    for tk in &forms {
+        last = interp.eval(tk).unwrap_or_else(|e| {
+            eprintln!("lprp: {}: {}", script.name, e);
+            process::exit(EXIT_EVAL);
+        });
+    }
+    if print {
+        println!("{}", last);
+    }
+}
+
+// ***** Conversion *****
+#[cfg(feature = "json")]
+struct Options {
+    encoding: Encoding,
+    forms: bool,
+    compact: bool,
+    file: Option<String>,
+}
+
+#[cfg(feature = "json")]
+fn options(args: &[String]) -> Options {
+    let mut opts = Options { encoding: Encoding::new(), forms: false, compact: false, file: None };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage(&format!("{} needs a value", arg))).as_str();
+        let enc = opts.encoding.clone();
+        opts.encoding = match arg.as_str() {
+            "--symbols" => match value() {
+                "tagged" => enc.symbols(SymbolStyle::Tagged),
+                "bare" => enc.symbols(SymbolStyle::Bare),
+                "prefixed" => enc.symbols(SymbolStyle::Prefixed('$')),
+                v if v.starts_with("prefixed:") && v.chars().count() == 10 => {
+                    enc.symbols(SymbolStyle::Prefixed(v.chars().last().unwrap()))
+                },
+                v => usage(&format!("bad --symbols {}", v)),
+            },
+            "--keywords" => match value() {
+                "symbols" => enc.keywords(KeywordStyle::Symbols),
+                "objects" => enc.keywords(KeywordStyle::Objects),
+                v => usage(&format!("bad --keywords {}", v)),
+            },
+            "--quotes" => match value() {
+                "tagged" => enc.quotes(QuoteStyle::Tagged),
+                "list" => enc.quotes(QuoteStyle::List),
+                v => usage(&format!("bad --quotes {}", v)),
+            },
+            "--booleans" => match value() {
+                "json" => enc.booleans(BooleanStyle::Json),
+                "symbols" => enc.booleans(BooleanStyle::Symbols),
+                v => usage(&format!("bad --booleans {}", v)),
+            },
+            "--forms" => {
+                opts.forms = true;
+                enc
+            },
+            "--compact" => {
+                opts.compact = true;
+                enc
+            },
+            a if a.starts_with("--") => usage(&format!("unknown option {}", a)),
+            _ if opts.file.is_some() => usage("more than one file"),
+            _ => {
+                opts.file = Some(arg.clone());
+                enc
+            },
+        };
+    }
+    opts
+}
+
+#[cfg(feature = "json")]
+fn print_sexp(tk: &Token) {
+    println!("{}", write(tk).unwrap_or_else(|e| fail(e.to_string())));
+}
+
+#[cfg(feature = "json")]
+fn to_json(args: &[String]) {
+    let opts = options(args);
+    let forms = read_all(&input(opts.file.as_ref())).unwrap_or_else(|e| syntax_error(e.to_string()));
+    let json = match (&forms[..], opts.forms) {
+        (_, true) => forms.iter().map(|tk| opts.encoding.to_json(tk)).collect(),
+        ([tk], false) => opts.encoding.to_json(tk),
+        (_, false) => fail(format!("expected one form, found {}; use --forms", forms.len())),
+    };
+    let json = json.unwrap_or_else(|e| fail(e.to_string()));
+    let text = match opts.compact {
+        true => serde_json::to_string(&json),
+        false => serde_json::to_string_pretty(&json),
+    };
+    println!("{}", text.unwrap_or_else(|e| fail(e.to_string())));
+}
+
+#[cfg(feature = "json")]
+fn to_sexp(args: &[String]) {
+    let opts = options(args);
+    let json: serde_json::Value = serde_json::from_str(&input(opts.file.as_ref()))
+        .unwrap_or_else(|e| syntax_error(format!("JSON Error: {}", e)));
+    let from_json = |j| opts.encoding.from_json(j).unwrap_or_else(|e| fail(e.to_string()));
+    match (&json, opts.forms) {
+        (serde_json::Value::Array(a), true) => a.iter().map(from_json).for_each(|tk| print_sexp(&tk)),
+        (_, true) => fail("--forms needs a JSON array".to_string()),
+        (j, false) => print_sexp(&from_json(j)),
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn to_json(_args: &[String]) {
+    fail("built without the json feature".to_string());
+}
+
+#[cfg(not(feature = "json"))]
+fn to_sexp(_args: &[String]) {
+    fail("built without the json feature".to_string());
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|s| &s[..]) {
+        Some("json") => to_json(&args[1..]),
+        Some("sexp") => to_sexp(&args[1..]),
+        Some("-h")|Some("--help") => println!("{}", USAGE),
+        _ => run(&args),
+    }
+}
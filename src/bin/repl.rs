@@ -1,27 +1,270 @@
-use lprp::reader::read;
-use std::error::Error;
-use std::io::{self, Write};
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use lprp::eval::{Interp, SPECIAL_FORMS};
+use lprp::reader::read_all;
+use lprp::syntax;
+use lprp::value::Value;
 
-fn main() {
+// An input runs over lines until its parens balance, with the
+// continuation prompt lined up under the first.
+const PROMPT: &str = "LPRP>> ";
+const CONTINUE: &str = "  ...> ";
+
+const COMMANDS: &[&str] = &[":load", ":type", ":ast", ":time", ":env", ":reset", ":help", ":quit"];
+
+const HELP: &str = "\
+:load FILE   evaluate the forms of FILE
+:type EXPR   print the type of the value of EXPR
+:ast EXPR    print the tokens EXPR reads as
+:time EXPR   evaluate EXPR and print how long it took
+:env         list the bindings made in this session
+:reset       start over with a fresh environment
+:help        print this help
+:quit        leave, as does Ctrl-D
+
+Tab completes commands, special forms and bound names.";
+
+// ***** Editor *****
+// Shows the paren matching the one at the cursor, and completes names.
+struct LprpHelper {
+    brackets: MatchingBracketHighlighter,
+    // Special forms and the names bound in the session, sorted.
+    names: Vec<String>,
+}
+
+impl LprpHelper {
+    fn update(&mut self, interp: &Interp) {
+        let bound = interp.global().bindings().into_iter().map(|(name, _)| name);
+        self.names = SPECIAL_FORMS.iter().map(|s| s.to_string()).chain(bound).collect();
+        self.names.sort();
+        self.names.dedup();
+    }
+}
+
+// The start of the word before `pos` and the names it could complete to.
+// A command can only start the line.
+fn candidates(names: &[String], line: &str, pos: usize) -> (usize, Vec<String>) {
+    let start = line[..pos].rfind(|c: char| c.is_whitespace() || "()'\"".contains(c)).map_or(0, |i| i + 1);
+    let word = &line[start..pos];
+    let found = match (start, word.starts_with(':')) {
+        (0, true) => COMMANDS.iter().filter(|c| c.starts_with(word)).map(|c| c.to_string()).collect(),
+        _ => names.iter().filter(|n| n.starts_with(word)).cloned().collect(),
+    };
+    (start, found)
+}
+
+#[test]
+fn test_candidates() {
+    let names: Vec<String> = vec!["car".to_string(), "cdr".to_string(), "cond".to_string()];
+    assert_eq!(candidates(&names, "(c", 2), (1, vec!["car".to_string(), "cdr".to_string(), "cond".to_string()]));
+    assert_eq!(candidates(&names, "(f 'co x)", 6), (4, vec!["cond".to_string()]));
+    assert_eq!(candidates(&names, ":t", 2), (0, vec![":type".to_string(), ":time".to_string()]));
+    assert_eq!(candidates(&names, "(:t", 3), (1, vec![]));
+}
+
+impl Completer for LprpHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(candidates(&self.names, line, pos))
+    }
+}
+
+impl Hinter for LprpHelper {
+    type Hint = String;
+}
+
+impl Validator for LprpHelper {}
+
+impl Highlighter for LprpHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.brackets.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.brackets.highlight_char(line, pos, kind)
+    }
+}
+
+impl Helper for LprpHelper {}
+
+type LprpEditor = Editor<LprpHelper, DefaultHistory>;
+
+// `$LPRP_HISTORY`, or `.lprp_history` in the home directory.
+fn history_path() -> Option<PathBuf> {
+    env::var_os("LPRP_HISTORY").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".lprp_history")))
+}
+
+// Read lines until they make a whole input. None at the end of input;
+// Ctrl-C drops what has been typed so far.
+fn read_input(editor: &mut LprpEditor) -> Result<Option<String>, ReadlineError> {
+    let mut input = String::new();
     loop {
-        let mut s = String::new();
-        print!("LPRP>> ");
-        io::stdout().flush().expect("Couldn't flush stdout.");
-        io::stdin().read_line(&mut s).expect("Failed.");
+        let prompt = if input.is_empty() { PROMPT } else { CONTINUE };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+            },
+            Err(ReadlineError::Interrupted) => return Ok(Some(String::new())),
+            // Unfinished input is still read, so that its error is shown.
+            Err(ReadlineError::Eof) if !input.is_empty() => return Ok(Some(input)),
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if syntax::is_complete(&input) {
+            return Ok(Some(input));
+        }
+    }
+}
 
-        if (s.starts_with("quit"))||(s.starts_with("(quit)")) {
-            break;
-        } else {
-            let s2 = &s.trim_end_matches("\n");
-            match read(&s2) {
-                Ok(r) => {
-                    println!("{:?}", r);
+// ***** Eval *****
+// Evaluate each form and print its value. An error is printed and ends
+// the input, but not the session. Returns whether every form evaluated.
+fn eval(interp: &mut Interp, input: &str) -> bool {
+    let forms = match read_all(input) {
+        Ok(forms) => forms,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        },
+    };
+    for tk in &forms {
+        match interp.eval(tk) {
+            Ok(v) => println!("{}", v),
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            },
+        }
+    }
+    true
+}
+
+// ***** Commands *****
+// Run `:name arg`. Returns false to leave the REPL.
+fn command(interp: &mut Interp, name: &str, arg: &str) -> bool {
+    let needs = |what: &str| {
+        if arg.is_empty() {
+            eprintln!("usage: {} {}", name, what);
+        }
+        !arg.is_empty()
+    };
+    match name {
+        ":load" if needs("FILE") => {
+            match fs::read_to_string(arg) {
+                Ok(src) => {
+                    match interp.eval_str(&src) {
+                        Ok(v) => println!("{}", v),
+                        Err(e) => eprintln!("{}: {}", arg, e),
+                    }
                 },
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                    break;
+                Err(e) => eprintln!("{}: {}", arg, e),
+            }
+        },
+        ":type" if needs("EXPR") => {
+            if let Some(v) = eval_quietly(interp, arg) {
+                println!("{}", v.type_name());
+            }
+        },
+        ":ast" if needs("EXPR") => {
+            match read_all(arg) {
+                Ok(forms) => forms.iter().for_each(|tk| println!("{:#?}", tk)),
+                Err(e) => eprintln!("{}", e),
+            }
+        },
+        ":time" if needs("EXPR") => {
+            let start = Instant::now();
+            if eval(interp, arg) {
+                println!("; {:.3?}", start.elapsed());
+            }
+        },
+        ":env" => {
+            for (name, v) in interp.global().bindings() {
+                if !matches!(v, Value::Builtin(_)) {
+                    println!("{} = {}", name, v);
                 }
             }
+        },
+        ":reset" => *interp = Interp::new(),
+        ":help" => println!("{}", HELP),
+        ":quit" => return false,
+        _ => (),
+    }
+    true
+}
+
+// The value of the last form, without printing the others.
+fn eval_quietly(interp: &mut Interp, input: &str) -> Option<Value> {
+    match interp.eval_str(input) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        },
+    }
+}
+
+fn main() {
+    let mut editor = LprpEditor::new().unwrap_or_else(|e| {
+        eprintln!("repl: {}", e);
+        std::process::exit(1);
+    });
+    editor.set_helper(Some(LprpHelper { brackets: MatchingBracketHighlighter::new(), names: vec![] }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There is no history file before the first session.
+        let _ = editor.load_history(path);
+    }
+
+    let mut interp = Interp::new();
+    loop {
+        if let Some(helper) = editor.helper_mut() {
+            helper.update(&interp);
+        }
+        let input = match read_input(&mut editor) {
+            Ok(Some(input)) => input,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("repl: {}", e);
+                break;
+            },
+        };
+        let input = input.trim();
+        if input.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input);
+        if input == "quit" || input == "(quit)" {
+            break;
+        }
+        // A known command; anything else, such as a keyword, is evaluated.
+        let (name, arg) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        if COMMANDS.contains(&name) {
+            if !command(&mut interp, name, arg.trim()) {
+                break;
+            }
+            continue;
+        }
+        eval(&mut interp, input);
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("repl: {}: {}", path.display(), e);
         }
     }
 }
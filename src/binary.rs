@@ -0,0 +1,443 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::slice;
+use std::str;
+use crate::borrowed;
+use crate::reader::{Token, LprpError};
+#[cfg(test)]
+use crate::reader::read;
+#[cfg(test)]
+use crate::core::Rng;
+
+
+// ***** Format *****
+// A binary encoding of tokens, for caching parsed data. A file is
+//
+//   "LPRB" version symbols forms
+//
+// where `symbols` is a count followed by each symbol name, and `forms`
+// is a count followed by each token. Counts, lengths and indices are
+// LEB128 varints. A token starts with a varint holding a tag in its low
+// three bits and a payload above them:
+//
+//   0 const   payload 0 is nil, 1 is t, and 2 is an integer too large
+//             to be tagged, given as 8 bytes little-endian
+//   1 int     the zigzag-encoded integer
+//   2 float   8 bytes little-endian follow
+//   3 symbol  the index of the name in `symbols`
+//   4 string  the length; the UTF-8 bytes follow
+//   5 quote   the quoted token follows
+//   6 list    the length; the elements follow
+//
+// Each symbol name is stored once however often it is used.
+pub const MAGIC: &[u8; 4] = b"LPRB";
+pub const VERSION: u64 = 1;
+
+// Deeper nesting is rejected when decoding, so bad input cannot
+// overflow the stack, and so it is also an error when encoding.
+const MAX_DEPTH: usize = 256;
+
+const CONST: u64 = 0;
+const INT: u64 = 1;
+const FLOAT: u64 = 2;
+const SYMBOL: u64 = 3;
+const STR: u64 = 4;
+const QUOTE: u64 = 5;
+const LIST: u64 = 6;
+
+const NIL: u64 = 0;
+const T: u64 = 1;
+const BIG_INT: u64 = 2;
+
+fn binary_error<T>(message: String) -> Result<T, LprpError> {
+    Err(LprpError::BinaryError(message))
+}
+
+// ***** Encoder *****
+pub fn encode(tk: &Token) -> Result<Vec<u8>, LprpError> {
+    encode_all(slice::from_ref(tk))
+}
+
+pub fn encode_all(forms: &[Token]) -> Result<Vec<u8>, LprpError> {
+    let mut enc = Encoder { symbols: HashMap::new(), names: vec![], body: vec![] };
+    put_varint(&mut enc.body, forms.len() as u64);
+    for tk in forms {
+        enc.token(tk, 0)?;
+    }
+
+    let mut out = MAGIC.to_vec();
+    put_varint(&mut out, VERSION);
+    put_varint(&mut out, enc.names.len() as u64);
+    for name in &enc.names {
+        put_varint(&mut out, name.len() as u64);
+        out.extend(name.as_bytes());
+    }
+    out.extend(enc.body);
+    Ok(out)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+struct Encoder<'t> {
+    symbols: HashMap<&'t str, usize>,
+    names: Vec<&'t str>,
+    body: Vec<u8>,
+}
+
+impl<'t> Encoder<'t> {
+    fn tag(&mut self, tag: u64, payload: u64) {
+        put_varint(&mut self.body, payload << 3 | tag);
+    }
+
+    fn token(&mut self, tk: &'t Token, depth: usize) -> Result<(), LprpError> {
+        if depth > MAX_DEPTH {
+            return binary_error(format!("nesting is deeper than {}", MAX_DEPTH));
+        }
+        match tk {
+            Token::Nil => self.tag(CONST, NIL),
+            Token::T => self.tag(CONST, T),
+            Token::Int(i) => {
+                let zigzag = (*i << 1 ^ *i >> 63) as u64;
+                if zigzag >> 61 == 0 {
+                    self.tag(INT, zigzag);
+                } else {
+                    self.tag(CONST, BIG_INT);
+                    self.body.extend(&i.to_le_bytes());
+                }
+            },
+            Token::Float(f) => {
+                self.tag(FLOAT, 0);
+                self.body.extend(&f.to_bits().to_le_bytes());
+            },
+            Token::Symbol(s) => {
+                let next = self.names.len();
+                let index = *self.symbols.entry(s).or_insert(next);
+                if index == next {
+                    self.names.push(s);
+                }
+                self.tag(SYMBOL, index as u64);
+            },
+            Token::Str(s) => {
+                self.tag(STR, s.len() as u64);
+                self.body.extend(s.as_bytes());
+            },
+            Token::Quote(q) => {
+                self.tag(QUOTE, 0);
+                return self.token(q, depth + 1);
+            },
+            Token::List(l) => {
+                self.tag(LIST, l.len() as u64);
+                for tk in l {
+                    self.token(tk, depth + 1)?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+// ***** Decoder *****
+// The forms of an encoded file, decoded one at a time. The symbol table
+// is read when the decoder is made, and the symbols and strings of the
+// `borrowed::Token`s it yields are slices of the input.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    symbols: Vec<&'a str>,
+    remaining: usize,
+}
+
+pub fn decode(input: &[u8]) -> Result<Token, LprpError> {
+    let mut forms = decode_all(input)?;
+    match forms.len() {
+        1 => Ok(forms.pop().unwrap()),
+        n => binary_error(format!("expected one form, found {}", n)),
+    }
+}
+
+pub fn decode_all(input: &[u8]) -> Result<Vec<Token>, LprpError> {
+    Decoder::new(input)?.map(|tk| tk.map(borrowed::Token::into_owned)).collect()
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Result<Decoder<'a>, LprpError> {
+        if !input.starts_with(MAGIC) {
+            return binary_error("not an lprp binary file".to_string());
+        }
+        let mut dec = Decoder { input, pos: MAGIC.len(), symbols: vec![], remaining: 0 };
+        match dec.varint()? {
+            VERSION => {},
+            v => return binary_error(format!("unsupported version {}", v)),
+        }
+        let count = dec.length()?;
+        dec.symbols.reserve(count);
+        for _ in 0..count {
+            let len = dec.length()?;
+            let name = dec.utf8(len)?;
+            dec.symbols.push(name);
+        }
+        dec.remaining = dec.length()?;
+        if dec.remaining == 0 {
+            dec.end()?;
+        }
+        Ok(dec)
+    }
+
+    pub fn symbols(&self) -> &[&'a str] {
+        &self.symbols
+    }
+
+    fn end(&self) -> Result<(), LprpError> {
+        match self.pos == self.input.len() {
+            true => Ok(()),
+            false => self.error("trailing bytes"),
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, LprpError> {
+        binary_error(format!("{} at byte {}", message, self.pos))
+    }
+
+    fn varint(&mut self) -> Result<u64, LprpError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = match self.input.get(self.pos) {
+                Some(b) => *b,
+                None => return self.error("unexpected end"),
+            };
+            self.pos += 1;
+            if shift == 63 && b > 1 {
+                break;
+            }
+            n |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        self.error("varint is too long")
+    }
+
+    // A count of things that take at least a byte each, so it cannot be
+    // more than the bytes left.
+    fn length(&mut self) -> Result<usize, LprpError> {
+        let n = self.varint()?;
+        self.fits(n)
+    }
+
+    fn fits(&self, n: u64) -> Result<usize, LprpError> {
+        match usize::try_from(n) {
+            Ok(n) if n <= self.input.len() - self.pos => Ok(n),
+            _ => self.error(&format!("length {} is past the end", n)),
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], LprpError> {
+        match self.input.get(self.pos..self.pos + n) {
+            Some(bytes) => {
+                self.pos += n;
+                Ok(bytes)
+            },
+            None => self.error("unexpected end"),
+        }
+    }
+
+    fn utf8(&mut self, n: usize) -> Result<&'a str, LprpError> {
+        let bytes = self.bytes(n)?;
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => {
+                self.pos -= n;
+                self.error("invalid UTF-8")
+            },
+        }
+    }
+
+    fn word(&mut self) -> Result<[u8; 8], LprpError> {
+        Ok(<[u8; 8]>::try_from(self.bytes(8)?).unwrap())
+    }
+
+    fn token(&mut self, depth: usize) -> Result<borrowed::Token<'a>, LprpError> {
+        if depth > MAX_DEPTH {
+            return self.error("nesting is too deep");
+        }
+        let start = self.pos;
+        let n = self.varint()?;
+        let (tag, payload) = (n & 7, n >> 3);
+        match (tag, payload) {
+            (CONST, NIL) => Ok(borrowed::Token::Nil),
+            (CONST, T) => Ok(borrowed::Token::T),
+            (CONST, BIG_INT) => Ok(borrowed::Token::Int(i64::from_le_bytes(self.word()?))),
+            (INT, z) => Ok(borrowed::Token::Int((z >> 1) as i64 ^ -((z & 1) as i64))),
+            (FLOAT, 0) => Ok(borrowed::Token::Float(f64::from_bits(u64::from_le_bytes(self.word()?)))),
+            (SYMBOL, i) => {
+                match self.symbols.get(i as usize) {
+                    Some(name) => Ok(borrowed::Token::Symbol(Cow::Borrowed(name))),
+                    None => {
+                        self.pos = start;
+                        self.error(&format!("no symbol {}", i))
+                    },
+                }
+            },
+            (STR, len) => {
+                let len = self.fits(len)?;
+                Ok(borrowed::Token::Str(Cow::Borrowed(self.utf8(len)?)))
+            },
+            (QUOTE, 0) => Ok(borrowed::Token::Quote(Box::new(self.token(depth + 1)?))),
+            (LIST, len) => {
+                let len = self.fits(len)?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(self.token(depth + 1)?);
+                }
+                Ok(borrowed::Token::List(items))
+            },
+            _ => {
+                self.pos = start;
+                self.error(&format!("bad tag {}", n))
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Result<borrowed::Token<'a>, LprpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let result = self.token(0).and_then(|tk| {
+            match self.remaining {
+                0 => self.end().map(|_| tk),
+                _ => Ok(tk),
+            }
+        });
+        if result.is_err() {
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+}
+
+#[test]
+fn test_binary() {
+    let tk = read("(defun f (x) (list x 'x \"str\" 1.5 -3 t nil x))").unwrap();
+    let bytes = encode(&tk).unwrap();
+    assert!(bytes.starts_with(b"LPRB\x01\x04\x05defun\x01f\x01x\x04list\x01"));
+    assert_eq!(decode(&bytes), Ok(tk.clone()));
+    assert_eq!(Decoder::new(&bytes).unwrap().symbols(), &["defun", "f", "x", "list"]);
+    let first = Decoder::new(&bytes).unwrap().next().unwrap().unwrap();
+    assert_eq!(first, borrowed::Token::from(&tk));
+    assert!(matches!(first.as_list().unwrap()[0], borrowed::Token::Symbol(Cow::Borrowed("defun"))));
+
+    let big = Token::List(vec![
+        Token::Int(i64::MIN), Token::Int(i64::MAX), Token::Int(1 << 60), Token::Int(-(1 << 60) - 1),
+        Token::Float(f64::NAN), Token::Float(-0.0), Token::Str(String::new()), Token::List(vec![]),
+    ]);
+    let forms = vec![tk, big, Token::Nil];
+    let bytes = encode_all(&forms).unwrap();
+    assert_eq!(decode_all(&bytes), Ok(forms));
+    assert_eq!(decode(&bytes), Err(LprpError::BinaryError("expected one form, found 3".to_string())));
+
+    let err = |bytes: &[u8]| match decode_all(bytes) {
+        Err(LprpError::BinaryError(m)) => m,
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(err(b"LPRS\x01"), "not an lprp binary file");
+    assert_eq!(err(b"LPRB\x02"), "unsupported version 2");
+    assert_eq!(err(b"LPRB\x01\x00\x01\x0b"), "no symbol 1 at byte 7");
+    assert_eq!(err(b"LPRB\x01\x00\x01\x07"), "bad tag 7 at byte 7");
+    assert_eq!(err(b"LPRB\x01\x00\x01\x36"), "length 6 is past the end at byte 8");
+    assert_eq!(err(b"LPRB\x01\x01\x01\xff\x01\x00"), "invalid UTF-8 at byte 7");
+    assert_eq!(err(b"LPRB\x01\x00\x01\x00\x00"), "trailing bytes at byte 8");
+    assert_eq!(err(b"LPRB\x01\x00\x00garbage"), "trailing bytes at byte 7");
+    assert_eq!(decode_all(b"LPRB\x01\x00\x00"), Ok(vec![]));
+    assert_eq!(err(b"LPRB\x01\x00\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x7f"), "varint is too long at byte 17");
+    let mut deep = b"LPRB\x01\x00\x01".to_vec();
+    deep.extend(vec![0x05; 5000]);
+    deep.push(0);
+    assert_eq!(err(&deep), "nesting is too deep at byte 264");
+}
+
+#[test]
+fn test_binary_depth() {
+    // What encodes decodes: nesting up to the limit, and no deeper.
+    let nest = |depth: usize| {
+        let mut tk = Token::Int(1);
+        for i in 0..depth {
+            tk = match i % 2 {
+                0 => Token::List(vec![tk]),
+                _ => Token::Quote(Box::new(tk)),
+            };
+        }
+        tk
+    };
+    let tk = nest(MAX_DEPTH);
+    assert_eq!(decode(&encode(&tk).unwrap()), Ok(tk));
+    let tk = Token::List(vec![Token::Nil, nest(MAX_DEPTH - 1)]);
+    assert_eq!(decode(&encode(&tk).unwrap()), Ok(tk));
+    for depth in &[MAX_DEPTH + 1, 300] {
+        assert_eq!(encode(&nest(*depth)), Err(LprpError::BinaryError("nesting is deeper than 256".to_string())));
+    }
+    assert!(encode_all(&[Token::Nil, nest(MAX_DEPTH + 1)]).is_err());
+}
+
+// Random tokens of every kind, including the awkward ones.
+#[cfg(test)]
+fn random_token(rng: &mut Rng, depth: u32) -> Token {
+    let words = ["a", "b", "long-symbol-name", ":key", "", "é"];
+    match rng.next(if depth == 0 { 8 } else { 10 }) {
+        0 => Token::Nil,
+        1 => Token::T,
+        2 => Token::Int(rng.next(300) as i64 - 150),
+        3 => Token::Int([i64::MIN, i64::MAX, 1 << 61, -(1 << 61)][rng.next(4) as usize]),
+        4 => Token::Float(rng.next(1000) as f64 / 7.0 - 50.0),
+        5 => Token::Symbol(words[rng.next(4) as usize].to_string()),
+        6 => Token::Str(words[rng.next(6) as usize].repeat(rng.next(40) as usize)),
+        7 => Token::Symbol(format!("sym{}", rng.next(200))),
+        8 => Token::Quote(Box::new(random_token(rng, depth - 1))),
+        _ => Token::List((0..rng.next(8)).map(|_| random_token(rng, depth - 1)).collect()),
+    }
+}
+
+#[test]
+fn test_binary_random() {
+    let mut rng = Rng(42);
+    for _ in 0..300 {
+        let forms: Vec<Token> = (0..rng.next(4)).map(|_| random_token(&mut rng, 4)).collect();
+        let bytes = encode_all(&forms).unwrap();
+        assert_eq!(decode_all(&bytes), Ok(forms.clone()));
+
+        // Damaged input is an error or some other forms, never a panic.
+        for _ in 0..20 {
+            let mut bad = bytes.clone();
+            match rng.next(3) {
+                0 => bad.truncate(rng.next(bytes.len() as u64) as usize),
+                1 => {
+                    let i = rng.next(bytes.len() as u64) as usize;
+                    bad[i] ^= 1 << rng.next(8);
+                },
+                _ => {
+                    let i = rng.next(bytes.len() as u64) as usize;
+                    bad.insert(i, rng.next(256) as u8);
+                },
+            }
+            let _ = decode_all(&bad);
+        }
+    }
+}
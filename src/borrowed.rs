@@ -0,0 +1,308 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::borrow::Cow;
+use std::str;
+use crate::lexer::{self, Atom, Kind, Lexeme, Lexer, Span};
+use crate::reader::{self, LprpError};
+use crate::syntax;
+
+
+// ***** Token *****
+// A token whose symbols and strings borrow from the text it was read
+// from. `read` never copies an atom, so reading a large file allocates
+// little more than its lists. `into_owned` gives a `reader::Token`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token<'a> {
+    T,
+    Nil,
+    Int(i64),
+    Float(f64),
+    Symbol(Cow<'a, str>),
+    Quote(Box<Token<'a>>),
+    Str(Cow<'a, str>),
+    List(Vec<Token<'a>>),
+}
+
+impl<'a> Token<'a> {
+    pub fn into_owned(self) -> reader::Token {
+        match self {
+            Token::T => reader::Token::T,
+            Token::Nil => reader::Token::Nil,
+            Token::Int(i) => reader::Token::Int(i),
+            Token::Float(f) => reader::Token::Float(f),
+            Token::Symbol(s) => reader::Token::Symbol(s.into_owned()),
+            Token::Quote(q) => reader::Token::Quote(Box::new(q.into_owned())),
+            Token::Str(s) => reader::Token::Str(s.into_owned()),
+            Token::List(l) => reader::Token::List(l.into_iter().map(Token::into_owned).collect()),
+        }
+    }
+
+    pub fn as_symbol(&self) -> Option<&str> {
+        match self {
+            Token::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Token::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Token<'a>]> {
+        match self {
+            Token::List(l) => Some(l),
+            Token::Nil => Some(&[]),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<Token<'a>> for reader::Token {
+    fn from(tk: Token<'a>) -> Self {
+        tk.into_owned()
+    }
+}
+
+// A view of an owned token that borrows its atoms.
+impl<'a> From<&'a reader::Token> for Token<'a> {
+    fn from(tk: &'a reader::Token) -> Self {
+        match tk {
+            reader::Token::T => Token::T,
+            reader::Token::Nil => Token::Nil,
+            reader::Token::Int(i) => Token::Int(*i),
+            reader::Token::Float(f) => Token::Float(*f),
+            reader::Token::Symbol(s) => Token::Symbol(Cow::Borrowed(s)),
+            reader::Token::Quote(q) => Token::Quote(Box::new(Token::from(&**q))),
+            reader::Token::Str(s) => Token::Str(Cow::Borrowed(s)),
+            reader::Token::List(l) => Token::List(l.iter().map(Token::from).collect()),
+        }
+    }
+}
+
+// ***** Reader *****
+// The tree builder over `lexer`, which `reader::read` also uses. Atoms
+// are slices of the input, as strings have no escapes to undo, so
+// reading a large file allocates little more than its lists. Parens,
+// plain symbols and strings are scanned from the bytes directly, as
+// `lexer` scans them. As with `reader::read`, every form must read, and
+// `read` gives the first.
+pub fn read(src: &str) -> Result<Token<'_>, LprpError> {
+    read_all(src)?.into_iter().next().ok_or(LprpError::ReadError)
+}
+
+pub fn read_all(src: &str) -> Result<Vec<Token<'_>>, LprpError> {
+    let mut b = Builder { src, pos: 0 };
+    let mut forms = vec![];
+    while let Some(lexeme) = b.next() {
+        forms.push(b.form(lexeme)?);
+    }
+    Ok(forms)
+}
+
+pub fn read_bytes(src: &[u8]) -> Result<Vec<Token<'_>>, LprpError> {
+    match str::from_utf8(src) {
+        Ok(s) => read_all(s),
+        Err(_) => Err(LprpError::ReadError),
+    }
+}
+
+struct Builder<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Builder<'a> {
+    // The lexeme at `pos`. Parens are the commonest and are made here.
+    fn lexeme(&mut self) -> Option<Lexeme> {
+        let start = self.pos;
+        let kind = match self.src.as_bytes().get(start)? {
+            b'(' => Kind::LParen,
+            b')' => Kind::RParen,
+            _ => {
+                let lexeme = Lexer::at(self.src, start).next()?;
+                self.pos = lexeme.span.end;
+                return Some(lexeme);
+            },
+        };
+        self.pos += 1;
+        Some(Lexeme { kind, span: Span { start, end: self.pos } })
+    }
+
+    // The next lexeme that is not whitespace or a comment.
+    fn next(&mut self) -> Option<Lexeme> {
+        loop {
+            match self.lexeme()? {
+                l if l.is_trivia() => {},
+                l => return Some(l),
+            }
+        }
+    }
+
+    // A plain symbol or a string, read without making a lexeme. Most
+    // atoms of a file are one.
+    fn plain(&mut self) -> Option<Token<'a>> {
+        let bytes = self.src.as_bytes();
+        while bytes.get(self.pos).is_some_and(|&b| lexer::is_space(b)) {
+            self.pos += 1;
+        }
+        let start = self.pos;
+        match bytes.get(start)? {
+            b if b.is_ascii_alphabetic() => {
+                let len = bytes[start..].iter().position(|&b| !lexer::is_symbol(b));
+                self.pos = len.map_or(bytes.len(), |len| start + len);
+                atom_token(Atom::Symbol, &self.src[start..self.pos]).ok()
+            },
+            b'"' => {
+                let len = bytes[start + 1..].iter().position(|&b| b == b'"')?;
+                self.pos = start + len + 2;
+                Some(Token::Str(Cow::Borrowed(&self.src[start + 1..start + len + 1])))
+            },
+            _ => None,
+        }
+    }
+
+    fn form(&mut self, lexeme: Lexeme) -> Result<Token<'a>, LprpError> {
+        match lexeme.kind {
+            Kind::LParen => self.list(lexeme.span.start),
+            // The quoted form must follow the quote directly.
+            Kind::Quote => {
+                match self.lexeme() {
+                    Some(l) if matches!(l.kind, Kind::LParen|Kind::Quote|Kind::Atom(_))
+                        && l.kind != Kind::Atom(Atom::Wildcard) => Ok(Token::Quote(Box::new(self.form(l)?))),
+                    Some(l @ Lexeme { kind: Kind::Error(_), .. }) => self.form(l),
+                    _ => Err(LprpError::SyntaxError),
+                }
+            },
+            Kind::Atom(atom) => atom_token(atom, lexeme.text(self.src)),
+            Kind::RParen => Err(syntax::delimiter_error(self.src, "unexpected `)`", lexeme.span.start)),
+            Kind::Error(LprpError::DelimiterError(what)) => {
+                Err(syntax::delimiter_error(self.src, &what, lexeme.span.start))
+            },
+            Kind::Error(e) => Err(e),
+            _ => Err(LprpError::SyntaxError),
+        }
+    }
+
+    fn list(&mut self, open: usize) -> Result<Token<'a>, LprpError> {
+        let mut items = vec![];
+        loop {
+            if let Some(tk) = self.plain() {
+                items.push(tk);
+                continue;
+            }
+            match self.next() {
+                Some(Lexeme { kind: Kind::RParen, .. }) => {
+                    // A dot may only come before the last element.
+                    let dot = items.iter().position(|tk: &Token| tk.as_symbol() == Some("."));
+                    if dot.is_some_and(|i| i == 0 || i + 2 != items.len()) {
+                        return Err(LprpError::SyntaxError);
+                    }
+                    return Ok(Token::List(items));
+                },
+                Some(Lexeme { kind: Kind::Dot, .. }) => items.push(Token::Symbol(Cow::Borrowed("."))),
+                Some(l) => items.push(self.form(l)?),
+                None => return Err(syntax::unclosed_error(self.src, open)),
+            }
+        }
+    }
+}
+
+pub(crate) fn atom_token(atom: Atom, text: &str) -> Result<Token<'_>, LprpError> {
+    match atom {
+        Atom::Int => text.parse().map(Token::Int).map_err(|_| LprpError::ReadNumError),
+        Atom::Float => text.parse().map(Token::Float).map_err(|_| LprpError::ReadNumError),
+        Atom::Symbol => {
+            match text {
+                "nil"|"NIL" => Ok(Token::Nil),
+                "t" => Ok(Token::T),
+                s => Ok(Token::Symbol(Cow::Borrowed(s))),
+            }
+        },
+        Atom::Keyword|Atom::Special|Atom::Wildcard => Ok(Token::Symbol(Cow::Borrowed(text))),
+        Atom::Str => {
+            let s = &text[1..];
+            Ok(Token::Str(Cow::Borrowed(s.strip_suffix('"').unwrap_or(s))))
+        },
+    }
+}
+
+#[test]
+fn test_borrowed_read() {
+    let src = "(defun f (x) (list x 'x \"a string\" -1.5 -> - t nil *v* :k _ . rest))";
+    let tk = read(src).unwrap();
+    assert_eq!(tk.clone().into_owned(), reader::read(src).unwrap());
+    let items = tk.as_list().unwrap();
+    assert_eq!(items[0].as_symbol(), Some("defun"));
+    match &items[3].as_list().unwrap()[3] {
+        Token::Str(Cow::Borrowed(s)) => assert_eq!(*s, "a string"),
+        tk => panic!("{:?}", tk),
+    }
+
+    assert_eq!(read_all("(a) 1 'b \"s\"").unwrap().len(), 4);
+    assert_eq!(read_bytes(b"(a b)").unwrap(), vec![Token::List(vec![
+        Token::Symbol(Cow::Borrowed("a")), Token::Symbol(Cow::Borrowed("b")),
+    ])]);
+    assert_eq!(read(""), Err(LprpError::ReadError));
+    assert_eq!(read_bytes(b"(\xff)"), Err(LprpError::ReadError));
+    assert_eq!(read("99999999999999999999"), Err(LprpError::ReadNumError));
+    assert_eq!(read("."), Err(LprpError::SyntaxError));
+
+    let owned = reader::read("(a \"b\" 'c)").unwrap();
+    assert_eq!(Token::from(&owned).into_owned(), owned);
+}
+
+// Atoms stay as written: `Nil` and `T` are plain symbols.
+#[test]
+fn test_borrowed_grammar() {
+    let owned = |src: &str| read(src).map(Token::into_owned);
+    let err = |src: &str| read(src).err();
+    assert_eq!(owned("(nil NIL Nil t T)"), Ok(reader::Token::List(vec![
+        reader::Token::Nil,
+        reader::Token::Nil,
+        reader::Token::Symbol("Nil".to_string()),
+        reader::Token::T,
+        reader::Token::Symbol("T".to_string()),
+    ])));
+    assert_eq!(owned("(1. -.5 12abc)"), Ok(reader::Token::List(vec![
+        reader::Token::Float(1.0),
+        reader::Token::Float(-0.5),
+        reader::Token::Int(12),
+        reader::Token::Symbol("abc".to_string()),
+    ])));
+    let delimiter = |message: &str| Some(LprpError::DelimiterError(message.to_string()));
+    assert_eq!(err("(a (b (c"), delimiter("unclosed `(` at 1:7, input ends at 1:9"));
+    assert_eq!(err("(\"unterminated"), delimiter("unterminated string at 1:2"));
+    assert_eq!(err("(1 -2 1-2)"), Some(LprpError::ReadNumError));
+    for src in &["(:a :b:)", "(*a)", "(*a*b)", "(x _ y) (_x)", "' a", "'_", "'.", "(a\rb)", "(é)"] {
+        assert_eq!(err(src), Some(LprpError::SyntaxError), "{}", src);
+    }
+    crate::core::for_random_lists(|a, _| {
+        let src = crate::value::Value::from(a).to_string();
+        assert_eq!(owned(&src).as_ref(), Ok(a), "{}", src);
+    });
+}
+
+// Symbols, strings and parens are scanned without `lexer`, and must read
+// as `syntax::parse` reads them with it.
+#[test]
+fn test_borrowed_like_lexer() {
+    let cases = [
+        "(a b-c d1 x->y a+b) (nil t T)", "(\"s\" \"\" \"a;b\" \"(\")", "(a ; c\n b) ; end",
+        "(a\t(b\n(c)))", "(a;c\n)", "(a \"open", "(abc\"s\"def)", "(a:b c*d e_f)", "(a . b)", "(a .b)",
+        "(1 -2 :k *v* _ 'x '(y))",
+    ];
+    for src in &cases {
+        let parse = syntax::parse(src);
+        let lexed = if parse.is_ok() { Some(parse.tokens()) } else { None };
+        let read = read_all(src).ok().map(|f| f.into_iter().map(Token::into_owned).collect());
+        assert_eq!(read, lexed, "{}", src);
+    }
+}
@@ -0,0 +1,538 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::rc::Rc;
+use crate::core;
+use crate::eval::{Builtin, Primitive};
+use crate::condition::Condition;
+use crate::pattern::Pattern;
+use crate::reader::Token;
+use crate::value::Value;
+
+
+pub static BUILTINS: &[Builtin] = &[
+    Builtin { name: "+", min: 0, max: None, prim: Primitive::Pure(add) },
+    Builtin { name: "-", min: 1, max: None, prim: Primitive::Pure(sub) },
+    Builtin { name: "*", min: 0, max: None, prim: Primitive::Pure(mul) },
+    Builtin { name: "/", min: 1, max: None, prim: Primitive::Pure(div) },
+    Builtin { name: "=", min: 1, max: None, prim: Primitive::Pure(num_eq) },
+    Builtin { name: "<", min: 1, max: None, prim: Primitive::Pure(lt) },
+    Builtin { name: ">", min: 1, max: None, prim: Primitive::Pure(gt) },
+    Builtin { name: "<=", min: 1, max: None, prim: Primitive::Pure(le) },
+    Builtin { name: ">=", min: 1, max: None, prim: Primitive::Pure(ge) },
+    Builtin { name: "not", min: 1, max: Some(1), prim: Primitive::Pure(not) },
+    Builtin { name: "null", min: 1, max: Some(1), prim: Primitive::Pure(not) },
+    Builtin { name: "eq", min: 2, max: Some(2), prim: Primitive::Pure(eq) },
+    Builtin { name: "eql", min: 2, max: Some(2), prim: Primitive::Pure(eql) },
+    Builtin { name: "equal", min: 2, max: Some(2), prim: Primitive::Pure(equal) },
+    Builtin { name: "equalp", min: 2, max: Some(2), prim: Primitive::Pure(equalp) },
+    Builtin { name: "atom", min: 1, max: Some(1), prim: Primitive::Pure(atom) },
+    Builtin { name: "consp", min: 1, max: Some(1), prim: Primitive::Pure(consp) },
+    Builtin { name: "listp", min: 1, max: Some(1), prim: Primitive::Pure(listp) },
+    Builtin { name: "numberp", min: 1, max: Some(1), prim: Primitive::Pure(numberp) },
+    Builtin { name: "symbolp", min: 1, max: Some(1), prim: Primitive::Pure(symbolp) },
+    Builtin { name: "stringp", min: 1, max: Some(1), prim: Primitive::Pure(stringp) },
+    Builtin { name: "functionp", min: 1, max: Some(1), prim: Primitive::Pure(functionp) },
+    Builtin { name: "car", min: 1, max: Some(1), prim: Primitive::Pure(car) },
+    Builtin { name: "cdr", min: 1, max: Some(1), prim: Primitive::Pure(cdr) },
+    Builtin { name: "cons", min: 2, max: Some(2), prim: Primitive::Pure(cons) },
+    Builtin { name: "rplaca", min: 2, max: Some(2), prim: Primitive::Pure(rplaca) },
+    Builtin { name: "rplacd", min: 2, max: Some(2), prim: Primitive::Pure(rplacd) },
+    Builtin { name: "list", min: 0, max: None, prim: Primitive::Pure(list) },
+    Builtin { name: "print", min: 1, max: Some(1), prim: Primitive::Pure(print) },
+    Builtin { name: "funcall", min: 1, max: None, prim: Primitive::Funcall },
+    Builtin { name: "apply", min: 2, max: None, prim: Primitive::Apply },
+    Builtin { name: "eval", min: 1, max: Some(1), prim: Primitive::Eval },
+    Builtin { name: "call/cc", min: 1, max: Some(1), prim: Primitive::CallCC },
+    Builtin {
+        name: "call-with-current-continuation",
+        min: 1,
+        max: Some(1),
+        prim: Primitive::CallCC,
+    },
+    Builtin { name: "dynamic-wind", min: 3, max: Some(3), prim: Primitive::DynamicWind },
+    Builtin { name: "error", min: 1, max: None, prim: Primitive::Error },
+    Builtin { name: "signal", min: 1, max: None, prim: Primitive::Signal },
+    Builtin { name: "%handler-case", min: 2, max: Some(2), prim: Primitive::HandlerCase },
+    Builtin { name: "%handler-bind", min: 2, max: Some(2), prim: Primitive::HandlerBind },
+    Builtin { name: "%match", min: 2, max: Some(2), prim: Primitive::Pure(match_pattern) },
+    Builtin { name: "make-condition", min: 1, max: None, prim: Primitive::Pure(make_condition) },
+    Builtin { name: "conditionp", min: 1, max: Some(1), prim: Primitive::Pure(conditionp) },
+    Builtin { name: "condition-type", min: 1, max: Some(1), prim: Primitive::Pure(condition_type) },
+    Builtin {
+        name: "condition-message",
+        min: 1,
+        max: Some(1),
+        prim: Primitive::Pure(condition_message),
+    },
+    Builtin {
+        name: "condition-irritants",
+        min: 1,
+        max: Some(1),
+        prim: Primitive::Pure(condition_irritants),
+    },
+];
+
+fn type_error<T>(name: &str, expected: &str, found: &Value) -> Result<T, Condition> {
+    let message = format!("{}: expected {}, found {}", name, expected, found.type_name());
+    Err(Condition::new("type-error", &message, vec![found.clone()]))
+}
+
+fn overflow<T>(name: &str) -> Result<T, Condition> {
+    let message = format!("{}: integer overflow", name);
+    Err(Condition::new("arithmetic-error", &message, vec![]))
+}
+
+fn bool_value(b: bool) -> Value {
+    if b { Value::T } else { Value::Nil }
+}
+
+// ***** Numbers *****
+#[derive(Clone, Copy)]
+enum Num {
+    Int(i64),
+    Float(f64),
+}
+
+impl Num {
+    fn to_f64(self) -> f64 {
+        match self {
+            Num::Int(i) => i as f64,
+            Num::Float(f) => f,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Num::Int(i) => Value::Int(i),
+            Num::Float(f) => Value::Float(f),
+        }
+    }
+}
+
+fn num(name: &str, v: &Value) -> Result<Num, Condition> {
+    match v {
+        Value::Int(i) => Ok(Num::Int(*i)),
+        Value::Float(f) => Ok(Num::Float(*f)),
+        _ => type_error(name, "number", v),
+    }
+}
+
+// Integers stay integers until an operand is a float.
+fn arith(name: &str, a: Num, b: Num,
+         int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64)
+    -> Result<Num, Condition>
+{
+    match (a, b) {
+        (Num::Int(x), Num::Int(y)) => {
+            match int(x, y) {
+                Some(r) => Ok(Num::Int(r)),
+                None => overflow(name),
+            }
+        },
+        _ => Ok(Num::Float(float(a.to_f64(), b.to_f64()))),
+    }
+}
+
+fn fold(name: &str, args: &[Value], init: Num,
+        int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64)
+    -> Result<Value, Condition>
+{
+    let mut acc = init;
+    for a in args {
+        acc = arith(name, acc, num(name, a)?, int, float)?;
+    }
+    Ok(acc.into_value())
+}
+
+fn add(args: &[Value]) -> Result<Value, Condition> {
+    fold("+", args, Num::Int(0), i64::checked_add, |a, b| a + b)
+}
+
+fn mul(args: &[Value]) -> Result<Value, Condition> {
+    fold("*", args, Num::Int(1), i64::checked_mul, |a, b| a * b)
+}
+
+fn sub(args: &[Value]) -> Result<Value, Condition> {
+    if args.len() == 1 {
+        return fold("-", args, Num::Int(0), i64::checked_sub, |a, b| a - b);
+    }
+    let mut acc = num("-", &args[0])?;
+    for a in &args[1..] {
+        acc = arith("-", acc, num("-", a)?, i64::checked_sub, |a, b| a - b)?;
+    }
+    Ok(acc.into_value())
+}
+
+// Integer division is exact when possible, otherwise a float.
+fn divide(a: Num, b: Num) -> Result<Num, Condition> {
+    match (a, b) {
+        (_, Num::Int(0)) => {
+            Err(Condition::new("division-by-zero", "/: division by zero", vec![]))
+        },
+        (Num::Int(x), Num::Int(y)) => {
+            match x.checked_rem(y) {
+                Some(0) => Ok(Num::Int(x / y)),
+                Some(_) => Ok(Num::Float(x as f64 / y as f64)),
+                None => overflow("/"),
+            }
+        },
+        _ => Ok(Num::Float(a.to_f64() / b.to_f64())),
+    }
+}
+
+fn div(args: &[Value]) -> Result<Value, Condition> {
+    if args.len() == 1 {
+        return Ok(divide(Num::Int(1), num("/", &args[0])?)?.into_value());
+    }
+    let mut acc = num("/", &args[0])?;
+    for a in &args[1..] {
+        acc = divide(acc, num("/", a)?)?;
+    }
+    Ok(acc.into_value())
+}
+
+// Integers are compared exactly, mixed operands as floats.
+fn compare(name: &str, args: &[Value], holds: fn(Ordering) -> bool)
+    -> Result<Value, Condition>
+{
+    let nums = args.iter()
+        .map(|a| num(name, a))
+        .collect::<Result<Vec<_>, _>>()?;
+    let all = nums.windows(2).all(|w| {
+        let ord = match (w[0], w[1]) {
+            (Num::Int(a), Num::Int(b)) => Some(a.cmp(&b)),
+            (a, b) => a.to_f64().partial_cmp(&b.to_f64()),
+        };
+        ord.is_some_and(holds)
+    });
+    Ok(bool_value(all))
+}
+
+fn num_eq(args: &[Value]) -> Result<Value, Condition> {
+    compare("=", args, |o| o == Ordering::Equal)
+}
+
+fn lt(args: &[Value]) -> Result<Value, Condition> {
+    compare("<", args, |o| o == Ordering::Less)
+}
+
+fn gt(args: &[Value]) -> Result<Value, Condition> {
+    compare(">", args, |o| o == Ordering::Greater)
+}
+
+fn le(args: &[Value]) -> Result<Value, Condition> {
+    compare("<=", args, |o| o != Ordering::Greater)
+}
+
+fn ge(args: &[Value]) -> Result<Value, Condition> {
+    compare(">=", args, |o| o != Ordering::Less)
+}
+
+#[test]
+fn test_arith() {
+    assert_eq!(add(&[Value::Int(1), Value::Int(2)]), Ok(Value::Int(3)));
+    assert_eq!(add(&[Value::Int(1), Value::Float(0.5)]), Ok(Value::Float(1.5)));
+    assert_eq!(sub(&[Value::Int(3)]), Ok(Value::Int(-3)));
+    assert_eq!(sub(&[Value::Int(3), Value::Int(1), Value::Int(1)]), Ok(Value::Int(1)));
+    assert_eq!(div(&[Value::Int(6), Value::Int(3)]), Ok(Value::Int(2)));
+    assert_eq!(div(&[Value::Int(7), Value::Int(2)]), Ok(Value::Float(3.5)));
+    assert!(div(&[Value::Int(1), Value::Int(0)]).is_err());
+    assert!(mul(&[Value::Int(i64::MAX), Value::Int(2)]).is_err());
+    assert!(add(&[Value::Int(1), Value::sym("a")]).is_err());
+}
+
+#[test]
+fn test_compare() {
+    assert_eq!(lt(&[Value::Int(1), Value::Int(2), Value::Int(3)]), Ok(Value::T));
+    assert_eq!(lt(&[Value::Int(1), Value::Int(3), Value::Int(2)]), Ok(Value::Nil));
+    assert_eq!(num_eq(&[Value::Int(1), Value::Float(1.0)]), Ok(Value::T));
+    assert_eq!(
+        num_eq(&[Value::Int(i64::MAX), Value::Int(i64::MAX - 1)]),
+        Ok(Value::Nil)
+        );
+}
+
+// ***** Predicates *****
+fn not(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(args[0].is_nil()))
+}
+
+// Cons cells and functions are compared by identity. Values have no
+// identity below that, since a number or string is copied wherever it
+// goes, so atoms compare by their representation: `(eq "a" "a")` is t.
+// `core::eq` instead compares a float or string Token by its address.
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Pair(x), Value::Pair(y)) => Rc::ptr_eq(x, y),
+        (Value::Float(x), Value::Float(y)) => x.to_bits() == y.to_bits(),
+        _ => a == b,
+    }
+}
+
+fn eq(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(same(&args[0], &args[1])))
+}
+
+// With numbers never boxed, `eql` has nothing left to add to `eq`.
+fn eql(args: &[Value]) -> Result<Value, Condition> {
+    eq(args)
+}
+
+// Compare lists element by element and atoms with `atoms`.
+fn structurally(a: &Value, b: &Value, atoms: fn(&Value, &Value) -> bool) -> bool {
+    let (mut l, mut r) = (a.clone(), b.clone());
+    while let (Value::Pair(x), Value::Pair(y)) = (&l, &r) {
+        if Rc::ptr_eq(x, y) {
+            return true;
+        }
+        if !structurally(&x.car.borrow(), &y.car.borrow(), atoms) {
+            return false;
+        }
+        let (nl, nr) = (x.cdr.borrow().clone(), y.cdr.borrow().clone());
+        l = nl;
+        r = nr;
+    }
+    atoms(&l, &r)
+}
+
+// `eql` at the atoms, but strings compare by content.
+fn equal(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(structurally(&args[0], &args[1], |a, b| {
+        match (a, b) {
+            (Value::Str(x), Value::Str(y)) => x == y,
+            _ => same(a, b),
+        }
+    })))
+}
+
+// `equal`, but strings ignore case and numbers compare exactly by value.
+fn equalp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(structurally(&args[0], &args[1], |a, b| {
+        match (a, b) {
+            (Value::Str(x), Value::Str(y)) => x.to_lowercase() == y.to_lowercase(),
+            (Value::Float(x), Value::Float(y)) => x == y || same(a, b),
+            (Value::Int(i), Value::Float(f))|(Value::Float(f), Value::Int(i)) => {
+                core::int_equals_float(*i, *f)
+            },
+            _ => same(a, b),
+        }
+    })))
+}
+
+fn atom(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(!matches!(args[0], Value::Pair(_))))
+}
+
+fn consp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(matches!(args[0], Value::Pair(_))))
+}
+
+fn listp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(matches!(args[0], Value::Pair(_)|Value::Nil)))
+}
+
+fn numberp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(matches!(args[0], Value::Int(_)|Value::Float(_))))
+}
+
+fn symbolp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(matches!(args[0], Value::Symbol(_)|Value::T|Value::Nil)))
+}
+
+fn stringp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(matches!(args[0], Value::Str(_))))
+}
+
+fn functionp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(args[0].is_procedure()))
+}
+
+#[test]
+fn test_eq() {
+    let l = Value::list(vec![Value::Int(1)]);
+    assert_eq!(eq(&[l.clone(), l.clone()]), Ok(Value::T));
+    assert_eq!(eq(&[l.clone(), Value::list(vec![Value::Int(1)])]), Ok(Value::Nil));
+    assert_eq!(equal(&[l, Value::list(vec![Value::Int(1)])]), Ok(Value::T));
+    assert_eq!(eq(&[Value::sym("a"), Value::sym("a")]), Ok(Value::T));
+    assert_eq!(eql(&[Value::Float(f64::NAN), Value::Float(f64::NAN)]), Ok(Value::T));
+    assert_eq!(eql(&[Value::Float(0.0), Value::Float(-0.0)]), Ok(Value::Nil));
+    assert_eq!(equal(&[Value::Int(1), Value::Float(1.0)]), Ok(Value::Nil));
+    let a = Value::list(vec![Value::Int(1), Value::Str("Abc".to_string())]);
+    let b = Value::list(vec![Value::Float(1.0), Value::Str("aBC".to_string())]);
+    assert_eq!(equalp(&[a, b]), Ok(Value::T));
+
+    // Each predicate implies the next, as in `core`.
+    let nan = Value::Float(f64::NAN);
+    assert_eq!(equal(&[nan.clone(), nan.clone()]), Ok(Value::T));
+    assert_eq!(equalp(&[nan.clone(), nan]), Ok(Value::T));
+    assert_eq!(eq(&[Value::Float(0.0), Value::Float(-0.0)]), Ok(Value::Nil));
+    assert_eq!(equalp(&[Value::Float(0.0), Value::Float(-0.0)]), Ok(Value::T));
+    assert_eq!(eq(&[Value::Str("a".to_string()), Value::Str("a".to_string())]), Ok(Value::T));
+    assert_eq!(equalp(&[Value::Int(9007199254740993), Value::Float(9007199254740992.0)]), Ok(Value::Nil));
+    assert_eq!(equalp(&[Value::Int(i64::MAX), Value::Float(9.223_372_036_854_776e18)]), Ok(Value::Nil));
+    assert_eq!(equalp(&[Value::Float(-3.0), Value::Int(-3)]), Ok(Value::T));
+}
+
+// ***** Lists *****
+fn car(args: &[Value]) -> Result<Value, Condition> {
+    match &args[0] {
+        Value::Nil => Ok(Value::Nil),
+        v @ Value::Pair(_) => Ok(v.car().unwrap()),
+        v => type_error("car", "list", v),
+    }
+}
+
+fn cdr(args: &[Value]) -> Result<Value, Condition> {
+    match &args[0] {
+        Value::Nil => Ok(Value::Nil),
+        v @ Value::Pair(_) => Ok(v.cdr().unwrap()),
+        v => type_error("cdr", "list", v),
+    }
+}
+
+fn cons(args: &[Value]) -> Result<Value, Condition> {
+    Ok(Value::cons(args[0].clone(), args[1].clone()))
+}
+
+// Destructively replace the car or cdr of a cons and return the cons.
+fn rplaca(args: &[Value]) -> Result<Value, Condition> {
+    if !args[0].set_car(args[1].clone()) {
+        return type_error("rplaca", "cons", &args[0]);
+    }
+    Ok(args[0].clone())
+}
+
+fn rplacd(args: &[Value]) -> Result<Value, Condition> {
+    if !args[0].set_cdr(args[1].clone()) {
+        return type_error("rplacd", "cons", &args[0]);
+    }
+    Ok(args[0].clone())
+}
+
+fn list(args: &[Value]) -> Result<Value, Condition> {
+    Ok(Value::list(args.to_vec()))
+}
+
+#[test]
+fn test_list_builtins() {
+    let l = list(&[Value::Int(1), Value::Int(2)]).unwrap();
+    assert_eq!(car(std::slice::from_ref(&l)), Ok(Value::Int(1)));
+    assert_eq!(cdr(&[l]), Ok(Value::list(vec![Value::Int(2)])));
+    assert_eq!(car(&[Value::Nil]), Ok(Value::Nil));
+    assert!(car(&[Value::Int(1)]).is_err());
+
+    let l = list(&[Value::Int(1), Value::Int(2)]).unwrap();
+    let tail = cdr(std::slice::from_ref(&l)).unwrap();
+    rplaca(&[tail, Value::Int(3)]).unwrap();
+    assert_eq!(l.to_string(), "(1 3)");
+    assert!(rplacd(&[Value::Nil, Value::Nil]).is_err());
+}
+
+// ***** Patterns *****
+// (%match 'pattern value) is nil if the value does not match, and
+// otherwise (t value...) with a value for each name in the pattern.
+fn match_pattern(args: &[Value]) -> Result<Value, Condition> {
+    let pattern = Token::try_from(&args[0]).and_then(|tk| Pattern::from_token(&tk));
+    match pattern {
+        Ok(p) => {
+            Ok(p.matches(&args[1]).map_or(Value::Nil, |b| {
+                Value::cons(Value::T, Value::list(b.into_values()))
+            }))
+        },
+        Err(e) => Err(Condition::new("program-error", &e.to_string(), vec![args[0].clone()])),
+    }
+}
+
+#[test]
+fn test_match_pattern() {
+    let pattern = Value::from(crate::reader::read("(a . b)").unwrap());
+    let v = Value::list(vec![Value::Int(1), Value::Int(2)]);
+    assert_eq!(match_pattern(&[pattern.clone(), v]).map(|v| v.to_string()), Ok("(t 1 (2))".to_string()));
+    assert_eq!(match_pattern(&[pattern, Value::Int(1)]), Ok(Value::Nil));
+    assert!(match_pattern(&[Value::sym("&rest"), Value::Int(1)]).is_err());
+}
+
+// ***** Conditions *****
+// Arguments of `error`, `signal` and `make-condition`:
+//   (error condition)
+//   (error "message" irritant...)
+//   (error 'kind "message" irritant...)
+pub(crate) fn condition_args(name: &str, default_kind: &str, args: &[Value])
+    -> Result<Rc<Condition>, Condition>
+{
+    match args {
+        [Value::Condition(c)] => Ok(c.clone()),
+        [Value::Str(msg), irritants @ ..] => {
+            Ok(Rc::new(Condition::new(default_kind, msg, irritants.to_vec())))
+        },
+        [Value::Symbol(kind), Value::Str(msg), irritants @ ..] => {
+            Ok(Rc::new(Condition::new(kind, msg, irritants.to_vec())))
+        },
+        [Value::Symbol(kind)] => Ok(Rc::new(Condition::new(kind, kind, vec![]))),
+        _ => type_error(name, "condition, message or condition type", &args[0]),
+    }
+}
+
+fn make_condition(args: &[Value]) -> Result<Value, Condition> {
+    condition_args("make-condition", "simple-error", args).map(Value::Condition)
+}
+
+fn conditionp(args: &[Value]) -> Result<Value, Condition> {
+    Ok(bool_value(matches!(args[0], Value::Condition(_))))
+}
+
+fn condition_type(args: &[Value]) -> Result<Value, Condition> {
+    match &args[0] {
+        Value::Condition(c) => Ok(Value::sym(&c.kind)),
+        v => type_error("condition-type", "condition", v),
+    }
+}
+
+fn condition_message(args: &[Value]) -> Result<Value, Condition> {
+    match &args[0] {
+        Value::Condition(c) => Ok(Value::Str(c.message.to_string())),
+        v => type_error("condition-message", "condition", v),
+    }
+}
+
+fn condition_irritants(args: &[Value]) -> Result<Value, Condition> {
+    match &args[0] {
+        Value::Condition(c) => Ok(Value::list(c.irritants.clone())),
+        v => type_error("condition-irritants", "condition", v),
+    }
+}
+
+#[test]
+fn test_conditions() {
+    let c = make_condition(&[
+        Value::sym("my-error"),
+        Value::Str("bad value".to_string()),
+        Value::Int(42),
+    ]).unwrap();
+    assert_eq!(condition_type(std::slice::from_ref(&c)), Ok(Value::sym("my-error")));
+    assert_eq!(
+        condition_message(std::slice::from_ref(&c)),
+        Ok(Value::Str("bad value".to_string()))
+        );
+    assert_eq!(
+        condition_irritants(std::slice::from_ref(&c)),
+        Ok(Value::list(vec![Value::Int(42)]))
+        );
+
+    let simple = make_condition(&[Value::Str("oops".to_string())]).unwrap();
+    assert_eq!(condition_type(&[simple]), Ok(Value::sym("simple-error")));
+    assert!(make_condition(&[Value::Int(1)]).is_err());
+}
+
+// ***** I/O *****
+fn print(args: &[Value]) -> Result<Value, Condition> {
+    println!("{}", args[0]);
+    Ok(args[0].clone())
+}
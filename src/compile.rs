@@ -0,0 +1,532 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::fmt;
+use std::rc::Rc;
+use crate::condition::Condition;
+use crate::eval::expand;
+use crate::value::Value;
+
+
+// ***** Bytecode *****
+// Local variables are addressed by (depth, index): `depth` counts the
+// enclosing function frames to walk up and `index` is the slot within
+// that frame. Globals are addressed by a symbol in the constant pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(u32),
+    Local(u16, u16),
+    // Store the top of the stack and keep it there.
+    SetLocal(u16, u16),
+    Global(u32),
+    SetGlobal(u32),
+    // Pop the top of the stack into a new binding.
+    DefLocal(u16),
+    DefGlobal(u32),
+    Pop,
+    Jump(u32),
+    // Pop, and jump if the value was nil.
+    JumpIfNil(u32),
+    // `and`: jump keeping the value if it is nil, otherwise pop it.
+    AndJump(u32),
+    // `or`: jump keeping the value if it is true, otherwise pop it.
+    OrJump(u32),
+    Closure(u32),
+    Call(u16),
+    TailCall(u16),
+    Return,
+    DefineCondition(u32, u32),
+    // Signal the condition in the constant pool. Forms that are malformed
+    // compile to this, so the error happens when the tree-walking
+    // evaluator would report it.
+    Raise(u32),
+}
+
+// A compiled function body.
+pub struct Proto {
+    pub name: Option<String>,
+    pub nparams: usize,
+    pub rest: bool,
+    // Parameters first, then the rest parameter, then the variables
+    // defined in the body with `defun` or `defvar`.
+    pub names: Vec<String>,
+    pub code: Vec<Op>,
+    pub consts: Vec<Value>,
+    pub protos: Vec<Rc<Proto>>,
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        writeln!(
+            f, "{} ({} params{}) slots: {}",
+            self.name.as_deref().unwrap_or("lambda"),
+            self.nparams,
+            if self.rest { " + rest" } else { "" },
+            self.names.join(" ")
+            )?;
+        for (pc, op) in self.code.iter().enumerate() {
+            match op {
+                Op::Const(i)|Op::Global(i)|Op::SetGlobal(i)|Op::DefGlobal(i)|Op::Raise(i) => {
+                    writeln!(f, "{:4} {:?}  ; {}", pc, op, self.consts[*i as usize])?;
+                },
+                _ => writeln!(f, "{:4} {:?}", pc, op)?,
+            }
+        }
+        for p in &self.protos {
+            write!(f, "{}", p)?;
+        }
+        Ok(())
+    }
+}
+
+// ***** Compiler *****
+// Compile a top-level form. Its code runs in the global environment.
+pub fn compile(expr: &Value) -> Rc<Proto> {
+    let mut c = Compiler { scopes: vec![] };
+    let mut b = Builder::new(None, 0, false, vec![]);
+    c.expr(&mut b, expr, true);
+    b.emit(Op::Return);
+    Rc::new(b.finish())
+}
+
+struct Builder {
+    proto: Proto,
+}
+
+impl Builder {
+    fn new(name: Option<String>, nparams: usize, rest: bool, names: Vec<String>) -> Builder {
+        Builder {
+            proto: Proto {
+                name,
+                nparams,
+                rest,
+                names,
+                code: vec![],
+                consts: vec![],
+                protos: vec![],
+            },
+        }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.proto.code.push(op);
+        self.proto.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.proto.code.len() as u32
+    }
+
+    // Point the jump at `at` to the current position.
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        self.proto.code[at] = match self.proto.code[at] {
+            Op::Jump(_) => Op::Jump(target),
+            Op::JumpIfNil(_) => Op::JumpIfNil(target),
+            Op::AndJump(_) => Op::AndJump(target),
+            Op::OrJump(_) => Op::OrJump(target),
+            op => op,
+        };
+    }
+
+    fn constant(&mut self, v: Value) -> u32 {
+        // Symbols are deduplicated, since every global access uses one.
+        if let Value::Symbol(s) = &v {
+            let found = self.proto.consts.iter()
+                .position(|c| c.as_symbol() == Some(s.as_str()));
+            if let Some(i) = found {
+                return i as u32;
+            }
+        }
+        self.proto.consts.push(v);
+        (self.proto.consts.len() - 1) as u32
+    }
+
+    fn raise(&mut self, c: Condition) {
+        let i = self.constant(Value::Condition(Rc::new(c)));
+        self.emit(Op::Raise(i));
+    }
+
+    fn finish(self) -> Proto {
+        self.proto
+    }
+}
+
+struct Compiler {
+    // Slot names of the enclosing functions, innermost last.
+    scopes: Vec<Vec<String>>,
+}
+
+fn malformed(form: &Value) -> Condition {
+    Condition::new("program-error", "malformed special form", vec![form.clone()])
+}
+
+impl Compiler {
+    fn resolve(&self, name: &str) -> Option<(u16, u16)> {
+        self.scopes.iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                scope.iter()
+                    .position(|n| n == name)
+                    .map(|i| (depth as u16, i as u16))
+            })
+    }
+
+    fn expr(&mut self, b: &mut Builder, expr: &Value, tail: bool) {
+        match expr {
+            Value::Symbol(s) if s.starts_with(':') => {
+                let i = b.constant(expr.clone());
+                b.emit(Op::Const(i));
+            },
+            Value::Symbol(s) => {
+                match self.resolve(s) {
+                    Some((d, i)) => b.emit(Op::Local(d, i)),
+                    None => {
+                        let i = b.constant(expr.clone());
+                        b.emit(Op::Global(i))
+                    },
+                };
+            },
+            Value::Pair(_) => self.form(b, expr, tail),
+            _ => {
+                let i = b.constant(expr.clone());
+                b.emit(Op::Const(i));
+            },
+        }
+    }
+
+    fn body(&mut self, b: &mut Builder, body: &Value, tail: bool) {
+        if body.is_nil() {
+            let i = b.constant(Value::Nil);
+            b.emit(Op::Const(i));
+            return;
+        }
+        let forms: Vec<Value> = body.iter().collect();
+        // The evaluator returns nil after a body with an improper tail.
+        let proper = body.to_vec().is_some();
+        for (n, form) in forms.iter().enumerate() {
+            let last = proper && n + 1 == forms.len();
+            self.expr(b, form, tail && last);
+            if !last {
+                b.emit(Op::Pop);
+            }
+        }
+        if !proper {
+            let i = b.constant(Value::Nil);
+            b.emit(Op::Const(i));
+        }
+    }
+
+    fn define(&mut self, b: &mut Builder, name: &str) {
+        match self.resolve(name) {
+            Some((0, i)) => {
+                b.emit(Op::DefLocal(i));
+            },
+            _ => {
+                let i = b.constant(Value::sym(name));
+                b.emit(Op::DefGlobal(i));
+            },
+        }
+        let i = b.constant(Value::sym(name));
+        b.emit(Op::Const(i));
+    }
+
+    fn assign(&mut self, b: &mut Builder, name: &str) {
+        match self.resolve(name) {
+            Some((d, i)) => b.emit(Op::SetLocal(d, i)),
+            None => {
+                let i = b.constant(Value::sym(name));
+                b.emit(Op::SetGlobal(i))
+            },
+        };
+    }
+
+    fn form(&mut self, b: &mut Builder, form: &Value, tail: bool) {
+        let head = form.car().unwrap();
+        let args = form.cdr().unwrap();
+
+        if let Value::Symbol(name) = &head {
+            match name.as_str() {
+                "quote" => {
+                    match args.to_vec().as_deref() {
+                        Some([x]) => {
+                            let i = b.constant(x.clone());
+                            b.emit(Op::Const(i));
+                        },
+                        _ => b.raise(malformed(form)),
+                    }
+                    return;
+                },
+                "if" => {
+                    let (test, then, alt) = match args.to_vec().as_deref() {
+                        Some([c, t]) => (c.clone(), t.clone(), Value::Nil),
+                        Some([c, t, e]) => (c.clone(), t.clone(), e.clone()),
+                        _ => return b.raise(malformed(form)),
+                    };
+                    self.expr(b, &test, false);
+                    let to_alt = b.emit(Op::JumpIfNil(0));
+                    self.expr(b, &then, tail);
+                    let to_end = b.emit(Op::Jump(0));
+                    b.patch(to_alt);
+                    self.expr(b, &alt, tail);
+                    b.patch(to_end);
+                    return;
+                },
+                "progn" => return self.body(b, &args, tail),
+                "lambda" => {
+                    let params = args.car().unwrap_or_default();
+                    let body = args.cdr().unwrap_or_default();
+                    self.lambda(b, None, &params, &body);
+                    return;
+                },
+                "defun" => {
+                    match (args.car(), args.cdr()) {
+                        (Some(Value::Symbol(n)), Some(rest)) if rest.car().is_some() => {
+                            let params = rest.car().unwrap();
+                            let body = rest.cdr().unwrap();
+                            if self.lambda(b, Some(n.clone()), &params, &body) {
+                                self.define(b, &n);
+                            }
+                        },
+                        _ => b.raise(malformed(form)),
+                    }
+                    return;
+                },
+                "defvar" => {
+                    match args.to_vec().as_deref() {
+                        Some([Value::Symbol(n)]) => {
+                            let i = b.constant(Value::Nil);
+                            b.emit(Op::Const(i));
+                            self.define(b, n);
+                        },
+                        Some([Value::Symbol(n), init]) => {
+                            self.expr(b, init, false);
+                            self.define(b, n);
+                        },
+                        _ => b.raise(malformed(form)),
+                    }
+                    return;
+                },
+                "setq" => {
+                    match (args.car(), args.cdr().and_then(|r| r.car())) {
+                        (Some(Value::Symbol(_)), Some(_)) => {},
+                        _ => return b.raise(malformed(form)),
+                    }
+                    let mut rest = args.clone();
+                    while let Value::Pair(_) = rest {
+                        match (rest.car(), rest.cdr().and_then(|r| r.car())) {
+                            (Some(Value::Symbol(n)), Some(init)) => {
+                                let n = n.clone();
+                                self.expr(b, &init, false);
+                                self.assign(b, &n);
+                            },
+                            _ => {
+                                let c = Condition::new("program-error", "malformed setq", vec![rest]);
+                                return b.raise(c);
+                            },
+                        }
+                        rest = rest.cdr().and_then(|r| r.cdr()).unwrap();
+                        // Only the value of the last assignment is kept.
+                        if let Value::Pair(_) = rest {
+                            b.emit(Op::Pop);
+                        }
+                    }
+                    return;
+                },
+                "and"|"or" => {
+                    let operands: Vec<Value> = args.iter().collect();
+                    if operands.is_empty() {
+                        let v = if name == "and" { Value::T } else { Value::Nil };
+                        let i = b.constant(v);
+                        b.emit(Op::Const(i));
+                        return;
+                    }
+                    let mut jumps = vec![];
+                    for (n, x) in operands.iter().enumerate() {
+                        let last = n + 1 == operands.len();
+                        self.expr(b, x, tail && last);
+                        if !last {
+                            let op = if name == "and" { Op::AndJump(0) } else { Op::OrJump(0) };
+                            jumps.push(b.emit(op));
+                        }
+                    }
+                    for j in jumps {
+                        b.patch(j);
+                    }
+                    return;
+                },
+                "define-condition" => {
+                    let (kind, parent) = match args.to_vec().as_deref() {
+                        Some([Value::Symbol(k), parents]) => {
+                            match parents.to_vec().as_deref() {
+                                Some([]) => (k.clone(), "condition".to_string()),
+                                Some([Value::Symbol(p)]) => (k.clone(), p.clone()),
+                                _ => return b.raise(malformed(form)),
+                            }
+                        },
+                        _ => return b.raise(malformed(form)),
+                    };
+                    let k = b.constant(Value::sym(&kind));
+                    let p = b.constant(Value::sym(&parent));
+                    b.emit(Op::DefineCondition(k, p));
+                    return;
+                },
+                _ => {
+                    match expand(name, form) {
+                        Ok(Some(expanded)) => return self.expr(b, &expanded, tail),
+                        Ok(None) => {},
+                        Err(c) => return b.raise(c),
+                    }
+                },
+            }
+        }
+
+        self.expr(b, &head, false);
+        let mut argc = 0;
+        let mut rest = args;
+        while let Value::Pair(_) = rest {
+            self.expr(b, &rest.car().unwrap(), false);
+            argc += 1;
+            rest = rest.cdr().unwrap();
+        }
+        if !rest.is_nil() {
+            let c = Condition::new("program-error", "improper argument list", vec![rest.clone()]);
+            return b.raise(c);
+        }
+        b.emit(if tail { Op::TailCall(argc) } else { Op::Call(argc) });
+    }
+
+    // Emit a closure, or a `Raise` if the lambda list is malformed.
+    // Returns whether the closure was emitted.
+    fn lambda(&mut self, b: &mut Builder, name: Option<String>, params: &Value, body: &Value)
+        -> bool
+    {
+        let bad = || {
+            Condition::new("program-error", "malformed lambda list", vec![params.clone()])
+        };
+        let mut names = vec![];
+        let mut rest = false;
+        let mut iter = params.iter();
+        while let Some(p) = iter.next() {
+            match p {
+                Value::Symbol(s) if s == "&rest" => {
+                    match (iter.next(), iter.next()) {
+                        (Some(Value::Symbol(r)), None) => {
+                            names.push(r.clone());
+                            rest = true;
+                        },
+                        _ => {
+                            b.raise(bad());
+                            return false;
+                        },
+                    }
+                },
+                Value::Symbol(s) => names.push(s.clone()),
+                _ => {
+                    b.raise(bad());
+                    return false;
+                },
+            }
+        }
+        // A name bound twice is an error, as in `make_lambda`.
+        let twice = names.iter().enumerate().any(|(i, n)| names[..i].contains(n));
+        if params.to_vec().is_none() || twice {
+            b.raise(bad());
+            return false;
+        }
+
+        let nparams = names.len() - rest as usize;
+        let mut scope = names.clone();
+        for n in defined_names(body) {
+            if !scope.contains(&n) {
+                scope.push(n);
+            }
+        }
+        let mut inner = Builder::new(name, nparams, rest, scope.clone());
+        self.scopes.push(scope);
+        self.body(&mut inner, body, true);
+        inner.emit(Op::Return);
+        self.scopes.pop();
+
+        b.proto.protos.push(Rc::new(inner.finish()));
+        let i = (b.proto.protos.len() - 1) as u32;
+        b.emit(Op::Closure(i));
+        true
+    }
+}
+
+// Names that `defun` and `defvar` may bind in the environment of a
+// function body. They get slots in the function's frame; until the
+// definition runs, the slot is unbound and lookups fall through to the
+// enclosing scopes, as in the tree-walking evaluator.
+fn defined_names(body: &Value) -> Vec<String> {
+    let mut names = vec![];
+    let mut pending: Vec<Value> = body.iter().collect();
+    pending.reverse();
+    while let Some(form) = pending.pop() {
+        let head = match form.car() {
+            Some(h) => h,
+            None => continue,
+        };
+        let args = form.cdr().unwrap();
+        match head.as_symbol() {
+            Some("quote")|Some("lambda")|Some("define-condition") => continue,
+            Some("defun") => {
+                if let Some(Value::Symbol(n)) = args.car() {
+                    names.push(n.clone());
+                }
+                continue;
+            },
+            Some("defvar") => {
+                if let Some(Value::Symbol(n)) = args.car() {
+                    names.push(n.clone());
+                }
+                pending.extend(args.iter().skip(1).collect::<Vec<_>>().into_iter().rev());
+                continue;
+            },
+            Some(h) => {
+                if let Ok(Some(expanded)) = expand(h, &form) {
+                    pending.push(expanded);
+                    continue;
+                }
+            },
+            None => {},
+        }
+        pending.extend(form.iter().collect::<Vec<_>>().into_iter().rev());
+    }
+    names
+}
+
+#[test]
+fn test_compile_lexical_addresses() {
+    let form = Value::from(crate::reader::read("(lambda (x) (lambda (y) (+ x y)))").unwrap());
+    let proto = compile(&form);
+    let outer = &proto.protos[0];
+    let inner = &outer.protos[0];
+    assert_eq!(
+        inner.code,
+        vec![Op::Global(0), Op::Local(1, 0), Op::Local(0, 0), Op::TailCall(2), Op::Return]
+        );
+    assert_eq!(inner.consts[0], Value::sym("+"));
+}
+
+#[test]
+fn test_compile_internal_defines() {
+    let form = Value::from(crate::reader::read(
+            "(lambda (x) (when x (defvar y 1)) (defun f () y) (f))"
+            ).unwrap());
+    let proto = compile(&form);
+    assert_eq!(proto.protos[0].names, vec!["x", "y", "f"]);
+}
+
+#[test]
+fn test_compile_malformed() {
+    let form = Value::from(crate::reader::read("(progn 1 (if))").unwrap());
+    let proto = compile(&form);
+    assert!(matches!(proto.code[2], Op::Raise(_)));
+}
@@ -0,0 +1,177 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use crate::reader::{Token, LprpError};
+use crate::value::Value;
+
+
+// A signalled condition. Conditions are first-class values inside the
+// evaluator and become `LprpError::Condition` when they reach Rust.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub kind: String,
+    pub message: String,
+    pub irritants: Vec<Value>,
+}
+
+impl Condition {
+    pub fn new(kind: &str, message: &str, irritants: Vec<Value>) -> Condition {
+        Condition {
+            kind: kind.to_string(),
+            message: message.to_string(),
+            irritants,
+        }
+    }
+}
+
+// "message: irritant irritant"
+fn write_condition<T: fmt::Display>(f: &mut fmt::Formatter, message: &str, irritants: &[T])
+    -> Result<(), fmt::Error>
+{
+    f.write_str(message)?;
+    for (i, x) in irritants.iter().enumerate() {
+        f.write_str(if i == 0 { ": " } else { " " })?;
+        write!(f, "{}", x)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}: ", self.kind)?;
+        write_condition(f, &self.message, &self.irritants)
+    }
+}
+
+pub(crate) fn display_error(f: &mut fmt::Formatter, kind: &str, message: &str, irritants: &[Token])
+    -> Result<(), fmt::Error>
+{
+    write!(f, "{}: ", kind)?;
+    let printed: Vec<Value> = irritants.iter().map(Value::from).collect();
+    write_condition(f, message, &printed)
+}
+
+// Irritants that are not plain data, such as closures, cross into Rust
+// as their printed representation.
+impl From<Condition> for LprpError {
+    fn from(c: Condition) -> Self {
+        let irritants = c.irritants.iter()
+            .map(|v| Token::try_from(v).unwrap_or_else(|_| Token::Str(v.to_string())))
+            .collect();
+        LprpError::Condition { kind: c.kind, message: c.message, irritants }
+    }
+}
+
+impl From<LprpError> for Condition {
+    fn from(e: LprpError) -> Self {
+        match e {
+            LprpError::Condition { kind, message, irritants } => {
+                Condition {
+                    kind,
+                    message,
+                    irritants: irritants.iter().map(Value::from).collect(),
+                }
+            },
+            LprpError::ConversionError|LprpError::TypeError { .. } => {
+                Condition::new("type-error", &e.to_string(), vec![])
+            },
+            _ => Condition::new("reader-error", &e.to_string(), vec![]),
+        }
+    }
+}
+
+#[test]
+fn test_condition_conversion() {
+    let c = Condition::new("type-error", "car: expected list", vec![Value::Int(1)]);
+    assert_eq!(c.to_string(), "type-error: car: expected list: 1");
+
+    let e = LprpError::from(c.clone());
+    assert_eq!(
+        e,
+        LprpError::Condition {
+            kind: "type-error".to_string(),
+            message: "car: expected list".to_string(),
+            irritants: vec![Token::Int(1)],
+        }
+        );
+    assert_eq!(e.to_string(), "type-error: car: expected list: 1");
+    assert_eq!(Condition::from(e), c);
+
+    let c = Condition::from(LprpError::SyntaxError);
+    assert_eq!(c.kind, "reader-error");
+}
+
+// ***** Condition types *****
+// Condition types form a single-inheritance hierarchy rooted at
+// `condition`. Types declared with `define-condition` are kept in
+// `Kinds`; unknown types are treated as subtypes of `error`.
+pub struct Kinds {
+    parents: HashMap<String, String>,
+}
+
+impl Kinds {
+    pub fn new() -> Kinds {
+        let mut parents = HashMap::new();
+        for (kind, parent) in &[
+            ("error", "condition"),
+            ("warning", "condition"),
+            ("simple-condition", "condition"),
+            ("simple-error", "error"),
+            ("type-error", "error"),
+            ("unbound-variable", "error"),
+            ("program-error", "error"),
+            ("reader-error", "error"),
+            ("arithmetic-error", "error"),
+            ("division-by-zero", "arithmetic-error"),
+        ] {
+            parents.insert(kind.to_string(), parent.to_string());
+        }
+        Kinds { parents }
+    }
+
+    pub fn define(&mut self, kind: &str, parent: &str) {
+        self.parents.insert(kind.to_string(), parent.to_string());
+    }
+
+    // Whether `kind` is `ancestor` or one of its subtypes.
+    pub fn is_a(&self, kind: &str, ancestor: &str) -> bool {
+        let mut cur = kind;
+        let mut steps = 0;
+        loop {
+            if cur == ancestor {
+                return true;
+            }
+            if cur == "condition" || steps > self.parents.len() {
+                return false;
+            }
+            cur = self.parents.get(cur).map_or("error", |p| p.as_str());
+            steps += 1;
+        }
+    }
+}
+
+impl Default for Kinds {
+    fn default() -> Self {
+        Kinds::new()
+    }
+}
+
+#[test]
+fn test_kinds() {
+    let mut kinds = Kinds::new();
+    assert!(kinds.is_a("division-by-zero", "arithmetic-error"));
+    assert!(kinds.is_a("division-by-zero", "condition"));
+    assert!(!kinds.is_a("warning", "error"));
+    assert!(kinds.is_a("my-error", "error"));
+
+    kinds.define("my-note", "warning");
+    assert!(kinds.is_a("my-note", "warning"));
+    assert!(!kinds.is_a("my-note", "error"));
+}
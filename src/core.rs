@@ -5,39 +5,186 @@
 // This file may not be copied, modified, on distributed except
 //  according to those terms.
 
-use crate::reader::{read, Token, LprpError};
+use crate::eval::Interp;
+#[cfg(test)]
+use crate::reader::read;
+use crate::reader::{Token, LprpError};
+use crate::value::Value;
 
 
-// ***** eq *****
+// ***** Equality *****
+// From strictest to loosest:
+//   eq      identity: the same token, or the same symbol, integer, `t`
+//           or `nil`. Strings, floats and lists are only eq to themselves.
+//   eql     eq, or floats with the same representation (so `NaN` is eql
+//           to itself, and `0.0` and `-0.0` are not eql).
+//   equal   structural: eql atoms, strings with the same characters, and
+//           lists whose elements are equal. `'x` is equal to `(quote x)`.
+//   equalp  equal, but strings ignore case and numbers compare by value
+//           across types, so `1` is equalp to `1.0`.
+// `()` and `nil` are the same object under all four.
+// The evaluator's predicates agree, except that its strings and floats
+// have no address: there `eq` compares them as `eql` and `equal` do.
+
+fn is_nil(tk: &Token) -> bool {
+    match tk {
+        Token::Nil => true,
+        Token::List(l) => l.is_empty(),
+        _ => false,
+    }
+}
+
 pub fn eq(lhs: &Token, rhs: &Token) -> bool {
-    lhs == rhs
+    if std::ptr::eq(lhs, rhs) {
+        return true;
+    }
+    match (lhs, rhs) {
+        (Token::T, Token::T) => true,
+        (Token::Int(a), Token::Int(b)) => a == b,
+        (Token::Symbol(a), Token::Symbol(b)) => a == b,
+        _ => is_nil(lhs) && is_nil(rhs),
+    }
 }
 
 #[test]
 fn test_eq() {
     let list_1 = read("'(1 2 3)").unwrap();
-    assert_eq!(eq(
+    assert!(eq(&list_1, &list_1));
+    assert!(!eq(&list_1, &list_1.clone()));
+
+    let sym_1 = read("cons").unwrap();
+    let sym_2 = read("conj").unwrap();
+    assert!(!eq(&sym_1, &sym_2));
+    assert!(eq(&sym_1, &read("cons").unwrap()));
+    assert!(eq(&Token::Int(3), &Token::Int(3)));
+    assert!(eq(&Token::Nil, &read("()").unwrap()));
+    let f = Token::Float(1.0);
+    assert!(eq(&f, &f));
+    assert!(!eq(&f, &f.clone()));
+    assert!(!eq(&Token::Str("a".to_string()), &Token::Str("a".to_string())));
+}
+
+pub fn eql(lhs: &Token, rhs: &Token) -> bool {
+    match (lhs, rhs) {
+        (Token::Float(a), Token::Float(b)) => a.to_bits() == b.to_bits(),
+        _ => eq(lhs, rhs),
+    }
+}
+
+#[test]
+fn test_eql() {
+    assert!(eql(&Token::Float(1.5), &Token::Float(1.5)));
+    assert!(eql(&Token::Float(f64::NAN), &Token::Float(f64::NAN)));
+    assert!(!eql(&Token::Float(0.0), &Token::Float(-0.0)));
+    assert!(!eql(&Token::Int(1), &Token::Float(1.0)));
+    assert!(!eql(&read("(1)").unwrap(), &read("(1)").unwrap()));
+}
+
+// Compare lists element by element and atoms with `same`.
+fn structurally(lhs: &Token, rhs: &Token, same: fn(&Token, &Token) -> bool) -> bool {
+    match (lhs, rhs) {
+        (Token::Quote(a), Token::Quote(b)) => structurally(a, b, same),
+        (Token::Quote(q), Token::List(l))|(Token::List(l), Token::Quote(q)) => {
+            match &l[..] {
+                [Token::Symbol(s), x] => s == "quote" && structurally(q, x, same),
+                _ => false,
+            }
+        },
+        (Token::List(a), Token::List(b)) if !a.is_empty() || !b.is_empty() => {
+            a.len() == b.len() && a.iter().zip(b).all(|(x, y)| structurally(x, y, same))
+        },
+        _ => same(lhs, rhs),
+    }
+}
+
+pub fn equal(lhs: &Token, rhs: &Token) -> bool {
+    structurally(lhs, rhs, |a, b| {
+        match (a, b) {
+            (Token::Str(x), Token::Str(y)) => x == y,
+            _ => eql(a, b),
+        }
+    })
+}
+
+#[test]
+fn test_equal() {
+    let list_1 = read("'(1 2 3)").unwrap();
+    assert!(equal(
             &list_1,
             &Token::Quote(Box::new(Token::List(vec![
                                                Token::Int(1),
                                                Token::Int(2),
                                                Token::Int(3),
-            ])))),
-            true
+            ]))))
             );
+    assert!(equal(&list_1, &read("(quote (1 2 3))").unwrap()));
+    assert!(equal(&read("(a \"s\" (2.0))").unwrap(), &read("(a \"s\" (2.0))").unwrap()));
+    assert!(!equal(&read("(1)").unwrap(), &read("(1.0)").unwrap()));
+    assert!(!equal(&Token::Str("A".to_string()), &Token::Str("a".to_string())));
+    assert!(equal(&read("(nil)").unwrap(), &read("(())").unwrap()));
+}
 
-    let sym_1 = read("cons").unwrap();
-    let sym_2 = read("conj").unwrap();
-    assert_eq!(eq(&sym_1, &sym_2), false);
+// Exact, where `i as f64 == f` would round: 2^63 is the first float
+// beyond i64. The evaluator's `equalp` uses this too.
+pub(crate) fn int_equals_float(i: i64, f: f64) -> bool {
+    f.fract() == 0.0 && (-9.223_372_036_854_776e18..9.223_372_036_854_776e18).contains(&f) && f as i64 == i
+}
+
+fn same_number(lhs: &Token, rhs: &Token) -> bool {
+    match (lhs, rhs) {
+        (Token::Int(a), Token::Int(b)) => a == b,
+        (Token::Float(a), Token::Float(b)) => a == b,
+        (Token::Int(i), Token::Float(f))|(Token::Float(f), Token::Int(i)) => int_equals_float(*i, *f),
+        _ => false,
+    }
+}
+
+pub fn equalp(lhs: &Token, rhs: &Token) -> bool {
+    structurally(lhs, rhs, |a, b| {
+        match (a, b) {
+            (Token::Str(x), Token::Str(y)) => x.to_lowercase() == y.to_lowercase(),
+            _ => eql(a, b) || same_number(a, b),
+        }
+    })
+}
+
+#[test]
+fn test_equalp() {
+    assert!(equalp(&read("(1 \"Hello\")").unwrap(), &read("(1.0 \"hELLO\")").unwrap()));
+    assert!(equalp(&Token::Float(0.0), &Token::Float(-0.0)));
+    assert!(equalp(&Token::Float(f64::NAN), &Token::Float(f64::NAN)));
+    assert!(!equalp(&Token::Int(i64::MAX), &Token::Float(9.223_372_036_854_776e18)));
+    assert!(!equalp(&Token::Int(1), &Token::Float(1.5)));
+    assert!(!equalp(&read("(a b)").unwrap(), &read("(a)").unwrap()));
+}
+
+#[test]
+fn test_equality_properties() {
+    // Each predicate implies the next, and all are reflexive.
+    let preds: [fn(&Token, &Token) -> bool; 4] = [eq, eql, equal, equalp];
+    for_random_lists(|a, b| {
+        for x in &[a, b] {
+            let items = items(x).unwrap();
+            for (l, r) in items.iter().zip(items.iter().skip(1)).chain(Some((*x, *x))) {
+                let results: Vec<bool> = preds.iter().map(|p| p(l, r)).collect();
+                for w in results.windows(2) {
+                    assert!(!w[0] || w[1]);
+                }
+                assert_eq!(equal(l, r), equal(r, l));
+            }
+            for p in &preds {
+                assert!(p(x, x));
+            }
+            assert!(equal(x, &(*x).clone()));
+        }
+    });
 }
 
 // ***** atom *****
 pub fn atom(tk: &Token) -> bool {
     match tk {
-        Token::List(l) => {
-            if l.len() == 0_usize { true } else { false }
-        },
-        Token::Quote(q) => atom(&(*q)),
+        Token::List(l) => l.is_empty(),
+        Token::Quote(q) => atom(q),
         _ => true
     }
 }
@@ -45,27 +192,87 @@ pub fn atom(tk: &Token) -> bool {
 #[test]
 fn test_atom() {
     let num = read("123").unwrap();
-    assert_eq!(atom(&num), true);
+    assert!(atom(&num));
 
     let nil = read("'()").unwrap();
-    assert_eq!(atom(&nil), true);
+    assert!(atom(&nil));
 
     let list = read("'(1 2 3)").unwrap();
-    assert_eq!(atom(&list), false);
+    assert!(!atom(&list));
 }
 
-// ***** car *****
-pub fn car(tk: &Token) -> Option<&Token> {
+// ***** Lists *****
+// `Token` lists are vectors, so every list here is proper: `nil`, `()`
+// and a quoted list all count as lists. A function returns `None` only
+// when an argument that must be a list is not one. Otherwise it gives
+// the Lisp answer, where running off the end of a list yields `nil`.
+// Empty results are always `Token::Nil`, except that `cdr` and the
+// other tails are slices of the list, which are empty instead.
+
+static NIL: Token = Token::Nil;
+
+// The elements of a list.
+pub fn items(tk: &Token) -> Option<&[Token]> {
     match tk {
-        Token::List(l) => {
-            let (hd, _) = l.split_first().unwrap();
-            Some(hd)
-        },
-        Token::Quote(q) => car(&(*q)),
+        Token::List(l) => Some(l),
+        Token::Nil => Some(&[]),
+        Token::Quote(q) => items(q),
         _ => None,
     }
 }
 
+// Build a list, `nil` when empty.
+pub fn list(items: Vec<Token>) -> Token {
+    if items.is_empty() {
+        Token::Nil
+    } else {
+        Token::List(items)
+    }
+}
+
+#[cfg(test)]
+pub(crate) struct Rng(pub(crate) u64);
+
+#[cfg(test)]
+impl Rng {
+    pub(crate) fn next(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+
+    // A random list of small atoms and nested lists.
+    fn list(&mut self, depth: u32) -> Token {
+        let len = self.next(6) as usize;
+        list((0..len).map(|_| self.token(depth)).collect())
+    }
+
+    fn token(&mut self, depth: u32) -> Token {
+        match self.next(if depth == 0 { 4 } else { 5 }) {
+            0 => Token::Int(self.next(5) as i64),
+            1 => Token::Symbol(["a", "b", "c"][self.next(3) as usize].to_string()),
+            2 => Token::Nil,
+            3 => Token::Str("s".to_string()),
+            _ => self.list(depth - 1),
+        }
+    }
+}
+
+// Run `check` on many random lists.
+#[cfg(test)]
+pub(crate) fn for_random_lists<F: FnMut(&Token, &Token)>(mut check: F) {
+    let mut rng = Rng(7);
+    for _ in 0..500 {
+        let a = rng.list(2);
+        let b = rng.list(2);
+        check(&a, &b);
+    }
+}
+
+// ***** car *****
+pub fn car(tk: &Token) -> Option<&Token> {
+    items(tk).map(|l| l.first().unwrap_or(&NIL))
+}
+
 #[test]
 fn test_car() {
     let list = read("(cons 1 2)").unwrap();
@@ -79,53 +286,348 @@ fn test_car() {
         car(&quote_list),
         Some(&Token::Int(1))
         );
+
+    assert_eq!(car(&Token::Nil), Some(&Token::Nil));
+    assert_eq!(car(&read("'()").unwrap()), Some(&Token::Nil));
+    assert_eq!(car(&Token::Int(1)), None);
 }
 
 // ***** cdr *****
-pub fn cdr(tk: &Token) -> Option<Token> {
-    match tk {
-        Token::List(l) => {
-            let (_, tl) = l.split_first().unwrap();
-            Some(Token::List(tl.to_vec()))
-        },
-        Token::Quote(q) => cdr(&(*q)),
-        _ => None
-    }
+// The tail is a slice of the list, so `cdr` is O(1) and copies nothing.
+// `list(tail.to_vec())` makes it a `Token` again.
+pub fn cdr(tk: &Token) -> Option<&[Token]> {
+    items(tk).map(|l| l.get(1..).unwrap_or(&[]))
+}
+
+#[cfg(test)]
+fn tail(l: Option<&[Token]>) -> Option<Token> {
+    l.map(|l| list(l.to_vec()))
 }
 
 #[test]
 fn test_cdr() {
     let list = read("(cons 1 2)").unwrap();
-
-    assert_eq!(
-        cdr(&list),
-        Some(Token::List(vec![Token::Int(1), Token::Int(2)]))
-    );
+    assert_eq!(cdr(&list), Some(&[Token::Int(1), Token::Int(2)][..]));
 
     let quote_list = read("'(1 2 3)").unwrap();
+    let mut tail = cdr(&quote_list).unwrap();
+    let mut seen = vec![];
+    while let [first, more @ ..] = tail {
+        seen.push(first.clone());
+        tail = more;
+    }
+    assert_eq!(seen, vec![Token::Int(2), Token::Int(3)]);
 
-    assert_eq!(
-        cdr(&quote_list),
-        Some(Token::List(vec![Token::Int(2), Token::Int(3)]))
-        );
+    assert_eq!(cdr(&Token::Nil), Some(&[][..]));
+    assert_eq!(cdr(&read("'(1)").unwrap()), Some(&[][..]));
+    assert_eq!(cdr(&Token::Str("s".to_string())), None);
+}
+
+#[test]
+fn test_car_cdr_properties() {
+    for_random_lists(|a, _| {
+        match items(a).unwrap() {
+            [] => assert_eq!((car(a), cdr(a)), (Some(&Token::Nil), Some(&[][..]))),
+            _ => assert_eq!(cons(car(a).unwrap().clone(), tail(cdr(a)).unwrap()).as_ref(), Some(a)),
+        }
+    });
+}
+
+// ***** cadr family *****
+pub fn caar(tk: &Token) -> Option<&Token> {
+    car(car(tk)?)
+}
+
+pub fn cadr(tk: &Token) -> Option<&Token> {
+    nth(1, tk)
+}
+
+pub fn cdar(tk: &Token) -> Option<&[Token]> {
+    cdr(car(tk)?)
+}
 
+pub fn cddr(tk: &Token) -> Option<&[Token]> {
+    nthcdr(2, tk)
+}
+
+pub fn caddr(tk: &Token) -> Option<&Token> {
+    nth(2, tk)
+}
+
+pub fn cdddr(tk: &Token) -> Option<&[Token]> {
+    nthcdr(3, tk)
+}
+
+pub fn cadddr(tk: &Token) -> Option<&Token> {
+    nth(3, tk)
+}
+
+#[test]
+fn test_cadr_family() {
+    let l = read("((a b) c d e)").unwrap();
+    assert_eq!(caar(&l), Some(&Token::Symbol("a".to_string())));
+    assert_eq!(cadr(&l), Some(&Token::Symbol("c".to_string())));
+    assert_eq!(tail(cdar(&l)), Some(read("(b)").unwrap()));
+    assert_eq!(tail(cddr(&l)), Some(read("(d e)").unwrap()));
+    assert_eq!(caddr(&l), Some(&Token::Symbol("d".to_string())));
+    assert_eq!(tail(cdddr(&l)), Some(read("(e)").unwrap()));
+    assert_eq!(cadddr(&l), Some(&Token::Symbol("e".to_string())));
+    assert_eq!(caar(&read("(1)").unwrap()), None);
+    assert_eq!(cadddr(&Token::Nil), Some(&Token::Nil));
+
+    for_random_lists(|a, _| {
+        let rest = tail(cdr(a)).unwrap();
+        assert_eq!(cadr(a), car(&rest));
+        assert_eq!(cddr(a), cdr(&rest));
+    });
+}
+
+// ***** cons *****
+// `Token` has no dotted pairs, so consing onto anything but a list is
+// `None`.
+pub fn cons(car: Token, cdr: Token) -> Option<Token> {
+    let mut l = vec![car];
+    l.extend_from_slice(items(&cdr)?);
+    Some(Token::List(l))
+}
+
+#[test]
+fn test_cons() {
     assert_eq!(
-        car(&cdr(&quote_list).unwrap()),
-        Some(&Token::Int(2)),
+        cons(Token::Int(1), read("(2 3)").unwrap()),
+        Some(read("(1 2 3)").unwrap())
         );
+    assert_eq!(cons(Token::Int(1), Token::Nil), Some(read("(1)").unwrap()));
+    assert_eq!(cons(Token::Int(1), Token::Int(2)), None);
+
+    for_random_lists(|a, b| {
+        let c = cons(b.clone(), a.clone()).unwrap();
+        assert_eq!(car(&c), Some(b));
+        assert_eq!(cdr(&c), items(a));
+        assert_eq!(length(&c), length(a).map(|n| n + 1));
+    });
+}
+
+// ***** length *****
+pub fn length(tk: &Token) -> Option<usize> {
+    items(tk).map(|l| l.len())
+}
+
+#[test]
+fn test_length() {
+    assert_eq!(length(&read("(1 (2 3) 4)").unwrap()), Some(3));
+    assert_eq!(length(&Token::Nil), Some(0));
+    assert_eq!(length(&Token::T), None);
+}
+
+// ***** append *****
+pub fn append(lists: &[Token]) -> Option<Token> {
+    let mut l = vec![];
+    for tk in lists {
+        l.extend_from_slice(items(tk)?);
+    }
+    Some(list(l))
+}
+
+#[test]
+fn test_append() {
+    let a = read("(1 2)").unwrap();
+    let b = read("'(3)").unwrap();
+    assert_eq!(append(&[a.clone(), Token::Nil, b]), Some(read("(1 2 3)").unwrap()));
+    assert_eq!(append(&[]), Some(Token::Nil));
+    assert_eq!(append(&[a, Token::Int(3)]), None);
+
+    for_random_lists(|a, b| {
+        let ab = append(&[a.clone(), b.clone()]).unwrap();
+        assert_eq!(length(&ab), Some(length(a).unwrap() + length(b).unwrap()));
+        assert_eq!(append(&[a.clone(), Token::Nil]).as_ref(), Some(a));
+        assert_eq!(nthcdr(length(a).unwrap(), &ab), items(b));
+    });
+}
+
+// ***** reverse *****
+pub fn reverse(tk: &Token) -> Option<Token> {
+    items(tk).map(|l| list(l.iter().rev().cloned().collect()))
+}
+
+#[test]
+fn test_reverse() {
+    assert_eq!(reverse(&read("(1 (2 3) 4)").unwrap()), Some(read("(4 (2 3) 1)").unwrap()));
+    assert_eq!(reverse(&Token::Nil), Some(Token::Nil));
+    assert_eq!(reverse(&Token::Int(1)), None);
+
+    for_random_lists(|a, b| {
+        assert_eq!(reverse(&reverse(a).unwrap()).as_ref(), Some(a));
+        assert_eq!(
+            reverse(&append(&[a.clone(), b.clone()]).unwrap()),
+            append(&[reverse(b).unwrap(), reverse(a).unwrap()])
+            );
+    });
+}
+
+// ***** nth *****
+pub fn nth(n: usize, tk: &Token) -> Option<&Token> {
+    items(tk).map(|l| l.get(n).unwrap_or(&NIL))
+}
+
+pub fn nthcdr(n: usize, tk: &Token) -> Option<&[Token]> {
+    items(tk).map(|l| l.get(n..).unwrap_or(&[]))
+}
+
+#[test]
+fn test_nth() {
+    let l = read("(a b c)").unwrap();
+    assert_eq!(nth(2, &l), Some(&Token::Symbol("c".to_string())));
+    assert_eq!(nth(3, &l), Some(&Token::Nil));
+    assert_eq!(tail(nthcdr(1, &l)), Some(read("(b c)").unwrap()));
+    assert_eq!(nthcdr(5, &l), Some(&[][..]));
+    assert_eq!(nth(0, &Token::Int(1)), None);
+
+    for_random_lists(|a, _| {
+        for n in 0..7 {
+            assert_eq!(nth(n, a), Some(nthcdr(n, a).unwrap().first().unwrap_or(&NIL)));
+        }
+        assert_eq!(nthcdr(0, a), items(a));
+    });
+}
+
+// ***** last *****
+// The list of the last element, as the last cons in Lisp.
+pub fn last(tk: &Token) -> Option<Token> {
+    items(tk).map(|l| list(l.last().cloned().into_iter().collect()))
+}
+
+#[test]
+fn test_last() {
+    assert_eq!(last(&read("(1 2 3)").unwrap()), Some(read("(3)").unwrap()));
+    assert_eq!(last(&Token::Nil), Some(Token::Nil));
+    assert_eq!(last(&Token::T), None);
+
+    for_random_lists(|a, _| {
+        let n = length(a).unwrap();
+        assert_eq!(last(a), tail(nthcdr(n.saturating_sub(1), a)));
+    });
+}
+
+// ***** member *****
+// The tail of the list starting at the first element equal to `item`,
+// or `nil`.
+pub fn member(item: &Token, tk: &Token) -> Option<Token> {
+    let l = items(tk)?;
+    let tail = match l.iter().position(|x| equal(x, item)) {
+        Some(i) => l[i..].to_vec(),
+        None => vec![],
+    };
+    Some(list(tail))
+}
+
+#[test]
+fn test_member() {
+    let l = read("(a b c)").unwrap();
+    let b = Token::Symbol("b".to_string());
+    assert_eq!(member(&b, &l), Some(read("(b c)").unwrap()));
+    assert_eq!(member(&Token::Int(1), &l), Some(Token::Nil));
+    assert_eq!(member(&b, &Token::Int(1)), None);
+
+    for_random_lists(|a, b| {
+        match member(b, a).unwrap() {
+            Token::Nil => assert!(!items(a).unwrap().contains(b)),
+            tail => assert_eq!(car(&tail), Some(b)),
+        }
+    });
+}
+
+// ***** assoc *****
+// The first entry of an association list whose car is equal to `key`,
+// or `nil`. Entries that are not lists are skipped.
+pub fn assoc<'a>(key: &Token, alist: &'a Token) -> Option<&'a Token> {
+    let found = items(alist)?
+        .iter()
+        .find(|entry| matches!(items(entry), Some([k, ..]) if equal(k, key)));
+    Some(found.unwrap_or(&NIL))
+}
+
+#[test]
+fn test_assoc() {
+    let alist = read("((a 1) 2 (b 2) (a 3))").unwrap();
+    let a = Token::Symbol("a".to_string());
+    assert_eq!(assoc(&a, &alist), Some(&read("(a 1)").unwrap()));
+    assert_eq!(assoc(&Token::T, &alist), Some(&Token::Nil));
+    assert_eq!(assoc(&a, &Token::Int(1)), None);
+
+    for_random_lists(|a, b| {
+        let entry = list(vec![b.clone(), Token::Int(0)]);
+        let alist = cons(entry.clone(), a.clone()).unwrap();
+        assert_eq!(assoc(b, &alist), Some(&entry));
+    });
+}
+
+// ***** mapcar *****
+pub fn mapcar<F: FnMut(&Token) -> Token>(f: F, tk: &Token) -> Option<Token> {
+    items(tk).map(|l| list(l.iter().map(f).collect()))
+}
+
+#[test]
+fn test_mapcar() {
+    let double = |tk: &Token| match tk {
+        Token::Int(i) => Token::Int(i * 2),
+        _ => Token::Nil,
+    };
+    assert_eq!(mapcar(double, &read("(1 2 3)").unwrap()), Some(read("(2 4 6)").unwrap()));
+    assert_eq!(mapcar(double, &Token::Int(1)), None);
+
+    for_random_lists(|a, _| {
+        assert_eq!(mapcar(Token::clone, a).as_ref(), Some(a));
+        assert_eq!(length(&mapcar(double, a).unwrap()), length(a));
+    });
+}
+
+// ***** filter *****
+pub fn filter<F: FnMut(&Token) -> bool>(mut pred: F, tk: &Token) -> Option<Token> {
+    items(tk).map(|l| list(l.iter().filter(|x| pred(x)).cloned().collect()))
+}
+
+#[test]
+fn test_filter() {
+    let is_int = |tk: &Token| matches!(tk, Token::Int(_));
+    assert_eq!(filter(is_int, &read("(1 a 2 \"s\")").unwrap()), Some(read("(1 2)").unwrap()));
+    assert_eq!(filter(is_int, &Token::T), None);
+
+    for_random_lists(|a, _| {
+        let ints = filter(is_int, a).unwrap();
+        assert!(items(&ints).unwrap().iter().all(is_int));
+        assert_eq!(filter(|_| true, a).as_ref(), Some(a));
+        assert_eq!(filter(|_| false, a), Some(Token::Nil));
+    });
+}
+
+// ***** reduce *****
+// Fold the list from the left, starting with `init`.
+pub fn reduce<F: FnMut(Token, &Token) -> Token>(f: F, init: Token, tk: &Token)
+    -> Option<Token>
+{
+    items(tk).map(|l| l.iter().fold(init, f))
+}
+
+#[test]
+fn test_reduce() {
+    let add = |acc: Token, x: &Token| match (acc, x) {
+        (Token::Int(a), Token::Int(b)) => Token::Int(a + b),
+        _ => Token::Nil,
+    };
+    assert_eq!(reduce(add, Token::Int(0), &read("(1 2 3)").unwrap()), Some(Token::Int(6)));
+    assert_eq!(reduce(add, Token::Int(0), &Token::Nil), Some(Token::Int(0)));
+    assert_eq!(reduce(add, Token::Int(0), &Token::T), None);
+
+    for_random_lists(|a, _| {
+        let rebuilt = reduce(|acc, x| cons(x.clone(), acc).unwrap(), Token::Nil, a).unwrap();
+        assert_eq!(Some(rebuilt), reverse(a));
+    });
 }
 
 // Check whether List's car is symbol or not.
 pub fn is_car_sym(tk: &Token) -> bool {
-    match car(tk) {
-        Some(t) => {
-            match t {
-                Token::Symbol(_) => true,
-                _ => false,
-            }
-        },
-        None => false
-    }
+    matches!(car(tk), Some(Token::Symbol(_)))
 }
 
 #[test]
@@ -142,11 +644,7 @@ fn test_is_car_sym() {
 
 // If List's car is symbol, return symbol.
 pub fn get_sym(tk: &Token) -> Option<&Token> {
-    if is_car_sym(tk) {
-        Some(car(tk).unwrap())
-    } else {
-        None
-    }
+    car(tk).filter(|hd| matches!(hd, Token::Symbol(_)))
 }
 
 #[test]
@@ -160,11 +658,12 @@ fn test_get_sym() {
 
     let e = read("'(1 2 3)").unwrap();
     assert_eq!(get_sym(&e), None);
+    assert_eq!(get_sym(&Token::Nil), None);
 }
 
-pub fn get_args(tk: &Token) -> Option<Token> {
+pub fn get_args(tk: &Token) -> Option<&[Token]> {
     if is_car_sym(tk) {
-        Some(cdr(tk).unwrap())
+        cdr(tk)
     } else {
         None
     }
@@ -176,26 +675,24 @@ fn test_get_args() {
 
     assert_eq!(
         get_args(&list),
-        Some(Token::List(vec![
-                         Token::Quote(Box::new(
-                                 Token::List(vec![
-                                             Token::Int(1),
-                                             Token::Int(2)
-                                 ])))
-        ])));
+        Some(&[Token::Quote(Box::new(
+                    Token::List(vec![
+                                Token::Int(1),
+                                Token::Int(2)
+                    ])))
+        ][..]));
+    assert_eq!(get_args(&read("(f)").unwrap()), Some(&[][..]));
 }
 
 
-pub fn eval<'a>(exp: &'a str) -> Result<Token, LprpError> {
-    let token = read(exp).unwrap();
-    
-    if atom(&token) {
-        return Ok(token);
-    } else {
-        match get_sym(&token) {
-            // TODO: Temporary return.
-            Some(sym) => return Ok(sym.clone()),
-            None => return Err(LprpError::SyntaxError)
-        }
-    }
+// Evaluate every expression in `exp` with a fresh interpreter.
+pub fn eval(exp: &str) -> Result<Value, LprpError> {
+    Interp::new().eval_str(exp)
+}
+
+#[test]
+fn test_eval() {
+    assert_eq!(eval("(car '(1 2))"), Ok(Value::Int(1)));
+    assert_eq!(eval("(defun f (x) (+ x 1)) (f 1)"), Ok(Value::Int(2)));
+    assert!(eval("(car 1)").is_err());
 }
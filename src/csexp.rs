@@ -0,0 +1,650 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::convert::TryFrom;
+use crate::reader::{self, Token, LprpError};
+
+
+// ***** Sexp *****
+// Rivest's S-expressions, in which every atom is a byte string with an
+// optional display hint. There are three ways to write one:
+//
+//   canonical  `(3:abc[10:text/plain]2:hi)`, one byte-exact encoding
+//              for signing and hashing.
+//   advanced   `(abc [text/plain] "hi")`, for people. An atom may be a
+//              token, a "quoted string", #hex#, |base64| or n:verbatim,
+//              and whitespace separates elements.
+//   transport  `{base64 of the canonical form}`, for 7-bit channels.
+//
+// `Sexp::parse` reads all three; transport may appear anywhere an
+// element can.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sexp {
+    Atom(Atom),
+    List(Vec<Sexp>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Atom {
+    pub hint: Option<Vec<u8>>,
+    pub data: Vec<u8>,
+}
+
+fn csexp_error<T>(message: String) -> Result<T, LprpError> {
+    Err(LprpError::CsexpError(message))
+}
+
+impl Sexp {
+    pub fn atom<T: Into<Vec<u8>>>(data: T) -> Sexp {
+        Sexp::Atom(Atom { hint: None, data: data.into() })
+    }
+
+    pub fn hinted<H: Into<Vec<u8>>, T: Into<Vec<u8>>>(hint: H, data: T) -> Sexp {
+        Sexp::Atom(Atom { hint: Some(hint.into()), data: data.into() })
+    }
+
+    // Any of the three forms.
+    pub fn parse(input: &[u8]) -> Result<Sexp, LprpError> {
+        Parser { input, pos: 0, canonical: false, depth: 0 }.parse_all()
+    }
+
+    // Only the canonical form, with nothing before or after it.
+    pub fn parse_canonical(input: &[u8]) -> Result<Sexp, LprpError> {
+        Parser { input, pos: 0, canonical: true, depth: 0 }.parse_all()
+    }
+
+    pub fn to_canonical(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.write_canonical(&mut out);
+        out
+    }
+
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        fn verbatim(bytes: &[u8], out: &mut Vec<u8>) {
+            out.extend(bytes.len().to_string().bytes());
+            out.push(b':');
+            out.extend(bytes);
+        }
+        match self {
+            Sexp::Atom(Atom { hint, data }) => {
+                if let Some(h) = hint {
+                    out.push(b'[');
+                    verbatim(h, out);
+                    out.push(b']');
+                }
+                verbatim(data, out);
+            },
+            Sexp::List(l) => {
+                out.push(b'(');
+                l.iter().for_each(|s| s.write_canonical(out));
+                out.push(b')');
+            },
+        }
+    }
+
+    pub fn to_advanced(&self) -> String {
+        match self {
+            Sexp::Atom(Atom { hint: Some(h), data }) => {
+                format!("[{}]{}", advanced_atom(h), advanced_atom(data))
+            },
+            Sexp::Atom(Atom { hint: None, data }) => advanced_atom(data),
+            Sexp::List(l) => {
+                let items: Vec<String> = l.iter().map(Sexp::to_advanced).collect();
+                format!("({})", items.join(" "))
+            },
+        }
+    }
+
+    pub fn to_transport(&self) -> String {
+        format!("{{{}}}", base64_encode(&self.to_canonical()))
+    }
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-./_:*+=".contains(&b)
+}
+
+// As a token when possible, then as a quoted string, and base64
+// otherwise.
+fn advanced_atom(data: &[u8]) -> String {
+    match data.first() {
+        Some(b) if !b.is_ascii_digit() && data.iter().all(|&b| is_token_byte(b)) => {
+            String::from_utf8(data.to_vec()).unwrap()
+        },
+        _ if data.iter().all(|&b| (0x20..0x7f).contains(&b) || b"\t\n\r".contains(&b)) => {
+            let mut s = String::from("\"");
+            for &b in data {
+                match b {
+                    b'"' => s.push_str("\\\""),
+                    b'\\' => s.push_str("\\\\"),
+                    b'\t' => s.push_str("\\t"),
+                    b'\n' => s.push_str("\\n"),
+                    b'\r' => s.push_str("\\r"),
+                    _ => s.push(b as char),
+                }
+            }
+            s.push('"');
+            s
+        },
+        _ => format!("|{}|", base64_encode(data)),
+    }
+}
+
+// ***** Base64 *****
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => s.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => s.push('='),
+            }
+        }
+    }
+    s
+}
+
+// Whitespace is skipped and padding is optional.
+fn base64_decode(text: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut n, mut bits) = (0u32, 0);
+    let text: Vec<u8> = text.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    let end = text.iter().position(|&b| b == b'=').unwrap_or(text.len());
+    if text[end..].iter().any(|&b| b != b'=') || text.len() - end > 2 {
+        return None;
+    }
+    for &b in &text[..end] {
+        n = n << 6 | BASE64.iter().position(|&c| c == b)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((n >> bits) as u8);
+            n &= (1 << bits) - 1;
+        }
+    }
+    match bits {
+        0|2|4 if n == 0 => Some(out),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_base64() {
+    let cases: &[(&[u8], &str)] = &[
+        (b"", ""), (b"f", "Zg=="), (b"fo", "Zm8="), (b"foo", "Zm9v"), (b"foob", "Zm9vYg=="),
+        (b"fooba", "Zm9vYmE="), (b"foobar", "Zm9vYmFy"), (&[0xff, 0x00, 0x80], "/wCA"),
+    ];
+    for (data, text) in cases {
+        assert_eq!(base64_encode(data), *text);
+        assert_eq!(base64_decode(text.as_bytes()).as_deref(), Some(*data));
+    }
+    assert_eq!(base64_decode(b"Zm9v\n YmE"), Some(b"fooba".to_vec()));
+    assert_eq!(base64_decode(b"Zm=9"), None);
+    assert_eq!(base64_decode(b"Zh=="), None);
+    assert_eq!(base64_decode(b"Z!=="), None);
+}
+
+// ***** Parser *****
+// Deeper nesting is rejected, so untrusted input cannot overflow the
+// stack.
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    canonical: bool,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, LprpError> {
+        csexp_error(format!("{} at byte {}", message, self.pos))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        if !self.canonical {
+            while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), LprpError> {
+        self.skip_space();
+        match self.peek() {
+            Some(c) if c == b => {
+                self.pos += 1;
+                Ok(())
+            },
+            _ => self.error(&format!("expected '{}'", b as char)),
+        }
+    }
+
+    fn parse_all(&mut self) -> Result<Sexp, LprpError> {
+        let sexp = self.parse()?;
+        self.skip_space();
+        match self.peek() {
+            None => Ok(sexp),
+            Some(_) => self.error("trailing input"),
+        }
+    }
+
+    fn parse(&mut self) -> Result<Sexp, LprpError> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'(') => {
+                if self.depth == MAX_DEPTH {
+                    return self.error("nesting is too deep");
+                }
+                self.pos += 1;
+                self.depth += 1;
+                let mut items = vec![];
+                loop {
+                    self.skip_space();
+                    match self.peek() {
+                        Some(b')') => {
+                            self.pos += 1;
+                            self.depth -= 1;
+                            return Ok(Sexp::List(items));
+                        },
+                        Some(_) => items.push(self.parse()?),
+                        None => return self.error("unclosed list"),
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                let hint = self.simple_string()?;
+                self.expect(b']')?;
+                self.skip_space();
+                Ok(Sexp::hinted(hint, self.simple_string()?))
+            },
+            Some(b'{') if !self.canonical => {
+                let start = self.pos + 1;
+                let end = match self.input[start..].iter().position(|&b| b == b'}') {
+                    Some(n) => start + n,
+                    None => return self.error("unclosed transport"),
+                };
+                let bytes = match base64_decode(&self.input[start..end]) {
+                    Some(bytes) => bytes,
+                    None => return self.error("bad base64 in transport"),
+                };
+                let sexp = Sexp::parse_canonical(&bytes)?;
+                self.pos = end + 1;
+                Ok(sexp)
+            },
+            Some(b')') => self.error("unexpected ')'"),
+            Some(_) => Ok(Sexp::atom(self.simple_string()?)),
+            None => self.error("unexpected end"),
+        }
+    }
+
+    fn simple_string(&mut self) -> Result<Vec<u8>, LprpError> {
+        let start = self.pos;
+        let len = match self.peek() {
+            Some(b) if b.is_ascii_digit() => Some(self.length()?),
+            _ => None,
+        };
+        let data = match (self.peek(), len) {
+            (Some(b':'), Some(n)) => {
+                self.pos += 1;
+                match self.input[self.pos..].get(..n) {
+                    Some(bytes) => {
+                        self.pos += n;
+                        bytes.to_vec()
+                    },
+                    None => return self.error(&format!("verbatim string of {} bytes is cut off", n)),
+                }
+            },
+            _ if self.canonical => return self.error("expected a verbatim string"),
+            (Some(b'"'), _) => self.quoted()?,
+            (Some(b'#'), _) => self.delimited(b'#', hex_decode, "hex")?,
+            (Some(b'|'), _) => self.delimited(b'|', base64_decode, "base64")?,
+            (Some(b), None) if is_token_byte(b) => {
+                while self.peek().is_some_and(is_token_byte) {
+                    self.pos += 1;
+                }
+                self.input[start..self.pos].to_vec()
+            },
+            _ => return self.error("expected an atom"),
+        };
+        match len {
+            Some(n) if n != data.len() => {
+                csexp_error(format!("string at byte {} has {} bytes, not {}", start, data.len(), n))
+            },
+            _ => Ok(data),
+        }
+    }
+
+    fn length(&mut self) -> Result<usize, LprpError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = &self.input[start..self.pos];
+        if digits.len() > 1 && digits[0] == b'0' {
+            self.pos = start;
+            return self.error("length with a leading zero");
+        }
+        match std::str::from_utf8(digits).unwrap().parse() {
+            Ok(n) => Ok(n),
+            Err(_) => self.error("length is too large"),
+        }
+    }
+
+    fn delimited(&mut self, delim: u8, decode: fn(&[u8]) -> Option<Vec<u8>>, name: &str)
+        -> Result<Vec<u8>, LprpError>
+    {
+        let start = self.pos + 1;
+        match self.input[start..].iter().position(|&b| b == delim) {
+            Some(n) => {
+                match decode(&self.input[start..start + n]) {
+                    Some(bytes) => {
+                        self.pos = start + n + 1;
+                        Ok(bytes)
+                    },
+                    None => self.error(&format!("bad {}", name)),
+                }
+            },
+            None => self.error(&format!("unclosed {}", name)),
+        }
+    }
+
+    fn quoted(&mut self) -> Result<Vec<u8>, LprpError> {
+        let mut out = vec![];
+        self.pos += 1;
+        loop {
+            let b = match self.peek() {
+                Some(b) => b,
+                None => return self.error("unclosed string"),
+            };
+            self.pos += 1;
+            match b {
+                b'"' => return Ok(out),
+                b'\\' => {
+                    let e = match self.peek() {
+                        Some(e) => e,
+                        None => return self.error("unclosed string"),
+                    };
+                    self.pos += 1;
+                    match e {
+                        b'b' => out.push(8),
+                        b't' => out.push(b'\t'),
+                        b'v' => out.push(11),
+                        b'n' => out.push(b'\n'),
+                        b'f' => out.push(12),
+                        b'r' => out.push(b'\r'),
+                        b'"'|b'\''|b'\\' => out.push(e),
+                        b'x' => out.push(self.escape(16, 2)?),
+                        b'0'..=b'7' => {
+                            self.pos -= 1;
+                            out.push(self.escape(8, 3)?);
+                        },
+                        // A line continuation.
+                        b'\n'|b'\r' => {
+                            if self.peek().is_some_and(|n| n != e && (n == b'\n' || n == b'\r')) {
+                                self.pos += 1;
+                            }
+                        },
+                        _ => {
+                            self.pos -= 1;
+                            return self.error("unknown escape");
+                        },
+                    }
+                },
+                _ => out.push(b),
+            }
+        }
+    }
+
+    fn escape(&mut self, radix: u32, digits: usize) -> Result<u8, LprpError> {
+        let text = self.input.get(self.pos..self.pos + digits)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u8::from_str_radix(d, radix).ok().filter(|_| d.chars().all(|c| c.is_digit(radix))));
+        match text {
+            Some(b) => {
+                self.pos += digits;
+                Ok(b)
+            },
+            None => self.error("bad escape"),
+        }
+    }
+}
+
+fn hex_decode(text: &[u8]) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits.chunks(2)
+        .map(|d| u8::from_str_radix(std::str::from_utf8(d).ok()?, 16).ok())
+        .collect()
+}
+
+#[test]
+fn test_csexp_parse() {
+    let abc = Sexp::List(vec![
+        Sexp::atom("abc"),
+        Sexp::hinted("text/plain", "hi"),
+        Sexp::List(vec![]),
+        Sexp::atom(vec![0, 255]),
+    ]);
+    let canonical = b"(3:abc[10:text/plain]2:hi()2:\x00\xff)";
+    assert_eq!(abc.to_canonical(), canonical.to_vec());
+    assert_eq!(Sexp::parse_canonical(canonical), Ok(abc.clone()));
+    assert_eq!(abc.to_advanced(), "(abc [text/plain]hi () |AP8=|)");
+    assert_eq!(Sexp::parse(abc.to_advanced().as_bytes()), Ok(abc.clone()));
+    assert_eq!(Sexp::parse(abc.to_transport().as_bytes()), Ok(abc.clone()));
+
+    let src = b"( abc 3:abc 3\"abc\" \"a\\x62\\143\" #61 6263# |YWJj| 3|YWJj| {MzphYmM=}\n\"ab\\\nc\")";
+    assert_eq!(Sexp::parse(src), Ok(Sexp::List(vec![Sexp::atom("abc"); 9])));
+    assert_eq!(Sexp::parse(b"\"\\t\\\"\\\\\""), Ok(Sexp::atom("\t\"\\")));
+
+    let err = |src: &[u8]| match Sexp::parse(src) {
+        Err(LprpError::CsexpError(m)) => m,
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(err(b"(3:abc"), "unclosed list at byte 6");
+    assert_eq!(err(b"4:abc"), "verbatim string of 4 bytes is cut off at byte 2");
+    assert_eq!(err(b"2\"abc\""), "string at byte 0 has 3 bytes, not 2");
+    assert_eq!(err(b"03:abc"), "length with a leading zero at byte 0");
+    assert_eq!(err(b"#6#"), "bad hex at byte 0");
+    assert_eq!(err(b"abc def"), "trailing input at byte 4");
+    assert_eq!(err(b"\"\\q\""), "unknown escape at byte 2");
+    assert!(Sexp::parse_canonical(b"(abc)").is_err());
+    assert!(Sexp::parse_canonical(b"(3:abc )").is_err());
+    assert!(Sexp::parse_canonical(b"{MzphYmM=}").is_err());
+
+    // Untrusted lengths and nesting.
+    assert_eq!(err(b"18446744073709551615:"), "verbatim string of 18446744073709551615 bytes is cut off at byte 21");
+    assert_eq!(err(b"99999999999999999999:"), "length is too large at byte 20");
+    let deep = |n| format!("{}{}", "(".repeat(n), ")".repeat(n));
+    assert!(Sexp::parse(deep(256).as_bytes()).is_ok());
+    assert_eq!(err(deep(257).as_bytes()), "nesting is too deep at byte 256");
+    assert_eq!(err("(".repeat(200000).as_bytes()), "nesting is too deep at byte 256");
+}
+
+// ***** Token *****
+// Tokens and S-expressions are mapped onto each other as follows:
+//
+//   t, integers, floats, symbols   their printed text as an atom
+//   strings                        their bytes as an atom
+//   nil, lists                     lists
+//   'x                             (quote x)
+//   (:display-hint "h" "data")     [h]data
+//
+// An atom reads back as the symbol, number or `t` it spells when the
+// lprp reader gives that token for it, and as a string otherwise, so
+// `"abc"` comes back as the symbol `abc`. A token whose text would read
+// back as another token is written with a hint naming its type instead:
+// the string "abc" as `[6:string]3:abc`, the symbol `a b` as
+// `[6:symbol]3:a b`, and an infinite float as `[5:float]3:inf`. So two
+// tokens share a canonical form only when they are the same datum, as
+// `'x` and `(quote x)` are. An atom that is not UTF-8 cannot be a token;
+// use `Sexp` for binary data.
+const TYPE_HINTS: &[&str] = &["string", "symbol", "float"];
+
+impl From<&Token> for Sexp {
+    fn from(tk: &Token) -> Self {
+        match tk {
+            Token::Nil => Sexp::List(vec![]),
+            Token::Quote(q) => Sexp::List(vec![Sexp::atom("quote"), Sexp::from(&**q)]),
+            Token::List(l) => {
+                match &l[..] {
+                    [Token::Symbol(k), Token::Str(h), Token::Str(d)]
+                        if k == ":display-hint" && !TYPE_HINTS.contains(&h.as_str()) =>
+                    {
+                        Sexp::hinted(h.as_bytes(), d.as_bytes())
+                    },
+                    _ => Sexp::List(l.iter().map(Sexp::from).collect()),
+                }
+            },
+            _ => token_atom(tk),
+        }
+    }
+}
+
+fn token_atom(tk: &Token) -> Sexp {
+    let (hint, text) = match tk {
+        Token::Str(s) => ("string", s.clone()),
+        Token::Symbol(s) => ("symbol", s.clone()),
+        Token::Float(f) if !f.is_finite() => ("float", f.to_string()),
+        _ => ("float", tk.to_string()),
+    };
+    match atom_token(text.as_bytes()) {
+        Ok(ref back) if back == tk => Sexp::atom(text),
+        _ => Sexp::hinted(hint, text),
+    }
+}
+
+fn atom_token(data: &[u8]) -> Result<Token, LprpError> {
+    let text = utf8(data)?;
+    if text.bytes().all(is_token_byte) && !text.is_empty() {
+        if let Ok(tk @ (Token::T|Token::Int(_)|Token::Float(_)|Token::Symbol(_))) = reader::read(&text) {
+            if tk.to_string() == text {
+                return Ok(tk);
+            }
+        }
+    }
+    Ok(Token::Str(text))
+}
+
+fn utf8(data: &[u8]) -> Result<String, LprpError> {
+    match String::from_utf8(data.to_vec()) {
+        Ok(text) => Ok(text),
+        Err(_) => csexp_error("atom is not UTF-8".to_string()),
+    }
+}
+
+impl TryFrom<&Sexp> for Token {
+    type Error = LprpError;
+
+    fn try_from(sexp: &Sexp) -> Result<Self, Self::Error> {
+        match sexp {
+            Sexp::Atom(Atom { hint: None, data }) => atom_token(data),
+            Sexp::Atom(Atom { hint: Some(h), data }) => {
+                match (&utf8(h)?[..], utf8(data)?) {
+                    ("string", text) => Ok(Token::Str(text)),
+                    ("symbol", text) => Ok(Token::Symbol(text)),
+                    ("float", text) => match text.parse() {
+                        Ok(f) => Ok(Token::Float(f)),
+                        Err(_) => csexp_error(format!("bad float {:?}", text)),
+                    },
+                    (h, text) => {
+                        let hint = Token::Str(h.to_string());
+                        Ok(Token::List(vec![Token::Symbol(":display-hint".to_string()), hint, Token::Str(text)]))
+                    },
+                }
+            },
+            Sexp::List(l) if l.is_empty() => Ok(Token::Nil),
+            Sexp::List(l) => {
+                let items = l.iter().map(Token::try_from).collect::<Result<Vec<_>, _>>()?;
+                match &items[..] {
+                    [Token::Symbol(q), _] if q == "quote" => {
+                        Ok(Token::Quote(Box::new(items.into_iter().nth(1).unwrap())))
+                    },
+                    _ => Ok(Token::List(items)),
+                }
+            },
+        }
+    }
+}
+
+// Read a token from any of the three forms.
+pub fn read(input: &[u8]) -> Result<Token, LprpError> {
+    Token::try_from(&Sexp::parse(input)?)
+}
+
+pub fn canonical(tk: &Token) -> Vec<u8> {
+    Sexp::from(tk).to_canonical()
+}
+
+pub fn advanced(tk: &Token) -> String {
+    Sexp::from(tk).to_advanced()
+}
+
+pub fn transport(tk: &Token) -> String {
+    Sexp::from(tk).to_transport()
+}
+
+#[test]
+fn test_csexp_token() {
+    let tk = reader::read("(public-key (rsa (e 65537) (n \"a b\")) t 1.5 nil 'x)").unwrap();
+    let bytes = canonical(&tk);
+    assert_eq!(bytes, b"(10:public-key(3:rsa(1:e5:65537)(1:n3:a b))1:t3:1.5()(5:quote1:x))".to_vec());
+    assert_eq!(read(&bytes), Ok(tk.clone()));
+    assert_eq!(advanced(&tk), "(public-key (rsa (e \"65537\") (n \"a b\")) t \"1.5\" () (quote x))");
+    assert_eq!(read(advanced(&tk).as_bytes()), Ok(tk.clone()));
+    assert_eq!(read(transport(&tk).as_bytes()), Ok(tk));
+
+    let hinted = reader::read("(:display-hint \"text/plain\" \"hi\")").unwrap();
+    assert_eq!(canonical(&hinted), b"[10:text/plain]2:hi".to_vec());
+    assert_eq!(read(b"[text/plain]hi"), Ok(hinted));
+    assert_eq!(read(b"(\"abc\" \"01\" 7:a:b(c)d)").unwrap(),
+               reader::read("(abc \"01\" \"a:b(c)d\")").unwrap());
+    assert_eq!(read(b"|/w==|"), Err(LprpError::CsexpError("atom is not UTF-8".to_string())));
+
+    // Strings and symbols spelled the same stay apart.
+    let user = reader::read("((name \"admin\") (name admin) (id \"7\") (id 7) \"a b\")").unwrap();
+    let bytes = canonical(&user);
+    assert_eq!(bytes, b"((4:name[6:string]5:admin)(4:name5:admin)(2:id[6:string]1:7)(2:id1:7)3:a b)".to_vec());
+    assert_eq!(read(&bytes), Ok(user.clone()));
+    assert_eq!(read(advanced(&user).as_bytes()), Ok(user));
+
+    let odd = Token::List(vec![
+        Token::Float(1e20),
+        Token::Float(1e-5),
+        Token::Float(f64::NEG_INFINITY),
+        Token::Float(f64::NAN),
+        Token::Str("1.50".to_string()),
+        Token::Str("nil".to_string()),
+        Token::Symbol("nil".to_string()),
+        Token::Symbol("a b".to_string()),
+        reader::read("(:display-hint \"string\" \"x\")").unwrap(),
+    ]);
+    let bytes = canonical(&odd);
+    assert_eq!(
+        bytes,
+        b"(23:100000000000000000000.07:0.00001[5:float]4:-inf[5:float]3:NaN4:1.503:nil\
+          [6:symbol]3:nil[6:symbol]3:a b(13::display-hint[6:string]6:string[6:string]1:x))".to_vec()
+        );
+    let back = read(&bytes).unwrap();
+    assert!(crate::core::equal(&back, &odd), "{:?}", back);
+    assert_eq!(read(b"[5:float]1:x"), Err(LprpError::CsexpError("bad float \"x\"".to_string())));
+
+    crate::core::for_random_lists(|a, b| {
+        assert_eq!(canonical(a) == canonical(b), crate::core::equal(a, b), "{:?} {:?}", a, b);
+        assert!(crate::core::equal(&read(&canonical(a)).unwrap(), a));
+    });
+}
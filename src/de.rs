@@ -0,0 +1,466 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+use serde::de::{self, Deserialize, DeserializeOwned, IntoDeserializer, Visitor};
+use crate::reader::{read, Token, LprpError};
+use crate::ser;
+
+
+// ***** Deserializer *****
+// Deserialize Rust data from `Token`s written as `ser` writes them.
+// Structs are read from keyword plists or from alists, and a missing
+// `Option` field is None. `(x)` is Some(x) when `ser` would wrap x. Hyphens in keywords and symbols match
+// underscores in field and variant names.
+#[derive(Clone, Copy)]
+pub struct Deserializer<'de> {
+    node: Node<'de>,
+}
+
+// A token, or the elements after the head of a list, which is how the
+// value of `(key a b)` in an alist is `(a b)`.
+#[derive(Clone, Copy)]
+enum Node<'de> {
+    One(&'de Token),
+    Rest(&'de [Token]),
+}
+
+static NIL: Token = Token::Nil;
+
+impl<'de> Deserializer<'de> {
+    pub fn new(tk: &'de Token) -> Self {
+        Deserializer { node: Node::One(tk) }
+    }
+
+    fn rest(items: &'de [Token]) -> Self {
+        match items {
+            [] => Deserializer::new(&NIL),
+            _ => Deserializer { node: Node::Rest(items) },
+        }
+    }
+
+    fn items(&self) -> Option<&'de [Token]> {
+        match self.node {
+            Node::One(Token::List(l)) => Some(l),
+            Node::One(Token::Nil) => Some(&[]),
+            Node::One(_) => None,
+            Node::Rest(items) => Some(items),
+        }
+    }
+
+    fn token(&self) -> Option<&'de Token> {
+        match self.node {
+            Node::One(tk) => Some(tk),
+            Node::Rest(_) => None,
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        self.token().map_or("list", Token::type_name)
+    }
+
+    fn expected<T>(&self, expected: &str) -> Result<T, LprpError> {
+        Err(LprpError::TypeError {
+            expected: expected.to_string(),
+            found: self.type_name().to_string(),
+        })
+    }
+
+    fn int<T: TryFrom<i64>>(&self, expected: &str) -> Result<T, LprpError> {
+        match self.token() {
+            Some(Token::Int(i)) => {
+                T::try_from(*i).map_err(|_| LprpError::TypeError {
+                    expected: expected.to_string(),
+                    found: format!("integer {}", i),
+                })
+            },
+            _ => self.expected(expected),
+        }
+    }
+}
+
+pub fn from_token<'de, T: Deserialize<'de>>(tk: &'de Token) -> Result<T, LprpError> {
+    T::deserialize(Deserializer::new(tk))
+}
+
+pub fn from_str<T: DeserializeOwned>(src: &str) -> Result<T, LprpError> {
+    from_token(&read(src)?)
+}
+
+impl de::Error for LprpError {
+    fn custom<T: Display>(msg: T) -> Self {
+        LprpError::SerdeError(msg.to_string())
+    }
+}
+
+// The name a keyword or symbol stands for: `name` if it is one of
+// `names` once underscores are read as hyphens.
+fn lookup(key: &str, names: &'static [&'static str]) -> Option<&'static str> {
+    names.iter().copied().find(|n| n.replace('_', "-") == key)
+}
+
+fn is_plist(items: &[Token]) -> bool {
+    items.len().is_multiple_of(2) && items.iter().step_by(2).all(Token::is_keyword)
+}
+
+// An alist entry `(key . value)` as its key and value.
+fn split_entry(tk: &Token) -> Result<(&Token, Deserializer<'_>), LprpError> {
+    match tk {
+        Token::List(l) => {
+            match &l[..] {
+                [k, dot, v] if dot.as_symbol() == Some(".") => Ok((k, Deserializer::new(v))),
+                [k, rest @ ..] => Ok((k, Deserializer::rest(rest))),
+                [] => Err(de::Error::custom("empty alist entry")),
+            }
+        },
+        _ => Err(LprpError::TypeError {
+            expected: "alist entry".to_string(),
+            found: tk.type_name().to_string(),
+        }),
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = LprpError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.token() {
+            Some(Token::T) => visitor.visit_bool(true),
+            Some(Token::Nil) => visitor.visit_unit(),
+            Some(Token::Int(i)) => visitor.visit_i64(*i),
+            Some(Token::Float(f)) => visitor.visit_f64(*f),
+            Some(Token::Str(s)) => visitor.visit_borrowed_str(s),
+            Some(Token::Symbol(s)) => visitor.visit_borrowed_str(s),
+            Some(Token::Quote(_)) => self.expected("data"),
+            _ => {
+                let items = self.items().unwrap();
+                if !items.is_empty() && is_plist(items) {
+                    visitor.visit_map(Plist { items, fields: &[] })
+                } else {
+                    visitor.visit_seq(Seq { items })
+                }
+            },
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.token() {
+            Some(Token::T) => visitor.visit_bool(true),
+            Some(Token::Nil) => visitor.visit_bool(false),
+            _ => self.expected("boolean"),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_i8(self.int("i8")?)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_i16(self.int("i16")?)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_i32(self.int("i32")?)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_i64(self.int("i64")?)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_u8(self.int("u8")?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_u16(self.int("u16")?)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_u32(self.int("u32")?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_u64(self.int("u64")?)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.token().and_then(Token::as_float) {
+            Some(f) => visitor.visit_f32(f as f32),
+            None => self.expected("f32"),
+        }
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.token().and_then(Token::as_float) {
+            Some(f) => visitor.visit_f64(f),
+            None => self.expected("f64"),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        let s = self.token().and_then(Token::as_str).unwrap_or_default();
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => visitor.visit_char(c),
+            _ => self.expected("char"),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.token() {
+            Some(Token::Str(s))|Some(Token::Symbol(s)) => visitor.visit_borrowed_str(s),
+            _ => self.expected("string"),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        let bytes = self.items()
+            .and_then(|l| l.iter().map(|b| b.as_int().and_then(|i| u8::try_from(i).ok())).collect());
+        match bytes {
+            Some(bytes) => visitor.visit_byte_buf(bytes),
+            None => self.expected("bytes"),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match (self.token(), self.items()) {
+            (Some(Token::Nil), _) => visitor.visit_none(),
+            (_, Some([x])) if ser::wraps(x) => visitor.visit_some(Deserializer::new(x)),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.items() {
+            Some([]) => visitor.visit_unit(),
+            _ => self.expected("nil"),
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+        -> Result<V::Value, LprpError>
+    {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+        -> Result<V::Value, LprpError>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.items() {
+            Some(items) => visitor.visit_seq(Seq { items }),
+            None => self.expected("list"),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LprpError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V)
+        -> Result<V::Value, LprpError>
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        match self.items() {
+            Some(items) if !items.is_empty() && is_plist(items) => {
+                visitor.visit_map(Plist { items, fields: &[] })
+            },
+            Some(items) => visitor.visit_map(Alist { items, fields: &[], value: None }),
+            None => self.expected("alist"),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self, _name: &'static str, fields: &'static [&'static str], visitor: V
+        ) -> Result<V::Value, LprpError>
+    {
+        match self.items() {
+            Some(items) if is_plist(items) => visitor.visit_map(Plist { items, fields }),
+            Some(items) => visitor.visit_map(Alist { items, fields, value: None }),
+            None => self.expected("plist or alist"),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self, _name: &'static str, variants: &'static [&'static str], visitor: V
+        ) -> Result<V::Value, LprpError>
+    {
+        match (self.token(), self.items()) {
+            (Some(Token::Symbol(s)), _) => {
+                visitor.visit_enum(Variant { name: s, variants, payload: None })
+            },
+            (_, Some([Token::Symbol(s), payload @ ..])) => {
+                visitor.visit_enum(Variant { name: s, variants, payload: Some(payload) })
+            },
+            _ => self.expected("enum variant"),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LprpError> {
+        visitor.visit_unit()
+    }
+}
+
+// ***** Access *****
+struct Seq<'de> {
+    items: &'de [Token],
+}
+
+impl<'de> de::SeqAccess<'de> for Seq<'de> {
+    type Error = LprpError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T)
+        -> Result<Option<T::Value>, LprpError>
+    {
+        match self.items.split_first() {
+            Some((first, rest)) => {
+                self.items = rest;
+                seed.deserialize(Deserializer::new(first)).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+// A key is given to serde as the field it names, when there is one.
+fn key<'de, K>(name: &'de str, fields: &'static [&'static str], seed: K) -> Result<K::Value, LprpError>
+    where K: de::DeserializeSeed<'de>
+{
+    match lookup(name, fields) {
+        Some(field) => seed.deserialize(field.into_deserializer()),
+        None => seed.deserialize(de::value::BorrowedStrDeserializer::new(name)),
+    }
+}
+
+struct Plist<'de> {
+    items: &'de [Token],
+    fields: &'static [&'static str],
+}
+
+impl<'de> de::MapAccess<'de> for Plist<'de> {
+    type Error = LprpError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K)
+        -> Result<Option<K::Value>, LprpError>
+    {
+        match self.items.first() {
+            Some(Token::Symbol(k)) => key(&k[1..], self.fields, seed).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V)
+        -> Result<V::Value, LprpError>
+    {
+        let value = &self.items[1];
+        self.items = &self.items[2..];
+        seed.deserialize(Deserializer::new(value))
+    }
+}
+
+struct Alist<'de> {
+    items: &'de [Token],
+    fields: &'static [&'static str],
+    value: Option<Deserializer<'de>>,
+}
+
+impl<'de> de::MapAccess<'de> for Alist<'de> {
+    type Error = LprpError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K)
+        -> Result<Option<K::Value>, LprpError>
+    {
+        let (first, rest) = match self.items.split_first() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        self.items = rest;
+        let (k, value) = split_entry(first)?;
+        self.value = Some(value);
+        match k {
+            Token::Symbol(name) if !self.fields.is_empty() => key(name, self.fields, seed).map(Some),
+            _ => seed.deserialize(Deserializer::new(k)).map(Some),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V)
+        -> Result<V::Value, LprpError>
+    {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value),
+            None => Err(de::Error::custom("value without a key")),
+        }
+    }
+}
+
+struct Variant<'de> {
+    name: &'de str,
+    variants: &'static [&'static str],
+    payload: Option<&'de [Token]>,
+}
+
+impl<'de> de::EnumAccess<'de> for Variant<'de> {
+    type Error = LprpError;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), LprpError> {
+        let name = key(self.name, self.variants, seed)?;
+        Ok((name, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'de> {
+    type Error = LprpError;
+
+    fn unit_variant(self) -> Result<(), LprpError> {
+        match self.payload {
+            None|Some([]) => Ok(()),
+            Some(_) => Err(de::Error::custom(format!("unexpected arguments to {}", self.name))),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, LprpError> {
+        match self.payload {
+            Some([value]) => seed.deserialize(Deserializer::new(value)),
+            _ => Err(de::Error::custom(format!("expected ({} value)", self.name))),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LprpError> {
+        de::Deserializer::deserialize_seq(Deserializer::rest(self.payload.unwrap_or_default()), visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V)
+        -> Result<V::Value, LprpError>
+    {
+        let rest = Deserializer::rest(self.payload.unwrap_or_default());
+        de::Deserializer::deserialize_struct(rest, "", fields, visitor)
+    }
+}
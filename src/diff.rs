@@ -0,0 +1,647 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use crate::core::equal;
+use crate::reader::{Token, LprpError};
+use crate::schema::locate;
+#[cfg(test)]
+use crate::reader::read;
+
+
+// ***** Edit *****
+// One step of an edit script. Paths give the index to take at each level
+// from the root, and refer to the tree as it is when the step is applied,
+// after the steps before it. `Insert` and `Move` put the element at the
+// last index of their path, shifting the ones after it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Insert { path: Vec<usize>, token: Token },
+    Delete { path: Vec<usize> },
+    Replace { path: Vec<usize>, token: Token },
+    // Within one list; `to` is the index once the element is taken out.
+    Move { from: Vec<usize>, to: usize },
+}
+
+// ***** Diff *****
+// An edit script that turns `old` into `new`. Lists are compared element
+// by element: the longest common subsequence is kept, equal elements out
+// of order are moved, and the rest are paired up between the kept ones
+// and diffed in turn, or else deleted and inserted.
+pub fn diff(old: &Token, new: &Token) -> Vec<Edit> {
+    let mut edits = vec![];
+    diff_at(&mut vec![], old, new, &mut edits);
+    edits
+}
+
+fn elements(tk: &Token) -> Option<&[Token]> {
+    match tk {
+        Token::List(l) => Some(l),
+        Token::Nil => Some(&[]),
+        _ => None,
+    }
+}
+
+// Lists that are worth diffing rather than replacing: both headed by the
+// same symbol, or neither by a symbol.
+fn similar(a: &Token, b: &Token) -> bool {
+    match (a, b) {
+        (Token::List(x), Token::List(y)) => {
+            let head = |l: &[Token]| l.first().and_then(Token::as_symbol).map(String::from);
+            head(x) == head(y)
+        },
+        _ => false,
+    }
+}
+
+fn diff_at(path: &mut Vec<usize>, old: &Token, new: &Token, edits: &mut Vec<Edit>) {
+    if equal(old, new) {
+        return;
+    }
+    let (a, b) = match (elements(old), elements(new)) {
+        (Some(a), Some(b)) if path.is_empty() || similar(old, new) || a.is_empty() || b.is_empty() => (a, b),
+        _ => {
+            edits.push(Edit::Replace { path: path.clone(), token: new.clone() });
+            return;
+        },
+    };
+
+    // Where each old element ends up: `target[i]` is its index in `b`.
+    // Kept elements first, then equal ones out of order, then lists
+    // with the same head, preferring the same gap between kept elements,
+    // and last whatever is left in the same gap.
+    let mut target: Vec<Option<usize>> = vec![None; a.len()];
+    let mut source: Vec<Option<usize>> = vec![None; b.len()];
+    let mut paired = vec![false; a.len()];
+    let (ca, cb) = classes(a, b);
+    for (i, j) in lcs(&ca, &cb) {
+        target[i] = Some(j);
+        source[j] = Some(i);
+    }
+    // `gap_a[i]` is the number of kept elements up to and including `i`.
+    let gaps = |kept: &[Option<usize>]| {
+        kept.iter().scan(0, |g, k| {
+            *g += k.is_some() as usize;
+            Some(*g)
+        }).collect::<Vec<_>>()
+    };
+    let (gap_a, gap_b) = (gaps(&target), gaps(&source));
+    for pass in 0..3 {
+        let mut any: HashMap<Key, Queue> = HashMap::new();
+        let mut same_gap: HashMap<(Key, usize), Queue> = HashMap::new();
+        for j in (0..b.len()).filter(|&j| source[j].is_none()) {
+            if let Some(k) = key(pass, cb[j], &b[j]) {
+                any.entry(k).or_default().js.push(j);
+                same_gap.entry((k, gap_b[j])).or_default().js.push(j);
+            }
+        }
+        for i in 0..a.len() {
+            let k = match key(pass, ca[i], &a[i]) {
+                Some(k) if target[i].is_none() => k,
+                _ => continue,
+            };
+            let found = same_gap.get_mut(&(k, gap_a[i])).and_then(|q| q.first(&source))
+                .or_else(|| any.get_mut(&k).filter(|_| pass < 2).and_then(|q| q.first(&source)));
+            if let Some(j) = found {
+                target[i] = Some(j);
+                source[j] = Some(i);
+                paired[i] = pass > 0;
+            }
+        }
+    }
+
+    // Delete from the end, so the indices before stay valid.
+    for i in (0..a.len()).rev() {
+        if target[i].is_none() {
+            path.push(i);
+            edits.push(Edit::Delete { path: path.clone() });
+            path.pop();
+        }
+    }
+    let mut cur: Vec<usize> = (0..a.len()).filter(|&i| target[i].is_some()).collect();
+    for (k, &i) in cur.iter().enumerate() {
+        if paired[i] {
+            path.push(k);
+            diff_at(path, &a[i], &b[target[i].unwrap()], edits);
+            path.pop();
+        }
+    }
+    // The elements outside a longest run in order are moved, each right
+    // after the one before it in `b`.
+    let mut moved = vec![true; a.len()];
+    for k in increasing(&cur.iter().map(|&i| target[i].unwrap()).collect::<Vec<_>>()) {
+        moved[cur[k]] = false;
+    }
+    let mut order: Vec<usize> = cur.clone();
+    order.sort_by_key(|&i| target[i]);
+    for r in 0..order.len() {
+        let i = order[r];
+        if !moved[i] {
+            continue;
+        }
+        let from = cur.iter().position(|&x| x == i).unwrap();
+        cur.remove(from);
+        let to = match r {
+            0 => 0,
+            _ => cur.iter().position(|&x| x == order[r - 1]).unwrap() + 1,
+        };
+        cur.insert(to, i);
+        if from != to {
+            path.push(from);
+            edits.push(Edit::Move { from: path.clone(), to });
+            path.pop();
+        }
+    }
+    for (j, x) in b.iter().enumerate() {
+        if source[j].is_none() {
+            path.push(j);
+            edits.push(Edit::Insert { path: path.clone(), token: x.clone() });
+            path.pop();
+        }
+    }
+}
+
+// A hash that agrees with `equal`: `()` hashes as `nil` and `'x` as
+// `(quote x)`. Atoms hash as Tokens, which tells apart only what `equal`
+// does too.
+fn hash_equal<H: Hasher>(tk: &Token, state: &mut H) {
+    match tk {
+        Token::List(l) if l.is_empty() => Token::Nil.hash(state),
+        Token::List(l) => {
+            l.len().hash(state);
+            l.iter().for_each(|x| hash_equal(x, state));
+        },
+        Token::Quote(q) => {
+            2usize.hash(state);
+            Token::Symbol("quote".to_string()).hash(state);
+            hash_equal(q, state);
+        },
+        _ => tk.hash(state),
+    }
+}
+
+// Number the elements of `a` and `b` so that equal ones, and only those,
+// get the same number.
+fn classes(a: &[Token], b: &[Token]) -> (Vec<usize>, Vec<usize>) {
+    let mut seen: HashMap<u64, Vec<(&Token, usize)>> = HashMap::new();
+    let mut count = 0;
+    let mut class = |tk| {
+        let mut h = DefaultHasher::new();
+        hash_equal(tk, &mut h);
+        let bucket = seen.entry(h.finish()).or_default();
+        match bucket.iter().find(|(x, _)| equal(x, tk)) {
+            Some(&(_, n)) => n,
+            None => {
+                bucket.push((tk, count));
+                count += 1;
+                count - 1
+            },
+        }
+    };
+    let ca = a.iter().map(&mut class).collect();
+    (ca, b.iter().map(&mut class).collect())
+}
+
+// What an old and a new element must share to be paired in `pass`:
+// being equal, being lists with the same head, or nothing.
+type Key<'a> = (usize, Option<&'a str>);
+
+fn key(pass: usize, class: usize, tk: &Token) -> Option<Key<'_>> {
+    match (pass, tk) {
+        (0, _) => Some((class, None)),
+        (1, Token::List(l)) => Some((0, l.first().and_then(Token::as_symbol))),
+        (1, _) => None,
+        _ => Some((0, None)),
+    }
+}
+
+// Indices into `b` in order, taken from the front as they get paired.
+#[derive(Default)]
+struct Queue {
+    js: Vec<usize>,
+    next: usize,
+}
+
+impl Queue {
+    fn first(&mut self, source: &[Option<usize>]) -> Option<usize> {
+        while self.js.get(self.next).is_some_and(|&j| source[j].is_some()) {
+            self.next += 1;
+        }
+        self.js.get(self.next).copied()
+    }
+}
+
+// Index pairs of a longest common subsequence, by Myers' algorithm in
+// linear space. It takes O((n + m) d) time for lists d edits apart, so
+// large lists with few changes stay cheap.
+fn lcs(a: &[usize], b: &[usize]) -> Vec<(usize, usize)> {
+    let mut pairs = vec![];
+    myers(a, b, (0, 0), &mut pairs);
+    pairs
+}
+
+// Keep the common prefix and suffix, and split the rest in two where a
+// shortest edit script crosses the middle.
+fn myers(a: &[usize], b: &[usize], at: (usize, usize), pairs: &mut Vec<(usize, usize)>) {
+    let pre = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suf = a[pre..].iter().rev().zip(b[pre..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[pre..a.len() - suf], &b[pre..b.len() - suf]);
+    pairs.extend((0..pre).map(|k| (at.0 + k, at.1 + k)));
+    let (i, j) = (at.0 + pre, at.1 + pre);
+    if let Some((x, y)) = middle(a, b) {
+        myers(&a[..x], &b[..y], (i, j), pairs);
+        myers(&a[x..], &b[y..], (i + x, j + y), pairs);
+    }
+    pairs.extend((0..suf).map(|k| (i + a.len() + k, j + b.len() + k)));
+}
+
+// Search forward from the start and backward from the end at once, and
+// return the point where the two paths meet. `fwd[k]` is how far along
+// `a` the forward path on diagonal `k` has got, and `bwd[k]` the same
+// from the end. None if `a` and `b` share no element.
+fn middle(a: &[usize], b: &[usize]) -> Option<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    if n == 0 || m == 0 {
+        return None;
+    }
+    let max = (n + m + 1) / 2;
+    let len = 2 * max + 2;
+    let mut fwd = vec![-1; len as usize];
+    let mut bwd = vec![-1; len as usize];
+    fwd[max as usize + 1] = 0;
+    bwd[max as usize + 1] = 0;
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    // Diagonals that have run off an edge are skipped from then on.
+    let (mut k1start, mut k1end, mut k2start, mut k2end) = (0, 0, 0, 0);
+    for d in 0..max {
+        for k1 in (-d + k1start..=d - k1end).step_by(2) {
+            let i = (max + k1) as usize;
+            let mut x1 = match k1 == -d || (k1 != d && fwd[i - 1] < fwd[i + 1]) {
+                true => fwd[i + 1],
+                false => fwd[i - 1] + 1,
+            };
+            let mut y1 = x1 - k1;
+            while x1 < n && y1 < m && a[x1 as usize] == b[y1 as usize] {
+                x1 += 1;
+                y1 += 1;
+            }
+            fwd[i] = x1;
+            if x1 > n {
+                k1end += 2;
+            } else if y1 > m {
+                k1start += 2;
+            } else if odd {
+                let j = max + delta - k1;
+                if (0..len).contains(&j) && bwd[j as usize] != -1 && x1 >= n - bwd[j as usize] {
+                    return Some((x1 as usize, y1 as usize));
+                }
+            }
+        }
+        for k2 in (-d + k2start..=d - k2end).step_by(2) {
+            let i = (max + k2) as usize;
+            let mut x2 = match k2 == -d || (k2 != d && bwd[i - 1] < bwd[i + 1]) {
+                true => bwd[i + 1],
+                false => bwd[i - 1] + 1,
+            };
+            let mut y2 = x2 - k2;
+            while x2 < n && y2 < m && a[(n - x2 - 1) as usize] == b[(m - y2 - 1) as usize] {
+                x2 += 1;
+                y2 += 1;
+            }
+            bwd[i] = x2;
+            if x2 > n {
+                k2end += 2;
+            } else if y2 > m {
+                k2start += 2;
+            } else if !odd {
+                let j = max + delta - k2;
+                if (0..len).contains(&j) && fwd[j as usize] != -1 && fwd[j as usize] >= n - x2 {
+                    let x1 = fwd[j as usize];
+                    return Some((x1 as usize, (max + x1 - j) as usize));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_lcs() {
+    assert_eq!(lcs(&[0, 1, 2], &[0, 1, 2]), vec![(0, 0), (1, 1), (2, 2)]);
+    assert_eq!(lcs(&[0, 1, 2, 3], &[4, 2, 1, 3]).len(), 2);
+    assert_eq!(lcs(&[5, 0, 1, 2, 6], &[7, 0, 3, 2, 8]), vec![(1, 1), (3, 3)]);
+    assert_eq!(lcs(&[], &[1]), vec![]);
+    assert_eq!(lcs(&[1, 2], &[3, 4]), vec![]);
+
+    // As long as one found the plain quadratic way.
+    let longest = |a: &[usize], b: &[usize]| {
+        let mut table = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                table[i][j] = match a[i] == b[j] {
+                    true => table[i + 1][j + 1] + 1,
+                    false => table[i + 1][j].max(table[i][j + 1]),
+                };
+            }
+        }
+        table[0][0]
+    };
+    let mut rng = crate::core::Rng(11);
+    for _ in 0..500 {
+        let a: Vec<usize> = (0..rng.next(12)).map(|_| rng.next(4) as usize).collect();
+        let b: Vec<usize> = (0..rng.next(12)).map(|_| rng.next(4) as usize).collect();
+        let pairs = lcs(&a, &b);
+        assert_eq!(pairs.len(), longest(&a, &b), "{:?} {:?}", a, b);
+        assert!(pairs.iter().all(|&(i, j)| a[i] == b[j]));
+        assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+    }
+
+    // Large lists with a few changes.
+    let a: Vec<usize> = (0..20_000).collect();
+    let mut b = a.clone();
+    b[5_000] = 99_999;
+    b.remove(15_000);
+    assert_eq!(lcs(&a, &b).len(), 19_998);
+}
+
+// Indices of a longest increasing subsequence of `xs`.
+fn increasing(xs: &[usize]) -> Vec<usize> {
+    // `tails[k]` ends the best run of length k + 1 found so far.
+    let mut tails: Vec<usize> = vec![];
+    let mut prev = vec![None; xs.len()];
+    for (i, &x) in xs.iter().enumerate() {
+        let k = tails.partition_point(|&t| xs[t] < x);
+        prev[i] = k.checked_sub(1).map(|k| tails[k]);
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+    let mut run = vec![];
+    let mut cur = tails.last().copied();
+    while let Some(i) = cur {
+        run.push(i);
+        cur = prev[i];
+    }
+    run.reverse();
+    run
+}
+
+#[test]
+fn test_diff() {
+    let old = read("(config (server :port 80) (db \"a\") (cache))").unwrap();
+    let new = read("(config (db \"a\") (server :port 8080) (log t))").unwrap();
+    assert_eq!(
+        diff(&old, &new),
+        vec![
+            Edit::Replace { path: vec![1, 2], token: Token::Int(8080) },
+            Edit::Replace { path: vec![3], token: read("(log t)").unwrap() },
+            Edit::Move { from: vec![1], to: 2 },
+        ]
+        );
+    assert_eq!(diff(&old, &old), vec![]);
+    assert_eq!(
+        diff(&read("(a b c)").unwrap(), &read("(c a x b)").unwrap()),
+        vec![
+            Edit::Move { from: vec![2], to: 0 },
+            Edit::Insert { path: vec![2], token: Token::Symbol("x".to_string()) },
+        ]
+        );
+    assert_eq!(
+        diff(&read("(a b c)").unwrap(), &read("(a)").unwrap()),
+        vec![Edit::Delete { path: vec![2] }, Edit::Delete { path: vec![1] }]
+        );
+    assert_eq!(
+        diff(&Token::Int(1), &Token::Int(2)),
+        vec![Edit::Replace { path: vec![], token: Token::Int(2) }]
+        );
+    // A form whose head changes is replaced rather than edited.
+    assert_eq!(
+        diff(&read("(a (f 1 2))").unwrap(), &read("(a (g 1 2))").unwrap()),
+        vec![Edit::Replace { path: vec![1], token: read("(g 1 2)").unwrap() }]
+        );
+
+    // A large generated list with a few changes.
+    let host = |i: i64, port: i64| read(&format!("(host h{} :port {})", i, port)).unwrap();
+    let old = Token::List((0..10_000).map(|i| host(i, 80)).collect());
+    let mut l: Vec<Token> = (0..10_000).map(|i| host(i, 80)).collect();
+    l[5_000] = host(5_000, 8080);
+    l.remove(7_000);
+    l.insert(100, Token::Int(0));
+    let new = Token::List(l);
+    let edits = diff(&old, &new);
+    assert_eq!(edits, vec![
+        Edit::Delete { path: vec![7_000] },
+        Edit::Replace { path: vec![5_000, 3], token: Token::Int(8080) },
+        Edit::Insert { path: vec![100], token: Token::Int(0) },
+    ]);
+    assert_eq!(patch(&old, &edits), Ok(new));
+}
+
+// ***** Patch *****
+fn patch_error<T>(message: &str, path: &[usize]) -> Result<T, LprpError> {
+    Err(LprpError::PatchError(format!("{} at {:?}", message, path)))
+}
+
+// The list holding the last step of `path`, and that step.
+fn parent<'a>(root: &'a mut Token, path: &[usize]) -> Result<(&'a mut Vec<Token>, usize), LprpError> {
+    let (&last, init) = match path.split_last() {
+        Some(p) => p,
+        None => return patch_error("no parent", path),
+    };
+    let mut tk = root;
+    for &i in init {
+        tk = match tk {
+            Token::List(l) if i < l.len() => &mut l[i],
+            _ => return patch_error("no such element", path),
+        };
+    }
+    if let Token::Nil = tk {
+        *tk = Token::List(vec![]);
+    }
+    match tk {
+        Token::List(l) => Ok((l, last)),
+        _ => patch_error("not a list", path),
+    }
+}
+
+// Apply `edits` to a copy of `tk`.
+pub fn patch(tk: &Token, edits: &[Edit]) -> Result<Token, LprpError> {
+    let mut root = tk.clone();
+    for edit in edits {
+        apply(&mut root, edit)?;
+    }
+    Ok(root)
+}
+
+fn apply(root: &mut Token, edit: &Edit) -> Result<(), LprpError> {
+    match edit {
+        Edit::Replace { path, token } if path.is_empty() => *root = token.clone(),
+        Edit::Replace { path, token } => {
+            match parent(root, path)? {
+                (l, i) if i < l.len() => l[i] = token.clone(),
+                _ => return patch_error("no such element", path),
+            }
+        },
+        Edit::Delete { path } => {
+            match parent(root, path)? {
+                (l, i) if i < l.len() => {
+                    l.remove(i);
+                },
+                _ => return patch_error("no such element", path),
+            }
+        },
+        Edit::Insert { path, token } => {
+            match parent(root, path)? {
+                (l, i) if i <= l.len() => l.insert(i, token.clone()),
+                _ => return patch_error("no such position", path),
+            }
+        },
+        Edit::Move { from, to } => {
+            match parent(root, from)? {
+                (l, i) if i < l.len() && *to < l.len() => {
+                    let x = l.remove(i);
+                    l.insert(*to, x);
+                },
+                _ => return patch_error("no such element", from),
+            }
+        },
+    }
+    Ok(())
+}
+
+#[test]
+fn test_patch() {
+    let old = read("(a b c)").unwrap();
+    assert_eq!(
+        patch(&old, &[Edit::Move { from: vec![0], to: 2 }, Edit::Insert { path: vec![0], token: Token::Int(1) }]),
+        read("(1 b c a)")
+        );
+    assert_eq!(
+        patch(&old, &[Edit::Delete { path: vec![3] }]),
+        Err(LprpError::PatchError("no such element at [3]".to_string()))
+        );
+    assert!(patch(&old, &[Edit::Insert { path: vec![0, 0], token: Token::Nil }]).is_err());
+    assert_eq!(
+        patch(&Token::Nil, &[Edit::Insert { path: vec![0], token: Token::T }]),
+        Ok(Token::List(vec![Token::T]))
+        );
+}
+
+#[test]
+fn test_diff_patch_random() {
+    crate::core::for_random_lists(|a, b| {
+        let edits = diff(a, b);
+        let patched = patch(a, &edits).unwrap();
+        assert!(equal(&patched, b), "{:?} -> {:?} by {:?} gives {:?}", a, b, edits, patched);
+        if equal(a, b) {
+            assert!(edits.is_empty());
+        }
+    });
+}
+
+// ***** Rendering *****
+// A unified-diff style listing: one hunk per edit, headed by where it
+// happens, with `-` for what goes and `+` for what comes.
+pub struct Unified<'a> {
+    old: &'a Token,
+    edits: &'a [Edit],
+}
+
+pub fn unified<'a>(old: &'a Token, edits: &'a [Edit]) -> Unified<'a> {
+    Unified { old, edits }
+}
+
+// The element at `path`, if there is one.
+fn get<'t>(root: &'t Token, path: &[usize]) -> Option<&'t Token> {
+    path.iter().try_fold(root, |tk, &i| elements(tk)?.get(i))
+}
+
+impl<'a> fmt::Display for Unified<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        // One copy is patched in place; what an edit removes is shown
+        // before it is applied, and where it inserts after.
+        let mut cur = self.old.clone();
+        for edit in self.edits {
+            let at = match edit {
+                Edit::Replace { path, .. } | Edit::Delete { path } | Edit::Insert { path, .. } => path,
+                Edit::Move { from, .. } => from,
+            };
+            let before = match edit {
+                Edit::Insert { .. } => None,
+                _ => get(&cur, at).map(|tk| (locate(&cur, at), tk.to_string())),
+            };
+            if apply(&mut cur, edit).is_err() {
+                return writeln!(f, "@@ invalid edit {:?} @@", edit);
+            }
+            let (from, old) = before.unwrap_or_default();
+            match edit {
+                Edit::Replace { token, .. } => {
+                    writeln!(f, "@@ {} @@", from)?;
+                    writeln!(f, "-{}", old)?;
+                    writeln!(f, "+{}", token)?;
+                },
+                Edit::Delete { .. } => {
+                    writeln!(f, "@@ {} @@", from)?;
+                    writeln!(f, "-{}", old)?;
+                },
+                Edit::Insert { path, token } => {
+                    writeln!(f, "@@ {} @@", locate(&cur, path))?;
+                    writeln!(f, "+{}", token)?;
+                },
+                Edit::Move { from: path, to } => {
+                    let mut dest = path.clone();
+                    *dest.last_mut().unwrap() = *to;
+                    writeln!(f, "@@ {} -> {} @@", from, locate(&cur, &dest))?;
+                    writeln!(f, " {}", old)?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_unified() {
+    let old = read("(config (server :port 80) (db \"a\") (cache))").unwrap();
+    let new = read("(config (db \"a\") (server :port 8080) (log t))").unwrap();
+    let edits = diff(&old, &new);
+    assert_eq!(
+        unified(&old, &edits).to_string(),
+        "@@ config > server[1] > :port @@\n\
+         -80\n\
+         +8080\n\
+         @@ config > cache[3] @@\n\
+         -(cache)\n\
+         +(log t)\n\
+         @@ config > server[1] -> config > server[2] @@\n \
+         (server :port 8080)\n"
+        );
+    assert_eq!(unified(&old, &[]).to_string(), "");
+
+    let edits = diff(&read("(a b)").unwrap(), &read("(b c)").unwrap());
+    assert_eq!(unified(&read("(a b)").unwrap(), &edits).to_string(), "@@ a > [0] @@\n-a\n@@ b > [1] @@\n+c\n");
+}
+
+#[test]
+fn test_unified_linear() {
+    let form = |port: usize| format!("(server :host \"example.com\" :port {})\n", port);
+    let old = read(&format!("(config {})", (0..10000).map(|_| form(80)).collect::<String>())).unwrap();
+    let new = read(&format!("(config {})", (0..10000).map(|i| form(if i % 10 == 0 { 8080 } else { 80 })).collect::<String>())).unwrap();
+    let edits = diff(&old, &new);
+    assert!(edits.len() >= 1000);
+    let start = std::time::Instant::now();
+    let text = unified(&old, &edits).to_string();
+    assert!(start.elapsed().as_secs() < 2, "{:?}", start.elapsed());
+    assert!(text.starts_with("@@ config > server[1] > :port @@\n-80\n+8080\n"));
+    assert_eq!(text.lines().filter(|l| l.starts_with("@@")).count(), edits.len());
+}
@@ -0,0 +1,1547 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::rc::Rc;
+use crate::builtins::{condition_args, BUILTINS};
+use crate::condition::{Condition, Kinds};
+use crate::heap;
+use crate::pattern::Pattern;
+use crate::reader::{read_all, Token, LprpError};
+use crate::value::Value;
+use crate::vm::Activation;
+
+
+fn raise<T>(kind: &str, message: &str, irritants: Vec<Value>) -> Result<T, Condition> {
+    Err(Condition::new(kind, message, irritants))
+}
+
+// ***** Environment *****
+#[derive(Clone)]
+pub struct Env(Rc<RefCell<Scope>>);
+
+struct Scope {
+    vars: HashMap<String, Value>,
+    parent: Option<Env>,
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env(Rc::new(RefCell::new(Scope {
+            vars: HashMap::new(),
+            parent: None,
+        })))
+    }
+
+    pub fn extend(&self) -> Env {
+        Env(Rc::new(RefCell::new(Scope {
+            vars: HashMap::new(),
+            parent: Some(self.clone()),
+        })))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Value> {
+        let mut env = self.clone();
+        loop {
+            let parent = {
+                let scope = env.0.borrow();
+                if let Some(v) = scope.vars.get(name) {
+                    return Some(v.clone());
+                }
+                scope.parent.clone()
+            };
+            env = parent?;
+        }
+    }
+
+    pub fn define(&self, name: &str, v: Value) {
+        self.0.borrow_mut().vars.insert(name.to_string(), v);
+    }
+
+    // Assign to an existing binding. Returns false if `name` is unbound.
+    pub fn set(&self, name: &str, v: Value) -> bool {
+        let mut env = self.clone();
+        loop {
+            let parent = {
+                let mut scope = env.0.borrow_mut();
+                if let Some(slot) = scope.vars.get_mut(name) {
+                    *slot = v;
+                    return true;
+                }
+                scope.parent.clone()
+            };
+            match parent {
+                Some(p) => env = p,
+                None => return false,
+            }
+        }
+    }
+}
+
+impl Env {
+    // Every binding in scope, inner ones shadowing outer, by name.
+    pub fn bindings(&self) -> Vec<(String, Value)> {
+        let mut seen = BTreeMap::new();
+        let mut env = Some(self.clone());
+        while let Some(e) = env {
+            let scope = e.0.borrow();
+            for (name, v) in &scope.vars {
+                seen.entry(name.clone()).or_insert_with(|| v.clone());
+            }
+            env = scope.parent.clone();
+        }
+        seen.into_iter().collect()
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env::new()
+    }
+}
+
+#[test]
+fn test_env() {
+    let global = Env::new();
+    global.define("x", Value::Int(1));
+    let local = global.extend();
+    local.define("y", Value::Int(2));
+
+    assert_eq!(local.lookup("x"), Some(Value::Int(1)));
+    assert_eq!(global.lookup("y"), None);
+    assert!(local.set("x", Value::Int(3)));
+    assert_eq!(global.lookup("x"), Some(Value::Int(3)));
+    assert!(!local.set("z", Value::Nil));
+
+    local.define("x", Value::Int(4));
+    assert_eq!(local.bindings(), vec![
+        ("x".to_string(), Value::Int(4)),
+        ("y".to_string(), Value::Int(2)),
+    ]);
+}
+
+// ***** Procedures *****
+pub struct Lambda {
+    name: Option<String>,
+    params: Vec<String>,
+    rest: Option<String>,
+    body: Value,
+    env: Env,
+}
+
+impl Lambda {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+pub type BuiltinFn = fn(&[Value]) -> Result<Value, Condition>;
+pub type NativeFnBox = Box<dyn Fn(&[Value]) -> Result<Value, LprpError>>;
+
+// Builtins that only compute a value are `Pure`. The others need
+// access to the machine and are handled by `Interp::apply`.
+pub enum Primitive {
+    Pure(BuiltinFn),
+    Apply,
+    Funcall,
+    Eval,
+    CallCC,
+    DynamicWind,
+    Error,
+    Signal,
+    HandlerCase,
+    HandlerBind,
+}
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub min: usize,
+    pub max: Option<usize>,
+    pub prim: Primitive,
+}
+
+// A Rust function registered with `Interp::define_fn`.
+pub struct NativeFn {
+    pub name: String,
+    func: NativeFnBox,
+}
+
+pub(crate) fn check_arity(name: &str, min: usize, max: Option<usize>, n: usize)
+    -> Result<(), Condition>
+{
+    if n < min || max.is_some_and(|m| n > m) {
+        let expected = match max {
+            Some(m) if m == min => format!("{}", min),
+            Some(m) => format!("{} to {}", min, m),
+            None => format!("at least {}", min),
+        };
+        let message = format!(
+            "wrong number of arguments to {}: expected {}, got {}",
+            name, expected, n
+            );
+        return raise("program-error", &message, vec![]);
+    }
+    Ok(())
+}
+
+// ***** Special forms *****
+// The names `eval_form` and `expand` treat specially, for tools such as
+// completion; they are not bound in any environment.
+pub static SPECIAL_FORMS: &[&str] = &[
+    "quote", "if", "progn", "lambda", "defun", "defvar", "setq", "and", "or",
+    "define-condition", "let", "let*", "cond", "when", "unless", "unwind-protect",
+    "ignore-errors", "handler-case", "handler-bind", "match",
+];
+
+#[test]
+fn test_special_forms() {
+    for name in SPECIAL_FORMS {
+        if let Err(LprpError::Condition { kind, .. }) = Interp::new().eval_str(&format!("({})", name)) {
+            assert_ne!(kind, "unbound-variable", "{}", name);
+        }
+    }
+    assert!(matches!(
+        Interp::new().eval_str("(progn-not)"),
+        Err(LprpError::Condition { kind, .. }) if kind == "unbound-variable"
+    ));
+}
+
+// ***** Derived forms *****
+// An expansion calls builtins by value rather than by name, so that it
+// still works where the user has rebound the name, as in
+// `(defun f (list) (handler-case ...))`.
+fn builtin(name: &str) -> Value {
+    Value::Builtin(BUILTINS.iter().find(|b| b.name == name).unwrap())
+}
+
+// Rewrite derived special forms into the core ones.
+pub(crate) fn expand(head: &str, form: &Value) -> Result<Option<Value>, Condition> {
+    let args = form.cdr().unwrap_or_default();
+    let malformed = || raise("program-error", &format!("malformed {}", head), vec![form.clone()]);
+    let thunk = |body: Value| {
+        Value::cons(Value::sym("lambda"), Value::cons(Value::Nil, body))
+    };
+
+    let expanded = match head {
+        "let" => {
+            let bindings = match args.car() {
+                Some(b) => b.clone(),
+                None => return malformed(),
+            };
+            let mut names = vec![];
+            let mut inits = vec![];
+            for b in bindings.iter() {
+                match b {
+                    Value::Symbol(_) => {
+                        names.push(b.clone());
+                        inits.push(Value::Nil);
+                    },
+                    Value::Pair(_) => {
+                        match b.to_vec().as_deref() {
+                            Some([n @ Value::Symbol(_)]) => {
+                                names.push(n.clone());
+                                inits.push(Value::Nil);
+                            },
+                            Some([n @ Value::Symbol(_), init]) => {
+                                names.push(n.clone());
+                                inits.push(init.clone());
+                            },
+                            _ => return malformed(),
+                        }
+                    },
+                    _ => return malformed(),
+                }
+            }
+            let body = args.cdr().unwrap_or_default();
+            let lambda = Value::cons(
+                Value::sym("lambda"),
+                Value::cons(Value::list(names), body)
+                );
+            Value::list_with_tail(vec![lambda], Value::list(inits))
+        },
+        "let*" => {
+            let body = args.cdr().unwrap_or_default();
+            match args.car() {
+                Some(bindings @ Value::Pair(_)) => {
+                    let first = Value::list(vec![bindings.car().unwrap()]);
+                    let rest = bindings.cdr().unwrap();
+                    let inner = if rest.is_nil() {
+                        Value::cons(Value::sym("let"), Value::cons(Value::Nil, body))
+                    } else {
+                        Value::cons(Value::sym("let*"), Value::cons(rest, body))
+                    };
+                    Value::list(vec![Value::sym("let"), first, inner])
+                },
+                Some(Value::Nil) => {
+                    Value::cons(Value::sym("let"), Value::cons(Value::Nil, body))
+                },
+                _ => return malformed(),
+            }
+        },
+        "cond" => {
+            match args.car() {
+                None => Value::Nil,
+                Some(clause @ Value::Pair(_)) => {
+                    let test = clause.car().unwrap();
+                    let body = clause.cdr().unwrap();
+                    let rest = Value::cons(
+                        Value::sym("cond"),
+                        args.cdr().unwrap()
+                        );
+                    if body.is_nil() {
+                        Value::list(vec![Value::sym("or"), test, rest])
+                    } else {
+                        Value::list(vec![
+                            Value::sym("if"),
+                            test,
+                            Value::cons(Value::sym("progn"), body),
+                            rest,
+                        ])
+                    }
+                },
+                _ => return malformed(),
+            }
+        },
+        "when"|"unless" => {
+            let test = match args.car() {
+                Some(t) => t.clone(),
+                None => return malformed(),
+            };
+            let body = Value::cons(
+                Value::sym("progn"),
+                args.cdr().unwrap()
+                );
+            if head == "when" {
+                Value::list(vec![Value::sym("if"), test, body, Value::Nil])
+            } else {
+                Value::list(vec![Value::sym("if"), test, Value::Nil, body])
+            }
+        },
+        // Cleanup forms run whenever control leaves the protected form,
+        // by normal return, a handler unwinding or a continuation.
+        "unwind-protect" => {
+            let protected = match args.car() {
+                Some(p) => p.clone(),
+                None => return malformed(),
+            };
+            let cleanup = args.cdr().unwrap();
+            Value::list(vec![
+                builtin("dynamic-wind"),
+                thunk(Value::Nil),
+                thunk(Value::list(vec![protected])),
+                thunk(cleanup),
+            ])
+        },
+        "ignore-errors" => {
+            Value::list(vec![
+                Value::sym("handler-case"),
+                Value::cons(Value::sym("progn"), args),
+                Value::list(vec![Value::sym("error"), Value::Nil, Value::Nil]),
+            ])
+        },
+        // (handler-case expr (type (var) body...)...) becomes
+        // (%handler-case (lambda () expr) (list 'type (lambda (var) body...) ...))
+        "handler-case" => {
+            let expr = match args.car() {
+                Some(e) => e.clone(),
+                None => return malformed(),
+            };
+            let mut spec = vec![builtin("list")];
+            for clause in args.cdr().unwrap().iter() {
+                let kind = match clause.car() {
+                    Some(k @ Value::Symbol(_))|Some(k @ Value::T) => k.clone(),
+                    _ => return malformed(),
+                };
+                let vars = match clause.cdr().and_then(|r| r.car()).and_then(|v| v.to_vec()) {
+                    Some(vars) => vars,
+                    None => return malformed(),
+                };
+                // The reader cannot produce `#ignore`, so it never shadows
+                // a user variable.
+                let param = match vars.as_slice() {
+                    [] => Value::sym("#ignore"),
+                    [v @ Value::Symbol(_)] => v.clone(),
+                    _ => return malformed(),
+                };
+                let body = clause.cdr().and_then(|r| r.cdr()).unwrap();
+                let handler = Value::cons(
+                    Value::sym("lambda"),
+                    Value::cons(Value::list(vec![param]), body)
+                    );
+                spec.push(Value::list(vec![Value::sym("quote"), kind]));
+                spec.push(handler);
+            }
+            Value::list(vec![
+                builtin("%handler-case"),
+                thunk(Value::list(vec![expr])),
+                Value::list(spec),
+            ])
+        },
+        // (handler-bind ((type handler)...) body...) becomes
+        // (%handler-bind (list 'type handler ...) (lambda () body...))
+        "handler-bind" => {
+            let mut spec = vec![builtin("list")];
+            for binding in args.car().unwrap_or_default().iter() {
+                match binding.to_vec().as_deref() {
+                    Some([kind @ Value::Symbol(_), handler]) => {
+                        spec.push(Value::list(vec![Value::sym("quote"), kind.clone()]));
+                        spec.push(handler.clone());
+                    },
+                    _ => return malformed(),
+                }
+            }
+            if args.car().is_none() {
+                return malformed();
+            }
+            Value::list(vec![
+                builtin("%handler-bind"),
+                Value::list(spec),
+                thunk(args.cdr().unwrap()),
+            ])
+        },
+        // (match expr (pattern [:when guard] body...)...) binds the value
+        // once, then each clause becomes
+        // (let ((#match (%match 'pattern #match-value)))
+        //   (if (and #match (apply (lambda vars guard) (cdr #match)))
+        //       (apply (lambda vars body...) (cdr #match))
+        //       (match #match-value clauses...)))
+        "match" => {
+            let value = Value::sym("#match-value");
+            let expr = match args.car() {
+                Some(e) => e,
+                None => return malformed(),
+            };
+            let clauses = args.cdr().unwrap();
+            if expr != value {
+                return Ok(Some(Value::list(vec![
+                    Value::sym("let"),
+                    Value::list(vec![Value::list(vec![value.clone(), expr])]),
+                    Value::cons(Value::sym("match"), Value::cons(value, clauses)),
+                ])));
+            }
+            let clause = match clauses.car() {
+                None => return Ok(Some(Value::Nil)),
+                Some(c @ Value::Pair(_)) => c,
+                Some(_) => return malformed(),
+            };
+            let pattern = clause.car().unwrap();
+            let vars = match Token::try_from(&pattern).and_then(|tk| Pattern::from_token(&tk)) {
+                Ok(p) => Value::list(p.vars().iter().map(|v| Value::sym(v)).collect()),
+                Err(_) => return malformed(),
+            };
+            let mut body = clause.cdr().unwrap();
+            let mut guard = None;
+            if body.car().as_ref().and_then(Value::as_symbol) == Some(":when") {
+                guard = match body.cdr().and_then(|r| r.car()) {
+                    Some(g) => Some(g),
+                    None => return malformed(),
+                };
+                body = body.cdr().and_then(|r| r.cdr()).unwrap();
+            }
+            let result = Value::sym("#match");
+            let call = |body: Value| {
+                Value::list(vec![
+                    builtin("apply"),
+                    Value::cons(Value::sym("lambda"), Value::cons(vars.clone(), body)),
+                    Value::list(vec![builtin("cdr"), result.clone()]),
+                ])
+            };
+            let test = match guard {
+                Some(g) => Value::list(vec![Value::sym("and"), result.clone(), call(Value::list(vec![g]))]),
+                None => result.clone(),
+            };
+            Value::list(vec![
+                Value::sym("let"),
+                Value::list(vec![Value::list(vec![
+                    result.clone(),
+                    Value::list(vec![
+                        builtin("%match"),
+                        Value::list(vec![Value::sym("quote"), pattern]),
+                        value.clone(),
+                    ]),
+                ])]),
+                Value::list(vec![
+                    Value::sym("if"),
+                    test,
+                    call(body),
+                    Value::cons(Value::sym("match"), Value::cons(value, clauses.cdr().unwrap())),
+                ]),
+            ])
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(expanded))
+}
+
+#[test]
+fn test_expand() {
+    let form = Value::from(crate::reader::read("(let ((a 1) b) (f a b))").unwrap());
+    assert_eq!(
+        expand("let", &form).unwrap().unwrap().to_string(),
+        "((lambda (a b) (f a b)) 1 nil)"
+        );
+
+    let form = Value::from(crate::reader::read("(cond ((f) 1) (t 2))").unwrap());
+    assert_eq!(
+        expand("cond", &form).unwrap().unwrap().to_string(),
+        "(if (f) (progn 1) (cond (t 2)))"
+        );
+
+    let form = Value::from(crate::reader::read("(f 1)").unwrap());
+    assert_eq!(expand("f", &form), Ok(None));
+}
+
+#[test]
+fn test_match() {
+    let mut interp = Interp::new();
+    let src = "(defun area (shape)
+                 (match shape
+                   (('circle r) (* 3 r r))
+                   (('rect w h) :when (= w h) (list 'square (* w h)))
+                   (('rect w h) (* w h))
+                   (('poly . points) (length points))
+                   (('box &key :w w :h h) (list w h))
+                   (_ 'unknown)))";
+    interp.eval_str(src).unwrap();
+    interp.eval_str("(defun length (l) (if l (+ 1 (length (cdr l))) 0))").unwrap();
+    let area = |interp: &mut Interp, shape: &str| {
+        interp.eval_str(&format!("(area '{})", shape)).map(|v| v.to_string())
+    };
+    assert_eq!(area(&mut interp, "(circle 2)"), Ok("12".to_string()));
+    assert_eq!(area(&mut interp, "(rect 2 3)"), Ok("6".to_string()));
+    assert_eq!(area(&mut interp, "(rect 3 3)"), Ok("(square 9)".to_string()));
+    assert_eq!(area(&mut interp, "(poly 1 2 3)"), Ok("3".to_string()));
+    assert_eq!(area(&mut interp, "(box :h 2)"), Ok("(nil 2)".to_string()));
+    assert_eq!(area(&mut interp, "(circle 1 2)"), Ok("unknown".to_string()));
+
+    // The value is evaluated once, and no clause gives nil.
+    assert_eq!(
+        interp.eval_str("(let ((n 0)) (match (setq n (+ n 1)) (2 'two) (x (list x n))))")
+            .map(|v| v.to_string()),
+        Ok("(1 1)".to_string())
+        );
+    assert_eq!(interp.eval_str("(match 1 (2 'two))"), Ok(Value::Nil));
+    assert_eq!(interp.eval_str("(match 1 (x))"), Ok(Value::Nil));
+    assert!(interp.eval_str("(match 1 ((a &rest) a))").is_err());
+    assert!(interp.eval_str("(match 1 (x :when))").is_err());
+
+    // Nor does it see the user's `apply`, `cdr` or `%match`.
+    interp.eval_str("(defun g (cdr) (match cdr (x x)))").unwrap();
+    assert_eq!(interp.eval_str("(g 5)"), Ok(Value::Int(5)));
+    assert_eq!(
+        interp.eval_str("(let ((apply 1) (%match 2)) (match '(1 2) ((a b) :when a b)))"),
+        Ok(Value::Int(2))
+        );
+}
+
+// ***** Continuations *****
+// A `dynamic-wind` extent that is currently active.
+pub struct Winder {
+    before: Value,
+    after: Value,
+}
+
+// A first-class continuation is a copy of the frame stack together with
+// the `dynamic-wind` extents active when it was captured. Frames are
+// immutable, so the copy can be re-entered any number of times.
+pub struct Continuation {
+    stack: Vec<Frame>,
+    winders: Vec<Rc<Winder>>,
+}
+
+// ***** Conditions *****
+// Handlers live on the frame stack. Signalling searches it from the top
+// for `HandlerCase` and `HandlerBind` frames, so the active handlers are
+// always those of the current dynamic extent, and continuations capture
+// them too. A `handler-case` clause unwinds to its frame like a
+// continuation would, so `dynamic-wind` and `unwind-protect` cleanups run.
+// A `handler-bind` handler runs on top of the signalling stack; if it
+// returns, the search goes on below it. Errors that no handler takes
+// unwind to the top level and are returned to Rust.
+
+// ***** Machine *****
+// The evaluator never recurses on the Rust stack. Pending work is kept
+// as heap-allocated frames in `Interp::stack`, and a call in tail
+// position replaces the current state instead of pushing a frame, so
+// tail-recursive loops run in constant space.
+pub(crate) enum State {
+    Eval(Value, Env),
+    // Run compiled code.
+    Exec(Activation),
+    Apply(Value, Vec<Value>),
+    Return(Value),
+    Fail(Rc<Condition>),
+}
+
+#[derive(Clone)]
+pub(crate) enum Frame {
+    If { then: Value, alt: Value, env: Env },
+    Seq { rest: Value, env: Env },
+    Call { done: Vec<Value>, rest: Value, env: Env },
+    Define { name: String, env: Env },
+    Setq { name: String, rest: Value, env: Env },
+    And { rest: Value, env: Env },
+    Or { rest: Value, env: Env },
+    // `dynamic-wind`: the before thunk is running.
+    WindBefore { before: Value, thunk: Value, after: Value },
+    // `dynamic-wind`: the body thunk is running inside the extent.
+    WindBody { after: Value, outer: Vec<Rc<Winder>> },
+    // Discard the incoming value and return `value` instead.
+    Deliver { value: Value },
+    // Run after/before thunks while control moves to a continuation.
+    // `steps` is in reverse order of execution.
+    Rewind { steps: Vec<(Value, Vec<Rc<Winder>>)>, winders: Vec<Rc<Winder>>, value: Value },
+    HandlerCase { handlers: Vec<(Value, Value)>, winders: Vec<Rc<Winder>> },
+    HandlerBind { handlers: Vec<(String, Value)> },
+    // Call `f` with the incoming value.
+    CallWith { f: Value },
+    // A `handler-bind` handler is running. While it runs, the handlers
+    // from `frame - 1` up are disabled. If it returns, the search for
+    // `cond` goes on from binding `skip` of that frame.
+    Decline { cond: Rc<Condition>, frame: usize, skip: usize, fatal: bool },
+    // Bottom frame of an unhandled error.
+    Fail { cond: Rc<Condition> },
+    // Compiled code waiting for the value of a call.
+    Vm(Activation),
+}
+
+pub struct Interp {
+    pub(crate) global: Env,
+    stack: Vec<Frame>,
+    winders: Vec<Rc<Winder>>,
+    pub(crate) kinds: Kinds,
+    pub(crate) peak: usize,
+}
+
+impl Interp {
+    pub fn new() -> Interp {
+        let global = Env::new();
+        for b in BUILTINS {
+            global.define(b.name, Value::Builtin(b));
+        }
+        Interp { global, stack: vec![], winders: vec![], kinds: Kinds::new(), peak: 0 }
+    }
+
+    pub fn global(&self) -> &Env {
+        &self.global
+    }
+
+    pub fn define(&mut self, name: &str, v: Value) {
+        self.global.define(name, v);
+    }
+
+    pub fn eval(&mut self, tk: &Token) -> Result<Value, LprpError> {
+        self.eval_value(Value::from(tk))
+    }
+
+    // Evaluate every form in `src` and return the value of the last one.
+    pub fn eval_str(&mut self, src: &str) -> Result<Value, LprpError> {
+        let mut last = Value::Nil;
+        for tk in read_all(src)? {
+            last = self.eval(&tk)?;
+        }
+        Ok(last)
+    }
+
+    pub fn eval_value(&mut self, expr: Value) -> Result<Value, LprpError> {
+        let env = self.global.clone();
+        self.run(State::Eval(expr, env))
+    }
+
+    // Register a Rust function. An `Err` it returns is signalled as a
+    // condition, so evaluated code can handle it.
+    pub fn define_fn<F>(&mut self, name: &str, func: F)
+        where F: Fn(&[Value]) -> Result<Value, LprpError> + 'static
+    {
+        let native = NativeFn { name: name.to_string(), func: Box::new(func) };
+        self.global.define(name, Value::Native(Rc::new(native)));
+    }
+
+    // Call a function value from Rust.
+    pub fn call(&mut self, f: &Value, args: Vec<Value>) -> Result<Value, LprpError> {
+        self.run(State::Apply(f.clone(), args))
+    }
+
+    pub(crate) fn run(&mut self, mut state: State) -> Result<Value, LprpError> {
+        loop {
+            let next = match state {
+                State::Eval(expr, env) => self.eval_step(expr, env),
+                State::Apply(f, args) => self.apply(f, args),
+                State::Exec(act) => self.exec_step(act),
+                State::Return(v) => {
+                    if heap::should_collect() {
+                        heap::collect();
+                    }
+                    match self.stack.pop() {
+                        Some(frame) => self.resume(frame, v),
+                        None => return Ok(v),
+                    }
+                },
+                State::Fail(cond) => {
+                    self.stack.clear();
+                    self.winders.clear();
+                    return Err(LprpError::from((*cond).clone()));
+                },
+            };
+            state = match next {
+                Ok(s) => s,
+                Err(c) => {
+                    let top = self.stack.len();
+                    self.signal(Rc::new(c), top, 0, true)
+                }
+            };
+        }
+    }
+
+    pub(crate) fn push(&mut self, frame: Frame) {
+        self.stack.push(frame);
+        self.peak = self.peak.max(self.stack.len());
+    }
+
+    fn eval_step(&mut self, expr: Value, env: Env) -> Result<State, Condition> {
+        match expr {
+            Value::Symbol(ref s) if s.starts_with(':') => Ok(State::Return(expr)),
+            Value::Symbol(ref s) => {
+                match env.lookup(s) {
+                    Some(v) => Ok(State::Return(v)),
+                    None => raise("unbound-variable", "unbound variable", vec![expr.clone()]),
+                }
+            },
+            Value::Pair(_) => self.eval_form(expr, env),
+            _ => Ok(State::Return(expr)),
+        }
+    }
+
+    fn eval_form(&mut self, form: Value, env: Env) -> Result<State, Condition> {
+        let head = form.car().unwrap();
+        let args = form.cdr().unwrap();
+        let malformed = || raise("program-error", "malformed special form", vec![form.clone()]);
+
+        if let Value::Symbol(ref name) = head {
+            match name.as_str() {
+                "quote" => {
+                    return match args.to_vec().as_deref() {
+                        Some([x]) => Ok(State::Return(x.clone())),
+                        _ => malformed(),
+                    };
+                },
+                "if" => {
+                    let (test, then, alt) = match args.to_vec().as_deref() {
+                        Some([c, t]) => (c.clone(), t.clone(), Value::Nil),
+                        Some([c, t, e]) => (c.clone(), t.clone(), e.clone()),
+                        _ => return malformed(),
+                    };
+                    self.push(Frame::If { then, alt, env: env.clone() });
+                    return Ok(State::Eval(test, env));
+                },
+                "progn" => return Ok(self.eval_body(args, env)),
+                "lambda" => {
+                    let params = args.car().unwrap_or_default();
+                    let body = args.cdr().unwrap_or_default();
+                    let l = make_lambda(None, &params, body, env)?;
+                    return Ok(State::Return(l));
+                },
+                "defun" => {
+                    let (fname, params, body) = match (args.car(), args.cdr()) {
+                        (Some(Value::Symbol(n)), Some(rest)) if rest.car().is_some() => {
+                            (n.clone(), rest.car().unwrap(), rest.cdr().unwrap())
+                        },
+                        _ => return malformed(),
+                    };
+                    let l = make_lambda(Some(fname.clone()), &params, body, env.clone())?;
+                    env.define(&fname, l);
+                    return Ok(State::Return(Value::Symbol(fname)));
+                },
+                "defvar" => {
+                    let (vname, init) = match args.to_vec().as_deref() {
+                        Some([Value::Symbol(n)]) => (n.clone(), Value::Nil),
+                        Some([Value::Symbol(n), init]) => (n.clone(), init.clone()),
+                        _ => return malformed(),
+                    };
+                    self.push(Frame::Define { name: vname, env: env.clone() });
+                    return Ok(State::Eval(init, env));
+                },
+                "setq" => {
+                    return match (args.car(), args.cdr().and_then(|r| r.car())) {
+                        (Some(Value::Symbol(n)), Some(init)) => {
+                            let rest = args.cdr().and_then(|r| r.cdr()).unwrap();
+                            self.push(Frame::Setq { name: n.clone(), rest, env: env.clone() });
+                            Ok(State::Eval(init.clone(), env))
+                        },
+                        _ => malformed(),
+                    };
+                },
+                "and" => {
+                    return match args {
+                        Value::Nil => Ok(State::Return(Value::T)),
+                        _ => {
+                            let first = args.car().unwrap();
+                            let rest = args.cdr().unwrap();
+                            if !rest.is_nil() {
+                                self.push(Frame::And { rest, env: env.clone() });
+                            }
+                            Ok(State::Eval(first, env))
+                        }
+                    };
+                },
+                "define-condition" => {
+                    let (kind, parent) = match args.to_vec().as_deref() {
+                        Some([Value::Symbol(k), parents]) => {
+                            match parents.to_vec().as_deref() {
+                                Some([]) => (k.clone(), "condition".to_string()),
+                                Some([Value::Symbol(p)]) => (k.clone(), p.clone()),
+                                _ => return malformed(),
+                            }
+                        },
+                        _ => return malformed(),
+                    };
+                    self.kinds.define(&kind, &parent);
+                    return Ok(State::Return(Value::Symbol(kind)));
+                },
+                "or" => {
+                    return match args {
+                        Value::Nil => Ok(State::Return(Value::Nil)),
+                        _ => {
+                            let first = args.car().unwrap();
+                            let rest = args.cdr().unwrap();
+                            if !rest.is_nil() {
+                                self.push(Frame::Or { rest, env: env.clone() });
+                            }
+                            Ok(State::Eval(first, env))
+                        }
+                    };
+                },
+                _ => {
+                    if let Some(expanded) = expand(name, &form)? {
+                        return Ok(State::Eval(expanded, env));
+                    }
+                },
+            }
+        }
+
+        self.push(Frame::Call { done: vec![], rest: args, env: env.clone() });
+        Ok(State::Eval(head, env))
+    }
+
+    // Evaluate a body of forms. The last form is in tail position.
+    fn eval_body(&mut self, body: Value, env: Env) -> State {
+        match (body.car(), body.cdr()) {
+            (Some(first), Some(rest)) => {
+                if !rest.is_nil() {
+                    self.push(Frame::Seq { rest: rest.clone(), env: env.clone() });
+                }
+                State::Eval(first.clone(), env)
+            },
+            _ => State::Return(Value::Nil),
+        }
+    }
+
+    fn resume(&mut self, frame: Frame, v: Value) -> Result<State, Condition> {
+        match frame {
+            Frame::If { then, alt, env } => {
+                if v.is_true() {
+                    Ok(State::Eval(then, env))
+                } else {
+                    Ok(State::Eval(alt, env))
+                }
+            },
+            Frame::Seq { rest, env } => Ok(self.eval_body(rest, env)),
+            Frame::Call { mut done, rest, env } => {
+                done.push(v);
+                match rest {
+                    Value::Pair(_) => {
+                        let next = rest.car().unwrap();
+                        let rest = rest.cdr().unwrap();
+                        self.push(Frame::Call { done, rest, env: env.clone() });
+                        Ok(State::Eval(next, env))
+                    },
+                    Value::Nil => {
+                        let f = done.remove(0);
+                        Ok(State::Apply(f, done))
+                    },
+                    _ => raise("program-error", "improper argument list", vec![rest]),
+                }
+            },
+            Frame::Define { name, env } => {
+                env.define(&name, v);
+                Ok(State::Return(Value::Symbol(name)))
+            },
+            Frame::Setq { name, rest, env } => {
+                if !env.set(&name, v.clone()) {
+                    return raise("unbound-variable", "unbound variable", vec![Value::Symbol(name)]);
+                }
+                match (rest.car(), rest.cdr().and_then(|r| r.car())) {
+                    (None, _) => Ok(State::Return(v)),
+                    (Some(Value::Symbol(n)), Some(init)) => {
+                        let next = rest.cdr().and_then(|r| r.cdr()).unwrap();
+                        let init = init.clone();
+                        self.push(Frame::Setq { name: n.clone(), rest: next, env: env.clone() });
+                        Ok(State::Eval(init, env))
+                    },
+                    _ => raise("program-error", "malformed setq", vec![rest]),
+                }
+            },
+            Frame::And { rest, env } => {
+                if v.is_true() {
+                    Ok(self.eval_chain(rest, env, true))
+                } else {
+                    Ok(State::Return(v))
+                }
+            },
+            Frame::Or { rest, env } => {
+                if v.is_true() {
+                    Ok(State::Return(v))
+                } else {
+                    Ok(self.eval_chain(rest, env, false))
+                }
+            },
+            Frame::WindBefore { before, thunk, after } => {
+                let outer = self.winders.clone();
+                self.winders.push(Rc::new(Winder { before, after: after.clone() }));
+                self.push(Frame::WindBody { after, outer });
+                Ok(State::Apply(thunk, vec![]))
+            },
+            Frame::WindBody { after, outer } => {
+                self.winders = outer;
+                self.push(Frame::Deliver { value: v });
+                Ok(State::Apply(after, vec![]))
+            },
+            Frame::Deliver { value } => Ok(State::Return(value)),
+            Frame::Rewind { mut steps, winders, value } => {
+                match steps.pop() {
+                    Some((thunk, during)) => {
+                        self.winders = during;
+                        self.push(Frame::Rewind { steps, winders, value });
+                        Ok(State::Apply(thunk, vec![]))
+                    },
+                    None => {
+                        self.winders = winders;
+                        Ok(State::Return(value))
+                    },
+                }
+            },
+            Frame::HandlerCase { .. }|Frame::HandlerBind { .. } => Ok(State::Return(v)),
+            Frame::CallWith { f } => Ok(State::Apply(f, vec![v])),
+            Frame::Decline { cond, frame, skip, fatal } => {
+                Ok(self.signal(cond, frame, skip, fatal))
+            },
+            Frame::Fail { cond } => Ok(State::Fail(cond)),
+            Frame::Vm(act) => self.resume_vm(act, v),
+        }
+    }
+
+    // Look for a handler of `cond` in the frames below index `top`,
+    // skipping the first `skip` bindings of frame `top - 1`.
+    fn signal(&mut self, cond: Rc<Condition>, top: usize, skip: usize, fatal: bool) -> State {
+        let kinds = &self.kinds;
+        let matches = |kind: &Value| {
+            match kind {
+                Value::T => true,
+                Value::Symbol(k) => kinds.is_a(&cond.kind, k),
+                _ => false,
+            }
+        };
+
+        let mut i = top;
+        let mut skip = skip;
+        while i > 0 {
+            i -= 1;
+            let skip_here = std::mem::take(&mut skip);
+            match &self.stack[i] {
+                Frame::HandlerCase { handlers, winders } => {
+                    let found = handlers.iter().find(|(kind, _)| matches(kind));
+                    if let Some((_, handler)) = found {
+                        let f = handler.clone();
+                        let winders = winders.clone();
+                        let mut stack = self.stack[..i].to_vec();
+                        stack.push(Frame::CallWith { f });
+                        return self.transfer(stack, winders, Value::Condition(cond));
+                    }
+                },
+                Frame::HandlerBind { handlers } => {
+                    let found = handlers.iter()
+                        .enumerate()
+                        .skip(skip_here)
+                        .find(|(_, (kind, _))| kinds.is_a(&cond.kind, kind));
+                    if let Some((n, (_, handler))) = found {
+                        let handler = handler.clone();
+                        let arg = Value::Condition(cond.clone());
+                        self.push(Frame::Decline { cond, frame: i + 1, skip: n + 1, fatal });
+                        return State::Apply(handler, vec![arg]);
+                    }
+                },
+                Frame::Decline { frame, .. } => {
+                    i = frame - 1;
+                },
+                _ => {},
+            }
+        }
+
+        if !fatal {
+            return State::Return(Value::Nil);
+        }
+        self.transfer(vec![Frame::Fail { cond }], vec![], Value::Nil)
+    }
+
+    // Transfer control to `k`. The after thunks of the extents being left
+    // run innermost first, then the before thunks of the extents being
+    // entered run outermost first.
+    fn throw(&mut self, k: &Continuation, value: Value) -> State {
+        self.transfer(k.stack.clone(), k.winders.clone(), value)
+    }
+
+    fn transfer(&mut self, stack: Vec<Frame>, winders: Vec<Rc<Winder>>, value: Value) -> State {
+        let common = self.winders.iter()
+            .zip(&winders)
+            .take_while(|(a, b)| Rc::ptr_eq(a, b))
+            .count();
+        let mut steps = vec![];
+        for i in (common..self.winders.len()).rev() {
+            steps.push((self.winders[i].after.clone(), self.winders[..i].to_vec()));
+        }
+        for i in common..winders.len() {
+            steps.push((winders[i].before.clone(), winders[..i].to_vec()));
+        }
+
+        self.stack = stack;
+        if steps.is_empty() {
+            self.winders = winders;
+            return State::Return(value);
+        }
+        steps.reverse();
+        self.push(Frame::Rewind { steps, winders, value });
+        State::Return(Value::Nil)
+    }
+
+    // Continue an `and`/`or` chain. The last operand is in tail position.
+    fn eval_chain(&mut self, rest: Value, env: Env, is_and: bool) -> State {
+        let first = rest.car().unwrap();
+        let rest = rest.cdr().unwrap();
+        if !rest.is_nil() {
+            let env = env.clone();
+            self.push(if is_and { Frame::And { rest, env } } else { Frame::Or { rest, env } });
+        }
+        State::Eval(first, env)
+    }
+
+    fn apply(&mut self, f: Value, mut args: Vec<Value>) -> Result<State, Condition> {
+        match f {
+            Value::Lambda(ref l) => {
+                let name = l.name().unwrap_or("lambda");
+                let max = if l.rest.is_some() { None } else { Some(l.params.len()) };
+                check_arity(name, l.params.len(), max, args.len())?;
+
+                let env = l.env.extend();
+                let rest = args.split_off(l.params.len());
+                for (p, a) in l.params.iter().zip(args) {
+                    env.define(p, a);
+                }
+                if let Some(r) = &l.rest {
+                    env.define(r, Value::list(rest));
+                }
+                Ok(self.eval_body(l.body.clone(), env))
+            },
+            Value::Builtin(b) => {
+                check_arity(b.name, b.min, b.max, args.len())?;
+                match b.prim {
+                    Primitive::Pure(func) => Ok(State::Return(func(&args)?)),
+                    Primitive::Funcall => {
+                        let f = args.remove(0);
+                        Ok(State::Apply(f, args))
+                    },
+                    Primitive::Apply => {
+                        let f = args.remove(0);
+                        let last = args.pop().unwrap();
+                        match last.to_vec() {
+                            Some(tail) => {
+                                args.extend(tail);
+                                Ok(State::Apply(f, args))
+                            },
+                            None => raise("type-error", "apply: expected list", vec![last]),
+                        }
+                    },
+                    Primitive::Eval => {
+                        let expr = args.pop().unwrap();
+                        Ok(State::Eval(expr, self.global.clone()))
+                    },
+                    Primitive::CallCC => {
+                        let k = Continuation {
+                            stack: self.stack.clone(),
+                            winders: self.winders.clone(),
+                        };
+                        let f = args.pop().unwrap();
+                        Ok(State::Apply(f, vec![Value::Continuation(Rc::new(k))]))
+                    },
+                    Primitive::Error|Primitive::Signal => {
+                        let fatal = matches!(b.prim, Primitive::Error);
+                        let kind = if fatal { "simple-error" } else { "simple-condition" };
+                        let cond = condition_args(b.name, kind, &args)?;
+                        let top = self.stack.len();
+                        Ok(self.signal(cond, top, 0, fatal))
+                    },
+                    Primitive::HandlerCase => {
+                        let spec = args.pop().unwrap().to_vec().unwrap_or_default();
+                        let thunk = args.pop().unwrap();
+                        let handlers = spec.chunks(2)
+                            .filter_map(|kv| match kv {
+                                [k, h] => Some((k.clone(), h.clone())),
+                                _ => None,
+                            })
+                            .collect();
+                        let winders = self.winders.clone();
+                        self.push(Frame::HandlerCase { handlers, winders });
+                        Ok(State::Apply(thunk, vec![]))
+                    },
+                    Primitive::HandlerBind => {
+                        let thunk = args.pop().unwrap();
+                        let spec = args.pop().unwrap().to_vec().unwrap_or_default();
+                        let handlers = spec.chunks(2)
+                            .filter_map(|kv| match kv {
+                                [Value::Symbol(k), h] => Some((k.clone(), h.clone())),
+                                _ => None,
+                            })
+                            .collect();
+                        self.push(Frame::HandlerBind { handlers });
+                        Ok(State::Apply(thunk, vec![]))
+                    },
+                    Primitive::DynamicWind => {
+                        let after = args.pop().unwrap();
+                        let thunk = args.pop().unwrap();
+                        let before = args.pop().unwrap();
+                        self.push(Frame::WindBefore { before: before.clone(), thunk, after });
+                        Ok(State::Apply(before, vec![]))
+                    },
+                }
+            },
+            Value::Closure(ref c) => self.apply_closure(c, args),
+            Value::Continuation(ref k) => {
+                check_arity("continuation", 0, Some(1), args.len())?;
+                Ok(self.throw(k, args.pop().unwrap_or_default()))
+            },
+            Value::Native(ref n) => {
+                match (n.func)(&args) {
+                    Ok(v) => Ok(State::Return(v)),
+                    Err(e) => Err(Condition::from(e)),
+                }
+            },
+            _ => raise("type-error", "not a function", vec![f]),
+        }
+    }
+}
+
+impl Default for Interp {
+    fn default() -> Self {
+        Interp::new()
+    }
+}
+
+fn make_lambda(name: Option<String>, params: &Value, body: Value, env: Env)
+    -> Result<Value, Condition>
+{
+    let malformed = || raise("program-error", "malformed lambda list", vec![params.clone()]);
+    let mut names = vec![];
+    let mut rest = None;
+    let mut iter = params.iter();
+    while let Some(p) = iter.next() {
+        match p {
+            Value::Symbol(s) if s == "&rest" => {
+                match (iter.next(), iter.next()) {
+                    (Some(Value::Symbol(r)), None) => rest = Some(r.clone()),
+                    _ => return malformed(),
+                }
+            },
+            Value::Symbol(s) => names.push(s.clone()),
+            _ => return malformed(),
+        }
+    }
+    // A name bound twice is an error, since neither binding could be meant.
+    let all: Vec<&String> = names.iter().chain(&rest).collect();
+    let twice = all.iter().enumerate().any(|(i, n)| all[..i].contains(n));
+    if params.to_vec().is_none() || twice {
+        return malformed();
+    }
+    Ok(Value::Lambda(Rc::new(Lambda { name, params: names, rest, body, env })))
+}
+
+#[test]
+fn test_interp_eval() {
+    let mut interp = Interp::new();
+    assert_eq!(interp.eval_str("(+ 1 2)"), Ok(Value::Int(3)));
+    assert_eq!(interp.eval_str("(if nil 1 2)"), Ok(Value::Int(2)));
+    assert_eq!(interp.eval_str("(car '(a b))"), Ok(Value::sym("a")));
+    assert_eq!(interp.eval_str(":key"), Ok(Value::sym(":key")));
+    assert_eq!(
+        interp.eval_str("(defun sq (x) (* x x))\n(sq 12)"),
+        Ok(Value::Int(144))
+        );
+    assert_eq!(
+        interp.eval_str("(let ((a 1) (b 2)) (list a b))").map(|v| v.to_string()),
+        Ok("(1 2)".to_string())
+        );
+    assert_eq!(
+        interp.eval_str("(cond ((= 1 2) 'no) ((< 1 2) 'yes))"),
+        Ok(Value::sym("yes"))
+        );
+    assert_eq!(
+        interp.eval_str("(defvar *n* 0)\n(setq *n* (+ *n* 5))\n*n*"),
+        Ok(Value::Int(5))
+        );
+    assert_eq!(
+        interp.eval_str("(apply + 1 2 '(3 4))"),
+        Ok(Value::Int(10))
+        );
+    assert_eq!(
+        interp.eval_str("((lambda (a &rest r) r) 1 2 3)").map(|v| v.to_string()),
+        Ok("(2 3)".to_string())
+        );
+}
+
+#[test]
+fn test_lambda_duplicate_params() {
+    let mut interp = Interp::new();
+    for src in &["((lambda (x x) x) 1 2)", "(lambda (x &rest x) x)", "(defun f (a b a) a)"] {
+        match interp.eval_str(src) {
+            Err(LprpError::Condition { message, .. }) => assert_eq!(message, "malformed lambda list"),
+            r => panic!("{}: {:?}", src, r),
+        }
+    }
+    assert_eq!(interp.eval_str("((lambda (x y) y) 1 2)"), Ok(Value::Int(2)));
+}
+
+#[cfg(test)]
+fn condition_error(kind: &str, message: &str, irritants: Vec<Token>) -> LprpError {
+    LprpError::Condition {
+        kind: kind.to_string(),
+        message: message.to_string(),
+        irritants,
+    }
+}
+
+#[test]
+fn test_interp_errors() {
+    let mut interp = Interp::new();
+    assert_eq!(
+        interp.eval_str("undefined"),
+        Err(condition_error(
+                "unbound-variable",
+                "unbound variable",
+                vec![Token::Symbol("undefined".to_string())]
+                ))
+        );
+    assert_eq!(
+        interp.eval_str("(1 2)"),
+        Err(condition_error("type-error", "not a function", vec![Token::Int(1)]))
+        );
+    assert_eq!(
+        interp.eval_str("((lambda (x) x))"),
+        Err(condition_error(
+                "program-error",
+                "wrong number of arguments to lambda: expected 1, got 0",
+                vec![]
+                ))
+        );
+    // The machine is still usable after an error.
+    assert_eq!(interp.eval_str("(+ 1 1)"), Ok(Value::Int(2)));
+}
+
+#[test]
+fn test_interp_tail_calls() {
+    let mut interp = Interp::new();
+    let src = "(defun count-down (n) (if (= n 0) 'done (count-down (- n 1))))
+               (count-down 100000)";
+    assert_eq!(interp.eval_str(src), Ok(Value::sym("done")));
+    assert!(interp.peak < 10);
+
+    // Tail calls through `cond`, `and` and `progn` are eliminated too.
+    let src = "(defun loop (n acc)
+                 (cond ((= n 0) acc)
+                       (t (progn nil (and t (loop (- n 1) (+ acc 1)))))))
+               (loop 100000 0)";
+    interp.peak = 0;
+    assert_eq!(interp.eval_str(src), Ok(Value::Int(100000)));
+    assert!(interp.peak < 10);
+}
+
+#[test]
+fn test_interp_deep_recursion() {
+    // Non-tail recursion grows the heap stack, not the Rust stack.
+    let mut interp = Interp::new();
+    let src = "(defun sum (n) (if (= n 0) 0 (+ n (sum (- n 1)))))
+               (sum 100000)";
+    assert_eq!(interp.eval_str(src), Ok(Value::Int(5000050000)));
+}
+
+#[test]
+fn test_interp_shared_structure() {
+    let mut interp = Interp::new();
+    let src = "(defvar a (list 1 2))
+               (defvar b (cons 0 (cdr a)))
+               (rplaca (cdr a) 'x)
+               (list b (eq (cdr a) (cdr b)) (eq a (list 1 'x)) (equal a (list 1 'x)))";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("((0 x) t nil t)".to_string())
+        );
+
+    // Cycles that become unreachable are collected while running.
+    let src = "(defun churn (n)
+                 (if (= n 0)
+                     'done
+                     (progn (let ((c (list 1 2))) (rplacd (cdr c) c)) (churn (- n 1)))))
+               (churn 20000)";
+    assert_eq!(interp.eval_str(src), Ok(Value::sym("done")));
+    assert!(heap::live() < 20000);
+}
+
+#[test]
+fn test_call_cc_escape() {
+    let mut interp = Interp::new();
+    assert_eq!(
+        interp.eval_str("(+ 1 (call/cc (lambda (k) (+ 10 (k 5)))))"),
+        Ok(Value::Int(6))
+        );
+    assert_eq!(
+        interp.eval_str("(+ 1 (call/cc (lambda (k) 2)))"),
+        Ok(Value::Int(3))
+        );
+
+    // Early exit from a loop.
+    let src = "(defun find-first (pred l)
+                 (call/cc
+                   (lambda (return)
+                     (defun walk (l)
+                       (when l
+                         (when (pred (car l)) (return (car l)))
+                         (walk (cdr l))))
+                     (walk l)
+                     nil)))
+               (find-first (lambda (x) (> x 2)) '(1 2 3 4))";
+    assert_eq!(interp.eval_str(src), Ok(Value::Int(3)));
+}
+
+#[test]
+fn test_call_cc_reentry() {
+    let mut interp = Interp::new();
+    let src = "(let ((k nil) (n 0))
+                 (call/cc (lambda (c) (setq k c)))
+                 (setq n (+ n 1))
+                 (if (< n 5) (k nil) n))";
+    assert_eq!(interp.eval_str(src), Ok(Value::Int(5)));
+}
+
+#[test]
+fn test_call_cc_generator() {
+    let mut interp = Interp::new();
+    let src = "(defun walk (l f)
+                 (when l (f (car l)) (walk (cdr l) f)))
+               (defun make-generator (l)
+                 (let ((return nil) (resume nil))
+                   (lambda ()
+                     (call/cc
+                       (lambda (r)
+                         (setq return r)
+                         (if resume
+                             (resume nil)
+                             (progn
+                               (walk l (lambda (x)
+                                         (call/cc (lambda (k)
+                                                    (setq resume k)
+                                                    (return x)))))
+                               (return 'done))))))))
+               (defvar g (make-generator '(1 2 3)))
+               (list (g) (g) (g) (g))";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(1 2 3 done)".to_string())
+        );
+}
+
+#[test]
+fn test_dynamic_wind() {
+    let mut interp = Interp::new();
+    let src = "(defvar trace nil)
+               (defun note (x) (setq trace (cons x trace)))
+               (list
+                 (dynamic-wind (lambda () (note 'before))
+                               (lambda () 'body)
+                               (lambda () (note 'after)))
+                 trace)";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(body (after before))".to_string())
+        );
+
+    // Escaping runs the after thunk.
+    let src = "(setq trace nil)
+               (list
+                 (call/cc
+                   (lambda (k)
+                     (dynamic-wind (lambda () (note 'before))
+                                   (lambda () (k 'escaped) 'unreachable)
+                                   (lambda () (note 'after)))))
+                 trace)";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(escaped (after before))".to_string())
+        );
+
+    // Re-entering runs the before thunk again.
+    let src = "(setq trace nil)
+               (let ((k nil) (n 0))
+                 (dynamic-wind (lambda () (note 'in))
+                               (lambda () (call/cc (lambda (c) (setq k c))))
+                               (lambda () (note 'out)))
+                 (setq n (+ n 1))
+                 (if (< n 3) (k nil))
+                 trace)";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(out in out in out in)".to_string())
+        );
+}
+
+#[test]
+fn test_handler_case() {
+    let mut interp = Interp::new();
+    assert_eq!(
+        interp.eval_str("(handler-case (car 1) (type-error (c) (condition-type c)))"),
+        Ok(Value::sym("type-error"))
+        );
+    assert_eq!(
+        interp.eval_str("(handler-case (/ 1 0) (arithmetic-error () 'caught))"),
+        Ok(Value::sym("caught"))
+        );
+    assert_eq!(
+        interp.eval_str(
+            "(handler-case (error 'my-error \"bad\" 1 2)
+               (type-error () 'wrong)
+               (error (c) (list (condition-message c) (condition-irritants c))))"
+            ).map(|v| v.to_string()),
+        Ok("(\"bad\" (1 2))".to_string())
+        );
+    // A clause that does not match lets the condition through.
+    assert_eq!(
+        interp.eval_str(
+            "(handler-case
+               (handler-case (error \"inner\") (type-error () 'wrong))
+               (simple-error (c) (condition-message c)))"
+            ),
+        Ok(Value::Str("inner".to_string()))
+        );
+    assert_eq!(interp.eval_str("(handler-case (+ 1 2) (error () 0))"), Ok(Value::Int(3)));
+    assert_eq!(interp.eval_str("(ignore-errors (car 1))"), Ok(Value::Nil));
+
+    // The expansions do not see the user's bindings of `list` and the rest.
+    let src = "(defun f (list) (handler-case (car list) (error () 'bad)))
+               (defun g (dynamic-wind progn lambda)
+                 (list (ignore-errors (car dynamic-wind)) (unwind-protect progn lambda)))
+               (let ((%handler-bind 1) (list 2))
+                 (handler-bind ((error (lambda (c) nil))) (cons (f 1) (cons (g 1 2 3) list))))";
+    assert_eq!(interp.eval_str(src).map(|v| v.to_string()), Ok("(bad (nil 2) . 2)".to_string()));
+}
+
+#[test]
+fn test_unwind_protect() {
+    let mut interp = Interp::new();
+    let src = "(defvar log nil)
+               (list
+                 (handler-case
+                   (unwind-protect (error \"boom\") (setq log (cons 'cleanup log)))
+                   (error () 'handled))
+                 (unwind-protect 'normal (setq log (cons 'again log)))
+                 log)";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(handled normal (again cleanup))".to_string())
+        );
+
+    // Cleanups also run when an error escapes to Rust.
+    assert_eq!(
+        interp.eval_str("(unwind-protect (error 'fatal \"oops\" 7) (setq log 'unwound))"),
+        Err(condition_error("fatal", "oops", vec![Token::Int(7)]))
+        );
+    assert_eq!(interp.eval_str("log"), Ok(Value::sym("unwound")));
+}
+
+#[test]
+fn test_handler_bind() {
+    let mut interp = Interp::new();
+    // A handler that returns declines, and `signal` then returns nil.
+    let src = "(defvar seen nil)
+               (list
+                 (handler-bind ((warning (lambda (c) (setq seen (condition-message c)))))
+                   (signal 'warning \"careful\"))
+                 seen)";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(nil \"careful\")".to_string())
+        );
+
+    // Handlers run before unwinding, so a continuation can recover.
+    let src = "(call/cc
+                 (lambda (k)
+                   (handler-bind ((error (lambda (c) (k 'recovered))))
+                     (error \"boom\"))))";
+    assert_eq!(interp.eval_str(src), Ok(Value::sym("recovered")));
+
+    // A handler runs with its own binding disabled.
+    let src = "(handler-case
+                 (handler-bind ((error (lambda (c) (error 'second \"again\"))))
+                   (error 'first \"once\"))
+                 (error (c) (condition-type c)))";
+    assert_eq!(interp.eval_str(src), Ok(Value::sym("second")));
+
+    // A declined error still reaches the outer handler.
+    let src = "(handler-case
+                 (handler-bind ((error (lambda (c) (setq seen 'inner))))
+                   (error \"x\"))
+                 (error () (list seen 'outer)))";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(inner outer)".to_string())
+        );
+}
+
+#[test]
+fn test_define_condition() {
+    let mut interp = Interp::new();
+    let src = "(define-condition low-disk (warning))
+               (list
+                 (handler-case (signal 'low-disk \"90%\")
+                   (error () 'error)
+                   (warning (c) (condition-type c)))
+                 (signal 'low-disk \"unhandled\"))";
+    assert_eq!(
+        interp.eval_str(src).map(|v| v.to_string()),
+        Ok("(low-disk nil)".to_string())
+        );
+}
+
+#[test]
+fn test_native_fn() {
+    let mut interp = Interp::new();
+    interp.define_fn("port", |args| {
+        match args {
+            [Value::Int(p)] if *p > 0 && *p < 65536 => Ok(Value::Int(*p)),
+            _ => Err(LprpError::ConversionError),
+        }
+    });
+    assert_eq!(interp.eval_str("(port 8080)"), Ok(Value::Int(8080)));
+    assert_eq!(
+        interp.eval_str("(handler-case (port -1) (type-error () 'invalid))"),
+        Ok(Value::sym("invalid"))
+        );
+    assert_eq!(
+        interp.eval_str("(port 0)"),
+        Err(condition_error("type-error", "Conversion Error", vec![]))
+        );
+
+    let f = interp.eval_str("(lambda (x) (* x 2))").unwrap();
+    assert_eq!(interp.call(&f, vec![Value::Int(21)]), Ok(Value::Int(42)));
+}
@@ -0,0 +1,180 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+use crate::value::{Pair, Value};
+
+
+// ***** Heap *****
+// Cons cells are reference counted, which frees acyclic structure as
+// soon as it is dropped. Cycles built with `rplaca`/`rplacd` keep their
+// own counts up, so every cell is also registered here and `collect`
+// finds the ones that are only referenced from other unreachable cells.
+//
+// The collector uses trial deletion: a cell whose strong count is
+// higher than the number of references from other cells is held from
+// outside the heap (by a variable, a frame, a closure or Rust code) and
+// is a root. Cells not reachable from a root are garbage; their fields
+// are cleared, which breaks the cycles and lets the counts drop to zero.
+// Cycles that pass through a closure's environment are not found, since
+// the environment looks like an outside reference.
+
+thread_local! {
+    static CELLS: RefCell<Vec<Weak<Pair>>> = const { RefCell::new(Vec::new()) };
+    static THRESHOLD: Cell<usize> = const { Cell::new(MIN_THRESHOLD) };
+}
+
+const MIN_THRESHOLD: usize = 4096;
+
+// Cells freed since the list last grew are dropped before it grows
+// again, so code that never collects, such as printing a token, does not
+// keep the allocation of every cell it made. Growing to at least twice
+// the cells left keeps this amortised O(1).
+pub(crate) fn register(pair: &Rc<Pair>) {
+    CELLS.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.len() == c.capacity() {
+            c.retain(|w| w.strong_count() > 0);
+            let left = c.len();
+            if left > c.capacity() / 2 {
+                c.reserve(left);
+            }
+        }
+        c.push(Rc::downgrade(pair));
+    });
+}
+
+// Whether enough cells were allocated since the last collection to make
+// another one worthwhile.
+pub fn should_collect() -> bool {
+    CELLS.with(|c| c.borrow().len()) >= THRESHOLD.with(|t| t.get())
+}
+
+// Number of cons cells that are alive.
+pub fn live() -> usize {
+    CELLS.with(|c| c.borrow().iter().filter(|w| w.strong_count() > 0).count())
+}
+
+// Reclaim unreachable cycles of cons cells and return how many cells
+// were freed.
+pub fn collect() -> usize {
+    let cells: Vec<Rc<Pair>> = CELLS.with(|c| {
+        let mut c = c.borrow_mut();
+        c.retain(|w| w.strong_count() > 0);
+        c.iter().filter_map(|w| w.upgrade()).collect()
+    });
+    let index: HashMap<*const Pair, usize> = cells.iter()
+        .enumerate()
+        .map(|(i, p)| (Rc::as_ptr(p), i))
+        .collect();
+
+    // Edges between registered cells. A field that is borrowed right now
+    // is treated as an outside reference to its cell.
+    let mut internal = vec![0; cells.len()];
+    let mut edges = vec![vec![]; cells.len()];
+    let mut rooted = vec![false; cells.len()];
+    for (i, p) in cells.iter().enumerate() {
+        for field in &[&p.car, &p.cdr] {
+            match field.try_borrow() {
+                Ok(v) => {
+                    if let Value::Pair(q) = &*v {
+                        if let Some(&j) = index.get(&Rc::as_ptr(q)) {
+                            internal[j] += 1;
+                            edges[i].push(j);
+                        }
+                    }
+                },
+                Err(_) => rooted[i] = true,
+            }
+        }
+    }
+
+    // `cells` itself holds one reference to each cell.
+    let mut pending: Vec<usize> = (0..cells.len())
+        .filter(|&i| rooted[i] || Rc::strong_count(&cells[i]) - 1 > internal[i])
+        .collect();
+    let mut live = vec![false; cells.len()];
+    while let Some(i) = pending.pop() {
+        if !mem::replace(&mut live[i], true) {
+            pending.extend(&edges[i]);
+        }
+    }
+
+    let mut garbage = vec![];
+    for (i, p) in cells.iter().enumerate() {
+        if !live[i] {
+            garbage.push(p.car.take());
+            garbage.push(p.cdr.take());
+        }
+    }
+    let freed = live.iter().filter(|l| !**l).count();
+    drop(garbage);
+    drop(cells);
+
+    let remaining = CELLS.with(|c| {
+        let mut c = c.borrow_mut();
+        c.retain(|w| w.strong_count() > 0);
+        c.len()
+    });
+    THRESHOLD.with(|t| t.set((remaining * 2).max(MIN_THRESHOLD)));
+    freed
+}
+
+#[test]
+fn test_collect_cycles() {
+    let before = live();
+    {
+        let a = Value::list(vec![Value::Int(1), Value::Int(2)]);
+        let last = a.cdr().unwrap();
+        last.set_cdr(a.clone());
+        let b = Value::cons(Value::Nil, Value::Nil);
+        b.set_car(b.clone());
+        assert_eq!(live(), before + 3);
+    }
+    // Dropped, but the cycles keep the cells alive.
+    assert_eq!(live(), before + 3);
+    assert_eq!(collect(), 3);
+    assert_eq!(live(), before);
+}
+
+#[test]
+fn test_collect_keeps_reachable() {
+    let a = Value::list(vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+    a.cdr().unwrap().cdr().unwrap().set_cdr(a.clone());
+    let shared = Value::cons(Value::Int(0), a.cdr().unwrap());
+    collect();
+    assert_eq!(shared.cdr().and_then(|d| d.car()), Some(Value::Int(2)));
+    assert_eq!(a.to_string(), "(1 2 3 ...)");
+    assert_eq!(a.to_vec(), None);
+
+    // Garbage hanging off a live cell is freed, the live cell is not.
+    let holder = Value::cons(Value::Nil, Value::Nil);
+    {
+        let cycle = Value::cons(Value::Int(9), Value::Nil);
+        cycle.set_cdr(cycle.clone());
+        holder.set_car(cycle);
+    }
+    collect();
+    assert_eq!(holder.car().and_then(|c| c.car()), Some(Value::Int(9)));
+    holder.set_car(Value::Nil);
+    assert_eq!(collect(), 1);
+}
+
+#[test]
+fn test_register_without_collect() {
+    let before = CELLS.with(|c| c.borrow().len());
+    let kept = Value::list((0..1000).map(Value::Int).collect());
+    for i in 0..100000 {
+        drop(Value::list(vec![Value::Int(i); 10]));
+    }
+    let len = CELLS.with(|c| c.borrow().len());
+    assert!(len < before + 1000 + 4 * MIN_THRESHOLD, "{}", len);
+    assert_eq!(kept.to_vec().map(|v| v.len()), Some(1000));
+}
@@ -0,0 +1,378 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use serde_json::{Map, Number, Value as Json};
+use crate::reader::{self, Token, LprpError};
+#[cfg(test)]
+use crate::reader::read;
+#[cfg(test)]
+use serde_json::json;
+
+
+// ***** Encoding *****
+// How tokens are written as JSON. Integers, floats and lists are JSON
+// numbers and arrays; `nil` is null and `()` is the empty array. The
+// other atoms depend on the encoding:
+//
+//   symbols   `Tagged`: {"$symbol": "foo"}, and strings are JSON strings.
+//             `Bare`: "foo", and strings are {"$string": "..."}.
+//             `Prefixed(c)`: "$foo" for c = '$'. A string starting with c
+//             is written with c doubled.
+//   keywords  `Symbols`: like any other symbol, e.g. {"$symbol": ":port"}.
+//             `Objects`: a plist `(:host "a" :port 80)` is the object
+//             {"host": "a", "port": 80}, unless a key starts with '$'.
+//   objects   An object whose keys all read as keywords reads as a plist.
+//             Any other, such as {"user_id": 1, "x.y": 2}, reads as
+//             `(object ("user_id" 1) ("x.y" 2))`, which `Objects` writes
+//             back as the object.
+//   quotes    `Tagged`: {"$quote": x}.
+//             `List`: `(quote x)`.
+//   t/nil     `Json`: true and null.
+//             `Symbols`: the symbols `t` and `nil`.
+//
+// With any encoding, JSON true, false and null read as t, nil and nil,
+// and an object without a "$" tag reads as a plist. The default encoding
+// is the first of each. Every encoding reads back what it writes, except
+// that with `QuoteStyle::List` the list `(quote x)` reads back as `'x`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolStyle {
+    Tagged,
+    Bare,
+    Prefixed(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeywordStyle {
+    Symbols,
+    Objects,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteStyle {
+    Tagged,
+    List,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BooleanStyle {
+    Json,
+    Symbols,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoding {
+    symbols: SymbolStyle,
+    keywords: KeywordStyle,
+    quotes: QuoteStyle,
+    booleans: BooleanStyle,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            symbols: SymbolStyle::Tagged,
+            keywords: KeywordStyle::Symbols,
+            quotes: QuoteStyle::Tagged,
+            booleans: BooleanStyle::Json,
+        }
+    }
+}
+
+pub fn to_json(tk: &Token) -> Result<Json, LprpError> {
+    Encoding::new().to_json(tk)
+}
+
+pub fn from_json(json: &Json) -> Result<Token, LprpError> {
+    Encoding::new().from_json(json)
+}
+
+fn json_error<T>(message: String) -> Result<T, LprpError> {
+    Err(LprpError::JsonError(message))
+}
+
+fn tagged(tag: &str, json: Json) -> Json {
+    let mut m = Map::new();
+    m.insert(tag.to_string(), json);
+    Json::Object(m)
+}
+
+// Whether `:key` reads back as that keyword.
+fn is_keyword(key: &str) -> bool {
+    let k = format!(":{}", key);
+    reader::read(&k).is_ok_and(|tk| tk.as_symbol() == Some(&k[..]))
+}
+
+// The entries of an `(object ("key" value) ...)` list, as `from_json`
+// makes for an object with a key that is not a keyword.
+fn object_entries(items: &[Token]) -> Option<Vec<(&str, &Token)>> {
+    let (tag, rest) = items.split_first()?;
+    if tag.as_symbol() != Some("object") {
+        return None;
+    }
+    let entries: Vec<(&str, &Token)> = rest.iter().map(|e| match e {
+        Token::List(kv) => match &kv[..] {
+            [Token::Str(k), v] => Some((&k[..], v)),
+            _ => None,
+        },
+        _ => None,
+    }).collect::<Option<_>>()?;
+    let mut keys: Vec<&str> = entries.iter().map(|(k, _)| *k).collect();
+    keys.sort_unstable();
+    keys.dedup();
+    // A lone "$" key would read back as a tag.
+    let tag = entries.len() == 1 && entries[0].0.starts_with('$');
+    match keys.len() == entries.len() && !tag && !keys.iter().all(|k| is_keyword(k)) {
+        true => Some(entries),
+        false => None,
+    }
+}
+
+// A plist that can be written as an object.
+fn object_keys(items: &[Token]) -> Option<Vec<&str>> {
+    if items.is_empty() || !items.len().is_multiple_of(2) {
+        return None;
+    }
+    let keys: Vec<&str> = items.iter().step_by(2)
+        .map(|k| k.as_symbol().filter(|_| k.is_keyword()).map(|s| &s[1..]))
+        .collect::<Option<_>>()?;
+    let mut sorted = keys.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    match sorted.len() == keys.len() && !keys.iter().any(|k| k.starts_with('$')) {
+        true => Some(keys),
+        false => None,
+    }
+}
+
+impl Encoding {
+    pub fn new() -> Encoding {
+        Encoding::default()
+    }
+
+    pub fn symbols(mut self, style: SymbolStyle) -> Encoding {
+        self.symbols = style;
+        self
+    }
+
+    pub fn keywords(mut self, style: KeywordStyle) -> Encoding {
+        self.keywords = style;
+        self
+    }
+
+    pub fn quotes(mut self, style: QuoteStyle) -> Encoding {
+        self.quotes = style;
+        self
+    }
+
+    pub fn booleans(mut self, style: BooleanStyle) -> Encoding {
+        self.booleans = style;
+        self
+    }
+
+    // ***** Token to JSON *****
+    pub fn to_json(&self, tk: &Token) -> Result<Json, LprpError> {
+        match tk {
+            Token::T => match self.booleans {
+                BooleanStyle::Json => Ok(Json::Bool(true)),
+                BooleanStyle::Symbols => Ok(self.symbol("t")),
+            },
+            Token::Nil => match self.booleans {
+                BooleanStyle::Json => Ok(Json::Null),
+                BooleanStyle::Symbols => Ok(self.symbol("nil")),
+            },
+            Token::Int(i) => Ok(Json::from(*i)),
+            Token::Float(f) => match Number::from_f64(*f) {
+                Some(n) => Ok(Json::Number(n)),
+                None => json_error(format!("cannot write float {}", f)),
+            },
+            Token::Symbol(s) => Ok(self.symbol(s)),
+            Token::Str(s) => Ok(self.string(s)),
+            Token::Quote(q) => match self.quotes {
+                QuoteStyle::Tagged => Ok(tagged("$quote", self.to_json(q)?)),
+                QuoteStyle::List => Ok(Json::Array(vec![self.symbol("quote"), self.to_json(q)?])),
+            },
+            Token::List(l) => {
+                if let (KeywordStyle::Objects, Some(keys)) = (self.keywords, object_keys(l)) {
+                    let mut m = Map::new();
+                    for (k, v) in keys.into_iter().zip(l.iter().skip(1).step_by(2)) {
+                        m.insert(k.to_string(), self.to_json(v)?);
+                    }
+                    return Ok(Json::Object(m));
+                }
+                if let (KeywordStyle::Objects, Some(entries)) = (self.keywords, object_entries(l)) {
+                    let mut m = Map::new();
+                    for (k, v) in entries {
+                        m.insert(k.to_string(), self.to_json(v)?);
+                    }
+                    return Ok(Json::Object(m));
+                }
+                Ok(Json::Array(l.iter().map(|tk| self.to_json(tk)).collect::<Result<_, _>>()?))
+            },
+        }
+    }
+
+    fn symbol(&self, s: &str) -> Json {
+        match self.symbols {
+            SymbolStyle::Tagged => tagged("$symbol", Json::from(s)),
+            SymbolStyle::Bare => Json::from(s),
+            SymbolStyle::Prefixed(c) => Json::String(format!("{}{}", c, s)),
+        }
+    }
+
+    fn string(&self, s: &str) -> Json {
+        match self.symbols {
+            SymbolStyle::Bare => tagged("$string", Json::from(s)),
+            SymbolStyle::Prefixed(c) if s.starts_with(c) => Json::String(format!("{}{}", c, s)),
+            _ => Json::from(s),
+        }
+    }
+
+    // ***** JSON to Token *****
+    pub fn from_json(&self, json: &Json) -> Result<Token, LprpError> {
+        match json {
+            Json::Null|Json::Bool(false) => Ok(Token::Nil),
+            Json::Bool(true) => Ok(Token::T),
+            Json::Number(n) => match (n.as_i64(), n.as_f64()) {
+                (Some(i), _) if !n.is_f64() => Ok(Token::Int(i)),
+                (_, Some(f)) if n.is_f64() => Ok(Token::Float(f)),
+                _ => json_error(format!("integer {} is out of range", n)),
+            },
+            Json::String(s) => match self.symbols {
+                SymbolStyle::Tagged => Ok(Token::Str(s.to_string())),
+                SymbolStyle::Bare => Ok(self.read_symbol(s)),
+                SymbolStyle::Prefixed(c) => {
+                    let mut chars = s.chars();
+                    match (chars.next(), chars.clone().next()) {
+                        (Some(a), Some(b)) if a == c && b == c => Ok(Token::Str(chars.as_str().to_string())),
+                        (Some(a), _) if a == c => Ok(self.read_symbol(chars.as_str())),
+                        _ => Ok(Token::Str(s.to_string())),
+                    }
+                },
+            },
+            Json::Array(a) => {
+                let items = a.iter().map(|j| self.from_json(j)).collect::<Result<Vec<_>, _>>()?;
+                match &items[..] {
+                    [Token::Symbol(q), _] if q == "quote" && self.quotes == QuoteStyle::List => {
+                        Ok(Token::Quote(Box::new(items.into_iter().nth(1).unwrap())))
+                    },
+                    _ => Ok(Token::List(items)),
+                }
+            },
+            Json::Object(m) => self.read_object(m),
+        }
+    }
+
+    fn read_symbol(&self, s: &str) -> Token {
+        match (self.booleans, s) {
+            (BooleanStyle::Symbols, "t") => Token::T,
+            (BooleanStyle::Symbols, "nil") => Token::Nil,
+            _ => Token::Symbol(s.to_string()),
+        }
+    }
+
+    fn read_object(&self, m: &Map<String, Json>) -> Result<Token, LprpError> {
+        let mut entries = m.iter();
+        if let (Some((tag, v)), None) = (entries.next(), entries.next()) {
+            match (&tag[..], v) {
+                ("$symbol", Json::String(s)) => return Ok(self.read_symbol(s)),
+                ("$string", Json::String(s)) => return Ok(Token::Str(s.to_string())),
+                ("$quote", v) => return Ok(Token::Quote(Box::new(self.from_json(v)?))),
+                (t, _) if t.starts_with('$') => return json_error(format!("bad {} object", t)),
+                _ => {},
+            }
+        }
+        if m.is_empty() {
+            return Ok(Token::Nil);
+        }
+        if !m.keys().all(|k| is_keyword(k)) {
+            let mut items = vec![Token::Symbol("object".to_string())];
+            for (k, v) in m {
+                items.push(Token::List(vec![Token::Str(k.to_string()), self.from_json(v)?]));
+            }
+            return Ok(Token::List(items));
+        }
+        let mut items = Vec::with_capacity(m.len() * 2);
+        for (k, v) in m {
+            items.push(Token::Symbol(format!(":{}", k)));
+            items.push(self.from_json(v)?);
+        }
+        Ok(Token::List(items))
+    }
+}
+
+#[test]
+fn test_json_default() {
+    let tk = read("(server \"web\" :port 80 :ratio 0.5 :tls t :opts nil '(a))").unwrap();
+    let json = to_json(&tk).unwrap();
+    assert_eq!(json, json!([
+        {"$symbol": "server"}, "web",
+        {"$symbol": ":port"}, 80,
+        {"$symbol": ":ratio"}, 0.5,
+        {"$symbol": ":tls"}, true,
+        {"$symbol": ":opts"}, null,
+        {"$quote": [{"$symbol": "a"}]},
+    ]));
+    assert_eq!(from_json(&json).unwrap(), tk);
+    assert_eq!(from_json(&json!([false, 1.0, {"a": 1}, {}])).unwrap(),
+               read("(nil 1.0 (:a 1) nil)").unwrap());
+    assert_eq!(to_json(&Token::Float(f64::NAN)),
+               Err(LprpError::JsonError("cannot write float NaN".to_string())));
+    assert!(from_json(&json!({"$symbol": 1})).is_err());
+    assert!(from_json(&json!(u64::MAX)).is_err());
+}
+
+#[test]
+fn test_json_encodings() {
+    let tk = read("(config (:name \"$5\" :hosts (\"a\" b) :tls t :q 'x))").unwrap();
+
+    let enc = Encoding::new().symbols(SymbolStyle::Prefixed('$')).keywords(KeywordStyle::Objects)
+        .quotes(QuoteStyle::List).booleans(BooleanStyle::Symbols);
+    let json = enc.to_json(&tk).unwrap();
+    assert_eq!(json, json!(["$config", {
+        "name": "$$5",
+        "hosts": ["a", "$b"],
+        "tls": "$t",
+        "q": ["$quote", "$x"],
+    }]));
+    assert_eq!(enc.from_json(&json).unwrap(), tk);
+
+    let enc = Encoding::new().symbols(SymbolStyle::Bare);
+    let json = enc.to_json(&tk).unwrap();
+    assert_eq!(json[0], json!("config"));
+    assert_eq!(json[1][1], json!({"$string": "$5"}));
+    assert_eq!(enc.from_json(&json).unwrap(), tk);
+
+    // Plists with repeated or "$" keys stay lists.
+    let enc = Encoding::new().symbols(SymbolStyle::Bare).keywords(KeywordStyle::Objects);
+    let mut tk = read("((:a 1 :a 2) (:a 1) (:a 1 :b))").unwrap();
+    if let Token::List(l) = &mut tk {
+        l[1] = Token::List(vec![Token::Symbol(":$a".to_string()), Token::Int(1)]);
+    }
+    assert_eq!(enc.to_json(&tk).unwrap(), json!([[":a", 1, ":a", 2], [":$a", 1], [":a", 1, ":b"]]));
+    assert_eq!(enc.from_json(&enc.to_json(&tk).unwrap()).unwrap(), tk);
+}
+
+#[test]
+fn test_json_object_keys() {
+    // Keys that do not read as keywords are kept as strings.
+    let json = json!({"user_id": 1, "x.y": {"a b": [2]}, "port": 80, "é": null});
+    let tk = from_json(&json).unwrap();
+    assert_eq!(tk, read("(object (\"user_id\" 1) (\"x.y\" (object (\"a b\" (2)))) (\"port\" 80) (\"é\" nil))").unwrap());
+    assert_eq!(read(&reader::write(&tk).unwrap()), Ok(tk.clone()));
+
+    let enc = Encoding::new().keywords(KeywordStyle::Objects);
+    assert_eq!(enc.from_json(&json).unwrap(), tk);
+    assert_eq!(enc.to_json(&tk).unwrap(), json);
+    assert_eq!(enc.to_json(&from_json(&json!({"$a": 1, "$b": 2})).unwrap()).unwrap(), json!({"$a": 1, "$b": 2}));
+
+    // Lists that only look like one stay lists.
+    for src in &["(object (\"a\" 1))", "(object (\"$a\" 1))", "(object (\"a b\" 1) (\"a b\" 2))", "(object (a 1))"] {
+        let tk = read(src).unwrap();
+        assert!(enc.to_json(&tk).unwrap().is_array(), "{}", src);
+        assert_eq!(enc.from_json(&enc.to_json(&tk).unwrap()).unwrap(), tk);
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use crate::reader::LprpError;
+
+
+// ***** Lexeme *****
+// The lexical tokens of lprp text, with the byte range each one covers.
+// Every byte of the input is in exactly one lexeme, whitespace and
+// comments included, so the text can be rebuilt from the lexemes, and a
+// malformed atom is an `Error` lexeme rather than the end of the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Atom {
+    Int,
+    Float,
+    // Including `nil`, `t` and `*`.
+    Symbol,
+    Keyword,
+    // `*name*`
+    Special,
+    Str,
+    // `_`
+    Wildcard,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    LParen,
+    RParen,
+    Quote,
+    // The `.` of a dotted list.
+    Dot,
+    Atom(Atom),
+    // From `;` to the end of the line.
+    Comment,
+    Whitespace,
+    Error(LprpError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub kind: Kind,
+    pub span: Span,
+}
+
+impl Lexeme {
+    pub fn text<'a>(&self, src: &'a str) -> &'a str {
+        &src[self.span.start..self.span.end]
+    }
+
+    // Whitespace and comments.
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, Kind::Whitespace|Kind::Comment)
+    }
+}
+
+// ***** Lexer *****
+pub struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+pub fn lex(src: &str) -> Vec<Lexeme> {
+    Lexer::new(src).collect()
+}
+
+pub(crate) fn is_space(b: u8) -> bool {
+    matches!(b, b' '|b'\n'|b'\t')
+}
+
+// Operator characters that may start a symbol, e.g. `+`, `<=` or `/`.
+fn is_operator(b: u8) -> bool {
+    b"+/<>=!?&%".contains(&b)
+}
+
+pub(crate) fn is_symbol(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'*' || is_operator(b)
+}
+
+fn is_num(b: u8) -> bool {
+    b.is_ascii_digit() || b == b'-' || b == b'.'
+}
+
+// `-?[0-9]+` is an integer and `-?[0-9]*.[0-9]*` a float.
+fn number(s: &str) -> Kind {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    let dots = digits.bytes().filter(|&b| b == b'.').count();
+    if digits.contains('-') {
+        return Kind::Error(LprpError::ReadNumError);
+    }
+    match dots {
+        0 if !digits.is_empty() => Kind::Atom(Atom::Int),
+        1 if digits.len() > 1 => Kind::Atom(Atom::Float),
+        _ => Kind::Error(LprpError::ReadNumError),
+    }
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a str) -> Lexer<'a> {
+        Lexer { src, pos: 0 }
+    }
+
+    // The lexemes from byte `pos`, which must start one.
+    pub(crate) fn at(src: &'a str, pos: usize) -> Lexer<'a> {
+        Lexer { src, pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_while(&mut self, pred: fn(u8) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(pred) {
+            self.pos += 1;
+        }
+        &self.src[start..self.pos]
+    }
+
+    fn kind(&mut self, b: u8) -> Kind {
+        let start = self.pos;
+        match b {
+            b' '|b'\n'|b'\t' => {
+                self.skip_while(is_space);
+                Kind::Whitespace
+            },
+            b';' => {
+                self.skip_while(|b| b != b'\n');
+                Kind::Comment
+            },
+            b'(' => {
+                self.pos += 1;
+                Kind::LParen
+            },
+            b')' => {
+                self.pos += 1;
+                Kind::RParen
+            },
+            b'\'' => {
+                self.pos += 1;
+                Kind::Quote
+            },
+            b'.' => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(b) if is_space(b) => Kind::Dot,
+                    _ => Kind::Error(LprpError::SyntaxError),
+                }
+            },
+            b'0'..=b'9'|b'-' => {
+                match self.skip_while(is_num) {
+                    // `-` alone or a symbol such as `->`.
+                    "-" => {
+                        self.skip_while(is_symbol);
+                        Kind::Atom(Atom::Symbol)
+                    },
+                    s => number(s),
+                }
+            },
+            b if b.is_ascii_alphabetic() || is_operator(b) => {
+                self.skip_while(is_symbol);
+                Kind::Atom(Atom::Symbol)
+            },
+            b'_' => {
+                self.pos += 1;
+                match self.peek() {
+                    None|Some(b' '|b'\n'|b'\t'|b')'|b';') => Kind::Atom(Atom::Wildcard),
+                    _ => {
+                        self.skip_while(|b| is_symbol(b) || b == b'_');
+                        Kind::Error(LprpError::SyntaxError)
+                    },
+                }
+            },
+            b'*' => {
+                let s = self.skip_while(is_symbol);
+                match (s, s.bytes().filter(|&b| b == b'*').count()) {
+                    ("*", _) => Kind::Atom(Atom::Symbol),
+                    (s, 2) if s.ends_with('*') => Kind::Atom(Atom::Special),
+                    _ => Kind::Error(LprpError::SyntaxError),
+                }
+            },
+            b':' => {
+                let s = self.skip_while(|b| is_symbol(b) || b == b':');
+                match s.bytes().filter(|&b| b == b':').count() {
+                    1 => Kind::Atom(Atom::Keyword),
+                    _ => Kind::Error(LprpError::SyntaxError),
+                }
+            },
+            b'"' => {
+                self.pos += 1;
+                self.skip_while(|b| b != b'"');
+                match self.peek() {
+                    Some(_) => {
+                        self.pos += 1;
+                        Kind::Atom(Atom::Str)
+                    },
+                    None => Kind::Error(LprpError::DelimiterError("unterminated string".to_string())),
+                }
+            },
+            _ => {
+                let len = self.src[start..].chars().next().map_or(1, char::len_utf8);
+                self.pos += len;
+                Kind::Error(LprpError::SyntaxError)
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Lexeme;
+
+    fn next(&mut self) -> Option<Lexeme> {
+        let start = self.pos;
+        let b = self.peek()?;
+        let kind = self.kind(b);
+        Some(Lexeme { kind, span: Span { start, end: self.pos } })
+    }
+}
+
+#[test]
+fn test_lexer() {
+    let src = "(defun f (x) ; doc\n  '(x . -1.5) \"s\" :k *v* _)";
+    let lexemes = lex(src);
+    let kinds: Vec<(Kind, &str)> = lexemes.iter()
+        .filter(|l| l.kind != Kind::Whitespace)
+        .map(|l| (l.kind.clone(), l.text(src)))
+        .collect();
+    assert_eq!(kinds, vec![
+        (Kind::LParen, "("),
+        (Kind::Atom(Atom::Symbol), "defun"),
+        (Kind::Atom(Atom::Symbol), "f"),
+        (Kind::LParen, "("),
+        (Kind::Atom(Atom::Symbol), "x"),
+        (Kind::RParen, ")"),
+        (Kind::Comment, "; doc"),
+        (Kind::Quote, "'"),
+        (Kind::LParen, "("),
+        (Kind::Atom(Atom::Symbol), "x"),
+        (Kind::Dot, "."),
+        (Kind::Atom(Atom::Float), "-1.5"),
+        (Kind::RParen, ")"),
+        (Kind::Atom(Atom::Str), "\"s\""),
+        (Kind::Atom(Atom::Keyword), ":k"),
+        (Kind::Atom(Atom::Special), "*v*"),
+        (Kind::Atom(Atom::Wildcard), "_"),
+        (Kind::RParen, ")"),
+    ]);
+    let text: String = lexemes.iter().map(|l| l.text(src)).collect();
+    assert_eq!(text, src);
+    assert_eq!(lexemes[1].span, Span { start: 1, end: 6 });
+}
+
+#[test]
+fn test_lexer_atoms() {
+    let kinds = |src: &str| -> Vec<Kind> { lex(src).into_iter().map(|l| l.kind).collect() };
+    assert_eq!(kinds("12abc"), vec![Kind::Atom(Atom::Int), Kind::Atom(Atom::Symbol)]);
+    assert_eq!(kinds("->list"), vec![Kind::Atom(Atom::Symbol)]);
+    assert_eq!(kinds("-"), vec![Kind::Atom(Atom::Symbol)]);
+    assert_eq!(kinds("1-2"), vec![Kind::Error(LprpError::ReadNumError)]);
+    assert_eq!(kinds("*"), vec![Kind::Atom(Atom::Symbol)]);
+    assert_eq!(kinds("*a"), vec![Kind::Error(LprpError::SyntaxError)]);
+    assert_eq!(kinds(":a:"), vec![Kind::Error(LprpError::SyntaxError)]);
+    assert_eq!(kinds("_x y"), vec![Kind::Error(LprpError::SyntaxError), Kind::Whitespace, Kind::Atom(Atom::Symbol)]);
+    assert_eq!(kinds(".x"), vec![Kind::Error(LprpError::SyntaxError), Kind::Atom(Atom::Symbol)]);
+    assert_eq!(kinds("\"open"), vec![Kind::Error(LprpError::DelimiterError("unterminated string".to_string()))]);
+    let bad = lex("é(");
+    assert_eq!(bad[0], Lexeme { kind: Kind::Error(LprpError::SyntaxError), span: Span { start: 0, end: 2 } });
+    assert_eq!(bad[1].kind, Kind::LParen);
+}
@@ -7,3 +7,26 @@
 
 pub mod core;
 pub mod reader;
+pub mod value;
+pub mod heap;
+pub mod eval;
+pub mod condition;
+pub mod compile;
+pub mod vm;
+pub mod query;
+pub mod pattern;
+pub mod schema;
+pub mod zipper;
+pub mod diff;
+pub mod csexp;
+pub mod binary;
+pub mod borrowed;
+pub mod lexer;
+pub mod syntax;
+#[cfg(feature = "serde")]
+pub mod ser;
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "json")]
+pub mod json;
+mod builtins;
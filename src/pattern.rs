@@ -0,0 +1,379 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use crate::core::equal;
+use crate::reader::{read, Token, LprpError};
+use crate::value::Value;
+
+
+// ***** Pattern *****
+// A pattern destructures a tree and binds its parts to names:
+//
+//   _                anything, without binding it
+//   x                anything, bound to `x`; a name used twice must
+//                    match equal parts
+//   1 "s" t nil :k   the literal itself
+//   'x               the literal `x`, which may also be a list
+//   (p q)            a list of exactly two elements
+//   (p . rest)       a list of at least one element; `rest` matches the
+//   (p &rest rest)   list of the remaining elements
+//   (p &key :k q)    one element followed by a keyword plist; `q`
+//                    matches the value of `:k`, or nil if it is absent
+//
+// `&rest` and `&key` may be combined, in that order, in which case both
+// see the remaining elements. Guards are checked by `matches_if`, and by
+// `:when` in the evaluator's `match` form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pat: Pat,
+    vars: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Pat {
+    Any,
+    Var(String),
+    Literal(Token),
+    List {
+        items: Vec<Pat>,
+        rest: Option<Box<Pat>>,
+        keys: Option<Vec<(String, Pat)>>,
+    },
+}
+
+fn pattern_error<T>(message: &str, tk: &Token) -> Result<T, LprpError> {
+    Err(LprpError::PatternError(format!("{}: {}", message, tk)))
+}
+
+impl Pattern {
+    pub fn parse(src: &str) -> Result<Pattern, LprpError> {
+        Pattern::from_token(&read(src)?)
+    }
+
+    pub fn from_token(tk: &Token) -> Result<Pattern, LprpError> {
+        let mut vars = vec![];
+        let pat = parse(tk, &mut vars)?;
+        Ok(Pattern { pat, vars })
+    }
+
+    // The names the pattern binds, in the order of their first use.
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    pub fn matches<T: Tree>(&self, tree: &T) -> Option<Bindings<T>> {
+        let mut bindings = Bindings { vars: vec![] };
+        if self.pat.matches(tree, &mut bindings) {
+            Some(bindings)
+        } else {
+            None
+        }
+    }
+
+    // `matches`, but only if `guard` accepts the bindings.
+    pub fn matches_if<T, F>(&self, tree: &T, guard: F) -> Option<Bindings<T>>
+        where T: Tree, F: FnOnce(&Bindings<T>) -> bool
+    {
+        self.matches(tree).filter(guard)
+    }
+}
+
+fn parse(tk: &Token, vars: &mut Vec<String>) -> Result<Pat, LprpError> {
+    match tk {
+        Token::Symbol(s) if s == "_" => Ok(Pat::Any),
+        Token::Symbol(s) if s == "." || s.starts_with('&') => {
+            pattern_error("misplaced", tk)
+        },
+        Token::Symbol(s) if !tk.is_keyword() => {
+            if !vars.contains(s) {
+                vars.push(s.to_string());
+            }
+            Ok(Pat::Var(s.to_string()))
+        },
+        Token::Quote(q) => Ok(Pat::Literal((**q).clone())),
+        Token::List(l) => parse_list(tk, l, vars),
+        _ => Ok(Pat::Literal(tk.clone())),
+    }
+}
+
+fn parse_list(tk: &Token, l: &[Token], vars: &mut Vec<String>) -> Result<Pat, LprpError> {
+    let mut items = vec![];
+    let mut rest = None;
+    let mut keys = None;
+    let mut it = l.iter();
+    while let Some(x) = it.next() {
+        match x.as_symbol() {
+            Some("."|"&rest") if rest.is_none() && keys.is_none() => {
+                match it.next() {
+                    Some(r) => rest = Some(Box::new(parse(r, vars)?)),
+                    None => return pattern_error("missing rest pattern", tk),
+                }
+                if x.as_symbol() == Some(".") && it.len() > 0 {
+                    return pattern_error("misplaced", tk);
+                }
+            },
+            Some("&key") if keys.is_none() => {
+                let mut pairs = vec![];
+                while let Some(k) = it.next() {
+                    match (k.is_keyword(), it.next()) {
+                        (true, Some(p)) => {
+                            pairs.push((k.as_symbol().unwrap().to_string(), parse(p, vars)?));
+                        },
+                        _ => return pattern_error("malformed &key", tk),
+                    }
+                }
+                keys = Some(pairs);
+            },
+            _ if rest.is_some() || keys.is_some() => return pattern_error("misplaced", tk),
+            _ => items.push(parse(x, vars)?),
+        }
+    }
+    Ok(Pat::List { items, rest, keys })
+}
+
+impl Pat {
+    fn matches<T: Tree>(&self, tree: &T, bindings: &mut Bindings<T>) -> bool {
+        match self {
+            Pat::Any => true,
+            Pat::Var(name) => {
+                match bindings.get(name) {
+                    Some(bound) => bound.same(tree),
+                    None => {
+                        bindings.vars.push((name.to_string(), tree.clone()));
+                        true
+                    },
+                }
+            },
+            Pat::Literal(lit) => tree.is(lit),
+            Pat::List { items, rest, keys } => {
+                let elements = match tree.elements() {
+                    Some(e) => e,
+                    None => return false,
+                };
+                let open = rest.is_some() || keys.is_some();
+                if elements.len() < items.len() || (!open && elements.len() > items.len()) {
+                    return false;
+                }
+                if !items.iter().zip(&elements).all(|(p, x)| p.matches(x, bindings)) {
+                    return false;
+                }
+                let remaining = &elements[items.len()..];
+                if let Some(r) = rest {
+                    if !r.matches(&T::from_elements(remaining.to_vec()), bindings) {
+                        return false;
+                    }
+                }
+                match keys {
+                    Some(keys) => match_keys(keys, remaining, bindings),
+                    None => true,
+                }
+            },
+        }
+    }
+}
+
+// The remaining elements must form a plist of keywords.
+fn match_keys<T: Tree>(keys: &[(String, Pat)], plist: &[T], bindings: &mut Bindings<T>) -> bool {
+    if !plist.len().is_multiple_of(2) || !plist.iter().step_by(2).all(|k| k.keyword().is_some()) {
+        return false;
+    }
+    keys.iter().all(|(key, p)| {
+        let value = plist.chunks(2)
+            .find(|kv| kv[0].keyword() == Some(key))
+            .map(|kv| kv[1].clone())
+            .unwrap_or_else(|| T::from_elements(vec![]));
+        p.matches(&value, bindings)
+    })
+}
+
+// ***** Bindings *****
+// Names bound by a successful match, in the order of `Pattern::vars`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bindings<T> {
+    vars: Vec<(String, T)>,
+}
+
+impl<T> Bindings<T> {
+    pub fn get(&self, name: &str) -> Option<&T> {
+        self.vars.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=(&str, &T)> {
+        self.vars.iter().map(|(n, v)| (n.as_str(), v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    pub fn into_values(self) -> Vec<T> {
+        self.vars.into_iter().map(|(_, v)| v).collect()
+    }
+}
+
+// ***** Tree *****
+// What patterns can match: `Token` for data read from text and `Value`
+// for the evaluator.
+pub trait Tree: Clone {
+    // The elements of a proper list, nil included, or `None`.
+    fn elements(&self) -> Option<Vec<Self>>;
+    fn from_elements(items: Vec<Self>) -> Self;
+    fn keyword(&self) -> Option<&str>;
+    fn is(&self, literal: &Token) -> bool;
+    fn same(&self, other: &Self) -> bool;
+}
+
+impl Tree for Token {
+    fn elements(&self) -> Option<Vec<Self>> {
+        match self {
+            Token::Nil => Some(vec![]),
+            Token::Quote(q) => Some(vec![Token::Symbol("quote".to_string()), (**q).clone()]),
+            Token::List(l) if l.len() < 2 || l[l.len() - 2].as_symbol() != Some(".") => {
+                Some(l.clone())
+            },
+            _ => None,
+        }
+    }
+
+    fn from_elements(items: Vec<Self>) -> Self {
+        Token::from(items)
+    }
+
+    fn keyword(&self) -> Option<&str> {
+        self.as_symbol().filter(|_| self.is_keyword())
+    }
+
+    fn is(&self, literal: &Token) -> bool {
+        equal(self, literal)
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        equal(self, other)
+    }
+}
+
+impl Tree for Value {
+    fn elements(&self) -> Option<Vec<Self>> {
+        self.to_vec()
+    }
+
+    fn from_elements(items: Vec<Self>) -> Self {
+        Value::list(items)
+    }
+
+    fn keyword(&self) -> Option<&str> {
+        self.as_symbol().filter(|s| s.len() > 1 && s.starts_with(':'))
+    }
+
+    fn is(&self, literal: &Token) -> bool {
+        *self == Value::from(literal)
+    }
+
+    fn same(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+#[cfg(test)]
+fn bound(p: &str, src: &str) -> Option<Vec<(String, String)>> {
+    let tk = read(src).unwrap();
+    Pattern::parse(p).unwrap().matches(&tk).map(|b| {
+        b.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    })
+}
+
+#[cfg(test)]
+fn pairs(v: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+    Some(v.iter().map(|(n, x)| (n.to_string(), x.to_string())).collect())
+}
+
+#[test]
+fn test_pattern_parse() {
+    let p = Pattern::parse("(defun name (x &rest args) &key :doc d . body)");
+    assert!(p.is_err());
+    let p = Pattern::parse("('defun name (x &rest args) . body)").unwrap();
+    assert_eq!(p.vars(), &["name", "x", "args", "body"]);
+    assert_eq!(Pattern::parse("(a x _ x)").map(|p| p.vars().len()), Ok(2));
+
+    assert!(Pattern::parse("(a &rest)").is_err());
+    assert!(Pattern::parse("(a &key :k)").is_err());
+    assert!(Pattern::parse("(a &key k v)").is_err());
+    assert!(Pattern::parse("(&key :k v x)").is_err());
+    assert!(Pattern::parse("(&rest r x)").is_err());
+    assert!(Pattern::parse("&rest").is_err());
+    assert_eq!(
+        Pattern::parse("(a &optional b)"),
+        Err(LprpError::PatternError("misplaced: &optional".to_string()))
+        );
+}
+
+#[test]
+fn test_pattern_match() {
+    assert_eq!(bound("_", "(1 2)"), pairs(&[]));
+    assert_eq!(bound("x", "(1 2)"), pairs(&[("x", "(1 2)")]));
+    assert_eq!(bound("(a (b c))", "(1 (2 \"s\"))"), pairs(&[("a", "1"), ("b", "2"), ("c", "\"s\"")]));
+    assert_eq!(bound("(a b)", "(1 2 3)"), None);
+    assert_eq!(bound("(a b c)", "(1 2)"), None);
+    assert_eq!(bound("(a b)", "1"), None);
+
+    // Literals
+    assert_eq!(bound("(op 1 x)", "(add 1 2)"), pairs(&[("op", "add"), ("x", "2")]));
+    assert_eq!(bound("('add 1 x)", "(add 1 2)"), pairs(&[("x", "2")]));
+    assert_eq!(bound("('add 1 x)", "(add 1.5 2)"), None);
+    assert_eq!(bound("(:k \"s\" t nil)", "(:k \"s\" t nil)"), pairs(&[]));
+    assert_eq!(bound("(:k \"s\" t nil)", "(:k \"s\" t ())"), pairs(&[]));
+    assert_eq!(bound("'(a b)", "(a b)"), pairs(&[]));
+    assert_eq!(bound("('quote x)", "'y"), pairs(&[("x", "y")]));
+
+    // Repeated names
+    assert_eq!(bound("(x x)", "((1 2) (1 2))"), pairs(&[("x", "(1 2)")]));
+    assert_eq!(bound("(x x)", "(1 2)"), None);
+
+    // Rest
+    assert_eq!(bound("(a . rest)", "(1 2 3)"), pairs(&[("a", "1"), ("rest", "(2 3)")]));
+    assert_eq!(bound("(a &rest rest)", "(1)"), pairs(&[("a", "1"), ("rest", "nil")]));
+    assert_eq!(bound("(a b . _)", "(1)"), None);
+    assert_eq!(bound("(a . (b c))", "(1 2 3)"), pairs(&[("a", "1"), ("b", "2"), ("c", "3")]));
+
+    // Keyword plists
+    let p = "('server name &key :port port :host _)";
+    assert_eq!(
+        bound(p, "(server web :host \"h\" :port 80)"),
+        pairs(&[("name", "web"), ("port", "80")])
+        );
+    assert_eq!(bound(p, "(server web)"), pairs(&[("name", "web"), ("port", "nil")]));
+    assert_eq!(bound(p, "(server web :port)"), None);
+    assert_eq!(bound(p, "(server web port 80)"), None);
+    assert_eq!(bound("(a &key :k 1)", "(x :k 2)"), None);
+    assert_eq!(
+        bound("(a &rest r &key :k k)", "(x :k 2)"),
+        pairs(&[("a", "x"), ("r", "(:k 2)"), ("k", "2")])
+        );
+}
+
+#[test]
+fn test_pattern_guard_and_values() {
+    let p = Pattern::parse("('range lo hi)").unwrap();
+    let ordered = |b: &Bindings<Token>| b.get("lo") < b.get("hi");
+    assert!(p.matches_if(&read("(range 1 5)").unwrap(), ordered).is_some());
+    assert!(p.matches_if(&read("(range 5 1)").unwrap(), ordered).is_none());
+
+    let v = Value::from(read("(range 1 (2 3))").unwrap());
+    let b = p.matches(&v).unwrap();
+    assert_eq!(b.len(), 2);
+    assert_eq!(b.get("hi").map(|v| v.to_string()), Some("(2 3)".to_string()));
+    assert_eq!(b.into_values(), vec![Value::Int(1), Value::from(read("(2 3)").unwrap())]);
+
+    // Improper lists are not lists to a pattern.
+    let dotted = Value::cons(Value::sym("range"), Value::Int(1));
+    assert_eq!(Pattern::parse("('range . r)").unwrap().matches(&dotted), None);
+    assert_eq!(Pattern::parse("(a . r)").unwrap().matches(&read("(1 . 2)").unwrap()), None);
+}
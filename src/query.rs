@@ -0,0 +1,392 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::collections::HashSet;
+use crate::core::equal;
+use crate::reader::{read, read_all, Token, LprpError};
+
+
+// ***** Query *****
+// A query is a path of steps, each applied to every node selected by
+// the previous one, starting from the root:
+//
+//   name          child lists whose head is the symbol `name`
+//   *             every element of a list
+//   **            the node itself and all of its descendants
+//   :key          the value after `:key` in a keyword plist
+//   [n]           the n-th element, counting from the end if negative
+//   [:key]        keep nodes whose `:key` value is present and not nil
+//   [:key op x]   keep nodes whose `:key` value compares to `x`
+//   [op x]        keep nodes that compare to `x`
+//
+// `op` is one of `=`, `!=`, `<`, `>`, `<=`, `>=`. `=` and `!=` use
+// `core::equal`; the others compare numbers or strings. The steps may
+// be wrapped in parentheses:
+//
+//   (server :port)                the ports of every `server` form
+//   (* :name)                     every `:name` value one level down
+//   (** service [:port > 8000])   services anywhere with a high port
+//   (server [-1])                 the last element of each server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Head(String),
+    Any,
+    Descend,
+    Key(String),
+    Index(i64),
+    Filter(Option<String>, Option<(Op, Token)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+fn query_error<T>(message: &str) -> Result<T, LprpError> {
+    Err(LprpError::QueryError(message.to_string()))
+}
+
+// The index of the `]` that closes the filter opened at `start`. A
+// string in the filter may hold a `]`; strings have no escapes, so each
+// `"` opens or closes one.
+fn closing(src: &str, start: usize) -> Option<usize> {
+    let mut quoted = false;
+    src[start..].char_indices()
+        .find(|&(_, c)| {
+            if c == '"' {
+                quoted = !quoted;
+            }
+            c == ']' && !quoted
+        })
+        .map(|(i, _)| start + i)
+}
+
+impl Query {
+    pub fn parse(src: &str) -> Result<Query, LprpError> {
+        let mut src = src.trim();
+        if src.starts_with('(') && src.ends_with(')') {
+            src = &src[1..src.len() - 1];
+        }
+
+        let mut steps = vec![];
+        let mut chars = src.char_indices().peekable();
+        while let Some(&(start, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+                continue;
+            }
+            if c == '[' {
+                let end = closing(src, start)
+                    .map_or_else(|| query_error("unclosed `[`"), Ok)?;
+                steps.push(parse_bracket(&src[start + 1..end])?);
+                while chars.peek().is_some_and(|&(i, _)| i <= end) {
+                    chars.next();
+                }
+                continue;
+            }
+            let mut end = src.len();
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_whitespace() || c == '[' {
+                    end = i;
+                    break;
+                }
+                chars.next();
+            }
+            steps.push(parse_step(&src[start..end])?);
+        }
+        Ok(Query { steps })
+    }
+
+    // The matching nodes, in document order.
+    pub fn select<'a>(&self, root: &'a Token) -> Vec<&'a Token> {
+        self.run(root).into_iter().map(|(_, tk)| tk).collect()
+    }
+
+    // Paths to the matching nodes. A path lists the element index to
+    // take at each level, starting from the root.
+    pub fn paths(&self, root: &Token) -> Vec<Vec<usize>> {
+        self.run(root).into_iter().map(|(path, _)| path).collect()
+    }
+
+    // Call `f` on every matching node and return how many there were.
+    // Nested matches are visited innermost first, so `f` may replace a
+    // node without disturbing the matches still to come.
+    pub fn update<F: FnMut(&mut Token)>(&self, root: &mut Token, mut f: F) -> usize {
+        let mut paths = self.paths(root);
+        paths.sort();
+        for path in paths.iter().rev() {
+            if let Some(tk) = get_path_mut(root, path) {
+                f(tk);
+            }
+        }
+        paths.len()
+    }
+
+    // Replace every matching node with `value`.
+    pub fn set(&self, root: &mut Token, value: &Token) -> usize {
+        self.update(root, |tk| *tk = value.clone())
+    }
+
+    fn run<'a>(&self, root: &'a Token) -> Vec<(Vec<usize>, &'a Token)> {
+        let mut nodes = vec![(vec![], root)];
+        for step in &self.steps {
+            let mut next = vec![];
+            let mut seen = HashSet::new();
+            for (path, tk) in nodes {
+                step.apply(path, tk, &mut |path, tk| {
+                    if seen.insert(path.clone()) {
+                        next.push((path, tk));
+                    }
+                });
+            }
+            nodes = next;
+        }
+        nodes
+    }
+}
+
+fn parse_step(s: &str) -> Result<Step, LprpError> {
+    match read(s) {
+        Ok(Token::Symbol(ref sym)) if sym == "*" => Ok(Step::Any),
+        Ok(Token::Symbol(ref sym)) if sym == "**" => Ok(Step::Descend),
+        Ok(Token::Symbol(sym)) if sym.starts_with(':') => Ok(Step::Key(sym)),
+        Ok(Token::Symbol(sym)) => Ok(Step::Head(sym)),
+        _ => query_error(&format!("invalid step `{}`", s)),
+    }
+}
+
+fn parse_bracket(s: &str) -> Result<Step, LprpError> {
+    let items = read_all(s).or_else(|_| query_error(&format!("invalid predicate `[{}]`", s)))?;
+    let op = |tk: &Token| {
+        match tk.as_symbol() {
+            Some("=") => Some(Op::Eq),
+            Some("!=") => Some(Op::Ne),
+            Some("<") => Some(Op::Lt),
+            Some(">") => Some(Op::Gt),
+            Some("<=") => Some(Op::Le),
+            Some(">=") => Some(Op::Ge),
+            _ => None,
+        }
+    };
+    match &items[..] {
+        [Token::Int(n)] => Ok(Step::Index(*n)),
+        [k] if k.is_keyword() => Ok(Step::Filter(k.as_symbol().map(String::from), None)),
+        [k, o, x] if k.is_keyword() && op(o).is_some() => {
+            Ok(Step::Filter(k.as_symbol().map(String::from), Some((op(o).unwrap(), x.clone()))))
+        },
+        [o, x] if op(o).is_some() => Ok(Step::Filter(None, Some((op(o).unwrap(), x.clone())))),
+        _ => query_error(&format!("invalid predicate `[{}]`", s)),
+    }
+}
+
+fn children(tk: &Token) -> &[Token] {
+    match tk {
+        Token::List(l) => l,
+        _ => &[],
+    }
+}
+
+// The value after `key` in a plist.
+fn plist_get<'a>(tk: &'a Token, key: &str) -> Option<(usize, &'a Token)> {
+    let l = children(tk);
+    l.iter()
+        .position(|x| x.as_symbol() == Some(key))
+        .and_then(|i| l.get(i + 1).map(|v| (i + 1, v)))
+}
+
+fn compare(op: Op, lhs: &Token, rhs: &Token) -> bool {
+    let ord = match (lhs.as_float(), rhs.as_float(), lhs.as_str(), rhs.as_str()) {
+        (Some(a), Some(b), _, _) => a.partial_cmp(&b),
+        (_, _, Some(a), Some(b)) => Some(a.cmp(b)),
+        _ => None,
+    };
+    match op {
+        Op::Eq => equal(lhs, rhs),
+        Op::Ne => !equal(lhs, rhs),
+        Op::Lt => ord.is_some_and(|o| o.is_lt()),
+        Op::Gt => ord.is_some_and(|o| o.is_gt()),
+        Op::Le => ord.is_some_and(|o| o.is_le()),
+        Op::Ge => ord.is_some_and(|o| o.is_ge()),
+    }
+}
+
+impl Step {
+    fn apply<'a, F>(&self, path: Vec<usize>, tk: &'a Token, out: &mut F)
+        where F: FnMut(Vec<usize>, &'a Token)
+    {
+        let child = |i: usize| {
+            let mut p = path.clone();
+            p.push(i);
+            p
+        };
+        match self {
+            Step::Head(name) => {
+                for (i, c) in children(tk).iter().enumerate() {
+                    if children(c).first().and_then(Token::as_symbol) == Some(name) {
+                        out(child(i), c);
+                    }
+                }
+            },
+            Step::Any => {
+                for (i, c) in children(tk).iter().enumerate() {
+                    out(child(i), c);
+                }
+            },
+            Step::Descend => {
+                let mut pending = vec![(path.clone(), tk)];
+                while let Some((p, t)) = pending.pop() {
+                    for (i, c) in children(t).iter().enumerate().rev() {
+                        let mut cp = p.clone();
+                        cp.push(i);
+                        pending.push((cp, c));
+                    }
+                    out(p, t);
+                }
+            },
+            Step::Key(key) => {
+                if let Some((i, v)) = plist_get(tk, key) {
+                    out(child(i), v);
+                }
+            },
+            Step::Index(n) => {
+                let l = children(tk);
+                let i = if *n < 0 { l.len() as i64 + n } else { *n };
+                if i >= 0 && (i as usize) < l.len() {
+                    out(child(i as usize), &l[i as usize]);
+                }
+            },
+            Step::Filter(key, test) => {
+                let subject = match key {
+                    Some(k) => plist_get(tk, k).map(|(_, v)| v),
+                    None => Some(tk),
+                };
+                let keep = match (subject, test) {
+                    (Some(v), Some((op, x))) => compare(*op, v, x),
+                    (Some(v), None) => !matches!(v, Token::Nil),
+                    (None, _) => false,
+                };
+                if keep {
+                    out(path, tk);
+                }
+            },
+        }
+    }
+}
+
+// The node at `path`, for editing in place.
+pub fn get_path_mut<'a>(root: &'a mut Token, path: &[usize]) -> Option<&'a mut Token> {
+    let mut tk = root;
+    for &i in path {
+        tk = match tk {
+            Token::List(l) => l.get_mut(i)?,
+            _ => return None,
+        };
+    }
+    Some(tk)
+}
+
+// Parse `query` and select from `root`.
+pub fn select<'a>(query: &str, root: &'a Token) -> Result<Vec<&'a Token>, LprpError> {
+    Ok(Query::parse(query)?.select(root))
+}
+
+#[cfg(test)]
+fn config() -> Token {
+    read("(config
+            (server :host \"a\" :port 80 :tags (web))
+            (server :host \"b\" :port 8080 :enabled nil)
+            (db :name \"main\" (replica :name \"r1\" :port 9000)))").unwrap()
+}
+
+#[test]
+fn test_query_parse() {
+    assert_eq!(
+        Query::parse("(server [:port >= 80] :host [0] [-1] * **)").map(|q| q.steps.len()),
+        Ok(7)
+        );
+    assert_eq!(Query::parse("server :port"), Query::parse("(server :port)"));
+    assert!(Query::parse("(server [:port ~ 80])").is_err());
+    assert!(Query::parse("(server [1").is_err());
+    assert!(Query::parse("(server [:host = \"]\"").is_err());
+    assert_eq!(
+        Query::parse("(server [:host = \"a]b\"] :port)").map(|q| q.steps[1].clone()),
+        Ok(Step::Filter(Some(":host".to_string()), Some((Op::Eq, Token::Str("a]b".to_string())))))
+        );
+    assert!(Query::parse("(1)").is_err());
+}
+
+#[test]
+fn test_query_select() {
+    let root = config();
+    assert_eq!(
+        select("(server :port)", &root),
+        Ok(vec![&Token::Int(80), &Token::Int(8080)])
+        );
+    assert_eq!(
+        select("(* :name)", &root),
+        Ok(vec![&Token::Str("main".to_string())])
+        );
+    assert_eq!(
+        select("(** :name)", &root),
+        Ok(vec![&Token::Str("main".to_string()), &Token::Str("r1".to_string())])
+        );
+    assert_eq!(
+        select("(server [:port > 100] :host)", &root),
+        Ok(vec![&Token::Str("b".to_string())])
+        );
+    assert_eq!(
+        select("(server [:host = \"a\"] :tags [0])", &root),
+        Ok(vec![&Token::Symbol("web".to_string())])
+        );
+    assert_eq!(select("(server [:tags])", &root).map(|v| v.len()), Ok(1));
+    assert_eq!(
+        select("(** :port [>= 8080])", &root),
+        Ok(vec![&Token::Int(8080), &Token::Int(9000)])
+        );
+    assert_eq!(select("(server [-1])", &root).map(|v| v.len()), Ok(2));
+    assert_eq!(
+        select("([3] [-1] :port)", &root),
+        Ok(vec![&Token::Int(9000)])
+        );
+    assert_eq!(select("(nothing :here)", &root), Ok(vec![]));
+    assert_eq!(
+        select("(db [:name != \"[r1]\"] :name)", &root),
+        Ok(vec![&Token::Str("main".to_string())])
+        );
+}
+
+#[test]
+fn test_query_update() {
+    let mut root = config();
+    let q = Query::parse("(server :port)").unwrap();
+    assert_eq!(q.paths(&root), vec![vec![1, 4], vec![2, 4]]);
+    assert_eq!(
+        q.update(&mut root, |tk| {
+            if let Token::Int(i) = tk {
+                *i += 1;
+            }
+        }),
+        2
+        );
+    assert_eq!(select("(server :port)", &root), Ok(vec![&Token::Int(81), &Token::Int(8081)]));
+
+    // Nested matches are replaced innermost first.
+    let all = Query::parse("(** [:name])").unwrap();
+    assert_eq!(all.set(&mut root, &Token::Nil), 2);
+    assert_eq!(select("(db)", &root), Ok(vec![]));
+    assert_eq!(root.as_list().map(|l| l[3].clone()), Some(Token::Nil));
+}
@@ -5,19 +5,36 @@
 // This file may not be copied, modified, on distributed except
 //  according to those terms.
 
-use std::fmt;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::iter::Peekable;
-use onigiri::tools as tls;
-use onigiri::validator as vld;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use crate::borrowed;
+use crate::condition::display_error;
 
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum LprpError {
     SyntaxError,
     ReadError,
     ReadNumError,
     ConversionError,
+    TypeError { expected: String, found: String },
+    QueryError(String),
+    PatternError(String),
+    SchemaError(String),
+    PatchError(String),
+    SerdeError(String),
+    JsonError(String),
+    CsexpError(String),
+    BinaryError(String),
+    // A token with no text that reads back as it.
+    WriteError(String),
+    // An unbalanced paren or string quote.
+    DelimiterError(String),
+    // A condition signalled by evaluated code that was not handled.
+    Condition { kind: String, message: String, irritants: Vec<Token> },
 }
 
 impl fmt::Display for LprpError {
@@ -27,22 +44,29 @@ impl fmt::Display for LprpError {
             Self::ReadError => f.write_str("Read Error"),
             Self::ReadNumError => f.write_str("Read Num Error"),
             Self::ConversionError => f.write_str("Conversion Error"),
+            Self::TypeError { ref expected, ref found } => {
+                write!(f, "Type Error: expected {}, found {}", expected, found)
+            },
+            Self::QueryError(ref message) => write!(f, "Query Error: {}", message),
+            Self::PatternError(ref message) => write!(f, "Pattern Error: {}", message),
+            Self::SchemaError(ref message) => write!(f, "Schema Error: {}", message),
+            Self::PatchError(ref message) => write!(f, "Patch Error: {}", message),
+            Self::SerdeError(ref message) => write!(f, "Serde Error: {}", message),
+            Self::JsonError(ref message) => write!(f, "JSON Error: {}", message),
+            Self::CsexpError(ref message) => write!(f, "Csexp Error: {}", message),
+            Self::BinaryError(ref message) => write!(f, "Binary Error: {}", message),
+            Self::WriteError(ref message) => write!(f, "Write Error: {}", message),
+            Self::DelimiterError(ref message) => write!(f, "Delimiter Error: {}", message),
+            Self::Condition { ref kind, ref message, ref irritants } => {
+                display_error(f, kind, message, irritants)
+            },
         }
     }
 }
-/*
-impl Error for LprpError {
-    fn description(&self) -> &str {
-        match *self {
-            Self::SyntaxError => "SyntaxError",
-            Self::ReadError => "ReadError",
-            Self::ReadNumError => "ReadNumError",
-            Self::ConversionError => "ConversionError: Not support its type."
-        }
-    }
-}*/
 
-#[derive(Debug, PartialEq, Clone)]
+impl Error for LprpError {}
+
+#[derive(Debug, Clone)]
 pub enum Token {
     T,
     Nil,
@@ -54,32 +78,123 @@ pub enum Token {
     List(Vec<Token>),
 }
 
-impl Into<Result<i64, LprpError>> for Token {
-    fn into(self) -> Result<i64, LprpError> {
+// ***** Ordering *****
+// Tokens are totally ordered so they can be used as map keys. Tokens of
+// different types order by type: nil, t, integers, floats, strings,
+// symbols, quoted forms, lists. Floats use the IEEE total order, so
+// `NaN` equals itself and `-0.0` sorts before `0.0`. This is equality
+// of representation; see `core` for the Lisp equality predicates.
+impl Token {
+    fn rank(&self) -> u8 {
+        match self {
+            Token::Nil => 0,
+            Token::T => 1,
+            Token::Int(_) => 2,
+            Token::Float(_) => 3,
+            Token::Str(_) => 4,
+            Token::Symbol(_) => 5,
+            Token::Quote(_) => 6,
+            Token::List(_) => 7,
+        }
+    }
+}
+
+impl Ord for Token {
+    fn cmp(&self, other: &Token) -> Ordering {
+        match (self, other) {
+            (Token::Int(a), Token::Int(b)) => a.cmp(b),
+            (Token::Float(a), Token::Float(b)) => a.total_cmp(b),
+            (Token::Str(a), Token::Str(b)) => a.cmp(b),
+            (Token::Symbol(a), Token::Symbol(b)) => a.cmp(b),
+            (Token::Quote(a), Token::Quote(b)) => a.cmp(b),
+            (Token::List(a), Token::List(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Token {
+    fn partial_cmp(&self, other: &Token) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Token {
+    fn eq(&self, other: &Token) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Token {}
+
+impl Hash for Token {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
         match self {
+            Token::T|Token::Nil => {},
+            Token::Int(i) => i.hash(state),
+            Token::Float(f) => f.to_bits().hash(state),
+            Token::Str(s)|Token::Symbol(s) => s.hash(state),
+            Token::Quote(q) => q.hash(state),
+            Token::List(l) => l.hash(state),
+        }
+    }
+}
+
+#[test]
+fn test_token_ord() {
+    use std::collections::{BTreeSet, HashMap};
+
+    let nan = Token::Float(f64::NAN);
+    assert_eq!(nan, nan.clone());
+    assert_ne!(Token::Float(0.0), Token::Float(-0.0));
+    assert_ne!(Token::Int(1), Token::Float(1.0));
+    assert!(Token::Int(100) < Token::Float(-1.0));
+    assert!(Token::Float(f64::NEG_INFINITY) < Token::Float(-0.0));
+    assert!(read("(1 2)").unwrap() < read("(1 3)").unwrap());
+
+    let mut m = HashMap::new();
+    m.insert(read("(:port 80)").unwrap(), 1);
+    m.insert(nan.clone(), 2);
+    assert_eq!(m.get(&read("(:port 80)").unwrap()), Some(&1));
+    assert_eq!(m.get(&nan), Some(&2));
+
+    let items = match read("(b 2.5 \"s\" a 1 nil)").unwrap() {
+        Token::List(l) => l,
+        _ => unreachable!(),
+    };
+    let set: BTreeSet<Token> = items.into_iter().collect();
+    let sorted: Vec<Token> = set.into_iter().collect();
+    assert_eq!(Token::List(sorted), read("(nil 1 2.5 \"s\" a b)").unwrap());
+}
+
+impl From<Token> for Result<i64, LprpError> {
+    fn from(val: Token) -> Self {
+        match val {
             Token::Int(i) => Ok(i),
             _ => Err(LprpError::ConversionError),
         }
     }
 }
 
-impl Into<Result<f64, LprpError>> for Token {
-    fn into(self) -> Result<f64, LprpError> {
-        match self {
+impl From<Token> for Result<f64, LprpError> {
+    fn from(val: Token) -> Self {
+        match val {
             Token::Float(f) => Ok(f),
             _ => Err(LprpError::ConversionError),
         }
     }
 }
 
-impl Into<Result<String, LprpError>> for Token {
-    fn into(self) -> Result<String, LprpError> {
-        match self {
+// Strings come back quoted; `String::try_from` returns them as they are.
+impl From<Token> for Result<String, LprpError> {
+    fn from(val: Token) -> Self {
+        match val {
             Token::Symbol(sym) => Ok(sym.to_string()),
             Token::Str(s) => {
                 let mut dq = String::from("\"");
                 dq.push_str(&s);
-                dq.push_str("\"");
+                dq.push('"');
                 Ok(dq.to_string())
             },
             _ => Err(LprpError::ConversionError),
@@ -94,299 +209,464 @@ fn test_token_into() {
     assert_eq!(st2, Ok("\"Hello, world!!\"".to_string()));
 }
 
-// ***** Int, Float *****
-fn is_lprp_num(ch: &char) -> bool {
-    (ch.is_ascii_digit())||(ch == &'-')||(ch == &'.')
+// ***** Accessors *****
+impl Token {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Token::T => "boolean",
+            Token::Nil => "null",
+            Token::Int(_) => "integer",
+            Token::Float(_) => "float",
+            Token::Symbol(_) => "symbol",
+            Token::Quote(_) => "quote",
+            Token::Str(_) => "string",
+            Token::List(_) => "list",
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Token::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    // Integers are widened to floats.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Token::Float(f) => Some(*f),
+            Token::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Token::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_symbol(&self) -> Option<&str> {
+        match self {
+            Token::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Elements of a list. `nil` is the empty list.
+    pub fn as_list(&self) -> Option<&[Token]> {
+        match self {
+            Token::List(l) => Some(l),
+            Token::Nil => Some(&[]),
+            _ => None,
+        }
+    }
+
+    // `:name`
+    pub fn is_keyword(&self) -> bool {
+        matches!(self, Token::Symbol(s) if s.len() > 1 && s.starts_with(':'))
+    }
+
+    // `*name*`
+    pub fn is_special_var(&self) -> bool {
+        matches!(self, Token::Symbol(s) if s.len() > 2 && s.starts_with('*') && s.ends_with('*'))
+    }
+
+    fn type_error<T>(&self, expected: &str) -> Result<T, LprpError> {
+        Err(LprpError::TypeError {
+            expected: expected.to_string(),
+            found: self.type_name().to_string(),
+        })
+    }
 }
 
-fn read_num<I>(chars: &mut Peekable<I>) -> Result<Token, LprpError>
-    where I: Iterator<Item=char>
-{
-    let mut num: Vec<char> = vec![];
+#[test]
+fn test_token_accessors() {
+    let tk = read("(1 2.5 \"s\" sym :key *global* nil)").unwrap();
+    let l = tk.as_list().unwrap();
+    assert_eq!(l[0].as_int(), Some(1));
+    assert_eq!(l[0].as_float(), Some(1.0));
+    assert_eq!(l[1].as_float(), Some(2.5));
+    assert_eq!(l[1].as_int(), None);
+    assert_eq!(l[2].as_str(), Some("s"));
+    assert_eq!(l[3].as_str(), None);
+    assert_eq!(l[3].as_symbol(), Some("sym"));
+    assert!(l[4].is_keyword() && !l[3].is_keyword());
+    assert!(l[5].is_special_var() && !l[4].is_special_var());
+    assert_eq!(l[6].as_list(), Some(&[][..]));
+    assert_eq!(l[0].as_list(), None);
+    assert!(!Token::Symbol("*".to_string()).is_special_var());
+}
 
-    loop {
-        match chars.peek() {
-            Some(c) if is_lprp_num(&c) => {
-                num.push(*c);
-            }
-            _ => {
-                if vld::is_integer(&num) {
-                    return Ok(Token::Int(tls::cast::<i64>(&num).unwrap()));
-                } else if vld::is_float(&num) {
-                    return Ok(Token::Float(tls::cast::<f64>(&num).unwrap()));
-                } else {
-                    return Err(LprpError::ReadNumError);
+// ***** Conversions *****
+// Token to Rust conversions fail with `LprpError::TypeError`, naming the
+// expected type and the type that was found.
+macro_rules! int_conversions {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<Token> for $t {
+                type Error = LprpError;
+
+                fn try_from(tk: Token) -> Result<Self, Self::Error> {
+                    match tk {
+                        Token::Int(i) => <$t>::try_from(i).map_err(|_| LprpError::TypeError {
+                            expected: stringify!($t).to_string(),
+                            found: format!("integer {}", i),
+                        }),
+                        _ => tk.type_error(stringify!($t)),
+                    }
                 }
             }
+        )*
+    };
+}
+
+int_conversions!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+impl TryFrom<Token> for f64 {
+    type Error = LprpError;
+
+    fn try_from(tk: Token) -> Result<Self, Self::Error> {
+        tk.as_float().map_or_else(|| tk.type_error("float"), Ok)
+    }
+}
+
+impl TryFrom<Token> for f32 {
+    type Error = LprpError;
+
+    fn try_from(tk: Token) -> Result<Self, Self::Error> {
+        tk.as_float().map_or_else(|| tk.type_error("float"), |f| Ok(f as f32))
+    }
+}
+
+// `t` and `nil`.
+impl TryFrom<Token> for bool {
+    type Error = LprpError;
+
+    fn try_from(tk: Token) -> Result<Self, Self::Error> {
+        match tk {
+            Token::T => Ok(true),
+            Token::Nil => Ok(false),
+            _ => tk.type_error("boolean"),
         }
-        chars.next();
     }
 }
 
-#[test]
-fn test_read_num() {
-    let mut i = "123".chars().peekable();
-    let mut f = "-0.12".chars().peekable();
-    assert_eq!(read_num(&mut i), Ok(Token::Int(123)));
-    assert_eq!(read_num(&mut f), Ok(Token::Float(-0.12)));
+impl TryFrom<Token> for String {
+    type Error = LprpError;
+
+    fn try_from(tk: Token) -> Result<Self, Self::Error> {
+        match tk {
+            Token::Str(s) => Ok(s),
+            _ => tk.type_error("string"),
+        }
+    }
 }
 
-// ***** Symbol *****
-fn is_lprp_symbol(ch: &char) -> bool {
-    (ch.is_ascii_alphabetic())||(ch == &'-')
+impl<T> TryFrom<Token> for Vec<T>
+    where T: TryFrom<Token, Error=LprpError>
+{
+    type Error = LprpError;
+
+    fn try_from(tk: Token) -> Result<Self, Self::Error> {
+        match tk {
+            Token::List(l) => l.into_iter().map(T::try_from).collect(),
+            Token::Nil => Ok(vec![]),
+            _ => tk.type_error("list"),
+        }
+    }
 }
 
-fn read_symbol<I>(chars: &mut Peekable<I>) -> Token
-    where I: Iterator<Item=char>
+// `nil` is `None`.
+impl<T> TryFrom<Token> for Option<T>
+    where T: TryFrom<Token, Error=LprpError>
 {
-    let mut sym = String::new();
+    type Error = LprpError;
+
+    fn try_from(tk: Token) -> Result<Self, Self::Error> {
+        match tk {
+            Token::Nil => Ok(None),
+            _ => T::try_from(tk).map(Some),
+        }
+    }
+}
 
-    loop {
-        match chars.peek() {
-            Some(c) if is_lprp_symbol(&c) => {
-                sym.push(*c);
+macro_rules! tuple_conversions {
+    ($(($n:expr; $($t:ident),*)),*) => {
+        $(
+            impl<$($t),*> TryFrom<Token> for ($($t,)*)
+                where $($t: TryFrom<Token, Error=LprpError>),*
+            {
+                type Error = LprpError;
+
+                fn try_from(tk: Token) -> Result<Self, Self::Error> {
+                    let expected = concat!("list of ", $n, " elements");
+                    match tk {
+                        Token::List(l) if l.len() == $n => {
+                            let mut items = l.into_iter();
+                            Ok(($($t::try_from(items.next().unwrap())?,)*))
+                        },
+                        Token::List(l) => Err(LprpError::TypeError {
+                            expected: expected.to_string(),
+                            found: format!("list of {} elements", l.len()),
+                        }),
+                        _ => tk.type_error(expected),
+                    }
+                }
             }
-            _ => {
-                if (&sym[..] == "nil")||(&sym[..] == "NIL") {
-                    return Token::Nil;
-                } else if &sym[..] == "t" {
-                    return Token::T;
-                } else {
-                    return Token::Symbol(sym.to_string());
+
+            impl<$($t),*> From<($($t,)*)> for Token
+                where $($t: Into<Token>),*
+            {
+                #[allow(non_snake_case)]
+                fn from(($($t,)*): ($($t,)*)) -> Self {
+                    Token::List(vec![$($t.into()),*])
                 }
             }
-        }
-        chars.next();
+        )*
+    };
+}
+
+tuple_conversions!((1; A), (2; A, B), (3; A, B, C), (4; A, B, C, D));
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Token {
+                fn from(i: $t) -> Self {
+                    Token::Int(i as i64)
+                }
+            }
+        )*
+    };
+}
+
+from_int!(i8, i16, i32, i64, u8, u16, u32);
+
+impl TryFrom<u64> for Token {
+    type Error = LprpError;
+
+    fn try_from(i: u64) -> Result<Self, Self::Error> {
+        i64::try_from(i).map(Token::Int).map_err(|_| LprpError::TypeError {
+            expected: "i64".to_string(),
+            found: format!("u64 {}", i),
+        })
     }
 }
 
-#[test]
-fn test_read_symbol() {
-    let mut sym = "with-open".chars().peekable();
-    let mut error_sym = "with_open".chars().peekable();
-    let mut nil = "nil".chars().peekable();
-    let mut t = "t".chars().peekable();
-    assert_eq!(read_symbol(&mut sym), Token::Symbol("with-open".to_string()));
-    assert_eq!(read_symbol(&mut error_sym), Token::Symbol("with".to_string()));
-    assert_eq!(read_symbol(&mut nil), Token::Nil);
-    assert_eq!(read_symbol(&mut t), Token::T);
+impl TryFrom<usize> for Token {
+    type Error = LprpError;
+
+    fn try_from(i: usize) -> Result<Self, Self::Error> {
+        Token::try_from(i as u64)
+    }
 }
 
-// ***** Keyword Symbol *****
-fn is_lprp_keyword(ch: &char) -> bool {
-    (is_lprp_symbol(&ch))||(ch == &':')
+impl From<f64> for Token {
+    fn from(f: f64) -> Self {
+        Token::Float(f)
+    }
 }
 
-fn read_keyword<I>(chars: &mut Peekable<I>) -> Result<Token, LprpError>
-    where I: Iterator<Item=char>
-{
-    let mut k: Vec<char> = vec![];
+impl From<f32> for Token {
+    fn from(f: f32) -> Self {
+        Token::Float(f as f64)
+    }
+}
 
-    loop {
-        match chars.peek() {
-            Some(c) if is_lprp_keyword(&c) => {
-                k.push(*c);
-            }
-            _ => {
-                let cnt = &k.iter()
-                    .filter(|&c| c == &':')
-                    .count();
-                if cnt != &1_usize {
-                    return Err(LprpError::SyntaxError);
-                } else {
-                    let k2 = tls::chars_to_string(&k);
-                    if !k2.starts_with(':') {
-                        return Err(LprpError::SyntaxError);
-                    } else {
-                        return Ok(Token::Symbol(k2.to_string()));
-                    }
-                }
-            }
+impl From<bool> for Token {
+    fn from(b: bool) -> Self {
+        if b { Token::T } else { Token::Nil }
+    }
+}
+
+impl From<String> for Token {
+    fn from(s: String) -> Self {
+        Token::Str(s)
+    }
+}
+
+impl From<&str> for Token {
+    fn from(s: &str) -> Self {
+        Token::Str(s.to_string())
+    }
+}
+
+// An empty vector is `nil`.
+impl<T: Into<Token>> From<Vec<T>> for Token {
+    fn from(v: Vec<T>) -> Self {
+        if v.is_empty() {
+            Token::Nil
+        } else {
+            Token::List(v.into_iter().map(Into::into).collect())
         }
-        chars.next();
+    }
+}
+
+impl<T: Into<Token>> From<Option<T>> for Token {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Token::Nil, Into::into)
     }
 }
 
 #[test]
-fn test_read_keyword() {
-    let mut key = ":my-key".chars().peekable();
-    let mut err_key = ":error:".chars().peekable();
+fn test_token_conversions() {
+    assert_eq!(i64::try_from(Token::Int(-3)), Ok(-3));
+    assert_eq!(u8::try_from(Token::Int(255)), Ok(255));
+    assert_eq!(
+        u8::try_from(Token::Int(256)),
+        Err(LprpError::TypeError { expected: "u8".to_string(), found: "integer 256".to_string() })
+        );
+    assert_eq!(
+        i32::try_from(Token::Str("1".to_string())).map_err(|e| e.to_string()),
+        Err("Type Error: expected i32, found string".to_string())
+        );
+    assert_eq!(f64::try_from(Token::Int(2)), Ok(2.0));
+    assert_eq!(bool::try_from(Token::Nil), Ok(false));
+    assert_eq!(String::try_from(Token::Str("s".to_string())), Ok("s".to_string()));
+
+    let tk = read("((1 2) (3))").unwrap();
+    assert_eq!(Vec::<Vec<i64>>::try_from(tk.clone()), Ok(vec![vec![1, 2], vec![3]]));
+    assert_eq!(
+        <(Vec<i64>, Option<i64>)>::try_from(tk).map_err(|e| e.to_string()),
+        Err("Type Error: expected i64, found list".to_string())
+        );
+    assert_eq!(Option::<i64>::try_from(Token::Nil), Ok(None));
     assert_eq!(
-        read_keyword(&mut key),
-        Ok(Token::Symbol(":my-key".to_string()))
+        <(i64, String)>::try_from(read("(1 \"a\" 2)").unwrap()),
+        Err(LprpError::TypeError {
+            expected: "list of 2 elements".to_string(),
+            found: "list of 3 elements".to_string(),
+        })
         );
     assert_eq!(
-        read_keyword(&mut err_key),
-        Err(LprpError::SyntaxError),
+        <(String, f64, bool)>::try_from(read("(\"port\" 1.5 t)").unwrap()),
+        Ok(("port".to_string(), 1.5, true))
         );
+
+    assert_eq!(Token::from(vec![1, 2]), read("(1 2)").unwrap());
+    assert_eq!(Token::from(Vec::<i64>::new()), Token::Nil);
+    assert_eq!(Token::from(("a", Some(1.5), None::<i64>)), read("(\"a\" 1.5 nil)").unwrap());
+    assert_eq!(Token::from(true), Token::T);
+    assert!(Token::try_from(u64::MAX).is_err());
+
+    // Round trip.
+    let v = vec![(1, "a".to_string()), (2, "b".to_string())];
+    assert_eq!(Vec::<(i64, String)>::try_from(Token::from(v.clone())), Ok(v));
 }
 
-// ***** Special Symbol *****
-fn is_lprp_special(ch: &char) -> bool {
-    (is_lprp_symbol(&ch))||(ch == &'*')
+// ***** Read *****
+// Text is split into lexemes by `lexer`, and `borrowed` builds the tree,
+// so this reader and `borrowed::read` accept the same syntax.
+pub fn read(expr: &str) -> Result<Token, LprpError> {
+    borrowed::read(expr).map(borrowed::Token::into_owned)
 }
 
-fn read_special<I>(chars: &mut Peekable<I>) -> Result<Token, LprpError>
-    where I: Iterator<Item=char>
-{
-    let mut sp: Vec<char> = vec![];
-    
-    loop {
-        match chars.peek() {
-            Some(c) if is_lprp_special(&c) => {
-                sp.push(*c);
-            }
-            _ => {
-                let cnt = &sp.iter()
-                    .filter(|&c| c == &'*')
-                    .count();
-                if cnt != &2_usize {
-                    return Err(LprpError::SyntaxError);
-                } else {
-                    let sp2 = tls::chars_to_string(&sp);
-                    if (sp2.starts_with('*'))&&(sp2.ends_with('*')) {
-                        return Ok(Token::Symbol(sp2.to_string()));
-                    } else {
-                        return Err(LprpError::SyntaxError);
-                    }
-                }
-            }
+// Read every top-level expression, e.g. the forms of a script.
+pub fn read_all(expr: &str) -> Result<Vec<Token>, LprpError> {
+    let forms = borrowed::read_all(expr)?;
+    Ok(forms.into_iter().map(borrowed::Token::into_owned).collect())
+}
+
+// ***** Write *****
+// Print `tk` as text that `read` reads back as `tk`. The printer has no
+// escapes, so this fails on a string holding `"`, a float that is NaN or
+// infinite, and a symbol that does not read as itself, such as `a b`.
+pub fn write(tk: &Token) -> Result<String, LprpError> {
+    fn check(tk: &Token) -> Result<(), LprpError> {
+        let fail = |what: String| Err(LprpError::WriteError(format!("cannot write {}", what)));
+        match tk {
+            Token::Str(s) if s.contains('"') => fail(format!("string {:?}", s)),
+            Token::Float(f) if !f.is_finite() => fail(format!("float {}", f)),
+            Token::Symbol(s) if read(s).as_ref().ok().and_then(Token::as_symbol) != Some(s) => {
+                fail(format!("symbol {:?}", s))
+            },
+            Token::List(l) => {
+                // `(a . b)` holds a `.` symbol.
+                let dot = l.len().checked_sub(2).filter(|&i| l[i].as_symbol() == Some("."));
+                l.iter().enumerate().filter(|&(i, _)| Some(i) != dot).try_for_each(|(_, x)| check(x))
+            },
+            Token::Quote(q) => check(q),
+            _ => Ok(()),
         }
-        chars.next();
     }
+    check(tk)?;
+    Ok(tk.to_string())
 }
 
 #[test]
-fn test_read_special() {
-    let mut sp = "*special*".chars().peekable();
-    let mut e = "*special".chars().peekable();
-    let mut e2 = "***".chars().peekable();
-    assert_eq!(
-        read_special(&mut sp),
-        Ok(Token::Symbol("*special*".to_string()))
-        );
-    assert_eq!(read_special(&mut e), Err(LprpError::SyntaxError));
-    assert_eq!(read_special(&mut e2), Err(LprpError::SyntaxError));
+fn test_write() {
+    for src in &["(a 1 -2.5 \"s\" :k *x* nil t)", "'(1 . 2)", "((a . \"b\") (c))", "100000000000000000000.0"] {
+        let tk = read(src).unwrap();
+        assert_eq!(read(&write(&tk).unwrap()), Ok(tk));
+    }
+    let cannot = |tk: Token| match write(&tk) {
+        Err(LprpError::WriteError(message)) => message,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(cannot(Token::Str("say \"hi\"".to_string())), "cannot write string \"say \\\"hi\\\"\"");
+    assert_eq!(cannot(Token::List(vec![Token::Float(f64::NAN)])), "cannot write float NaN");
+    assert_eq!(cannot(Token::Float(f64::NEG_INFINITY)), "cannot write float -inf");
+    for s in &["a b", "nil", "", "1", ".", "é"] {
+        assert_eq!(cannot(Token::Symbol(s.to_string())), format!("cannot write symbol {:?}", s));
+    }
 }
 
-// ***** Str *****
-fn read_string<I>(chars: &mut Peekable<I>) -> Token
-    where I: Iterator<Item=char>
-{
-    chars.next();
-    
-    let mut s = String::new();
-
-    loop {
-        match chars.peek() {
-            Some(c) if c != &'\"' => {
-                s.push(*c);
-            }
-            _ => {
-                chars.next();
-                return Token::Str(s.to_string());
-            }
-        }
-        chars.next();
-    }
+#[test]
+fn test_read_num() {
+    assert_eq!(read("123"), Ok(Token::Int(123)));
+    assert_eq!(read("-0.12"), Ok(Token::Float(-0.12)));
+    assert_eq!(read("1."), Ok(Token::Float(1.0)));
+    assert_eq!(read("1-2"), Err(LprpError::ReadNumError));
+    assert_eq!(read("99999999999999999999"), Err(LprpError::ReadNumError));
+
+    assert_eq!(read("-"), Ok(Token::Symbol("-".to_string())));
+    assert_eq!(read("->list"), Ok(Token::Symbol("->list".to_string())));
+    assert_eq!(read("(12abc)"), read("(12 abc)"));
 }
 
 #[test]
-fn test_read_string() {
-    let mut s = "\"(Oops!)\"".chars().peekable();
-    assert_eq!(read_string(&mut s), Token::Str("(Oops!)".to_string()));
+fn test_read_symbol() {
+    assert_eq!(read("with-open"), Ok(Token::Symbol("with-open".to_string())));
+    assert_eq!(read("with_open"), Err(LprpError::SyntaxError));
+    assert_eq!(read("nil"), Ok(Token::Nil));
+    assert_eq!(read("NIL"), Ok(Token::Nil));
+    assert_eq!(read("t"), Ok(Token::T));
+    assert_eq!(read("T"), Ok(Token::Symbol("T".to_string())));
+
+    assert_eq!(read("<="), Ok(Token::Symbol("<=".to_string())));
+    assert_eq!(read("call/cc"), Ok(Token::Symbol("call/cc".to_string())));
+    assert_eq!(read("let*"), Ok(Token::Symbol("let*".to_string())));
+    assert_eq!(read("é"), Err(LprpError::SyntaxError));
 }
 
-// ***** List *****
-fn read_list<I>(mut chars: &mut Peekable<I>) -> Result<Token, LprpError>
-    where I: Iterator<Item=char>
-{
-    chars.next();
-
-    let mut v: Vec<Token> = vec![];
-
-    loop {
-        match chars.peek() {
-            Some(c) => {
-                match *c {
-                    '(' => {
-                        match read_list(&mut chars) {
-                            Ok(l) => {
-                                v.push(l);
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    ')' => {
-                        chars.next();
-                        return Ok(Token::List(v.to_vec()));
-                    },
-                    ' '|'\n'|'\t' => {
-                        chars.next();
-                    },
-                    '0' ..= '9'|'-' => {
-                        match read_num(&mut chars) {
-                            Ok(n) => {
-                                v.push(n);
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    'a' ..= 'z'|'A' ..= 'Z' => {
-                        v.push(read_symbol(&mut chars));
-                    },
-                    '*' => {
-                        match read_special(&mut chars) {
-                            Ok(sp) => {
-                                v.push(sp);
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    ':' => {
-                        match read_keyword(&mut chars) {
-                            Ok(k) => {
-                                v.push(k);
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    '\"' => {
-                        v.push(read_string(&mut chars));
-                    },
-                    '\'' => {
-                        match read_quote(&mut chars) {
-                            Ok(q) => {
-                                v.push(q);
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    _ => {
-                        return Err(LprpError::SyntaxError);
-                    }
-                }
-            },
-            _ => return Ok(Token::List(v.to_vec())),
-        }
-    }
+#[test]
+fn test_read_keyword() {
+    assert_eq!(read(":my-key"), Ok(Token::Symbol(":my-key".to_string())));
+    assert_eq!(read(":error:"), Err(LprpError::SyntaxError));
+    assert_eq!(read("(a:b)"), read("(a :b)"));
+}
+
+#[test]
+fn test_read_special() {
+    assert_eq!(read("*special*"), Ok(Token::Symbol("*special*".to_string())));
+    assert_eq!(read("*special"), Err(LprpError::SyntaxError));
+    assert_eq!(read("***"), Err(LprpError::SyntaxError));
+    assert_eq!(read("*a*b"), Err(LprpError::SyntaxError));
+    assert_eq!(read("*"), Ok(Token::Symbol("*".to_string())));
+}
+
+#[test]
+fn test_read_string() {
+    assert_eq!(read("\"(Oops!)\""), Ok(Token::Str("(Oops!)".to_string())));
+    assert_eq!(read("\"é ; x\""), Ok(Token::Str("é ; x".to_string())));
 }
 
 #[test]
 fn test_read_list() {
-    let mut l = "(1 (2 3))".chars().peekable();
     assert_eq!(
-        read_list(&mut l),
+        read("(1 (2 3))"),
         Ok(Token::List(vec![
                     Token::Int(1),
                     Token::List(vec![
@@ -395,61 +675,29 @@ fn test_read_list() {
                     ])
         ]))
     );
-}
 
-// ***** Quote *****
-fn read_quote<I>(mut chars: &mut Peekable<I>) -> Result<Token, LprpError>
-    where I: Iterator<Item=char>
-{
-    chars.next();
-
-    match chars.peek() {
-        Some(c) => {
-            match *c {
-                '(' => {
-                    match read_list(&mut chars) {
-                        Ok(l) => Ok(Token::Quote(Box::new(l))),
-                        Err(e) => Err(e)
-                    }
-                },
-                '0' ..= '9'|'-' => {
-                    match read_num(&mut chars) {
-                        Ok(n) => Ok(Token::Quote(Box::new(n))),
-                        Err(e) => Err(e),
-                    }
-                },
-                'a' ..= 'z'|'A' ..= 'Z' => Ok(Token::Quote(Box::new(read_symbol(&mut chars)))),
-                ':' => {
-                    match read_keyword(&mut chars) {
-                        Ok(k) => Ok(Token::Quote(Box::new(k))),
-                        Err(e) => Err(e),
-                    }
-                },
-                '*' => {
-                    match read_special(&mut chars) {
-                        Ok(sp) => Ok(Token::Quote(Box::new(sp))),
-                        Err(e) => Err(e),
-                    }
-                },
-                '\"' => Ok(Token::Quote(Box::new(read_string(&mut chars)))),
-                '\'' => {
-                    match read_quote(&mut chars) {
-                        Ok(q) => Ok(Token::Quote(Box::new(q))),
-                        Err(e) => Err(e),
-                    }
-                },
-                _ => Err(LprpError::SyntaxError)
-            }
-        },
-        _ => Err(LprpError::SyntaxError)
-    }
+    // A dotted tail reads as a `.` symbol before the last element.
+    assert_eq!(
+        read("(a _ . rest)"),
+        Ok(Token::List(vec![
+                    Token::Symbol("a".to_string()),
+                    Token::Symbol("_".to_string()),
+                    Token::Symbol(".".to_string()),
+                    Token::Symbol("rest".to_string()),
+        ]))
+    );
+    assert_eq!(read("(. a)"), Err(LprpError::SyntaxError));
+    assert_eq!(read("(a . b c)"), Err(LprpError::SyntaxError));
+    assert_eq!(read("(a .b)"), Err(LprpError::SyntaxError));
+    assert_eq!(read("(x _x)"), Err(LprpError::SyntaxError));
+    assert_eq!(read("(a\tb\nc)"), read("(a b c)"));
+    assert_eq!(read("(a\rb)"), Err(LprpError::SyntaxError));
 }
 
 #[test]
 fn test_read_quote() {
-    let mut q = "'(1 2 3)".chars().peekable();
     assert_eq!(
-        read_quote(&mut q),
+        read("'(1 2 3)"),
         Ok(Token::Quote(
                 Box::new(
                     Token::List(vec![
@@ -460,108 +708,17 @@ fn test_read_quote() {
                 )
             )
         );
-}
-
-fn read_expr<I>(mut chars: &mut Peekable<I>) -> Result<Token, LprpError>
-    where I: Iterator<Item=char>
-{
-    let mut v: Vec<Token> = vec![];
-
-    loop {
-        match chars.peek() {
-            Some(c) => {
-                match *c {
-                    '(' => {
-                        match read_list(&mut chars) {
-                            Ok(l) => {
-                                v.push(l);
-                                chars.next();
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    '0' ..= '9'|'-' => {
-                        match read_num(&mut chars) {
-                            Ok(num) => {
-                                v.push(num);
-                                chars.next();
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    'a' ..= 'z'|'A' ..= 'Z' => {
-                        v.push(read_symbol(&mut chars));
-                        chars.next();
-                    },
-                    '*' => {
-                        match read_special(&mut chars) {
-                            Ok(sp) => {
-                                v.push(sp);
-                                chars.next();
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                       }
-                    },
-                    ':' => {
-                        match read_keyword(&mut chars) {
-                            Ok(k) => {
-                                v.push(k);
-                                chars.next();
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    '\"' => {
-                        v.push(read_string(&mut chars));
-                        chars.next();
-                    },
-                    '\'' => {
-                        match read_quote(&mut chars) {
-                            Ok(q) => {
-                                v.push(q);
-                                chars.next();
-                            },
-                            Err(e) => {
-                                return Err(e);
-                            }
-                        }
-                    },
-                    ' '|'\n'|'\t' => {
-                        chars.next();
-                    }
-                    _ => return Err(LprpError::SyntaxError),
-                }
-            },
-            None => return Ok(Token::List(v.to_vec())),
-        }
-    }
-}
-pub fn read<'a>(expr: &'a str) -> Result<Token, LprpError> {
-    let mut chars = expr.chars().peekable();
-    match read_expr(&mut chars) {
-        Ok(ex) => {
-            match ex {
-                Token::List(l) => Ok(l[0].clone()),
-                _ => Err(LprpError::ReadError),
-            }
-        },
-        Err(e) => Err(e),
-    }
+    assert_eq!(read("''a"), Ok(Token::Quote(Box::new(Token::Quote(Box::new(Token::Symbol("a".to_string())))))));
+    assert_eq!(read("':k"), Ok(Token::Quote(Box::new(Token::Symbol(":k".to_string())))));
+    assert_eq!(read("' a"), Err(LprpError::SyntaxError));
+    assert_eq!(read("'_"), Err(LprpError::SyntaxError));
+    assert_eq!(read("'."), Err(LprpError::SyntaxError));
 }
 
 #[test]
 fn test_read() {
-    let mut expr ="((1 -2.3)\n (*a* :b))".chars().peekable();
     assert_eq!(
-        read_list(&mut expr),
+        read("((1 -2.3)\n (*a* :b))"),
         Ok(Token::List(vec![
             Token::List(vec![
                 Token::Int(1),
@@ -573,4 +730,45 @@ fn test_read() {
             ])
         ]))
     );
+    // Comments run to the end of the line.
+    assert_eq!(read("(a ; the first\n b) ; done"), read("(a b)"));
+    assert_eq!(read(""), Err(LprpError::ReadError));
+    assert_eq!(read("; nothing"), Err(LprpError::ReadError));
+}
+
+#[test]
+fn test_read_delimiters() {
+    let delimiter = |message: &str| LprpError::DelimiterError(message.to_string());
+    assert_eq!(read("(a) b)"), Err(delimiter("unexpected `)` at 1:6")));
+    assert_eq!(read("(a (b)"), Err(delimiter("unclosed `(` at 1:1, input ends at 1:7")));
+    assert_eq!(read("(say \"hi)"), Err(delimiter("unterminated string at 1:6")));
+    // The indentation shows where the first form should have ended.
+    let src = "(defun f (x)\n  (g x)\n\n(defun h ()\n  1)\n";
+    assert_eq!(
+        read_all(src),
+        Err(delimiter("unclosed `(` at 1:1, input ends at 6:1; a `)` may be missing at 2:8"))
+        );
+}
+
+#[test]
+fn test_read_all() {
+    assert_eq!(
+        read_all("(defun id (x) x)\n(id 1)"),
+        Ok(vec![
+            Token::List(vec![
+                Token::Symbol("defun".to_string()),
+                Token::Symbol("id".to_string()),
+                Token::List(vec![Token::Symbol("x".to_string())]),
+                Token::Symbol("x".to_string()),
+            ]),
+            Token::List(vec![
+                Token::Symbol("id".to_string()),
+                Token::Int(1),
+            ]),
+        ])
+    );
+    assert_eq!(read_all(""), Ok(vec![]));
+    assert_eq!(read_all("(a)(b) c'd"), Ok(vec![
+        read("(a)").unwrap(), read("(b)").unwrap(), read("c").unwrap(), read("'d").unwrap(),
+    ]));
 }
@@ -0,0 +1,588 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use crate::core::equal;
+use crate::reader::{read, Token, LprpError};
+
+
+// ***** Schema *****
+// A schema describes the documents it accepts, as an S-expression:
+//
+//   (schema config
+//     (form config :rest (or server db))
+//     (form server :args (symbol)
+//                  :required (:port port :host string)
+//                  :optional (:tags (list-of symbol) :mode (one-of fast slow)))
+//     (form db :args (string))
+//     (type port (range 1 65535)))
+//
+// The first element after `schema` is the type of the document. A form
+// is a list headed by its name, with the positional arguments in `:args`,
+// any further positional arguments of type `:rest`, then a plist of the
+// `:required` and `:optional` keywords. Types are
+//
+//   any integer float number string symbol keyword boolean null list
+//   (one-of literal...)   one of the literals
+//   (or type...)          any of the types
+//   (list-of type)        a list of elements of the type
+//   (range lo hi)         a number from lo to hi inclusive
+//   name                  a form or a `type` defined in the schema
+#[derive(Debug, Clone)]
+pub struct Schema {
+    root: Type,
+    forms: HashMap<String, Form>,
+    types: HashMap<String, Type>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Any,
+    Integer,
+    Float,
+    Number,
+    Str,
+    Symbol,
+    Keyword,
+    Boolean,
+    Null,
+    List,
+    OneOf(Vec<Token>),
+    Or(Vec<Type>),
+    ListOf(Box<Type>),
+    Range(f64, f64),
+    Named(String),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Form {
+    args: Vec<Type>,
+    rest: Option<Type>,
+    required: Vec<(String, Type)>,
+    optional: Vec<(String, Type)>,
+}
+
+fn schema_error<T>(message: &str, tk: &Token) -> Result<T, LprpError> {
+    Err(LprpError::SchemaError(format!("{}: {}", message, tk)))
+}
+
+impl Schema {
+    pub fn parse(src: &str) -> Result<Schema, LprpError> {
+        Schema::from_token(&read(src)?)
+    }
+
+    pub fn from_token(tk: &Token) -> Result<Schema, LprpError> {
+        let (root, defs) = match tk.as_list() {
+            Some([head, root, defs @ ..]) if head.as_symbol() == Some("schema") => (root, defs),
+            _ => return schema_error("expected (schema type definition...)", tk),
+        };
+        let mut schema = Schema {
+            root: parse_type(root)?,
+            forms: HashMap::new(),
+            types: HashMap::new(),
+        };
+        for def in defs {
+            match def.as_list() {
+                Some([kind, name, rest @ ..]) if kind.as_symbol() == Some("form") => {
+                    let name = def_name(name, def)?;
+                    let form = parse_form(rest, def)?;
+                    if schema.forms.insert(name.to_string(), form).is_some() {
+                        return schema_error("duplicate definition", def);
+                    }
+                },
+                Some([kind, name, ty]) if kind.as_symbol() == Some("type") => {
+                    let name = def_name(name, def)?;
+                    if schema.types.insert(name.to_string(), parse_type(ty)?).is_some() {
+                        return schema_error("duplicate definition", def);
+                    }
+                },
+                _ => return schema_error("expected (form name ...) or (type name type)", def),
+            }
+        }
+        schema.check_names()?;
+        Ok(schema)
+    }
+
+    // Every name refers to a definition, and no type is an alternative of
+    // itself, which would make checking it loop forever.
+    fn check_names(&self) -> Result<(), LprpError> {
+        let mut used = vec![&self.root];
+        for form in self.forms.values() {
+            used.extend(&form.args);
+            used.extend(&form.rest);
+            used.extend(form.required.iter().chain(&form.optional).map(|(_, t)| t));
+        }
+        used.extend(self.types.values());
+        while let Some(ty) = used.pop() {
+            match ty {
+                Type::Named(n) if !self.forms.contains_key(n) && !self.types.contains_key(n) => {
+                    return schema_error("undefined type", &Token::Symbol(n.to_string()));
+                },
+                Type::Or(ts) => used.extend(ts),
+                Type::ListOf(t) => used.push(t),
+                _ => {},
+            }
+        }
+        let mut names: Vec<&String> = self.types.keys().collect();
+        names.sort();
+        for name in names {
+            let mut pending = vec![&self.types[name]];
+            let mut seen: Vec<&str> = vec![];
+            while let Some(ty) = pending.pop() {
+                match ty {
+                    Type::Named(n) if n == name => {
+                        return schema_error("circular type", &Token::Symbol(name.to_string()));
+                    },
+                    Type::Named(n) if self.types.contains_key(n) && !seen.contains(&&n[..]) => {
+                        seen.push(n);
+                        pending.push(&self.types[n]);
+                    },
+                    Type::Or(ts) => pending.extend(ts),
+                    _ => {},
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check a document, collecting every error.
+    pub fn validate(&self, tk: &Token) -> Result<(), Vec<SchemaError>> {
+        let mut errors = vec![];
+        let mut v = Validator { schema: self, path: vec![], errors: &mut errors };
+        v.check(&self.root, tk, tk);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn def_name<'a>(name: &'a Token, def: &Token) -> Result<&'a str, LprpError> {
+    match name.as_symbol() {
+        Some(n) if !name.is_keyword() => Ok(n),
+        _ => schema_error("expected a name", def),
+    }
+}
+
+fn parse_type(tk: &Token) -> Result<Type, LprpError> {
+    let ty = match tk {
+        Token::Symbol(s) => {
+            match &s[..] {
+                "any" => Type::Any,
+                "integer" => Type::Integer,
+                "float" => Type::Float,
+                "number" => Type::Number,
+                "string" => Type::Str,
+                "symbol" => Type::Symbol,
+                "keyword" => Type::Keyword,
+                "boolean" => Type::Boolean,
+                "null" => Type::Null,
+                "list" => Type::List,
+                _ if tk.is_keyword() => return schema_error("expected a type", tk),
+                _ => Type::Named(s.to_string()),
+            }
+        },
+        Token::List(l) => {
+            match l.split_first() {
+                Some((head, args)) => {
+                    match (head.as_symbol(), args) {
+                        (Some("one-of"), _) => Type::OneOf(args.to_vec()),
+                        (Some("or"), _) => {
+                            Type::Or(args.iter().map(parse_type).collect::<Result<_, _>>()?)
+                        },
+                        (Some("list-of"), [t]) => Type::ListOf(Box::new(parse_type(t)?)),
+                        (Some("range"), [lo, hi]) => {
+                            match (lo.as_float(), hi.as_float()) {
+                                (Some(lo), Some(hi)) => Type::Range(lo, hi),
+                                _ => return schema_error("expected (range lo hi)", tk),
+                            }
+                        },
+                        _ => return schema_error("expected a type", tk),
+                    }
+                },
+                None => return schema_error("expected a type", tk),
+            }
+        },
+        _ => return schema_error("expected a type", tk),
+    };
+    Ok(ty)
+}
+
+fn parse_form(options: &[Token], def: &Token) -> Result<Form, LprpError> {
+    let mut form = Form::default();
+    for kv in options.chunks(2) {
+        match (kv[0].as_symbol(), kv.get(1)) {
+            (Some(":args"), Some(Token::List(args))) => {
+                form.args = args.iter().map(parse_type).collect::<Result<_, _>>()?;
+            },
+            (Some(":args"), Some(Token::Nil)) => form.args = vec![],
+            (Some(":rest"), Some(t)) => form.rest = Some(parse_type(t)?),
+            (Some(":required"), Some(keys)) => form.required = parse_keys(keys, def)?,
+            (Some(":optional"), Some(keys)) => form.optional = parse_keys(keys, def)?,
+            _ => return schema_error("expected :args, :rest, :required or :optional", def),
+        }
+    }
+    let mut names: Vec<&String> = form.required.iter().chain(&form.optional).map(|(k, _)| k).collect();
+    names.sort();
+    if names.windows(2).any(|w| w[0] == w[1]) {
+        return schema_error("duplicate keyword", def);
+    }
+    Ok(form)
+}
+
+fn parse_keys(keys: &Token, def: &Token) -> Result<Vec<(String, Type)>, LprpError> {
+    let l = match keys {
+        Token::List(l) if l.len() % 2 == 0 => &l[..],
+        Token::Nil => &[],
+        _ => return schema_error("expected (:keyword type ...)", def),
+    };
+    l.chunks(2).map(|kv| {
+        if !kv[0].is_keyword() {
+            return schema_error("expected a keyword", &kv[0]);
+        }
+        Ok((kv[0].as_symbol().unwrap().to_string(), parse_type(&kv[1])?))
+    }).collect()
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let join = |ts: &[String]| ts.join(" ");
+        match self {
+            Type::Any => f.write_str("any"),
+            Type::Integer => f.write_str("integer"),
+            Type::Float => f.write_str("float"),
+            Type::Number => f.write_str("number"),
+            Type::Str => f.write_str("string"),
+            Type::Symbol => f.write_str("symbol"),
+            Type::Keyword => f.write_str("keyword"),
+            Type::Boolean => f.write_str("boolean"),
+            Type::Null => f.write_str("null"),
+            Type::List => f.write_str("list"),
+            Type::OneOf(ls) => {
+                let ls: Vec<String> = ls.iter().map(|l| l.to_string()).collect();
+                write!(f, "(one-of {})", join(&ls))
+            },
+            Type::Or(ts) => {
+                let ts: Vec<String> = ts.iter().map(|t| t.to_string()).collect();
+                write!(f, "(or {})", join(&ts))
+            },
+            Type::ListOf(t) => write!(f, "(list-of {})", t),
+            Type::Range(lo, hi) => write!(f, "(range {} {})", lo, hi),
+            Type::Named(n) => f.write_str(n),
+        }
+    }
+}
+
+// ***** Errors *****
+// A validation error. `path` locates the offending element as the index
+// to take at each level from the root, like `query::Query::paths`, and
+// `location` spells it out with form names and keywords, for example
+// `config > server[2] > :port`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError {
+    pub path: Vec<usize>,
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn describe(tk: &Token) -> String {
+    match tk {
+        Token::List(l) => {
+            match l.first().and_then(Token::as_symbol) {
+                Some(head) => format!("({} ...)", head),
+                None => "list".to_string(),
+            }
+        },
+        _ => format!("{} {}", tk.type_name(), tk),
+    }
+}
+
+// The location of `path` in `root`.
+pub(crate) fn locate(root: &Token, path: &[usize]) -> String {
+    let name = |tk: &Token| {
+        match tk {
+            Token::List(l) => l.first().and_then(Token::as_symbol).map(String::from),
+            _ => None,
+        }
+    };
+    let mut steps = vec![name(root).unwrap_or_else(|| "document".to_string())];
+    let mut tk = root;
+    for &i in path {
+        let l = match tk {
+            Token::List(l) => l,
+            _ => break,
+        };
+        let key = i.checked_sub(1).map(|k| &l[k]).filter(|k| k.is_keyword());
+        steps.push(match (key, name(&l[i])) {
+            (Some(k), _) => k.to_string(),
+            (None, Some(n)) => format!("{}[{}]", n, i),
+            (None, None) => format!("[{}]", i),
+        });
+        tk = &l[i];
+    }
+    steps.join(" > ")
+}
+
+// ***** Validation *****
+struct Validator<'a> {
+    schema: &'a Schema,
+    path: Vec<usize>,
+    errors: &'a mut Vec<SchemaError>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, root: &Token, message: String) {
+        self.errors.push(SchemaError {
+            path: self.path.clone(),
+            location: locate(root, &self.path),
+            message,
+        });
+    }
+
+    fn at<F: FnOnce(&mut Self)>(&mut self, i: usize, f: F) {
+        self.path.push(i);
+        f(self);
+        self.path.pop();
+    }
+
+    // Whether `tk` has the type, without reporting anything.
+    fn accepts(&self, ty: &Type, tk: &Token, root: &Token) -> bool {
+        let mut errors = vec![];
+        let mut v = Validator { schema: self.schema, path: self.path.clone(), errors: &mut errors };
+        v.check(ty, tk, root);
+        errors.is_empty()
+    }
+
+    fn check(&mut self, ty: &Type, tk: &Token, root: &Token) {
+        let ok = match ty {
+            Type::Any => true,
+            Type::Integer => matches!(tk, Token::Int(_)),
+            Type::Float => matches!(tk, Token::Float(_)),
+            Type::Number => matches!(tk, Token::Int(_)|Token::Float(_)),
+            Type::Str => matches!(tk, Token::Str(_)),
+            Type::Symbol => matches!(tk, Token::Symbol(_)) && !tk.is_keyword(),
+            Type::Keyword => tk.is_keyword(),
+            Type::Boolean => matches!(tk, Token::T|Token::Nil),
+            Type::Null => matches!(tk, Token::Nil) || tk.as_list() == Some(&[]),
+            Type::List => matches!(tk, Token::List(_)|Token::Nil),
+            Type::OneOf(ls) => ls.iter().any(|l| equal(l, tk)),
+            Type::Range(lo, hi) => tk.as_float().is_some_and(|x| *lo <= x && x <= *hi),
+            Type::ListOf(t) => {
+                match tk.as_list() {
+                    Some(l) => {
+                        for (i, x) in l.iter().enumerate() {
+                            self.at(i, |v| v.check(t, x, root));
+                        }
+                        return;
+                    },
+                    None => false,
+                }
+            },
+            Type::Or(ts) => {
+                // A form named by its head reports its own errors.
+                let head = tk.as_list().and_then(|l| l.first()).and_then(Token::as_symbol);
+                if let Some(t) = ts.iter().find(|t| self.resolves_to_form(t, head)) {
+                    return self.check(t, tk, root);
+                }
+                ts.iter().any(|t| self.accepts(t, tk, root))
+            },
+            Type::Named(n) => {
+                if let Some(form) = self.schema.forms.get(n) {
+                    return self.check_form(n, form, tk, root);
+                }
+                // Failures of the alias itself are reported by its name.
+                let mut errors = vec![];
+                let mut v = Validator { schema: self.schema, path: self.path.clone(), errors: &mut errors };
+                v.check(&self.schema.types[n], tk, root);
+                if errors.iter().all(|e| e.path != self.path) {
+                    self.errors.extend(errors);
+                    return;
+                }
+                false
+            },
+        };
+        if !ok {
+            self.error(root, format!("expected {}, found {}", ty, describe(tk)));
+        }
+    }
+
+    fn resolves_to_form(&self, mut ty: &'a Type, head: Option<&str>) -> bool {
+        while let Type::Named(n) = ty {
+            if self.schema.forms.contains_key(n) {
+                return Some(&n[..]) == head;
+            }
+            ty = &self.schema.types[n];
+        }
+        false
+    }
+
+    fn check_form(&mut self, name: &str, form: &Form, tk: &Token, root: &Token) {
+        let l = match tk.as_list() {
+            Some(l) if l.first().and_then(Token::as_symbol) == Some(name) => l,
+            _ => {
+                self.error(root, format!("expected ({} ...), found {}", name, describe(tk)));
+                return;
+            },
+        };
+
+        // Positional arguments run up to the first keyword, if the form
+        // takes any.
+        let has_keys = !form.required.is_empty() || !form.optional.is_empty();
+        let npos = match l[1..].iter().position(|x| has_keys && x.is_keyword()) {
+            Some(n) => n,
+            None => l.len() - 1,
+        };
+        let nargs = form.args.len();
+        if npos < nargs || (form.rest.is_none() && npos > nargs) {
+            let expected = if form.rest.is_some() { "at least " } else { "" };
+            self.error(root, format!(
+                "{} takes {}{} argument{}, found {}",
+                name, expected, nargs, if nargs == 1 { "" } else { "s" }, npos
+                ));
+        }
+        for (i, x) in l.iter().enumerate().take(npos + 1).skip(1) {
+            let ty = match form.args.get(i - 1).or(form.rest.as_ref()) {
+                Some(ty) => ty,
+                None => break,
+            };
+            self.at(i, |v| v.check(ty, x, root));
+        }
+
+        let mut seen = vec![];
+        let mut i = npos + 1;
+        while i < l.len() {
+            let key = l[i].as_symbol().unwrap_or_default();
+            let ty = form.required.iter()
+                .chain(&form.optional)
+                .find(|(k, _)| k == key)
+                .map(|(_, t)| t);
+            match (ty, l.get(i + 1)) {
+                (_, _) if !l[i].is_keyword() => {
+                    self.at(i, |v| v.error(root, format!("expected a keyword, found {}", describe(&l[i]))));
+                },
+                (None, _) => {
+                    self.at(i, |v| v.error(root, format!("unknown keyword {} for {}", key, name)));
+                },
+                (Some(_), _) if seen.contains(&key) => {
+                    self.at(i, |v| v.error(root, format!("duplicate keyword {}", key)));
+                },
+                (Some(_), None) => {
+                    self.at(i, |v| v.error(root, format!("missing value for {}", key)));
+                },
+                (Some(ty), Some(x)) => self.at(i + 1, |v| v.check(ty, x, root)),
+            }
+            seen.push(key);
+            i += 2;
+        }
+        for (key, _) in &form.required {
+            if !seen.contains(&&key[..]) {
+                self.error(root, format!("{} requires {}", name, key));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn config_schema() -> Schema {
+    Schema::parse("(schema config
+                     (form config :rest (or server db))
+                     (form server :args (symbol)
+                                  :required (:port port :host string)
+                                  :optional (:tags (list-of symbol) :mode (one-of fast slow)))
+                     (form db :args (string))
+                     (type port (range 1 65535)))").unwrap()
+}
+
+#[cfg(test)]
+fn errors(schema: &Schema, src: &str) -> Vec<String> {
+    match schema.validate(&read(src).unwrap()) {
+        Ok(()) => vec![],
+        Err(es) => es.iter().map(|e| e.to_string()).collect(),
+    }
+}
+
+#[test]
+fn test_schema_parse() {
+    config_schema();
+    assert!(Schema::parse("(schema x)").is_err());
+    assert!(Schema::parse("(schema integer (form a :args integer))").is_err());
+    assert!(Schema::parse("(schema integer (form a :what ()))").is_err());
+    assert!(Schema::parse("(schema integer (form a :required (:k integer) :optional (:k string)))").is_err());
+    assert!(Schema::parse("(schema integer (form a) (form a))").is_err());
+    assert!(Schema::parse("(schema integer (type a (range 1)))").is_err());
+    assert_eq!(
+        Schema::parse("(schema thing)").err(),
+        Some(LprpError::SchemaError("undefined type: thing".to_string()))
+        );
+    assert!(Schema::parse("(schema a (type a b) (type b (or integer (list-of a))))").is_ok());
+    assert_eq!(
+        Schema::parse("(schema a (type a b) (type b (or integer a)))").err(),
+        Some(LprpError::SchemaError("circular type: a".to_string()))
+        );
+}
+
+#[test]
+fn test_schema_validate() {
+    let schema = config_schema();
+    let ok = "(config
+                (server web :host \"a\" :port 80 :tags (x y))
+                (db \"main\")
+                (server api :port 8080 :host \"b\" :mode fast))";
+    assert_eq!(errors(&schema, ok), Vec::<String>::new());
+    assert_eq!(errors(&schema, "(config)"), Vec::<String>::new());
+
+    assert_eq!(
+        errors(&schema, "(config (server web :host \"a\" :port \"80\"))"),
+        vec!["config > server[1] > :port: expected port, found string \"80\""]
+        );
+    assert_eq!(
+        errors(&schema, "(config (db \"main\") (server web :host 1 :port 0 :mode slowest))"),
+        vec![
+            "config > server[2] > :host: expected string, found integer 1",
+            "config > server[2] > :port: expected port, found integer 0",
+            "config > server[2] > :mode: expected (one-of fast slow), found symbol slowest",
+        ]
+        );
+    assert_eq!(
+        errors(&schema, "(config (server :port 1 :colour red :port 2 :host))"),
+        vec![
+            "config > server[1]: server takes 1 argument, found 0",
+            "config > server[1] > [3]: unknown keyword :colour for server",
+            "config > server[1] > [5]: duplicate keyword :port",
+            "config > server[1] > [7]: missing value for :host",
+        ]
+        );
+    assert_eq!(
+        errors(&schema, "(config (server web :port 1) (db) (cache) 5)"),
+        vec![
+            "config > server[1]: server requires :host",
+            "config > db[2]: db takes 1 argument, found 0",
+            "config > cache[3]: expected (or server db), found (cache ...)",
+            "config > [4]: expected (or server db), found integer 5",
+        ]
+        );
+    assert_eq!(
+        errors(&schema, "(server web :host \"a\" :port 1 :tags (x 1))"),
+        vec![
+            "server: expected (config ...), found (server ...)",
+        ]
+        );
+
+    let schema = Schema::parse("(schema (list-of (or integer point)) (form point :args (number number)))").unwrap();
+    let es = schema.validate(&read("(1 (point 1 2.5) (point 1 \"2\"))").unwrap()).unwrap_err();
+    assert_eq!(es.len(), 1);
+    assert_eq!(es[0].path, vec![2, 2]);
+    assert_eq!(es[0].location, "document > point[2] > [2]");
+}
@@ -0,0 +1,405 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::convert::TryFrom;
+use std::fmt::Display;
+use serde::ser::{self, Serialize};
+use crate::reader::{self, Token, LprpError};
+
+
+// ***** Serializer *****
+// Serialize Rust data to `Token`s:
+//
+//   bool               t or nil
+//   integers, floats   Int and Float
+//   char, strings      Str
+//   bytes              a list of integers
+//   None, ()           nil
+//   Some(x)            x, or `(x)` if x is nil or `(nil)`, `((nil))` ...,
+//                      so that Some(false) or Some(None) is not None
+//   sequences, tuples  lists
+//   maps               alists of `(key . value)`
+//   structs            keyword plists `(:field value ...)`, or alists of
+//                      `(field . value)` with `StructStyle::Alist`
+//   enum variants      `name`, `(name value)`, `(name a b)` or
+//                      `(name :field value ...)`
+//
+// Underscores in field and variant names become hyphens, since the
+// reader does not allow them in symbols. Other than that names are kept
+// as they are; `#[serde(rename_all = "kebab-case")]` gives the usual
+// lisp spelling for enums.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StructStyle {
+    Plist,
+    Alist,
+}
+
+#[derive(Debug, Clone)]
+pub struct Serializer {
+    style: StructStyle,
+}
+
+impl Default for Serializer {
+    fn default() -> Self {
+        Serializer { style: StructStyle::Plist }
+    }
+}
+
+impl Serializer {
+    pub fn new() -> Serializer {
+        Serializer::default()
+    }
+
+    pub fn struct_style(mut self, style: StructStyle) -> Serializer {
+        self.style = style;
+        self
+    }
+}
+
+pub fn to_token<T: Serialize + ?Sized>(value: &T) -> Result<Token, LprpError> {
+    value.serialize(&Serializer::new())
+}
+
+// Serialize to text that `reader::read` reads back.
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, LprpError> {
+    token_to_string(&to_token(value)?)
+}
+
+pub(crate) fn token_to_string(tk: &Token) -> Result<String, LprpError> {
+    reader::write(tk).map_err(|e| match e {
+        LprpError::WriteError(message) => LprpError::SerdeError(message),
+        e => e,
+    })
+}
+
+impl ser::Error for LprpError {
+    fn custom<T: Display>(msg: T) -> Self {
+        LprpError::SerdeError(msg.to_string())
+    }
+}
+
+pub(crate) fn symbol(name: &str) -> Token {
+    Token::Symbol(name.replace('_', "-"))
+}
+
+fn keyword(name: &str) -> Token {
+    Token::Symbol(format!(":{}", name.replace('_', "-")))
+}
+
+// `(key . value)`, written without the dot when the value is a list.
+fn entry(key: Token, value: Token) -> Token {
+    match value {
+        Token::Nil => Token::List(vec![key]),
+        Token::List(mut l) => {
+            l.insert(0, key);
+            Token::List(l)
+        },
+        v => Token::List(vec![key, Token::Symbol(".".to_string()), v]),
+    }
+}
+
+// Whether `Some` wraps a value: one that reads as None, or that reads
+// as one so wrapped.
+pub(crate) fn wraps(tk: &Token) -> bool {
+    match tk {
+        Token::Nil => true,
+        Token::List(l) => match &l[..] {
+            [] => true,
+            [x] => wraps(x),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn tagged(tag: Option<Token>, items: Vec<Token>) -> Token {
+    match tag {
+        Some(t) => Token::List(Some(t).into_iter().chain(items).collect()),
+        None => Token::from(items),
+    }
+}
+
+impl<'a> ser::Serializer for &'a Serializer {
+    type Ok = Token;
+    type Error = LprpError;
+    type SerializeSeq = Seq<'a>;
+    type SerializeTuple = Seq<'a>;
+    type SerializeTupleStruct = Seq<'a>;
+    type SerializeTupleVariant = Seq<'a>;
+    type SerializeMap = Map<'a>;
+    type SerializeStruct = Struct<'a>;
+    type SerializeStructVariant = Struct<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Token, LprpError> {
+        Token::try_from(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Token, LprpError> {
+        Ok(Token::Str(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Token, LprpError> {
+        Ok(Token::from(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Token, LprpError> {
+        Ok(Token::from(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Token, LprpError> {
+        Ok(Token::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Token, LprpError> {
+        match value.serialize(self)? {
+            tk if wraps(&tk) => Ok(Token::List(vec![tk])),
+            tk => Ok(tk),
+        }
+    }
+
+    fn serialize_unit(self) -> Result<Token, LprpError> {
+        Ok(Token::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Token, LprpError> {
+        Ok(Token::Nil)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+        -> Result<Token, LprpError>
+    {
+        Ok(symbol(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T)
+        -> Result<Token, LprpError>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self, _name: &'static str, _index: u32, variant: &'static str, value: &T
+        ) -> Result<Token, LprpError>
+    {
+        Ok(Token::List(vec![symbol(variant), value.serialize(self)?]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Seq<'a>, LprpError> {
+        Ok(Seq { ser: self, tag: None, items: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Seq<'a>, LprpError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Seq<'a>, LprpError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self, _name: &'static str, _index: u32, variant: &'static str, len: usize
+        ) -> Result<Seq<'a>, LprpError>
+    {
+        Ok(Seq { ser: self, tag: Some(symbol(variant)), items: Vec::with_capacity(len) })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Map<'a>, LprpError> {
+        Ok(Map { ser: self, entries: vec![], key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Struct<'a>, LprpError> {
+        Ok(Struct { ser: self, tag: None, items: vec![] })
+    }
+
+    fn serialize_struct_variant(
+        self, _name: &'static str, _index: u32, variant: &'static str, _len: usize
+        ) -> Result<Struct<'a>, LprpError>
+    {
+        Ok(Struct { ser: self, tag: Some(symbol(variant)), items: vec![] })
+    }
+}
+
+pub struct Seq<'a> {
+    ser: &'a Serializer,
+    tag: Option<Token>,
+    items: Vec<Token>,
+}
+
+impl<'a> Seq<'a> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LprpError> {
+        self.items.push(value.serialize(self.ser)?);
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for Seq<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LprpError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(tagged(self.tag, self.items))
+    }
+}
+
+impl<'a> ser::SerializeTuple for Seq<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LprpError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(tagged(self.tag, self.items))
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Seq<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LprpError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(tagged(self.tag, self.items))
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Seq<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LprpError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(tagged(self.tag, self.items))
+    }
+}
+
+pub struct Map<'a> {
+    ser: &'a Serializer,
+    entries: Vec<Token>,
+    key: Option<Token>,
+}
+
+impl<'a> ser::SerializeMap for Map<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), LprpError> {
+        self.key = Some(key.serialize(self.ser)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LprpError> {
+        let key = self.key.take().ok_or_else(|| LprpError::SerdeError("value without a key".to_string()))?;
+        self.entries.push(entry(key, value.serialize(self.ser)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(Token::from(self.entries))
+    }
+}
+
+pub struct Struct<'a> {
+    ser: &'a Serializer,
+    tag: Option<Token>,
+    items: Vec<Token>,
+}
+
+impl<'a> Struct<'a> {
+    fn push<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), LprpError> {
+        let value = value.serialize(self.ser)?;
+        match self.ser.style {
+            StructStyle::Plist => {
+                self.items.push(keyword(key));
+                self.items.push(value);
+            },
+            StructStyle::Alist => self.items.push(entry(symbol(key), value)),
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeStruct for Struct<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+        -> Result<(), LprpError>
+    {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(tagged(self.tag, self.items))
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Struct<'a> {
+    type Ok = Token;
+    type Error = LprpError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+        -> Result<(), LprpError>
+    {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Token, LprpError> {
+        Ok(tagged(self.tag, self.items))
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use crate::borrowed;
+use crate::lexer::{Atom, Kind, Lexeme, Lexer, Span};
+use crate::reader::{LprpError, Token};
+
+
+// ***** Node *****
+// A form with the span of text it was read from. Where `read` stops at
+// the first mistake, `parse` puts an `Error` node in its place and goes
+// on, so editors still get a tree for the rest of the text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeKind {
+    Atom(Token),
+    Quote(Box<Node>),
+    List(Vec<Node>),
+    // Text that could not be read; see the diagnostics for why.
+    Error,
+}
+
+impl Node {
+    // The token this node reads as, or None if it contains an error.
+    pub fn to_token(&self) -> Option<Token> {
+        match &self.kind {
+            NodeKind::Atom(tk) => Some(tk.clone()),
+            NodeKind::Quote(node) => node.to_token().map(|tk| Token::Quote(Box::new(tk))),
+            NodeKind::List(nodes) => nodes.iter().map(Node::to_token).collect::<Option<_>>().map(Token::List),
+            NodeKind::Error => None,
+        }
+    }
+
+    // The token this node reads as, leaving out error nodes and the
+    // quotes around them.
+    pub fn partial_token(&self) -> Option<Token> {
+        match &self.kind {
+            NodeKind::Atom(tk) => Some(tk.clone()),
+            NodeKind::Quote(node) => node.partial_token().map(|tk| Token::Quote(Box::new(tk))),
+            NodeKind::List(nodes) => Some(Token::List(nodes.iter().filter_map(Node::partial_token).collect())),
+            NodeKind::Error => None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.kind == NodeKind::Error
+    }
+
+    pub fn children(&self) -> &[Node] {
+        match &self.kind {
+            NodeKind::List(nodes) => nodes,
+            NodeKind::Quote(node) => std::slice::from_ref(node),
+            _ => &[],
+        }
+    }
+}
+
+// ***** Diagnostic *****
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub error: LprpError,
+    pub message: String,
+    pub span: Span,
+    // Other places involved, e.g. where an unclosed list was given up.
+    pub notes: Vec<Note>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}: {}", self.error, self.message)
+    }
+}
+
+// 0-based line and column, in chars, of a byte offset.
+pub fn position(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset];
+    let line = before.matches('\n').count();
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[start..].chars().count())
+}
+
+// 1-based `line:col` of a byte offset, for messages.
+pub fn location(src: &str, offset: usize) -> String {
+    let (line, col) = position(src, offset);
+    format!("{}:{}", line + 1, col + 1)
+}
+
+// ***** Delimiters *****
+// Where the `)` of the `(` at `open` most likely belongs, going by
+// indentation: after the last lexeme before the first line that starts
+// no further right than the `(`. None if every later line is indented
+// deeper, in which case the end of input is as good a guess as any.
+pub fn missing_paren(src: &str, open: usize) -> Option<usize> {
+    let (_, col) = position(src, open);
+    let mut last = open + 1;
+    // The start of the current line once past the `(`, and whether only
+    // whitespace has come on it so far.
+    let mut line = None;
+    let mut indent = false;
+    for lexeme in Lexer::at(src, open + 1) {
+        let text = lexeme.text(src);
+        let fresh = text.rfind('\n').map(|i| lexeme.span.start + i + 1);
+        if lexeme.kind == Kind::Whitespace {
+            if fresh.is_some() {
+                line = fresh;
+                indent = true;
+            }
+            continue;
+        }
+        if let (Some(line), true) = (line, indent) {
+            if lexeme.span.start - line <= col && lexeme.kind != Kind::Comment {
+                return Some(last);
+            }
+        }
+        indent = false;
+        if lexeme.kind != Kind::Comment {
+            last = lexeme.span.end;
+        }
+        // A string may run over lines.
+        if fresh.is_some() {
+            line = fresh;
+        }
+    }
+    None
+}
+
+// Whether `src` is a whole input, as a REPL sees it: no list or string
+// is still open, and no quote is waiting for its form. Other mistakes,
+// such as a stray `)`, are left for `read` to report.
+pub fn is_complete(src: &str) -> bool {
+    let mut depth = 0usize;
+    let mut last = None;
+    for lexeme in Lexer::new(src).filter(|l| !l.is_trivia()) {
+        match lexeme.kind {
+            Kind::LParen => depth += 1,
+            Kind::RParen => depth = depth.saturating_sub(1),
+            Kind::Error(LprpError::DelimiterError(_)) => return false,
+            _ => (),
+        }
+        last = Some(lexeme.kind);
+    }
+    depth == 0 && last != Some(Kind::Quote)
+}
+
+#[test]
+fn test_is_complete() {
+    assert!(is_complete("(f 1) 'x ; done"));
+    assert!(is_complete(""));
+    assert!(is_complete("a)"));
+    assert!(!is_complete("(defun f (x)\n  (g x)"));
+    assert!(!is_complete("(print \"a (b"));
+    assert!(!is_complete("(list 1) '"));
+    assert!(!is_complete("(a ; )"));
+}
+
+// The error `read` gives for a list still open at the end of input.
+pub(crate) fn unclosed_error(src: &str, open: usize) -> LprpError {
+    let mut message = format!("unclosed `(` at {}, input ends at {}", location(src, open), location(src, src.len()));
+    if let Some(at) = missing_paren(src, open) {
+        message.push_str(&format!("; a `)` may be missing at {}", location(src, at)));
+    }
+    LprpError::DelimiterError(message)
+}
+
+// The error `read` gives for a stray `)` or an unterminated string.
+pub(crate) fn delimiter_error(src: &str, what: &str, at: usize) -> LprpError {
+    LprpError::DelimiterError(format!("{} at {}", what, location(src, at)))
+}
+
+// ***** Parse *****
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parse {
+    pub forms: Vec<Node>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Parse {
+    pub fn is_ok(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    // The forms as tokens, leaving out error nodes.
+    pub fn tokens(&self) -> Vec<Token> {
+        self.forms.iter().filter_map(Node::partial_token).collect()
+    }
+}
+
+// Read every form of `src`, recovering from errors. Malformed atoms
+// become error nodes, and a stray `)` is reported and skipped, so the
+// parens still give the tree its shape. A list left open is closed where
+// `missing_paren` suggests, if that reads with no more diagnostics, so
+// the forms after it are not swallowed.
+pub fn parse(src: &str) -> Parse {
+    let (parse, unclosed) = Parser::new(src, HashMap::new()).run();
+    let breaks: HashMap<usize, usize> = unclosed.into_iter()
+        .filter_map(|open| missing_paren(src, open).map(|at| (open, at)))
+        .collect();
+    if breaks.is_empty() {
+        return parse;
+    }
+    let (closed, _) = Parser::new(src, breaks).run();
+    if closed.diagnostics.len() <= parse.diagnostics.len() { closed } else { parse }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    lexer: Peekable<Lexer<'a>>,
+    diagnostics: Vec<Diagnostic>,
+    // Where to close lists left open, by the offset of their `(`.
+    breaks: HashMap<usize, usize>,
+    unclosed: Vec<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str, breaks: HashMap<usize, usize>) -> Parser<'a> {
+        Parser { src, lexer: Lexer::new(src).peekable(), diagnostics: vec![], breaks, unclosed: vec![] }
+    }
+
+    fn run(mut self) -> (Parse, Vec<usize>) {
+        let mut forms = vec![];
+        while let Some(lexeme) = self.next() {
+            match lexeme.kind {
+                Kind::RParen => {
+                    let error = LprpError::DelimiterError("unexpected `)`".to_string());
+                    self.report(error, "no list is open", lexeme.span, vec![]);
+                },
+                _ => forms.push(self.form(lexeme)),
+            }
+        }
+        (Parse { forms, diagnostics: self.diagnostics }, self.unclosed)
+    }
+
+    fn next(&mut self) -> Option<Lexeme> {
+        self.lexer.find(|l| !l.is_trivia())
+    }
+
+    // The start of the next lexeme that is not whitespace or a comment.
+    fn peek_start(&mut self) -> Option<usize> {
+        while self.lexer.next_if(Lexeme::is_trivia).is_some() {}
+        self.lexer.peek().map(|l| l.span.start)
+    }
+
+    fn report(&mut self, error: LprpError, message: &str, span: Span, notes: Vec<Note>) {
+        self.diagnostics.push(Diagnostic { error, message: message.to_string(), span, notes });
+    }
+
+    fn note(&self, message: &str, at: usize) -> Note {
+        Note { message: message.to_string(), span: Span { start: at, end: at } }
+    }
+
+    fn error(&mut self, error: LprpError, message: &str, span: Span) -> Node {
+        self.report(error, message, span, vec![]);
+        Node { kind: NodeKind::Error, span }
+    }
+
+    fn form(&mut self, lexeme: Lexeme) -> Node {
+        let span = lexeme.span;
+        match lexeme.kind {
+            Kind::LParen => self.list(span),
+            Kind::Quote => self.quote(span),
+            Kind::Atom(atom) => {
+                match borrowed::atom_token(atom, lexeme.text(self.src)) {
+                    Ok(tk) => Node { kind: NodeKind::Atom(tk.into_owned()), span },
+                    Err(e) => self.error(e, "number out of range", span),
+                }
+            },
+            Kind::Error(e @ LprpError::DelimiterError(_)) => {
+                let end = self.note("input ends here", self.src.len());
+                self.report(e, "input ends before the closing `\"`", span, vec![end]);
+                Node { kind: NodeKind::Error, span }
+            },
+            Kind::Error(LprpError::ReadNumError) => self.error(LprpError::ReadNumError, "malformed number", span),
+            Kind::Error(e) => self.error(e, "malformed atom", span),
+            Kind::Dot => self.error(LprpError::SyntaxError, "`.` outside a list", span),
+            _ => self.error(LprpError::SyntaxError, "unexpected `)`", span),
+        }
+    }
+
+    // The quoted form must follow the quote directly. Otherwise only the
+    // quote is an error, and what follows is read on its own.
+    fn quote(&mut self, span: Span) -> Node {
+        let follows = self.lexer.peek().is_some_and(|l| {
+            matches!(l.kind, Kind::LParen|Kind::Quote|Kind::Atom(_)|Kind::Error(_))
+                && l.kind != Kind::Atom(Atom::Wildcard)
+        });
+        if !follows {
+            return self.error(LprpError::SyntaxError, "`'` must be followed by a form", span);
+        }
+        let lexeme = self.lexer.next().unwrap();
+        let node = self.form(lexeme);
+        let span = Span { start: span.start, end: node.span.end };
+        Node { kind: NodeKind::Quote(Box::new(node)), span }
+    }
+
+    fn list(&mut self, open: Span) -> Node {
+        let mut items = vec![];
+        let mut dots = vec![];
+        let unclosed = LprpError::DelimiterError("unclosed `(`".to_string());
+        let end = loop {
+            let at = self.breaks.get(&open.start).copied();
+            if let Some(at) = at.filter(|&at| self.peek_start().is_none_or(|start| start >= at)) {
+                let message = format!("a `)` may be missing at {}", location(self.src, at));
+                let note = self.note("a `)` may belong here", at);
+                self.report(unclosed, &message, open, vec![note]);
+                break at;
+            }
+            match self.next() {
+                Some(Lexeme { kind: Kind::RParen, span }) => break span.end,
+                Some(Lexeme { kind: Kind::Dot, span }) => {
+                    dots.push(items.len());
+                    items.push(Node { kind: NodeKind::Atom(Token::Symbol(".".to_string())), span });
+                },
+                Some(l) => items.push(self.form(l)),
+                None => {
+                    let mut notes = vec![self.note("input ends here", self.src.len())];
+                    notes.extend(missing_paren(self.src, open.start).map(|at| self.note("a `)` may belong here", at)));
+                    self.report(unclosed, "input ends before its `)`", open, notes);
+                    self.unclosed.push(open.start);
+                    break self.src.len();
+                },
+            }
+        };
+        // A dot may only come once, before the last element.
+        let last = items.len();
+        for (n, i) in dots.into_iter().enumerate() {
+            if n > 0 || i == 0 || i + 2 != last {
+                let span = items[i].span;
+                items[i] = self.error(LprpError::SyntaxError, "`.` must come before the last element", span);
+            }
+        }
+        Node { kind: NodeKind::List(items), span: Span { start: open.start, end } }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let src = "(defun f (x) ; doc\n  '(x . rest))\n(g 1)";
+    let parse = parse(src);
+    assert!(parse.is_ok());
+    assert_eq!(parse.tokens(), crate::reader::read_all(src).unwrap());
+    assert_eq!(parse.forms[0].span, Span { start: 0, end: 33 });
+    let quote = &parse.forms[0].children()[3];
+    assert_eq!(&src[quote.span.start..quote.span.end], "'(x . rest)");
+    assert_eq!(parse.forms[1].span, Span { start: 34, end: 39 });
+
+    crate::core::for_random_lists(|a, _| {
+        let src = crate::value::Value::from(a).to_string();
+        let parse = self::parse(&src);
+        assert!(parse.is_ok(), "{}", src);
+        assert_eq!(parse.forms[0].to_token().as_ref(), Some(a));
+    });
+}
+
+#[test]
+fn test_parse_recover() {
+    let src = "(let ((x 1-2) (y é)) 'y) )\n(:a: ' (f . g h) 99999999999999999999 ok)";
+    let parse = parse(src);
+    let errors: Vec<(&str, LprpError)> = parse.diagnostics.iter()
+        .map(|d| (&src[d.span.start..d.span.end], d.error.clone()))
+        .collect();
+    assert_eq!(errors, vec![
+        ("1-2", LprpError::ReadNumError),
+        ("é", LprpError::SyntaxError),
+        (")", LprpError::DelimiterError("unexpected `)`".to_string())),
+        (":a:", LprpError::SyntaxError),
+        ("'", LprpError::SyntaxError),
+        (".", LprpError::SyntaxError),
+        ("99999999999999999999", LprpError::ReadNumError),
+    ]);
+    assert_eq!(parse.forms.len(), 2);
+    assert!(parse.forms[0].children()[1].children()[0].children()[1].is_error());
+    assert_eq!(parse.forms[0].to_token(), None);
+    assert_eq!(parse.tokens(), crate::reader::read_all("(let ((x) (y)) 'y) ((f g h) ok)").unwrap());
+    assert_eq!(position("(a\n (é b", 8), (1, 4));
+    assert_eq!(parse.diagnostics[0].to_string(), "Read Num Error: malformed number");
+}
+
+#[test]
+fn test_parse_delimiters() {
+    // The first form is closed where its indentation ends, so the second
+    // is still read on its own.
+    let src = "(defun f (x)\n  (g x)\n\n(defun h ()\n  \"s)\n";
+    let parse = parse(src);
+    assert_eq!(parse.forms.len(), 2);
+    assert_eq!(parse.forms[0].to_token(), crate::reader::read("(defun f (x) (g x))").ok());
+    let messages: Vec<String> = parse.diagnostics.iter().map(Diagnostic::to_string).collect();
+    assert_eq!(messages, vec![
+        "Delimiter Error: unclosed `(`: a `)` may be missing at 2:8",
+        "Delimiter Error: unterminated string: input ends before the closing `\"`",
+        "Delimiter Error: unclosed `(`: input ends before its `)`",
+    ]);
+    assert_eq!(parse.diagnostics[0].span, Span { start: 0, end: 1 });
+    assert_eq!(parse.diagnostics[0].notes[0].span, Span { start: 20, end: 20 });
+    assert_eq!(parse.diagnostics[2].notes[0].span, Span { start: src.len(), end: src.len() });
+
+    assert_eq!(missing_paren("(a\n  (b\n  c)", 0), None);
+    assert_eq!(missing_paren("(a\n  (b\n  c)", 6), Some(7));
+    assert_eq!(missing_paren("(a ; (\n(b)", 0), Some(2));
+    assert_eq!(missing_paren("(a \"s\n\" (b)\n  c\n(d)", 0), Some(15));
+    assert_eq!(missing_paren("(a\n  ; c\n  b\n(d)", 0), Some(12));
+    assert_eq!(missing_paren("  (a\n\tb\n x", 2), Some(4));
+}
+
+// Guessing where a `)` is missing takes one pass, however long the
+// list left open is.
+#[test]
+fn test_missing_paren_linear() {
+    let src = format!("(root\n{}", "  (item :port 8080 :host \"example.com\")\n".repeat(40000));
+    let start = std::time::Instant::now();
+    assert_eq!(missing_paren(&src, 0), None);
+    assert!(crate::reader::read(&src).is_err());
+    assert!(start.elapsed().as_secs() < 5, "{:?}", start.elapsed());
+}
@@ -0,0 +1,605 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use crate::reader::{Token, LprpError};
+use crate::condition::Condition;
+use crate::eval::{Builtin, Continuation, Lambda, NativeFn};
+use crate::heap;
+use crate::vm::Closure;
+
+
+// Runtime value of the evaluator.
+//
+// Lists are built from shared, mutable cons cells, so `cdr` is O(1),
+// sublists are shared instead of copied, and `eq` can tell two equal
+// lists apart. Cells are reference counted; cycles made with `rplaca`
+// and `rplacd` are reclaimed by `heap::collect`.
+#[derive(Clone, Default)]
+pub enum Value {
+    T,
+    #[default]
+    Nil,
+    Int(i64),
+    Float(f64),
+    Symbol(String),
+    Str(String),
+    Pair(Rc<Pair>),
+    Lambda(Rc<Lambda>),
+    // A compiled function.
+    Closure(Rc<Closure>),
+    Builtin(&'static Builtin),
+    Continuation(Rc<Continuation>),
+    Native(Rc<NativeFn>),
+    Condition(Rc<Condition>),
+}
+
+pub struct Pair {
+    pub(crate) car: RefCell<Value>,
+    pub(crate) cdr: RefCell<Value>,
+}
+
+// Unlink the cdr chain iteratively, so that dropping a long list
+// does not overflow the stack.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut next = mem::take(self.cdr.get_mut());
+        while let Value::Pair(rc) = next {
+            match Rc::try_unwrap(rc) {
+                Ok(mut pair) => next = mem::take(pair.cdr.get_mut()),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Value {
+    pub fn cons(car: Value, cdr: Value) -> Value {
+        let pair = Rc::new(Pair { car: RefCell::new(car), cdr: RefCell::new(cdr) });
+        heap::register(&pair);
+        Value::Pair(pair)
+    }
+
+    pub fn list(items: Vec<Value>) -> Value {
+        Value::list_with_tail(items, Value::Nil)
+    }
+
+    pub fn list_with_tail(items: Vec<Value>, tail: Value) -> Value {
+        items.into_iter()
+            .rev()
+            .fold(tail, |acc, v| Value::cons(v, acc))
+    }
+
+    pub fn sym(name: &str) -> Value {
+        Value::Symbol(name.to_string())
+    }
+
+    pub fn car(&self) -> Option<Value> {
+        match self {
+            Value::Pair(p) => Some(p.car.borrow().clone()),
+            _ => None,
+        }
+    }
+
+    pub fn cdr(&self) -> Option<Value> {
+        match self {
+            Value::Pair(p) => Some(p.cdr.borrow().clone()),
+            _ => None,
+        }
+    }
+
+    // Replace the car of a cons cell in place. Returns false for
+    // anything else.
+    pub fn set_car(&self, v: Value) -> bool {
+        match self {
+            Value::Pair(p) => {
+                p.car.replace(v);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn set_cdr(&self, v: Value) -> bool {
+        match self {
+            Value::Pair(p) => {
+                p.cdr.replace(v);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Whether both values are the same cons cell.
+    pub fn ptr_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Pair(a), Value::Pair(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    // Everything except `nil` is true.
+    pub fn is_true(&self) -> bool {
+        !self.is_nil()
+    }
+
+    pub fn is_procedure(&self) -> bool {
+        matches!(
+            self,
+            Value::Lambda(_)|Value::Closure(_)|Value::Builtin(_)
+                |Value::Continuation(_)|Value::Native(_)
+            )
+    }
+
+    pub fn as_symbol(&self) -> Option<&str> {
+        match self {
+            Value::Symbol(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Iterate over the elements of a list. An improper tail is ignored.
+    pub fn iter(&self) -> Iter {
+        Iter { cur: self.clone() }
+    }
+
+    // Elements of a proper list, or `None` for anything else, including
+    // circular lists.
+    pub fn to_vec(&self) -> Option<Vec<Value>> {
+        let mut v = vec![];
+        let mut cur = self.clone();
+        let mut slow = self.clone();
+        loop {
+            match cur {
+                Value::Nil => return Some(v),
+                Value::Pair(ref p) => {
+                    v.push(p.car.borrow().clone());
+                    let next = p.cdr.borrow().clone();
+                    cur = next;
+                },
+                _ => return None,
+            }
+            if v.len() % 2 == 0 {
+                slow = slow.cdr().unwrap();
+                if slow.ptr_eq(&cur) {
+                    return None;
+                }
+            }
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::T => "boolean",
+            Value::Nil => "null",
+            Value::Int(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Symbol(_) => "symbol",
+            Value::Str(_) => "string",
+            Value::Pair(_) => "cons",
+            Value::Lambda(_)|Value::Closure(_)|Value::Builtin(_)
+                |Value::Continuation(_)|Value::Native(_) => {
+                "function"
+            },
+            Value::Condition(_) => "condition",
+        }
+    }
+}
+
+pub struct Iter {
+    cur: Value,
+}
+
+impl Iterator for Iter {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        let (car, cdr) = match &self.cur {
+            Value::Pair(p) => (p.car.borrow().clone(), p.cdr.borrow().clone()),
+            _ => return None,
+        };
+        self.cur = cdr;
+        Some(car)
+    }
+}
+
+#[test]
+fn test_value_list() {
+    let l = Value::list(vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+    assert_eq!(l.car(), Some(Value::Int(1)));
+    assert_eq!(l.cdr().and_then(|d| d.car()), Some(Value::Int(2)));
+    assert_eq!(l.iter().count(), 3);
+    assert_eq!(
+        l.to_vec(),
+        Some(vec![Value::Int(1), Value::Int(2), Value::Int(3)])
+        );
+
+    let dotted = Value::cons(Value::Int(1), Value::Int(2));
+    assert_eq!(dotted.to_vec(), None);
+    assert_eq!(Value::Nil.to_vec(), Some(vec![]));
+}
+
+#[test]
+fn test_value_drop_long_list() {
+    let l = Value::list((0..1_000_000).map(Value::Int).collect());
+    assert_eq!(l.iter().count(), 1_000_000);
+    drop(l);
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::T, Value::T) => true,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Pair(_), Value::Pair(_)) => {
+                let mut l = self.clone();
+                let mut r = other.clone();
+                loop {
+                    let (nl, nr) = match (&l, &r) {
+                        (Value::Pair(a), Value::Pair(b)) => {
+                            if Rc::ptr_eq(a, b) {
+                                return true;
+                            }
+                            if *a.car.borrow() != *b.car.borrow() {
+                                return false;
+                            }
+                            (a.cdr.borrow().clone(), b.cdr.borrow().clone())
+                        },
+                        _ => return l == r,
+                    };
+                    l = nl;
+                    r = nr;
+                }
+            },
+            (Value::Lambda(a), Value::Lambda(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => a.name == b.name,
+            (Value::Continuation(a), Value::Continuation(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Condition(a), Value::Condition(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// ***** Printer *****
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write_value(f, self, &mut HashSet::new())
+    }
+}
+
+// `open` holds the cells being printed, so that a circular reference
+// prints as `...` instead of looping.
+fn write_value(f: &mut fmt::Formatter, v: &Value, open: &mut HashSet<*const Pair>)
+    -> Result<(), fmt::Error>
+{
+    let p = match v {
+        Value::Pair(p) => p,
+        _ => return write_atom(f, v),
+    };
+    if open.contains(&Rc::as_ptr(p)) {
+        return f.write_str("...");
+    }
+    let car = p.car.borrow().clone();
+    let cdr = p.cdr.borrow().clone();
+    if let (Value::Symbol(q), Some(x), Some(Value::Nil)) = (&car, cdr.car(), cdr.cdr()) {
+        if q == "quote" {
+            open.insert(Rc::as_ptr(p));
+            f.write_str("'")?;
+            write_value(f, &x, open)?;
+            open.remove(&Rc::as_ptr(p));
+            return Ok(());
+        }
+    }
+
+    let mut cells = vec![];
+    let mut cur = v.clone();
+    f.write_str("(")?;
+    while let Value::Pair(p) = &cur {
+        if !cells.is_empty() {
+            f.write_str(" ")?;
+        }
+        if open.contains(&Rc::as_ptr(p)) {
+            f.write_str("...")?;
+            cur = Value::Nil;
+            break;
+        }
+        open.insert(Rc::as_ptr(p));
+        cells.push(Rc::as_ptr(p));
+        let car = p.car.borrow().clone();
+        write_value(f, &car, open)?;
+        let next = p.cdr.borrow().clone();
+        cur = next;
+    }
+    if !cur.is_nil() {
+        f.write_str(" . ")?;
+        write_value(f, &cur, open)?;
+    }
+    for c in cells {
+        open.remove(&c);
+    }
+    f.write_str(")")
+}
+
+fn write_atom(f: &mut fmt::Formatter, v: &Value) -> Result<(), fmt::Error> {
+    match v {
+        Value::T => f.write_str("t"),
+        Value::Nil => f.write_str("nil"),
+        Value::Int(i) => write!(f, "{}", i),
+        // Plain decimals only: `{:?}` switches to `1e20`, which the
+        // reader takes as `1 e20`. There is no syntax for inf or NaN.
+        Value::Float(x) if x.is_finite() => {
+            let s = x.to_string();
+            match s.contains('.') {
+                true => f.write_str(&s),
+                false => write!(f, "{}.0", s),
+            }
+        },
+        Value::Float(x) => write!(f, "#<float {}>", x),
+        Value::Symbol(s) => f.write_str(s),
+        Value::Str(s) => write!(f, "\"{}\"", s),
+        Value::Pair(_) => unreachable!(),
+        Value::Lambda(l) => {
+            match l.name() {
+                Some(name) => write!(f, "#<lambda {}>", name),
+                None => f.write_str("#<lambda>"),
+            }
+        },
+        Value::Closure(c) => {
+            match c.name() {
+                Some(name) => write!(f, "#<lambda {}>", name),
+                None => f.write_str("#<lambda>"),
+            }
+        },
+        Value::Builtin(b) => write!(f, "#<builtin {}>", b.name),
+        Value::Continuation(_) => f.write_str("#<continuation>"),
+        Value::Native(n) => write!(f, "#<builtin {}>", n.name),
+        Value::Condition(c) => write!(f, "#<condition {}>", c),
+    }
+}
+
+// Tokens print as the values they convert to, without making the cons
+// cells: `(a . (b))` prints as `(a b)` and `(quote x)` as `'x`.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write_token(f, self)
+    }
+}
+
+enum Elem<'a> {
+    Quote,
+    Token(&'a Token),
+}
+
+impl<'a> Elem<'a> {
+    fn is_quote(&self) -> bool {
+        match self {
+            Elem::Quote => true,
+            Elem::Token(tk) => tk.as_symbol() == Some("quote"),
+        }
+    }
+}
+
+// The elements of a token as a list, with the atom after a dot. None
+// for an atom.
+fn spine(tk: &Token) -> Option<(Vec<Elem<'_>>, Option<&Token>)> {
+    match tk {
+        Token::Nil => Some((vec![], None)),
+        Token::Quote(q) => Some((vec![Elem::Quote, Elem::Token(q)], None)),
+        Token::List(l) => {
+            match l.len().checked_sub(2).filter(|&i| l[i].as_symbol() == Some(".")) {
+                Some(i) => {
+                    let mut items: Vec<Elem> = l[..i].iter().map(Elem::Token).collect();
+                    let tail = match spine(&l[i + 1]) {
+                        Some((rest, tail)) => {
+                            items.extend(rest);
+                            tail
+                        },
+                        None => Some(&l[i + 1]),
+                    };
+                    Some((items, tail))
+                },
+                None => Some((l.iter().map(Elem::Token).collect(), None)),
+            }
+        },
+        _ => None,
+    }
+}
+
+fn write_token(f: &mut fmt::Formatter, tk: &Token) -> Result<(), fmt::Error> {
+    let write_elem = |f: &mut fmt::Formatter, e: &Elem| match e {
+        Elem::Quote => f.write_str("quote"),
+        Elem::Token(tk) => write_token(f, tk),
+    };
+    let (items, tail) = match spine(tk) {
+        Some(spine) => spine,
+        None => return write_atom(f, &Value::from(tk)),
+    };
+    match (&items[..], tail) {
+        ([], None) => f.write_str("nil"),
+        ([], Some(tail)) => write_token(f, tail),
+        ([q, x], None) if q.is_quote() => {
+            f.write_str("'")?;
+            write_elem(f, x)
+        },
+        _ => {
+            f.write_str("(")?;
+            for (i, e) in items.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(" ")?;
+                }
+                write_elem(f, e)?;
+            }
+            if let Some(tail) = tail {
+                f.write_str(" . ")?;
+                write_token(f, tail)?;
+            }
+            f.write_str(")")
+        },
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[test]
+fn test_value_display() {
+    let v = Value::from(crate::reader::read("(a 1 2.0 \"s\" '(t nil))").unwrap());
+    assert_eq!(v.to_string(), "(a 1 2.0 \"s\" '(t nil))");
+
+    let dotted = Value::cons(Value::Int(1), Value::Int(2));
+    assert_eq!(dotted.to_string(), "(1 . 2)");
+}
+
+#[test]
+fn test_value_display_float() {
+    use crate::reader::read;
+    for x in &[1.5, -0.0, 1e20, 1e-5, 0.1 + 0.2, f64::MAX, f64::MIN_POSITIVE, -5e-324] {
+        let text = Value::Float(*x).to_string();
+        assert_eq!(read(&text), Ok(Token::Float(*x)), "{}", text);
+    }
+    assert_eq!(Value::Float(1e20).to_string(), "100000000000000000000.0");
+    assert_eq!(Value::Float(0.00001).to_string(), "0.00001");
+    assert_eq!(Value::Float(f64::NEG_INFINITY).to_string(), "#<float -inf>");
+    assert!(read(&Value::Float(f64::NAN).to_string()).is_err());
+}
+
+#[test]
+fn test_token_display() {
+    use crate::reader::read;
+    let cases = [
+        "(a 1 2.0 \"s\" '(t nil))", "(a . b)", "(a . (b c))", "(a . nil)", "(a . 'x)", "(quote x)",
+        "(quote x y)", "('quote x)", "''x", "(a . (b . (c . d)))", "()", "(nil)",
+    ];
+    for src in &cases {
+        let tk = read(src).unwrap();
+        assert_eq!(tk.to_string(), Value::from(&tk).to_string(), "{}", src);
+    }
+    assert_eq!(read("(a . (b . c))").unwrap().to_string(), "(a b . c)");
+    crate::core::for_random_lists(|a, _| assert_eq!(a.to_string(), Value::from(a).to_string()));
+}
+
+// ***** Token conversion *****
+impl From<&Token> for Value {
+    fn from(tk: &Token) -> Self {
+        match tk {
+            Token::T => Value::T,
+            Token::Nil => Value::Nil,
+            Token::Int(i) => Value::Int(*i),
+            Token::Float(f) => Value::Float(*f),
+            Token::Symbol(s) => Value::Symbol(s.to_string()),
+            Token::Str(s) => Value::Str(s.to_string()),
+            Token::Quote(q) => {
+                Value::list(vec![Value::sym("quote"), Value::from(&**q)])
+            },
+            Token::List(l) => {
+                match l.len().checked_sub(2).filter(|&i| l[i].as_symbol() == Some(".")) {
+                    Some(i) => {
+                        Value::list_with_tail(
+                            l[..i].iter().map(Value::from).collect(),
+                            Value::from(&l[i + 1])
+                            )
+                    },
+                    None => Value::list(l.iter().map(Value::from).collect()),
+                }
+            },
+        }
+    }
+}
+
+impl From<Token> for Value {
+    fn from(tk: Token) -> Self {
+        Value::from(&tk)
+    }
+}
+
+impl TryFrom<&Value> for Token {
+    type Error = LprpError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v {
+            Value::T => Ok(Token::T),
+            Value::Nil => Ok(Token::Nil),
+            Value::Int(i) => Ok(Token::Int(*i)),
+            Value::Float(f) => Ok(Token::Float(*f)),
+            Value::Symbol(s) => Ok(Token::Symbol(s.to_string())),
+            Value::Str(s) => Ok(Token::Str(s.to_string())),
+            Value::Pair(_) => {
+                let items = match v.to_vec() {
+                    Some(items) => items,
+                    None => return dotted_token(v),
+                };
+                if let [Value::Symbol(q), x] = &items[..] {
+                    if q == "quote" {
+                        return Ok(Token::Quote(Box::new(Token::try_from(x)?)));
+                    }
+                }
+                items.iter()
+                    .map(Token::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(Token::List)
+            },
+            Value::Lambda(_)|Value::Closure(_)|Value::Builtin(_)|Value::Continuation(_)
+                |Value::Native(_)|Value::Condition(_) => Err(LprpError::ConversionError),
+        }
+    }
+}
+
+// An improper list becomes a list with a `.` before its tail. Cycles
+// cannot be converted.
+fn dotted_token(v: &Value) -> Result<Token, LprpError> {
+    let mut items = vec![];
+    let mut rest = v.clone();
+    let mut slow = v.clone();
+    while let Value::Pair(_) = rest {
+        items.push(Token::try_from(&rest.car().unwrap())?);
+        rest = rest.cdr().unwrap();
+        if items.len() % 2 == 0 {
+            slow = slow.cdr().unwrap();
+            if slow.ptr_eq(&rest) {
+                return Err(LprpError::ConversionError);
+            }
+        }
+    }
+    items.push(Token::Symbol(".".to_string()));
+    items.push(Token::try_from(&rest)?);
+    Ok(Token::List(items))
+}
+
+#[test]
+fn test_value_token_conversion() {
+    let tk = crate::reader::read("(cons '(1 2.0) \"s\")").unwrap();
+    let v = Value::from(&tk);
+    assert_eq!(Token::try_from(&v), Ok(tk));
+
+    let dotted = Value::cons(Value::Int(1), Value::Int(2));
+    let tk = crate::reader::read("(1 . 2)").unwrap();
+    assert_eq!(Token::try_from(&dotted), Ok(tk.clone()));
+    assert_eq!(Value::from(&tk).to_string(), "(1 . 2)");
+
+    let circular = Value::list(vec![Value::Int(1), Value::Int(2)]);
+    circular.cdr().unwrap().set_cdr(circular.clone());
+    assert_eq!(Token::try_from(&circular), Err(LprpError::ConversionError));
+    circular.set_cdr(Value::Nil);
+}
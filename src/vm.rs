@@ -0,0 +1,297 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::compile::{compile, Op, Proto};
+use crate::condition::Condition;
+use crate::eval::{check_arity, Frame, Interp, State};
+use crate::reader::{read_all, Token, LprpError};
+use crate::value::Value;
+
+
+// ***** Virtual machine *****
+// Compiled code runs on the same machine as the tree-walking evaluator.
+// A suspended activation is just another frame, so calls between
+// compiled closures, lambdas and builtins, `call/cc`, `dynamic-wind` and
+// the condition system all work unchanged, and tail calls still push
+// nothing.
+
+// Variables of one call of a compiled function. A slot is `None` while
+// a variable defined in the body has not been defined yet.
+pub struct VmEnv {
+    slots: RefCell<Vec<Option<Value>>>,
+    proto: Rc<Proto>,
+    parent: Option<Rc<VmEnv>>,
+}
+
+impl VmEnv {
+    fn up(self: &Rc<VmEnv>, depth: u16) -> &Rc<VmEnv> {
+        let mut env = self;
+        for _ in 0..depth {
+            env = env.parent.as_ref().unwrap();
+        }
+        env
+    }
+
+    // Find a bound variable by name, starting from this frame.
+    fn find(self: &Rc<VmEnv>, name: &str) -> Option<(Rc<VmEnv>, usize)> {
+        let mut env = Some(self);
+        while let Some(e) = env {
+            let found = e.proto.names.iter()
+                .position(|n| n == name)
+                .filter(|&i| e.slots.borrow()[i].is_some());
+            if let Some(i) = found {
+                return Some((e.clone(), i));
+            }
+            env = e.parent.as_ref();
+        }
+        None
+    }
+}
+
+pub struct Closure {
+    proto: Rc<Proto>,
+    env: Option<Rc<VmEnv>>,
+}
+
+impl Closure {
+    pub fn name(&self) -> Option<&str> {
+        self.proto.name.as_deref()
+    }
+}
+
+// A compiled function that is running or suspended in a call.
+#[derive(Clone)]
+pub(crate) struct Activation {
+    proto: Rc<Proto>,
+    pc: usize,
+    env: Option<Rc<VmEnv>>,
+    stack: Vec<Value>,
+}
+
+fn unbound(name: &str) -> Condition {
+    Condition::new("unbound-variable", "unbound variable", vec![Value::sym(name)])
+}
+
+impl Interp {
+    // Compile and run a form.
+    pub fn exec(&mut self, tk: &Token) -> Result<Value, LprpError> {
+        self.exec_value(&Value::from(tk))
+    }
+
+    // Compile and run every form in `src` and return the value of the
+    // last one.
+    pub fn exec_str(&mut self, src: &str) -> Result<Value, LprpError> {
+        let mut last = Value::Nil;
+        for tk in read_all(src)? {
+            last = self.exec(&tk)?;
+        }
+        Ok(last)
+    }
+
+    pub fn exec_value(&mut self, expr: &Value) -> Result<Value, LprpError> {
+        let act = Activation { proto: compile(expr), pc: 0, env: None, stack: vec![] };
+        self.run(State::Exec(act))
+    }
+
+    pub(crate) fn apply_closure(&mut self, c: &Closure, mut args: Vec<Value>)
+        -> Result<State, Condition>
+    {
+        let proto = &c.proto;
+        let name = proto.name.as_deref().unwrap_or("lambda");
+        let max = if proto.rest { None } else { Some(proto.nparams) };
+        check_arity(name, proto.nparams, max, args.len())?;
+
+        let rest = args.split_off(proto.nparams);
+        let mut slots: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+        if proto.rest {
+            slots.push(Some(Value::list(rest)));
+        }
+        slots.resize(proto.names.len(), None);
+        let env = VmEnv { slots: RefCell::new(slots), proto: proto.clone(), parent: c.env.clone() };
+        Ok(State::Exec(Activation {
+            proto: proto.clone(),
+            pc: 0,
+            env: Some(Rc::new(env)),
+            stack: vec![],
+        }))
+    }
+
+    // Run `act` until it calls, returns or fails.
+    pub(crate) fn exec_step(&mut self, mut act: Activation) -> Result<State, Condition> {
+        loop {
+            let op = act.proto.code[act.pc];
+            act.pc += 1;
+            match op {
+                Op::Const(i) => act.stack.push(act.proto.consts[i as usize].clone()),
+                Op::Local(depth, i) => {
+                    let env = act.env.as_ref().unwrap().up(depth);
+                    let v = env.slots.borrow()[i as usize].clone();
+                    let v = match v {
+                        Some(v) => v,
+                        None => self.lookup_outer(env, &env.proto.names[i as usize])?,
+                    };
+                    act.stack.push(v);
+                },
+                Op::SetLocal(depth, i) => {
+                    let v = act.stack.last().cloned().unwrap();
+                    let env = act.env.as_ref().unwrap().up(depth);
+                    let bound = env.slots.borrow()[i as usize].is_some();
+                    if bound {
+                        env.slots.borrow_mut()[i as usize] = Some(v);
+                    } else {
+                        self.set_outer(env, &env.proto.names[i as usize], v)?;
+                    }
+                },
+                Op::Global(i) => {
+                    let name = act.proto.consts[i as usize].as_symbol().unwrap();
+                    match self.global.lookup(name) {
+                        Some(v) => act.stack.push(v),
+                        None => return Err(unbound(name)),
+                    }
+                },
+                Op::SetGlobal(i) => {
+                    let name = act.proto.consts[i as usize].as_symbol().unwrap();
+                    if !self.global.set(name, act.stack.last().cloned().unwrap()) {
+                        return Err(unbound(name));
+                    }
+                },
+                Op::DefLocal(i) => {
+                    let v = act.stack.pop();
+                    act.env.as_ref().unwrap().slots.borrow_mut()[i as usize] = v;
+                },
+                Op::DefGlobal(i) => {
+                    let name = act.proto.consts[i as usize].as_symbol().unwrap();
+                    self.global.define(name, act.stack.pop().unwrap());
+                },
+                Op::Pop => {
+                    act.stack.pop();
+                },
+                Op::Jump(to) => act.pc = to as usize,
+                Op::JumpIfNil(to) => {
+                    if act.stack.pop().unwrap().is_nil() {
+                        act.pc = to as usize;
+                    }
+                },
+                Op::AndJump(to) => {
+                    if act.stack.last().unwrap().is_nil() {
+                        act.pc = to as usize;
+                    } else {
+                        act.stack.pop();
+                    }
+                },
+                Op::OrJump(to) => {
+                    if act.stack.last().unwrap().is_true() {
+                        act.pc = to as usize;
+                    } else {
+                        act.stack.pop();
+                    }
+                },
+                Op::Closure(i) => {
+                    let proto = act.proto.protos[i as usize].clone();
+                    let c = Closure { proto, env: act.env.clone() };
+                    act.stack.push(Value::Closure(Rc::new(c)));
+                },
+                Op::Call(n)|Op::TailCall(n) => {
+                    let args = act.stack.split_off(act.stack.len() - n as usize);
+                    let f = act.stack.pop().unwrap();
+                    if let Op::Call(_) = op {
+                        self.push(Frame::Vm(act));
+                    }
+                    return Ok(State::Apply(f, args));
+                },
+                Op::Return => return Ok(State::Return(act.stack.pop().unwrap())),
+                Op::DefineCondition(k, p) => {
+                    let kind = act.proto.consts[k as usize].clone();
+                    let parent = &act.proto.consts[p as usize];
+                    self.kinds.define(kind.as_symbol().unwrap(), parent.as_symbol().unwrap());
+                    act.stack.push(kind);
+                },
+                Op::Raise(i) => {
+                    match &act.proto.consts[i as usize] {
+                        Value::Condition(c) => return Err((**c).clone()),
+                        _ => unreachable!(),
+                    }
+                },
+            }
+        }
+    }
+
+    // Continue an activation with the value of the call it made.
+    pub(crate) fn resume_vm(&mut self, mut act: Activation, v: Value)
+        -> Result<State, Condition>
+    {
+        act.stack.push(v);
+        Ok(State::Exec(act))
+    }
+
+    // A slot that is not defined yet falls through to the enclosing
+    // scopes, like a missing binding in an `Env`.
+    fn lookup_outer(&self, env: &Rc<VmEnv>, name: &str) -> Result<Value, Condition> {
+        let outer = env.parent.as_ref().and_then(|p| p.find(name));
+        match outer {
+            Some((e, i)) => Ok(e.slots.borrow()[i].clone().unwrap()),
+            None => self.global.lookup(name).ok_or_else(|| unbound(name)),
+        }
+    }
+
+    fn set_outer(&self, env: &Rc<VmEnv>, name: &str, v: Value) -> Result<(), Condition> {
+        let outer = env.parent.as_ref().and_then(|p| p.find(name));
+        match outer {
+            Some((e, i)) => {
+                e.slots.borrow_mut()[i] = Some(v);
+                Ok(())
+            },
+            None if self.global.set(name, v) => Ok(()),
+            None => Err(unbound(name)),
+        }
+    }
+}
+
+#[test]
+fn test_vm_exec() {
+    let mut interp = Interp::new();
+    assert_eq!(interp.exec_str("(+ 1 2)"), Ok(Value::Int(3)));
+    assert_eq!(interp.exec_str("(if nil 1 2)"), Ok(Value::Int(2)));
+    assert_eq!(interp.exec_str("(and 1 nil 2)"), Ok(Value::Nil));
+    assert_eq!(interp.exec_str("(or nil 2 3)"), Ok(Value::Int(2)));
+    assert_eq!(
+        interp.exec_str("(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))\n(fact 10)"),
+        Ok(Value::Int(3628800))
+        );
+    assert_eq!(
+        interp.exec_str("(defun adder (x) (lambda (y) (setq x (+ x y))))\n\
+                         (defvar a (adder 10))\n(a 1)\n(a 2)"),
+        Ok(Value::Int(13))
+        );
+    // Compiled and evaluated functions call each other.
+    assert_eq!(interp.eval_str("(fact 5)"), Ok(Value::Int(120)));
+    assert_eq!(interp.eval_str("(funcall a 3)"), Ok(Value::Int(16)));
+    assert_eq!(interp.eval_str("fact").map(|v| v.to_string()), Ok("#<lambda fact>".to_string()));
+}
+
+#[test]
+fn test_vm_tail_calls() {
+    let mut interp = Interp::new();
+    let src = "(defun loop (n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1))))\n\
+               (loop 100000 0)";
+    assert_eq!(interp.exec_str(src), Ok(Value::Int(100000)));
+    assert!(interp.peak < 10);
+}
+
+#[test]
+fn test_vm_internal_defines() {
+    let mut interp = Interp::new();
+    let src = "(defvar y 'global)\n\
+               (defun f (x) (defvar before y) (when x (defvar y 'local)) (list before y))\n\
+               (list (f nil) (f t))";
+    assert_eq!(
+        interp.exec_str(src).map(|v| v.to_string()),
+        Ok("((global global) (global local))".to_string())
+        );
+}
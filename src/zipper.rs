@@ -0,0 +1,381 @@
+// Copyright 2019 Masahiko Hamazawa
+//
+// Licensed under the MIT license <LICENSE or
+//  http://opensource.org/licenses/MIT>.
+// This file may not be copied, modified, on distributed except
+//  according to those terms.
+
+use std::mem;
+use crate::reader::Token;
+#[cfg(test)]
+use crate::reader::read;
+
+
+// ***** Zipper *****
+// A cursor on one node of a tree. The focused node is held apart from
+// its siblings and the siblings of its ancestors, so moving to a
+// neighbour and editing the focus take constant time, and only moving up
+// rebuilds a list. The tree is put back together by `root`.
+//
+// Moves and edits return false, leaving the zipper as it was, when they
+// are not possible.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Zipper {
+    focus: Token,
+    crumbs: Vec<Crumb>,
+}
+
+// The rest of a list around the focus. `right` is reversed, so that the
+// nearest sibling on either side is at the end.
+#[derive(Debug, Clone, PartialEq)]
+struct Crumb {
+    left: Vec<Token>,
+    right: Vec<Token>,
+}
+
+fn take_list(tk: &mut Token) -> Option<Vec<Token>> {
+    match tk {
+        Token::List(l) => Some(mem::take(l)),
+        Token::Nil => Some(vec![]),
+        _ => None,
+    }
+}
+
+impl Zipper {
+    pub fn new(root: Token) -> Zipper {
+        Zipper { focus: root, crumbs: vec![] }
+    }
+
+    pub fn focus(&self) -> &Token {
+        &self.focus
+    }
+
+    pub fn focus_mut(&mut self) -> &mut Token {
+        &mut self.focus
+    }
+
+    pub fn depth(&self) -> usize {
+        self.crumbs.len()
+    }
+
+    // The index of the focus at each level, as in `query::Query::paths`.
+    pub fn path(&self) -> Vec<usize> {
+        self.crumbs.iter().map(|c| c.left.len()).collect()
+    }
+
+    // The whole tree, with the zipper left where it is.
+    pub fn tree(&self) -> Token {
+        self.clone().root()
+    }
+
+    pub fn root(mut self) -> Token {
+        while self.up() {}
+        self.focus
+    }
+
+    // ***** Navigation *****
+    // To the first element of the focused list.
+    pub fn down(&mut self) -> bool {
+        match &self.focus {
+            Token::List(l) if !l.is_empty() => {
+                let mut right = take_list(&mut self.focus).unwrap();
+                right.reverse();
+                self.focus = right.pop().unwrap();
+                self.crumbs.push(Crumb { left: vec![], right });
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn up(&mut self) -> bool {
+        match self.crumbs.pop() {
+            Some(Crumb { mut left, right }) => {
+                let focus = mem::replace(&mut self.focus, Token::Nil);
+                left.push(focus);
+                left.extend(right.into_iter().rev());
+                self.focus = Token::List(left);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn left(&mut self) -> bool {
+        match self.crumbs.last_mut() {
+            Some(c) if !c.left.is_empty() => {
+                let prev = c.left.pop().unwrap();
+                c.right.push(mem::replace(&mut self.focus, prev));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    pub fn right(&mut self) -> bool {
+        match self.crumbs.last_mut() {
+            Some(c) if !c.right.is_empty() => {
+                let next = c.right.pop().unwrap();
+                c.left.push(mem::replace(&mut self.focus, next));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Follow a path from the focus. If a step does not exist, the
+    // zipper goes back up to where it started.
+    pub fn descend(&mut self, path: &[usize]) -> bool {
+        let depth = self.depth();
+        let found = path.iter().all(|&i| self.down() && (0..i).all(|_| self.right()));
+        if !found {
+            while self.depth() > depth {
+                self.up();
+            }
+        }
+        found
+    }
+
+    // ***** Editing *****
+    // Replace the focus and return the old one.
+    pub fn replace(&mut self, tk: Token) -> Token {
+        mem::replace(&mut self.focus, tk)
+    }
+
+    pub fn insert_left(&mut self, tk: Token) -> bool {
+        match self.crumbs.last_mut() {
+            Some(c) => {
+                c.left.push(tk);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn insert_right(&mut self, tk: Token) -> bool {
+        match self.crumbs.last_mut() {
+            Some(c) => {
+                c.right.push(tk);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Add `tk` as the first element of the focused list.
+    pub fn insert_child(&mut self, tk: Token) -> bool {
+        match take_list(&mut self.focus) {
+            Some(mut l) => {
+                l.insert(0, tk);
+                self.focus = Token::List(l);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Remove the focus and return it. The focus moves to the next
+    // sibling, or the previous one, or else the parent.
+    pub fn remove(&mut self) -> Option<Token> {
+        let c = self.crumbs.last_mut()?;
+        match c.right.pop().or_else(|| c.left.pop()) {
+            Some(next) => Some(mem::replace(&mut self.focus, next)),
+            None => {
+                self.crumbs.pop();
+                Some(mem::replace(&mut self.focus, Token::List(vec![])))
+            },
+        }
+    }
+
+    // ***** Paredit *****
+    // These act on the focus as the list being edited.
+
+    // `x` becomes `(x)`.
+    pub fn wrap(&mut self) {
+        let focus = mem::replace(&mut self.focus, Token::Nil);
+        self.focus = Token::List(vec![focus]);
+    }
+
+    // `(a (b c) d)` with `(b c)` focused becomes `(a b c d)`, with the
+    // focus on `b`.
+    pub fn splice(&mut self) -> bool {
+        if self.crumbs.is_empty() {
+            return false;
+        }
+        let mut items = match take_list(&mut self.focus) {
+            Some(l) => l,
+            None => return false,
+        };
+        if items.is_empty() {
+            self.remove();
+            return true;
+        }
+        let c = self.crumbs.last_mut().unwrap();
+        items.reverse();
+        self.focus = items.pop().unwrap();
+        c.right.extend(items);
+        true
+    }
+
+    // `(a) b` becomes `(a b)`.
+    pub fn slurp_forward(&mut self) -> bool {
+        match (&self.focus, self.crumbs.last()) {
+            (Token::List(_)|Token::Nil, Some(c)) if !c.right.is_empty() => {
+                let next = self.crumbs.last_mut().unwrap().right.pop().unwrap();
+                let mut l = take_list(&mut self.focus).unwrap();
+                l.push(next);
+                self.focus = Token::List(l);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // `a (b)` becomes `(a b)`.
+    pub fn slurp_backward(&mut self) -> bool {
+        match (&self.focus, self.crumbs.last()) {
+            (Token::List(_)|Token::Nil, Some(c)) if !c.left.is_empty() => {
+                let prev = self.crumbs.last_mut().unwrap().left.pop().unwrap();
+                let mut l = take_list(&mut self.focus).unwrap();
+                l.insert(0, prev);
+                self.focus = Token::List(l);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // `(a b)` becomes `(a) b`.
+    pub fn barf_forward(&mut self) -> bool {
+        match (&mut self.focus, self.crumbs.last_mut()) {
+            (Token::List(l), Some(c)) if !l.is_empty() => {
+                c.right.push(l.pop().unwrap());
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // `(a b)` becomes `a (b)`.
+    pub fn barf_backward(&mut self) -> bool {
+        match (&mut self.focus, self.crumbs.last_mut()) {
+            (Token::List(l), Some(c)) if !l.is_empty() => {
+                c.left.push(l.remove(0));
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+fn show(tk: &Token) -> String {
+    tk.to_string()
+}
+
+#[test]
+fn test_zipper_navigation() {
+    let mut z = Zipper::new(read("(a (b c) d)").unwrap());
+    assert!(!z.up());
+    assert!(!z.left());
+    assert!(z.down());
+    assert_eq!(z.focus(), &Token::Symbol("a".to_string()));
+    assert!(!z.down());
+    assert!(!z.left());
+    assert!(z.right());
+    assert!(z.down());
+    assert!(z.right());
+    assert_eq!(show(z.focus()), "c");
+    assert_eq!(z.path(), vec![1, 1]);
+    assert_eq!(z.depth(), 2);
+    assert!(!z.right());
+    assert!(z.up());
+    assert_eq!(show(z.focus()), "(b c)");
+    assert_eq!(show(&z.tree()), "(a (b c) d)");
+    assert_eq!(z.path(), vec![1]);
+
+    let mut z = Zipper::new(read("(a (b c) d)").unwrap());
+    assert!(z.descend(&[1, 0]));
+    assert_eq!(show(z.focus()), "b");
+    assert!(!z.descend(&[0]));
+    assert!(z.up() && z.up());
+    assert!(!z.descend(&[5]));
+    assert_eq!(z.depth(), 0);
+
+    // A path that fails part way leaves the focus where it was.
+    assert!(z.descend(&[2]));
+    assert!(!z.descend(&[0]));
+    assert!(z.left());
+    for path in &[&[1, 5][..], &[1, 0, 0], &[1, 1, 3]] {
+        assert!(!z.descend(path));
+        assert_eq!((show(z.focus()), z.path()), ("(b c)".to_string(), vec![1]));
+    }
+    assert_eq!(show(&z.root()), "(a (b c) d)");
+}
+
+#[test]
+fn test_zipper_edit() {
+    let mut z = Zipper::new(read("(a b c)").unwrap());
+    assert!(!z.insert_left(Token::Int(0)));
+    assert_eq!(z.remove(), None);
+    z.descend(&[1]);
+    assert_eq!(z.replace(Token::Int(2)), Token::Symbol("b".to_string()));
+    assert!(z.insert_left(Token::Int(1)));
+    assert!(z.insert_right(Token::Int(3)));
+    assert_eq!(show(&z.tree()), "(a 1 2 3 c)");
+    assert_eq!(z.remove(), Some(Token::Int(2)));
+    assert_eq!(z.focus(), &Token::Int(3));
+    z.right();
+    assert_eq!(z.remove(), Some(Token::Symbol("c".to_string())));
+    assert_eq!(z.focus(), &Token::Int(3));
+    assert_eq!(show(&z.tree()), "(a 1 3)");
+    if let Token::Int(i) = z.focus_mut() {
+        *i *= 10;
+    }
+    assert!(z.up());
+    assert!(z.insert_child(Token::Str("s".to_string())));
+    assert_eq!(show(&z.tree()), "(\"s\" a 1 30)");
+
+    // Removing the last element leaves the parent focused and empty.
+    let mut z = Zipper::new(read("((x))").unwrap());
+    z.descend(&[0, 0]);
+    assert_eq!(z.remove(), Some(Token::Symbol("x".to_string())));
+    assert_eq!(z.focus(), &Token::List(vec![]));
+    assert!(z.insert_child(Token::Int(1)));
+    assert_eq!(show(&z.root()), "((1))");
+}
+
+#[test]
+fn test_zipper_paredit() {
+    let mut z = Zipper::new(read("(a (b c) d e)").unwrap());
+    z.descend(&[1]);
+    assert!(z.slurp_forward());
+    assert_eq!(show(&z.tree()), "(a (b c d) e)");
+    assert!(z.slurp_backward());
+    assert_eq!(show(&z.tree()), "((a b c d) e)");
+    assert!(!z.slurp_backward());
+    assert!(z.barf_forward() && z.barf_forward());
+    assert_eq!(show(&z.tree()), "((a b) c d e)");
+    assert!(z.barf_backward());
+    assert_eq!(show(&z.tree()), "(a (b) c d e)");
+    assert!(z.splice());
+    assert_eq!(show(z.focus()), "b");
+    assert_eq!(show(&z.tree()), "(a b c d e)");
+    assert!(!z.splice());
+    assert!(!z.slurp_forward());
+    z.wrap();
+    assert!(z.slurp_forward() && z.slurp_forward());
+    assert_eq!(show(&z.tree()), "(a (b c d) e)");
+    assert!(z.barf_forward() && z.barf_forward() && z.barf_forward());
+    assert!(!z.barf_forward());
+    assert_eq!(show(&z.tree()), "(a nil b c d e)");
+    assert!(z.splice());
+    assert_eq!(show(z.focus()), "b");
+    assert_eq!(show(&z.root()), "(a b c d e)");
+
+    // The root can be wrapped but not spliced.
+    let mut z = Zipper::new(read("(a)").unwrap());
+    assert!(!z.splice());
+    z.wrap();
+    assert_eq!(show(&z.root()), "((a))");
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn lprp(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lprp"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn script(name: &str, src: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, src).unwrap();
+    path.display().to_string()
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[test]
+fn test_cli_run() {
+    let greet = script("greet.lprp", "#!/usr/bin/env lprp\n(defun greet (names)\n  (print (car names)))\n(greet *argv*)\n");
+    let out = lprp(&[&greet, "world", "-e"], "");
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(text(&out.stdout), "\"world\"\n");
+
+    let out = lprp(&["-e", "(list (car *argv*) (+ 1 2))", "a"], "");
+    assert_eq!((out.status.code(), text(&out.stdout)), (Some(0), "(\"a\" 3)\n".to_string()));
+
+    let out = lprp(&["-", "x"], "(print *argv*) (+ 1 2)");
+    assert_eq!((out.status.code(), text(&out.stdout)), (Some(0), "(\"x\")\n".to_string()));
+    let out = lprp(&[], "(print 1)");
+    assert_eq!(text(&out.stdout), "1\n");
+
+    let failing = script("failing.lprp", "(print 1)\n(car 1)\n(print 2)\n");
+    let out = lprp(&[&failing], "");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(text(&out.stdout), "1\n");
+    assert_eq!(text(&out.stderr), format!("lprp: {}: type-error: car: expected list, found integer: 1\n", failing));
+
+    assert_eq!(lprp(&["/nonexistent.lprp"], "").status.code(), Some(4));
+    assert_eq!(lprp(&["--bogus"], "").status.code(), Some(2));
+    assert_eq!(lprp(&["-e"], "").status.code(), Some(2));
+}
+
+#[test]
+fn test_cli_read_only() {
+    let good = script("good.lprp", "(car 1)\n");
+    let bad = script("bad.lprp", "(server web\n  :port 80\n(client é)\n");
+
+    // Nothing is evaluated.
+    let out = lprp(&["--read-only", &good], "");
+    assert_eq!((out.status.code(), text(&out.stderr)), (Some(0), String::new()));
+
+    let out = lprp(&["--read-only", &good, &bad], "");
+    assert_eq!(out.status.code(), Some(3));
+    assert_eq!(text(&out.stderr), format!(
+        "{0}:1:1: Delimiter Error: unclosed `(`: a `)` may be missing at 2:11\n{0}:3:9: Syntax Error: malformed atom\n",
+        bad
+    ));
+
+    // A script that does not read is not run at all.
+    let out = lprp(&[&bad], "");
+    assert_eq!((out.status.code(), text(&out.stdout)), (Some(3), String::new()));
+    assert_eq!(lprp(&["--read-only"], "(a").status.code(), Some(3));
+    assert_eq!(lprp(&["--read-only", "-e", "(a)"], "").status.code(), Some(0));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_cli_convert_floats() {
+    let src = "(x 100000000000000000000.0 0.00001 -0.0 1.5 (2.0))\n";
+    let json = lprp(&["json", "--compact"], src);
+    assert_eq!(text(&json.stdout), "[{\"$symbol\":\"x\"},1e+20,0.00001,-0.0,1.5,[2.0]]\n");
+    let back = lprp(&["sexp"], &text(&json.stdout));
+    assert_eq!((back.status.code(), text(&back.stdout)), (Some(0), src.to_string()));
+
+    let out = lprp(&["sexp"], "[{\"$symbol\":\"x\"}, 1e20, 0.00001, 1e-300]");
+    let sexp = text(&out.stdout);
+    assert_eq!(lprp(&["json", "--compact"], &sexp).stdout, b"[{\"$symbol\":\"x\"},1e+20,0.00001,1e-300]\n");
+
+    // Nothing is printed that would read back as something else.
+    let out = lprp(&["sexp"], "{\"$symbol\": \"x y\"}");
+    assert_eq!((out.status.code(), text(&out.stdout)), (Some(1), String::new()));
+    assert_eq!(text(&out.stderr), "lprp: Write Error: cannot write symbol \"x y\"\n");
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_cli_convert_exit_status() {
+    // Input that does not read is a syntax error, as when running.
+    let out = lprp(&["json"], "(a");
+    assert_eq!((out.status.code(), text(&out.stdout)), (Some(3), String::new()));
+    assert_eq!(lprp(&["sexp"], "[1, ").status.code(), Some(3));
+    assert_eq!(lprp(&["json"], "(a b)").status.code(), Some(0));
+
+    // Input that reads but does not convert is not.
+    assert_eq!(lprp(&["json"], "(a) (b)").status.code(), Some(1));
+    assert_eq!(lprp(&["sexp", "--forms"], "{}").status.code(), Some(1));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_cli_convert_object_keys() {
+    // Keys that are not keywords convert, and come back as the same object.
+    let out = lprp(&["sexp"], "{\"user_id\": 1, \"x.y\": 2}");
+    let sexp = text(&out.stdout);
+    assert_eq!((out.status.code(), sexp.trim()), (Some(0), "(object (\"user_id\" 1) (\"x.y\" 2))"));
+    let out = lprp(&["json", "--compact", "--keywords", "objects"], &sexp);
+    assert_eq!(out.stdout, b"{\"user_id\":1,\"x.y\":2}\n");
+}
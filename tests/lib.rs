@@ -23,3 +23,13 @@ fn test_read() {
         ]))
         );
 }
+
+#[test]
+fn test_eval() {
+    use lprp::core::eval;
+    use lprp::value::Value;
+
+    let src = "(defun fact (n acc) (if (= n 0) acc (fact (- n 1) (* n acc))))
+               (fact 20 1)";
+    assert_eq!(eval(src), Ok(Value::Int(2432902008176640000)));
+}
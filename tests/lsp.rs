@@ -0,0 +1,202 @@
+#![cfg(feature = "json")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use serde_json::{json, Value};
+use lprp::reader::{read_all, Token};
+
+// A scripted client talking to `lprp-lsp` over its stdio.
+struct Client {
+    child: Child,
+    output: BufReader<ChildStdout>,
+    id: u64,
+}
+
+impl Client {
+    fn new() -> Client {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_lprp-lsp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let output = BufReader::new(child.stdout.take().unwrap());
+        Client { child, output, id: 0 }
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        let stdin = self.child.stdin.as_mut().unwrap();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        stdin.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            self.output.read_line(&mut line).unwrap();
+            match line.trim_end() {
+                "" => break,
+                l => length = l.trim_start_matches("Content-Length: ").parse().unwrap(),
+            }
+        }
+        let mut body = vec![0; length];
+        self.output.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.id += 1;
+        self.send(json!({ "jsonrpc": "2.0", "id": self.id, "method": method, "params": params }));
+        let reply = self.receive();
+        assert_eq!(reply["id"], self.id);
+        reply
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn at(&mut self, method: &str, line: u64, character: u64) -> Value {
+        let params = json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        });
+        self.request(method, params)["result"].clone()
+    }
+
+    fn exit(mut self) -> i32 {
+        assert_eq!(self.request("shutdown", Value::Null)["result"], Value::Null);
+        self.notify("exit", Value::Null);
+        self.child.wait().unwrap().code().unwrap()
+    }
+}
+
+const URI: &str = "file:///config.lprp";
+
+fn open(client: &mut Client, text: &str) -> Value {
+    let doc = json!({ "uri": URI, "languageId": "lprp", "version": 1, "text": text });
+    client.notify("textDocument/didOpen", json!({ "textDocument": doc }));
+    client.receive()
+}
+
+fn range(sl: u64, sc: u64, el: u64, ec: u64) -> Value {
+    json!({ "start": { "line": sl, "character": sc }, "end": { "line": el, "character": ec } })
+}
+
+#[test]
+fn test_lsp_session() {
+    let mut client = Client::new();
+    let init = client.request("initialize", json!({ "capabilities": {} }));
+    assert_eq!(init["result"]["capabilities"]["hoverProvider"], true);
+
+    let src = "(defun area (w h)\n  (* w h))\n\n(server web :port 80)\n";
+    let published = open(&mut client, src);
+    assert_eq!(published["method"], "textDocument/publishDiagnostics");
+    assert_eq!(published["params"]["diagnostics"], json!([]));
+
+    let symbols = client.request("textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } }));
+    let symbols = &symbols["result"];
+    assert_eq!(symbols[0]["name"], "area");
+    assert_eq!(symbols[0]["detail"], "defun");
+    assert_eq!(symbols[0]["range"], range(0, 0, 1, 10));
+    assert_eq!(symbols[1]["name"], "server");
+    assert_eq!(symbols[1]["selectionRange"], range(3, 1, 3, 7));
+
+    // The cursor just after `(* w h)` matches its `(`.
+    let parens = client.at("textDocument/documentHighlight", 1, 9);
+    assert_eq!(parens, json!([{ "range": range(1, 2, 1, 3) }, { "range": range(1, 8, 1, 9) }]));
+
+    let hover = client.at("textDocument/hover", 1, 3);
+    assert_eq!(hover["contents"]["value"], "`*` builtin, takes at least 0 arguments");
+    assert_eq!(client.at("textDocument/hover", 0, 8), Value::Null);
+
+    let params = json!({ "textDocument": { "uri": URI }, "positions": [{ "line": 1, "character": 5 }] });
+    let selection = &client.request("textDocument/selectionRange", params)["result"][0];
+    assert_eq!(selection["range"], range(1, 5, 1, 6));
+    assert_eq!(selection["parent"]["range"], range(1, 2, 1, 9));
+    assert_eq!(selection["parent"]["parent"]["range"], range(0, 0, 1, 10));
+    assert_eq!(selection["parent"]["parent"]["parent"], Value::Null);
+
+    let unknown = client.request("textDocument/rename", json!({}));
+    assert_eq!(unknown["error"]["code"], -32601);
+    assert_eq!(client.exit(), 0);
+}
+
+#[test]
+fn test_lsp_diagnostics_and_formatting() {
+    let mut client = Client::new();
+    client.request("initialize", json!({ "capabilities": {} }));
+
+    let published = open(&mut client, "(defun f (x)\n  (g x)\n\n(h 1-2 é)\n");
+    let diagnostics = &published["params"]["diagnostics"];
+    assert_eq!(diagnostics[0]["range"], range(0, 0, 0, 1));
+    assert_eq!(diagnostics[0]["message"], "Delimiter Error: unclosed `(`: a `)` may be missing at 2:8");
+    assert_eq!(diagnostics[0]["relatedInformation"][0]["location"]["range"], range(1, 7, 1, 7));
+    assert_eq!(diagnostics[1]["message"], "Read Num Error: malformed number");
+    assert_eq!(diagnostics[2]["message"], "Syntax Error: malformed atom");
+    assert_eq!(diagnostics[2]["range"], range(3, 7, 3, 8));
+
+    // Still outlined while broken.
+    let symbols = client.request("textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } }));
+    assert_eq!(symbols["result"].as_array().unwrap().len(), 2);
+
+    let long = "(defparameter *servers* '((web :host \"example.com\" :port 80) (db :host \"database.example.com\" :port 5432)))";
+    let text = format!("; servers\n{}\n(list   1\n  2)\n", long);
+    let change = json!({ "textDocument": { "uri": URI, "version": 2 }, "contentChanges": [{ "text": text }] });
+    client.notify("textDocument/didChange", change);
+    assert_eq!(client.receive()["params"]["diagnostics"], json!([]));
+    let edits = client.request("textDocument/formatting", json!({ "textDocument": { "uri": URI }, "options": {} }));
+    assert_eq!(edits["result"], json!([
+        {
+            "range": range(1, 0, 1, 107),
+            "newText": "(defparameter *servers*\n  '((web :host \"example.com\" :port 80)\n    (db :host \"database.example.com\" :port 5432)))",
+        },
+        { "range": range(2, 0, 3, 4), "newText": "(list 1 2)" },
+    ]));
+    assert_eq!(client.exit(), 0);
+}
+
+#[test]
+fn test_lsp_formatting_floats() {
+    let mut client = Client::new();
+    client.request("initialize", json!({ "capabilities": {} }));
+
+    // `1e20` reads as `1 e20`, and `inf` and `NaN` are symbols.
+    let floats = "(f 100000000000000000000.0  0.00001 1e20 inf NaN 1.50 -0.0)";
+    let text = format!("{}\n(g  {}.0)\n", floats, "9".repeat(400));
+    assert_eq!(open(&mut client, &text)["params"]["diagnostics"], json!([]));
+    let edits = client.request("textDocument/formatting", json!({ "textDocument": { "uri": URI }, "options": {} }));
+    let formatted = "(f 100000000000000000000.0 0.00001 1 e20 inf NaN 1.5 -0.0)";
+    assert_eq!(edits["result"], json!([{ "range": range(0, 0, 0, floats.len() as u64), "newText": formatted }]));
+    assert_eq!(read_all(formatted), read_all(floats));
+
+    // The second form reads as an infinite float, which has no syntax, so
+    // it is left as written.
+    let g = Token::List(vec![Token::Symbol("g".to_string()), Token::Float(f64::INFINITY)]);
+    assert_eq!(read_all(&text).unwrap()[1], g);
+    assert_eq!(client.exit(), 0);
+}
+
+// Positions are looked up in a line index, so requests on a large file
+// take time in proportion to it.
+#[test]
+fn test_lsp_large_document() {
+    let mut client = Client::new();
+    client.request("initialize", json!({ "capabilities": {} }));
+
+    let forms = 20000;
+    let text: String = (0..forms).map(|i| format!("(defun f{} (x)\n  (list   x \"é\" {}))\n", i, i)).collect();
+    let start = std::time::Instant::now();
+    assert_eq!(open(&mut client, &text)["params"]["diagnostics"], json!([]));
+    let symbols = client.request("textDocument/documentSymbol", json!({ "textDocument": { "uri": URI } }));
+    let symbols = symbols["result"].as_array().unwrap();
+    assert_eq!(symbols.len(), forms);
+    assert_eq!(symbols[forms - 1]["selectionRange"], range(2 * forms as u64 - 2, 7, 2 * forms as u64 - 2, 13));
+    let edits = client.request("textDocument/formatting", json!({ "textDocument": { "uri": URI }, "options": {} }));
+    let edits = edits["result"].as_array().unwrap();
+    assert_eq!(edits.len(), forms);
+    assert_eq!(edits[1]["range"], range(2, 0, 3, 19));
+    assert!(start.elapsed().as_secs() < 20, "{:?}", start.elapsed());
+    assert_eq!(client.exit(), 0);
+}
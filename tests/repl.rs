@@ -0,0 +1,78 @@
+#![cfg(feature = "repl")]
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// Run the REPL on piped input, with its history kept in `history`.
+fn repl(input: &str, history: &PathBuf) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_repl"))
+        .env("LPRP_HISTORY", history)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn lines(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes).lines().map(str::to_string).collect()
+}
+
+#[test]
+fn test_repl_session() {
+    let history = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("repl_session_history");
+    let _ = fs::remove_file(&history);
+
+    let input = "(defun add1 (x)\n  (+ x 1))\n\n(add1 1) '(a \"b c\")\n(car 1)\n(add1 2)\n(a))\n(list 1\n";
+    let out = repl(input, &history);
+    assert!(out.status.success());
+    assert_eq!(lines(&out.stdout), vec!["add1", "2", "(a \"b c\")", "3"]);
+    assert_eq!(lines(&out.stderr), vec![
+        "type-error: car: expected list, found integer: 1",
+        "Delimiter Error: unexpected `)` at 1:4",
+        "Delimiter Error: unclosed `(` at 1:1, input ends at 1:8",
+    ]);
+
+    // History is kept across sessions, one entry per input.
+    let out = repl("quitter\nquit\n(add1 5)\n", &history);
+    assert_eq!(lines(&out.stdout), Vec::<String>::new());
+    let saved = fs::read_to_string(&history).unwrap();
+    assert!(saved.contains("(add1 2)"), "{}", saved);
+    assert!(saved.contains("quitter"), "{}", saved);
+    assert!(!saved.contains("(add1 5)"), "{}", saved);
+}
+
+#[test]
+fn test_repl_commands() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let history = dir.join("repl_commands_history");
+    let script = dir.join("repl_commands.lprp");
+    fs::write(&script, "(defvar *base* 10)\n(defun scale (x) (* x *base*))\n").unwrap();
+    let load = format!(":load {}\n", script.display());
+    let session = |input: &str| {
+        let out = repl(&format!("{}{}", load, input), &history);
+        (lines(&out.stdout), lines(&out.stderr))
+    };
+
+    let (out, err) = session(":type (scale 2)\n:type\n:env\n:port\n");
+    assert_eq!(out, vec!["scale", "integer", "*base* = 10", "scale = #<lambda scale>", ":port"]);
+    assert_eq!(err, vec!["usage: :type EXPR"]);
+
+    let (out, _) = session(":ast '(a 1)\n");
+    assert_eq!(out[1..].join("\n"), format!("{:#?}", lprp::reader::read("'(a 1)").unwrap()));
+
+    let (out, _) = session(":time (scale\n 3)\n");
+    assert_eq!(out[1], "30");
+    assert!(out[2].starts_with("; "), "{}", out[2]);
+
+    let (out, err) = session(":reset\n:env\n(scale 1)\n:quit\n(scale 1)\n");
+    assert_eq!(out, vec!["scale"]);
+    assert_eq!(err, vec!["unbound-variable: unbound variable: scale"]);
+
+    let (out, _) = session(":help\n");
+    assert!(out[1].starts_with(":load FILE"));
+}
@@ -0,0 +1,185 @@
+#![cfg(feature = "serde")]
+
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use lprp::reader::{read, write, LprpError};
+use lprp::ser::{self, Serializer, StructStyle};
+use lprp::de;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Server {
+    host: String,
+    port: u16,
+    use_tls: bool,
+    tags: Vec<String>,
+    timeout: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Shape {
+    Empty,
+    Circle(f64),
+    Rect(i32, i32),
+    Named { name: String, sides: u8 },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Config {
+    name: String,
+    servers: Vec<Server>,
+    shapes: Vec<Shape>,
+    limits: BTreeMap<String, i64>,
+}
+
+fn server() -> Server {
+    Server {
+        host: "localhost".to_string(),
+        port: 8080,
+        use_tls: true,
+        tags: vec!["a".to_string(), "b".to_string()],
+        timeout: None,
+    }
+}
+
+fn config() -> Config {
+    let mut limits = BTreeMap::new();
+    limits.insert("cpu".to_string(), 4);
+    limits.insert("mem".to_string(), 512);
+    Config {
+        name: "prod".to_string(),
+        servers: vec![server(), Server { timeout: Some(1.5), tags: vec![], ..server() }],
+        shapes: vec![
+            Shape::Empty,
+            Shape::Circle(2.0),
+            Shape::Rect(3, 4),
+            Shape::Named { name: "tri".to_string(), sides: 3 },
+        ],
+        limits,
+    }
+}
+
+#[test]
+fn test_serde_plist() {
+    let s = ser::to_string(&server()).unwrap();
+    assert_eq!(s, "(:host \"localhost\" :port 8080 :use-tls t :tags (\"a\" \"b\") :timeout nil)");
+    assert_eq!(de::from_str::<Server>(&s).unwrap(), server());
+
+    // Missing options are None, and fields may come in any order.
+    let src = "(:tags nil :port 1 :use-tls nil :host \"h\")";
+    let got: Server = de::from_str(src).unwrap();
+    assert_eq!(got.port, 1);
+    assert!(!got.use_tls && got.tags.is_empty() && got.timeout.is_none());
+
+    let c = config();
+    assert_eq!(de::from_str::<Config>(&ser::to_string(&c).unwrap()).unwrap(), c);
+}
+
+#[test]
+fn test_serde_alist() {
+    let ser = Serializer::new().struct_style(StructStyle::Alist);
+    let tk = server().serialize(&ser).unwrap();
+    assert_eq!(tk, read("((host . \"localhost\") (port . 8080) (use-tls . t) (tags \"a\" \"b\") (timeout))").unwrap());
+    assert_eq!(de::from_token::<Server>(&tk).unwrap(), server());
+
+    let c = config();
+    let tk = c.serialize(&ser).unwrap();
+    assert_eq!(de::from_token::<Config>(&tk).unwrap(), c);
+}
+
+#[test]
+fn test_serde_enum_and_map() {
+    assert_eq!(ser::to_string(&Shape::Empty).unwrap(), "empty");
+    assert_eq!(ser::to_string(&Shape::Circle(2.5)).unwrap(), "(circle 2.5)");
+    assert_eq!(ser::to_string(&Shape::Rect(1, 2)).unwrap(), "(rect 1 2)");
+    let named = Shape::Named { name: "sq".to_string(), sides: 4 };
+    assert_eq!(ser::to_string(&named).unwrap(), "(named :name \"sq\" :sides 4)");
+    assert_eq!(de::from_str::<Shape>("(named :sides 4 :name \"sq\")").unwrap(), named);
+    assert_eq!(de::from_str::<Shape>("(circle 1)").unwrap(), Shape::Circle(1.0));
+
+    let mut m = BTreeMap::new();
+    m.insert(1, vec![1, 2]);
+    m.insert(2, vec![]);
+    assert_eq!(ser::to_string(&m).unwrap(), "((1 1 2) (2))");
+    assert_eq!(de::from_str::<BTreeMap<i32, Vec<i32>>>("((1 1 2) (2))").unwrap(), m);
+    assert_eq!(de::from_str::<BTreeMap<String, i32>>("((a . 1) (\"b\" . 2))").unwrap().len(), 2);
+
+    let t: (i32, String, Option<bool>) = de::from_str("(1 \"x\" t)").unwrap();
+    assert_eq!(t, (1, "x".to_string(), Some(true)));
+    assert_eq!(de::from_str::<Vec<u8>>("nil").unwrap(), Vec::<u8>::new());
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Limits {
+    timeout: f64,
+    ratio: Option<f64>,
+}
+
+#[test]
+fn test_serde_floats() {
+    let s = ser::to_string(&Limits { timeout: 0.00001, ratio: Some(1e20) }).unwrap();
+    assert_eq!(s, "(:timeout 0.00001 :ratio 100000000000000000000.0)");
+
+    let floats = [1e20, 1e-5, -0.0, 0.1 + 0.2, 1e300, -2.5e-300, f64::MAX, f64::MIN_POSITIVE, 5e-324];
+    for &x in &floats {
+        let limits = Limits { timeout: x, ratio: Some(-x) };
+        let s = ser::to_string(&limits).unwrap();
+        let back: Limits = de::from_str(&s).unwrap();
+        assert_eq!(back.timeout.to_bits(), x.to_bits(), "{}", s);
+        assert_eq!(back.ratio.map(f64::to_bits), Some((-x).to_bits()), "{}", s);
+    }
+
+    // The reader has no syntax for these.
+    for &x in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+        let limits = Limits { timeout: 1.0, ratio: Some(x) };
+        assert!(matches!(ser::to_string(&limits), Err(LprpError::SerdeError(_))));
+        assert!(ser::to_token(&limits).is_ok());
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Options {
+    flag: Option<bool>,
+    list: Option<Vec<i32>>,
+    nested: Option<Option<i32>>,
+    unit: Option<()>,
+}
+
+#[test]
+fn test_serde_options() {
+    // Some(x) is wrapped when x alone would read as None.
+    assert_eq!(ser::to_string(&Some(false)).unwrap(), "(nil)");
+    assert_eq!(ser::to_string(&Some(Some(None::<i32>))).unwrap(), "((nil))");
+    assert_eq!(ser::to_string(&Some(vec![1])).unwrap(), "(1)");
+    assert_eq!(ser::to_string(&Some(vec![None::<i32>])).unwrap(), "((nil))");
+    assert_eq!(de::from_str::<Option<Vec<Option<i32>>>>("((nil))").unwrap(), Some(vec![None]));
+
+    let cases = [
+        Options { flag: Some(false), list: Some(vec![]), nested: Some(None), unit: Some(()) },
+        Options { flag: None, list: None, nested: None, unit: None },
+        Options { flag: Some(true), list: Some(vec![1, 2]), nested: Some(Some(3)), unit: Some(()) },
+    ];
+    for ser in &[Serializer::new(), Serializer::new().struct_style(StructStyle::Alist)] {
+        for options in &cases {
+            let s = write(&options.serialize(ser).unwrap()).unwrap();
+            assert_eq!(&de::from_str::<Options>(&s).unwrap(), options, "{}", s);
+        }
+    }
+    let deep: Option<Option<Option<Vec<bool>>>> = Some(Some(Some(vec![])));
+    let s = ser::to_string(&deep).unwrap();
+    assert_eq!((s.as_str(), de::from_str(&s).unwrap()), ("(((nil)))", deep));
+}
+
+#[test]
+fn test_serde_errors() {
+    assert_eq!(
+        de::from_str::<Server>("(:host 1 :port 1 :use-tls t :tags nil)").unwrap_err(),
+        LprpError::TypeError { expected: "string".to_string(), found: "integer".to_string() });
+    assert_eq!(
+        de::from_str::<u8>("300").unwrap_err(),
+        LprpError::TypeError { expected: "u8".to_string(), found: "integer 300".to_string() });
+    assert!(matches!(de::from_str::<Server>("(:host \"h\")"), Err(LprpError::SerdeError(_))));
+    assert!(matches!(de::from_str::<Shape>("(hexagon 6)"), Err(LprpError::SerdeError(_))));
+    assert!(matches!(ser::to_string("say \"hi\""), Err(LprpError::SerdeError(_))));
+    assert!(ser::to_token(&u64::MAX).is_err());
+}
@@ -0,0 +1,196 @@
+use lprp::eval::Interp;
+use lprp::reader::read_all;
+
+// Run every form of `src` with both the tree-walking evaluator and the
+// bytecode VM, each on a fresh machine, and compare the results form
+// by form.
+fn check(src: &str) {
+    let forms = read_all(src).unwrap();
+    let mut eval = Interp::new();
+    let mut vm = Interp::new();
+    for tk in &forms {
+        let expected = eval.eval(tk).map(|v| v.to_string());
+        let got = vm.exec(tk).map(|v| v.to_string());
+        assert_eq!(got, expected, "{:?}\nin {}", tk, src);
+    }
+}
+
+#[test]
+fn test_vm_basics() {
+    check("(+ 1 2) (- 10 4 3) (* 2 3.5) (/ 7 2) (/ 1 0)");
+    check("(if t 1 2) (if nil 1) (if nil 1 2) (if) (if 1 2 3 4)");
+    check("(and) (and 1 2 3) (and 1 nil 3) (or) (or nil nil) (or nil 2 3)");
+    check("(progn) (progn 1 2 3) (quote a) (quote) :key");
+    check("undefined (1 2) (car 1) (car '(1 2)) (cdr nil)");
+    check("(cond ((= 1 2) 'a) ((< 1 2) 'b)) (cond) (when t 1 2) (unless t 1) (let ((a 1)) a)");
+    check("(let* ((a 1) (b (+ a 1))) (list a b)) (let (a) a) (let ((a 1 2)) a) (let)");
+}
+
+#[test]
+fn test_vm_functions() {
+    check("(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1))))) (fact 20) fact");
+    check("((lambda (x y) (list y x)) 1 2) ((lambda (x) x)) ((lambda (x) x) 1 2)");
+    check("((lambda (a &rest r) (list a r)) 1 2 3) ((lambda (&rest r) r))");
+    check("(lambda (1) 1) (lambda (&rest) 1) (lambda (&rest a b) 1) (lambda x x) (defun f)");
+    check("(defun f (x) x) (f 1) (defun g (x 1) x) (g 1) (lambda) ((lambda))");
+    check("((lambda (x x) x) 1 2) ((lambda (x &rest x) x) 1 2) (defun d (a b a) a) (d 1 2 3)");
+    check("(funcall (lambda (x) (* x x)) 5) (apply + 1 2 '(3 4)) (apply (lambda (&rest r) r) '(1 2))");
+    check("(defun compose (f g) (lambda (x) (funcall f (funcall g x))))
+           (funcall (compose car cdr) '(1 2 3))
+           (mapcar 1 2)");
+    check("(defun sum (n) (if (= n 0) 0 (+ n (sum (- n 1))))) (sum 10000)");
+    check("(defun loop (n acc) (if (= n 0) acc (loop (- n 1) (+ acc n)))) (loop 100000 0)");
+    check("(eval '(+ 1 2)) (eval '(defun h () 'h)) (h) (functionp h) (functionp car)");
+    check("(defun f (x) (match x ((a . b) (list b a)) (('k &key :v v) :when v v) (_ 'other)))
+           (f '(1 2)) (f '(k :v 3)) (f '(k :w 3)) (f 1) (f '(k :v))");
+    check("(match '(1 1) ((x x) x)) (match) (match 1 2) (match 1 ((a &rest) a)) (match 1 (x :when))");
+    check("(defun g (cdr) (match cdr (x x))) (g 5) (let ((apply 1)) (match '(1 2) ((a b) b)))");
+}
+
+#[test]
+fn test_vm_variables() {
+    check("(defvar x 1) x (setq x 2) x (setq y 1) (setq x 1 x) (setq 1 2) (setq)");
+    check("(defun counter () (let ((n 0)) (lambda () (setq n (+ n 1)))))
+           (defvar c (counter)) (c) (c) (funcall c)");
+    check("(defvar y 'global)
+           (defun f (x) (defvar before y) (when x (defvar y 'local)) (list before y))
+           (f nil) (f t) y");
+    check("(defun outer ()
+             (defun inner () 'inner)
+             (defvar v 1)
+             (list (inner) v))
+           (outer) (inner) v");
+    check("(defun f () (setq z 1)) (f) (defvar z 0) (f) z");
+    check("(defun f (x) (let ((g (lambda () x))) (defvar x 10) (list x (funcall g)))) (f 1)");
+    check("(defun f () (let ((g (lambda () w))) (list (funcall g) (progn (defvar w 2) (funcall g)))))
+           (defvar w 1) (f)");
+    check("(let ((a 1)) (defun get-a () a) (defun set-a (v) (setq a v))) (set-a 5) (get-a)");
+}
+
+#[test]
+fn test_vm_call_cc() {
+    check("(+ 1 (call/cc (lambda (k) (+ 10 (k 5)))))");
+    check("(defvar saved nil)
+           (defvar n 0)
+           (list (call/cc (lambda (k) (setq saved k) 0)))
+           (setq n (+ n 1))
+           (if (< n 3) (saved n) n)");
+    check("(let ((k nil) (n 0))
+             (call/cc (lambda (c) (setq k c)))
+             (setq n (+ n 1))
+             (if (< n 5) (k nil) n))");
+    check("(defun walk (l f)
+             (when l (f (car l)) (walk (cdr l) f)))
+           (defun make-generator (l)
+             (let ((return nil) (resume nil))
+               (lambda ()
+                 (call/cc
+                   (lambda (r)
+                     (setq return r)
+                     (if resume
+                         (resume nil)
+                         (progn
+                           (walk l (lambda (x)
+                                     (call/cc (lambda (k)
+                                                (setq resume k)
+                                                (return x)))))
+                           (return 'done))))))))
+           (defvar g (make-generator '(1 2 3)))
+           (list (g) (g) (g) (g))");
+    check("(defvar trace nil)
+           (defun note (x) (setq trace (cons x trace)))
+           (let ((k nil) (n 0))
+             (dynamic-wind (lambda () (note 'in))
+                           (lambda () (call/cc (lambda (c) (setq k c))))
+                           (lambda () (note 'out)))
+             (setq n (+ n 1))
+             (if (< n 3) (k nil))
+             trace)");
+    check("(call/cc (lambda (k) (dynamic-wind (lambda () 1) (lambda () (k 'escaped)) (lambda () 3))))");
+    check("(call/cc 1) (call/cc (lambda (k) (k 1 2)))");
+}
+
+#[test]
+fn test_vm_conditions() {
+    check("(handler-case (/ 1 0) (division-by-zero (c) (condition-type c)))");
+    check("(handler-case (car 1) (arithmetic-error () 'no) (error (c) (condition-message c)))");
+    check("(handler-case (error \"boom\" 1 2) (error (c) (condition-irritants c)))");
+    check("(handler-case (+ 1 2) (error () 'no)) (handler-case) (handler-case 1 (x))");
+    check("(defvar log nil)
+           (handler-case (unwind-protect (error \"x\") (setq log 'cleaned)) (error () log))
+           log");
+    check("(define-condition my-error (error)) (define-condition my-note ())
+           (handler-case (error 'my-error \"mine\") (error (c) (condition-type c)))
+           (handler-case (signal 'my-note \"note\") (my-note () 'noted))
+           (signal 'my-note \"note\") (define-condition 1 2)");
+    check("(defvar seen nil)
+           (handler-bind ((error (lambda (c) (setq seen (condition-message c)))))
+             (handler-case (error \"inner\") (error () 'handled)))
+           (handler-case
+             (handler-bind ((error (lambda (c) (setq seen 'bound))))
+               (error \"outer\"))
+             (error () (list 'caught seen)))");
+    check("(ignore-errors (car 1)) (ignore-errors 1 2) (error \"top\" 'x) (unwind-protect 1 2)");
+    check("(defun safe-div (a b) (handler-case (/ a b) (division-by-zero () 'inf)))
+           (list (safe-div 4 2) (safe-div 1 0))");
+    check("(defun f (list) (handler-case (car list) (error () 'bad))) (f 1)
+           (let ((dynamic-wind 1)) (unwind-protect dynamic-wind 2))");
+}
+
+// ***** Random programs *****
+// A small linear congruential generator keeps the test deterministic.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+const VARS: &[&str] = &["a", "b", "c", "f", "undefined"];
+
+fn gen(rng: &mut Rng, depth: u32) -> String {
+    if depth == 0 {
+        return match rng.next(6) {
+            0|1 => format!("{}", rng.next(10) as i64 - 3),
+            2 => "nil".to_string(),
+            3 => "'(1 2)".to_string(),
+            _ => VARS[rng.next(VARS.len() as u64) as usize].to_string(),
+        };
+    }
+    let d = depth - 1;
+    match rng.next(18) {
+        0 => format!("(+ {} {})", gen(rng, d), gen(rng, d)),
+        1 => format!("(* {} {})", gen(rng, d), gen(rng, d)),
+        2 => format!("(/ {} {})", gen(rng, d), gen(rng, d)),
+        3 => format!("(if {} {} {})", gen(rng, d), gen(rng, d), gen(rng, d)),
+        4 => format!("(and {} {})", gen(rng, d), gen(rng, d)),
+        5 => format!("(or {} {})", gen(rng, d), gen(rng, d)),
+        6 => format!("(let ((a {}) (b {})) {})", gen(rng, d), gen(rng, d), gen(rng, d)),
+        7 => format!("(lambda (a) {})", gen(rng, d)),
+        8 => format!("(funcall {} {})", gen(rng, d), gen(rng, d)),
+        9 => format!("(setq {} {})", VARS[rng.next(4) as usize], gen(rng, d)),
+        10 => format!("(defvar {} {})", VARS[rng.next(4) as usize], gen(rng, d)),
+        11 => format!("(handler-case {} (error (e) {}))", gen(rng, d), gen(rng, d)),
+        12 => format!("(cons {} {})", gen(rng, d), gen(rng, d)),
+        13 => format!("(car {})", gen(rng, d)),
+        14 => format!("(progn {} {})", gen(rng, d), gen(rng, d)),
+        15 => format!("(call/cc (lambda (k) {} (k {})))", gen(rng, d), gen(rng, d)),
+        16 => format!("(unwind-protect {} (setq c {}))", gen(rng, d), gen(rng, d)),
+        _ => format!("({} {})", gen(rng, d), gen(rng, d)),
+    }
+}
+
+#[test]
+fn test_vm_random_programs() {
+    let mut rng = Rng(42);
+    for _ in 0..500 {
+        let mut src = "(defvar a 1) (defvar b 2) (defvar c 3) (defvar f (lambda (x) x))".to_string();
+        for _ in 0..4 {
+            src.push(' ');
+            src.push_str(&gen(&mut rng, 4));
+        }
+        check(&src);
+    }
+}